@@ -164,9 +164,32 @@ pub async fn sign_message<R: Runtime>(
     message: &solana_message::Message,
     key_id: Ed25519KeyId,
     derivation_path: Option<&DerivationPath>,
+) -> Result<solana_signature::Signature, (RejectCode, String)> {
+    sign_serialized_message(runtime, message.serialize(), key_id, derivation_path).await
+}
+
+/// Sign a versioned Solana message (e.g. a `v0` message using address lookup tables) with
+/// threshold EdDSA.
+///
+/// This behaves like [`sign_message`], except that the signed bytes are the serialization of the
+/// [`VersionedMessage`](solana_message::VersionedMessage), which includes the version prefix.
+pub async fn sign_versioned_message<R: Runtime>(
+    runtime: &R,
+    message: &solana_message::VersionedMessage,
+    key_id: Ed25519KeyId,
+    derivation_path: Option<&DerivationPath>,
+) -> Result<solana_signature::Signature, (RejectCode, String)> {
+    sign_serialized_message(runtime, message.serialize(), key_id, derivation_path).await
+}
+
+async fn sign_serialized_message<R: Runtime>(
+    runtime: &R,
+    message: Vec<u8>,
+    key_id: Ed25519KeyId,
+    derivation_path: Option<&DerivationPath>,
 ) -> Result<solana_signature::Signature, (RejectCode, String)> {
     let arg = SignWithSchnorrArgument {
-        message: message.serialize(),
+        message,
         derivation_path: derivation_path.cloned().unwrap_or_default().into(),
         key_id: SchnorrKeyId {
             algorithm: SchnorrAlgorithm::Ed25519,
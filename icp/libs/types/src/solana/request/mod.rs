@@ -435,6 +435,22 @@ impl TryFrom<solana_transaction::Transaction> for SendTransactionParams {
     }
 }
 
+impl TryFrom<solana_transaction::versioned::VersionedTransaction> for SendTransactionParams {
+    type Error = RpcError;
+
+    fn try_from(
+        transaction: solana_transaction::versioned::VersionedTransaction,
+    ) -> Result<Self, RpcError> {
+        let serialized = bincode::serialize(&transaction).map_err(|e| {
+            RpcError::ValidationError(format!("Transaction serialization failed: {e}"))
+        })?;
+        Ok(Self::from_encoded_transaction(
+            BASE64_STANDARD.encode(serialized),
+            SendTransactionEncoding::Base64,
+        ))
+    }
+}
+
 /// The encoding format for the transaction argument to the Solana
 /// [`sendTransaction`](https://solana.com/docs/rpc/http/sendtransaction) RPC method call.
 #[derive(Clone, Debug, PartialEq, CandidType, Deserialize, Serialize)]
@@ -0,0 +1,99 @@
+// Conversions between raw on-chain amounts and human-readable decimal strings
+use crate::types::Asset;
+
+/// Number of decimal places between an asset's smallest unit and its display unit
+pub fn asset_decimals(asset: &Asset) -> usize {
+    match asset {
+        Asset::Bitcoin => 8, // satoshis
+        Asset::Solana => 9,  // lamports
+        Asset::SplToken { decimals, .. } => *decimals as usize,
+    }
+}
+
+/// Format a raw amount (satoshis/lamports/token atoms) in the asset's display unit
+/// The result is exact, with trailing fractional zeros removed (e.g. 150000000 sats -> "1.5")
+pub fn format_amount(asset: &Asset, raw: u64) -> String {
+    let decimals = asset_decimals(asset);
+    let digits = format!("{:0>width$}", raw, width = decimals + 1);
+    let (whole, fraction) = digits.split_at(digits.len() - decimals);
+    let fraction = fraction.trim_end_matches('0');
+
+    if fraction.is_empty() {
+        whole.to_string()
+    } else {
+        format!("{}.{}", whole, fraction)
+    }
+}
+
+/// Parse a display-unit amount (e.g. "0.0015" BTC) into the asset's smallest unit
+/// Amounts are never rounded: digits beyond the asset's precision are only accepted if they are zero
+pub fn parse_amount(asset: &Asset, human: &str) -> Result<u64, String> {
+    let decimals = asset_decimals(asset);
+    let human = human.trim();
+    let (whole, fraction) = human.split_once('.').unwrap_or((human, ""));
+
+    let is_digits = |part: &str| part.chars().all(|c| c.is_ascii_digit());
+    if (whole.is_empty() && fraction.is_empty()) || !is_digits(whole) || !is_digits(fraction) {
+        return Err(format!("Invalid amount: {}", human));
+    }
+
+    let fraction = if fraction.len() > decimals {
+        if fraction[decimals..].chars().any(|c| c != '0') {
+            return Err(format!(
+                "Amount {} has more than {} decimal places",
+                human, decimals
+            ));
+        }
+        &fraction[..decimals]
+    } else {
+        fraction
+    };
+
+    format!("{}{:0<width$}", whole, fraction, width = decimals)
+        .parse::<u64>()
+        .map_err(|_| format!("Amount too large: {}", human))
+}
+
+/// Amount of an order leg given as a raw amount, a display-unit string, or both
+/// A raw amount of 0 counts as not given; when both are given they must agree exactly,
+/// which catches SPL amounts scaled by the wrong number of decimals
+pub fn resolve_amount(asset: &Asset, raw: u64, human: Option<&str>) -> Result<u64, String> {
+    let Some(human) = human else {
+        return Ok(raw);
+    };
+
+    let parsed = parse_amount(asset, human)?;
+    if raw != 0 && raw != parsed {
+        return Err(format!(
+            "Amount mismatch: {} with {} decimals is {} in the smallest unit, not {}",
+            human.trim(),
+            asset_decimals(asset),
+            parsed,
+            raw
+        ));
+    }
+    Ok(parsed)
+}
+
+/// Add two amounts, failing with `AmountOverflow` instead of wrapping or panicking
+pub fn checked_add(a: u64, b: u64) -> Result<u64, String> {
+    a.checked_add(b)
+        .ok_or_else(|| format!("AmountOverflow: {} + {} exceeds u64", a, b))
+}
+
+/// Subtract `b` from `a`, failing with `AmountOverflow` if the result would be negative
+pub fn checked_sub(a: u64, b: u64) -> Result<u64, String> {
+    a.checked_sub(b)
+        .ok_or_else(|| format!("AmountOverflow: {} - {} is negative", a, b))
+}
+
+/// Multiply two amounts, failing with `AmountOverflow` instead of wrapping or panicking
+pub fn checked_mul(a: u64, b: u64) -> Result<u64, String> {
+    a.checked_mul(b)
+        .ok_or_else(|| format!("AmountOverflow: {} * {} exceeds u64", a, b))
+}
+
+/// Sum amounts (e.g. UTXO values), failing with `AmountOverflow` if the total exceeds u64
+pub fn checked_sum(amounts: impl IntoIterator<Item = u64>) -> Result<u64, String> {
+    amounts.into_iter().try_fold(0, checked_add)
+}
@@ -2,15 +2,27 @@
 // It includes UTXO selection algorithms, transaction building, fee estimation, and
 // BIP-32 derivation path handling used across all Bitcoin address types.
 
+use crate::amounts::{checked_add, checked_mul, checked_sum};
 use crate::basic_bitcoin::BitcoinContext;
+use crate::storage::read_config;
 use bitcoin::{
     self, absolute::LockTime, blockdata::witness::Witness, hashes::Hash, transaction::Version,
-    Address, Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid,
+    Address, AddressType, Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid,
 };
 use ic_cdk::bitcoin_canister::{
-    bitcoin_get_current_fee_percentiles, GetCurrentFeePercentilesRequest, Utxo,
+    bitcoin_get_current_fee_percentiles, bitcoin_send_transaction, GetCurrentFeePercentilesRequest,
+    Network, SendTransactionRequest, Utxo,
 };
+use ic_cdk::call::{Error as CallError, RejectCode};
+use ic_cdk::management_canister::{
+    http_request, HttpMethod, HttpRequestArgs, HttpRequestResult, TransformArgs, TransformContext,
+};
+use std::cell::{Cell, RefCell};
 use std::fmt;
+use std::future::Future;
+use std::rc::Rc;
+use std::task::{Poll, Waker};
+use std::time::Duration;
 
 /// Selects UTXOs using a greedy algorithm to cover the required amount plus fee.
 ///
@@ -18,27 +30,29 @@ use std::fmt;
 /// them until the total value covers the payment amount plus transaction fee.
 /// This approach helps consolidate older UTXOs and can reduce wallet fragmentation.
 ///
-/// Returns an error if the total UTXO value is insufficient to cover the payment and fee.
+/// Returns an error if the total UTXO value is insufficient to cover the payment and fee,
+/// or an `AmountOverflow` error if the amounts being added up exceed u64.
 pub fn select_utxos_greedy(
     own_utxos: &[Utxo],
     amount: u64,
     fee: u64,
 ) -> Result<Vec<&Utxo>, String> {
     // Greedily select UTXOs in reverse order (oldest last) until we cover amount + fee.
+    let required = checked_add(amount, fee)?;
     let mut utxos_to_spend = vec![];
     let mut total_spent = 0;
     for utxo in own_utxos.iter().rev() {
-        total_spent += utxo.value;
+        total_spent = checked_add(total_spent, utxo.value)?;
         utxos_to_spend.push(utxo);
-        if total_spent >= amount + fee {
+        if total_spent >= required {
             break;
         }
     }
 
     // Abort if we can't cover the payment + fee.
-    if total_spent < amount + fee {
+    if total_spent < required {
         return Err(format!(
-            "Insufficient balance: {}, trying to transfer {} satoshi with fee {}",
+            "InsufficientBalance: {}, trying to transfer {} satoshi with fee {}",
             total_spent, amount, fee
         ));
     }
@@ -46,6 +60,63 @@ pub fn select_utxos_greedy(
     Ok(utxos_to_spend)
 }
 
+/// Selects the smallest UTXOs to merge into a single output, and the fee for doing so.
+///
+/// Up to `max_inputs` UTXOs are taken in increasing order of value, since small UTXOs
+/// are the ones that become uneconomical to spend as fees rise. The fee is estimated
+/// from the virtual size of a P2WPKH transaction with one output:
+/// 11 vbytes of overhead, 68 per input and 31 for the output.
+///
+/// Returns an error if fewer than two UTXOs would be merged, or if the fee would
+/// consume the whole consolidated value.
+pub fn select_consolidation_utxos(
+    own_utxos: &[Utxo],
+    max_inputs: usize,
+    fee_per_vbyte: u64,
+) -> Result<(Vec<&Utxo>, u64), String> {
+    let mut utxos_to_spend: Vec<&Utxo> = own_utxos.iter().collect();
+    utxos_to_spend.sort_by_key(|utxo| utxo.value);
+    utxos_to_spend.truncate(max_inputs);
+
+    if utxos_to_spend.len() < 2 {
+        return Err("Need at least two UTXOs to consolidate".to_string());
+    }
+
+    let vsize = 11 + 68 * utxos_to_spend.len() as u64 + 31;
+    let fee = checked_mul(vsize, fee_per_vbyte)? / 1000;
+    let total = checked_sum(utxos_to_spend.iter().map(|utxo| utxo.value))?;
+
+    if fee >= total {
+        return Err(format!(
+            "Consolidation fee of {} satoshi exceeds the consolidated value of {} satoshi",
+            fee, total
+        ));
+    }
+
+    Ok((utxos_to_spend, fee))
+}
+
+/// Computes the largest amount a payout spending every UTXO can send, and its fee.
+///
+/// The payout has no change output, so its virtual size is 11 vbytes of overhead,
+/// 68 per input and 43 for the output (P2TR, the largest common output type).
+///
+/// Returns an `InsufficientBalance` error if the fee would consume all of the UTXOs.
+pub fn max_payout_amount(own_utxos: &[Utxo], fee_per_vbyte: u64) -> Result<(u64, u64), String> {
+    let vsize = 11 + 68 * own_utxos.len() as u64 + 43;
+    let fee = checked_mul(vsize, fee_per_vbyte)? / 1000;
+    let total = checked_sum(own_utxos.iter().map(|utxo| utxo.value))?;
+
+    if fee >= total {
+        return Err(format!(
+            "InsufficientBalance: a fee of {} satoshi exceeds the {} satoshi held",
+            fee, total
+        ));
+    }
+
+    Ok((total - fee, fee))
+}
+
 /// Selects a single UTXO that can cover the required amount plus fee.
 ///
 /// This function is used when you need to tie a specific operation to a single UTXO,
@@ -54,8 +125,9 @@ pub fn select_utxos_greedy(
 ///
 /// Returns an error if no single UTXO has enough value to cover the payment and fee.
 pub fn select_one_utxo(own_utxos: &[Utxo], amount: u64, fee: u64) -> Result<Vec<&Utxo>, String> {
+    let required = checked_add(amount, fee)?;
     for utxo in own_utxos.iter().rev() {
-        if utxo.value >= amount + fee {
+        if utxo.value >= required {
             return Ok(vec![&utxo]);
         }
     }
@@ -66,6 +138,176 @@ pub fn select_one_utxo(own_utxos: &[Utxo], amount: u64, fee: u64) -> Result<Vec<
     ))
 }
 
+/// Returns the outpoints (txid bytes, vout) a transaction spends, keyed like `Utxo::outpoint`.
+///
+/// `build_transaction_with_fee` keeps the raw UTXO txid bytes, so these match the UTXOs
+/// the inputs were built from.
+pub fn spent_outpoints(transaction: &Transaction) -> Vec<(Vec<u8>, u32)> {
+    transaction
+        .input
+        .iter()
+        .map(|input| {
+            (
+                input.previous_output.txid.to_byte_array().to_vec(),
+                input.previous_output.vout,
+            )
+        })
+        .collect()
+}
+
+/// Checks that a payout address locks funds to a script type a wallet can spend.
+///
+/// Future witness versions parse fine but nothing can spend them yet, and pay-to-anchor
+/// outputs can be spent by anyone, so sending to either would lose the funds.
+pub fn check_supported_script_type(address: &Address) -> Result<(), String> {
+    match address.address_type() {
+        Some(
+            AddressType::P2pkh
+            | AddressType::P2sh
+            | AddressType::P2wpkh
+            | AddressType::P2wsh
+            | AddressType::P2tr,
+        ) => Ok(()),
+        _ => Err(format!(
+            "Unsupported Bitcoin address type: {} does not pay a standard script",
+            address
+        )),
+    }
+}
+
+/// Broadcast attempts made before a transient failure is given up on.
+pub const BROADCAST_MAX_ATTEMPTS: u32 = 3;
+/// Wait before the first broadcast retry; doubled before each further one.
+pub const BROADCAST_INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+
+/// A failed broadcast, split by whether sending the same transaction again can help.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BroadcastError {
+    /// The Bitcoin canister was rate limiting or temporarily unavailable.
+    Transient(String),
+    /// The transaction itself was rejected, e.g. malformed or spending missing inputs.
+    Permanent(String),
+}
+
+impl BroadcastError {
+    /// System-transient rejects cover rate limiting and full queues, and a call that could not
+    /// be made never reached the canister. Canister rejects, e.g. of a malformed transaction,
+    /// would fail the same way again.
+    fn classify(error: CallError) -> Self {
+        let transient = match &error {
+            CallError::CallRejected(rejected) => matches!(
+                rejected.reject_code(),
+                Ok(RejectCode::SysTransient | RejectCode::SysUnknown)
+            ),
+            CallError::CallPerformFailed(_) => true,
+            _ => false,
+        };
+        let message = format!("{:?}", error);
+        if transient {
+            BroadcastError::Transient(message)
+        } else {
+            BroadcastError::Permanent(message)
+        }
+    }
+}
+
+impl fmt::Display for BroadcastError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BroadcastError::Transient(e) => write!(f, "Failed to broadcast transaction: {}", e),
+            BroadcastError::Permanent(e) => {
+                write!(f, "Transaction rejected by the Bitcoin canister: {}", e)
+            }
+        }
+    }
+}
+
+/// Sends a signed transaction to the Bitcoin canister, retrying transient failures with backoff.
+///
+/// Every attempt sends the same bytes, so a retry after an attempt that did reach the
+/// network is harmless.
+pub async fn broadcast_transaction(
+    network: Network,
+    transaction: Vec<u8>,
+) -> Result<(), BroadcastError> {
+    retry_broadcast(
+        BROADCAST_MAX_ATTEMPTS,
+        BROADCAST_INITIAL_BACKOFF,
+        || {
+            let request = SendTransactionRequest {
+                network,
+                transaction: transaction.clone(),
+            };
+            async move {
+                bitcoin_send_transaction(&request)
+                    .await
+                    .map_err(BroadcastError::classify)
+            }
+        },
+        sleep,
+    )
+    .await
+}
+
+/// Calls `send` up to `max_attempts` times, waiting `backoff` before the first retry and twice
+/// as long before each further one. Permanent failures are returned right away.
+pub async fn retry_broadcast<F, Fut, S, SFut>(
+    max_attempts: u32,
+    backoff: Duration,
+    mut send: F,
+    mut sleep: S,
+) -> Result<(), BroadcastError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), BroadcastError>>,
+    S: FnMut(Duration) -> SFut,
+    SFut: Future<Output = ()>,
+{
+    let mut attempt = 1;
+    let mut delay = backoff;
+    loop {
+        match send().await {
+            Ok(()) => return Ok(()),
+            Err(BroadcastError::Transient(e)) if attempt < max_attempts => {
+                ic_cdk::println!(
+                    "⚠️ Broadcast attempt {} failed, retrying in {:?}: {}",
+                    attempt,
+                    delay,
+                    e
+                );
+                sleep(delay).await;
+                delay = delay.saturating_mul(2);
+                attempt += 1;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// Resolves once `delay` has passed, woken by a one-shot timer.
+pub async fn sleep(delay: Duration) {
+    let done = Rc::new(Cell::new(false));
+    let waker: Rc<RefCell<Option<Waker>>> = Rc::new(RefCell::new(None));
+
+    let (timer_done, timer_waker) = (done.clone(), waker.clone());
+    ic_cdk_timers::set_timer(delay, async move {
+        timer_done.set(true);
+        if let Some(waker) = timer_waker.borrow_mut().take() {
+            waker.wake();
+        }
+    });
+
+    std::future::poll_fn(|cx| {
+        if done.get() {
+            Poll::Ready(())
+        } else {
+            *waker.borrow_mut() = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    })
+    .await
+}
+
 /// Represents the primary output type for a Bitcoin transaction.
 ///
 /// This enum allows transaction builders to specify whether they want to send
@@ -150,9 +392,10 @@ pub fn build_transaction_with_fee(
 
     // Calculate change and add change output if above dust threshold.
     // This prevents value loss while avoiding uneconomical outputs.
-    let total_in: u64 = utxos_to_spend.iter().map(|u| u.value).sum();
+    let total_in = checked_sum(utxos_to_spend.iter().map(|u| u.value))?;
+    let total_out = checked_add(checked_sum(outputs.iter().map(|o| o.value.to_sat()))?, fee)?;
     let change = total_in
-        .checked_sub(outputs.iter().map(|o| o.value.to_sat()).sum::<u64>() + fee)
+        .checked_sub(total_out)
         .ok_or("fee exceeds inputs")?;
 
     if change >= DUST_THRESHOLD {
@@ -181,8 +424,9 @@ pub fn build_transaction_with_fee(
 /// the median (50th percentile) fee rate, which provides a good balance between
 /// confirmation time and cost. The fee rate is returned in millisatoshis per byte.
 ///
-/// On regtest networks (local development), fee data is typically unavailable since
-/// there are no standard transactions, so the function falls back to a static rate
+/// When the Bitcoin canister has no estimate (the call fails, or on regtest/quiet testnets
+/// where there are no standard transactions), the rate is fetched from the fee API configured
+/// through `btc_fee_api_url`, if any. Otherwise the function falls back to a static rate
 /// of 2,000 millisatoshis/vbyte (2 sat/vB) which is reasonable for testing.
 ///
 /// # Returns
@@ -190,20 +434,154 @@ pub fn build_transaction_with_fee(
 pub async fn get_fee_per_byte(ctx: &BitcoinContext) -> u64 {
     // Query recent fee percentiles from the Bitcoin network.
     // This gives us real-time fee data based on recent transaction activity.
-    let fee_percentiles = bitcoin_get_current_fee_percentiles(&GetCurrentFeePercentilesRequest {
+    // Use the 50th percentile (median) for balanced confirmation time and cost.
+    // This avoids both overpaying (high percentiles) and slow confirmation (low percentiles).
+    let native_fee = bitcoin_get_current_fee_percentiles(&GetCurrentFeePercentilesRequest {
         network: ctx.network,
     })
     .await
-    .unwrap();
-
-    if fee_percentiles.is_empty() {
-        // Empty percentiles indicate that we're likely on regtest with no standard transactions.
-        // Use a reasonable fallback that works for development and testing.
-        2000 // 2 sat/vB in millisatoshis
-    } else {
-        // Use the 50th percentile (median) for balanced confirmation time and cost.
-        // This avoids both overpaying (high percentiles) and slow confirmation (low percentiles).
-        fee_percentiles[50]
+    .ok()
+    .and_then(|fee_percentiles| fee_percentiles.get(50).copied());
+
+    let fee_api_url = read_config(|config| config.btc_fee_api_url.clone());
+    fallback_fee_per_byte(native_fee, fee_api_url, fetch_api_fee_per_byte).await
+}
+
+/// Static fee rate used when neither the Bitcoin canister nor the fee API has an estimate.
+const FALLBACK_FEE_PER_BYTE: u64 = 2_000; // 2 sat/vB in millisatoshis
+
+/// Prefers the Bitcoin canister's estimate, only calling the fee API when it is missing.
+pub async fn fallback_fee_per_byte<F, Fut>(
+    native_fee: Option<u64>,
+    fee_api_url: Option<String>,
+    fetch_api_fee: F,
+) -> u64
+where
+    F: FnOnce(String) -> Fut,
+    Fut: Future<Output = Result<u64, String>>,
+{
+    if let Some(fee_per_byte) = native_fee {
+        return fee_per_byte;
+    }
+    let Some(url) = fee_api_url else {
+        return FALLBACK_FEE_PER_BYTE;
+    };
+    match fetch_api_fee(url).await {
+        Ok(fee_per_byte) => fee_per_byte,
+        Err(e) => {
+            ic_cdk::println!("⚠️ Fee API unavailable, using static fee rate: {}", e);
+            FALLBACK_FEE_PER_BYTE
+        }
+    }
+}
+
+/// Fetches the recommended fee rate from a mempool.space style `/fees/recommended` endpoint.
+///
+/// The response goes through [`transform_fee_response`] so every replica sees the same bytes.
+async fn fetch_api_fee_per_byte(url: String) -> Result<u64, String> {
+    let request = HttpRequestArgs {
+        url,
+        max_response_bytes: Some(FEE_API_MAX_RESPONSE_BYTES),
+        method: HttpMethod::GET,
+        transform: Some(TransformContext::from_name(
+            "transform_fee_response".to_string(),
+            vec![],
+        )),
+        ..Default::default()
+    };
+
+    let response = http_request(&request)
+        .await
+        .map_err(|e| format!("Fee API request failed: {:?}", e))?;
+    if response.status != candid::Nat::from(200u32) {
+        return Err(format!("Fee API returned status {}", response.status));
+    }
+    parse_recommended_fee(&response.body)
+}
+
+const FEE_API_MAX_RESPONSE_BYTES: u64 = 2_048;
+
+/// Reads the half-hour fee (sat/vB) from a `/fees/recommended` body, in millisatoshis per byte.
+pub fn parse_recommended_fee(body: &[u8]) -> Result<u64, String> {
+    let fees: serde_json::Value =
+        serde_json::from_slice(body).map_err(|e| format!("Invalid fee API response: {}", e))?;
+    let sat_per_vbyte = fees
+        .get("halfHourFee")
+        .and_then(serde_json::Value::as_u64)
+        .ok_or("Fee API response has no halfHourFee")?;
+    Ok(sat_per_vbyte.saturating_mul(1_000))
+}
+
+/// Strips a fee API response down to the status and the half-hour fee.
+///
+/// Replicas must agree on the transformed response, so headers (dates, request ids) are dropped
+/// and the body is re-serialized with only the field the canister reads.
+pub fn transform_fee_response(args: TransformArgs) -> HttpRequestResult {
+    let response = args.response;
+    let body = match parse_recommended_fee(&response.body) {
+        Ok(fee_per_byte) => format!("{{\"halfHourFee\":{}}}", fee_per_byte / 1_000).into_bytes(),
+        Err(_) => Vec::new(),
+    };
+    HttpRequestResult {
+        status: response.status,
+        headers: vec![],
+        body,
+    }
+}
+
+/// Returns the fee rate Bitcoin sends should use.
+///
+/// Operators can pin the rate through `btc_fee_per_byte_override` (e.g. during fee spikes or
+/// for testing); otherwise the median network rate from [`get_fee_per_byte`] is used.
+/// Either way the rate is clamped to `btc_min_fee_per_byte`/`btc_max_fee_per_byte`, since
+/// testnet estimates can be zero and a zero-fee transaction is never relayed.
+///
+/// # Returns
+/// Fee rate in millisatoshis per byte (1,000 msat = 1 satoshi).
+pub async fn get_effective_fee_per_byte(ctx: &BitcoinContext) -> u64 {
+    let (fee_override, min_fee, max_fee) = read_config(|config| {
+        (
+            config.btc_fee_per_byte_override,
+            config.btc_min_fee_per_byte,
+            config.btc_max_fee_per_byte,
+        )
+    });
+    let fee_per_byte = resolve_fee_per_byte(fee_override, || get_fee_per_byte(ctx)).await;
+    clamp_fee_per_byte(fee_per_byte, min_fee, max_fee)
+}
+
+/// Keeps a fee rate within `[min_fee, max_fee]`; the floor wins if the bounds cross.
+pub fn clamp_fee_per_byte(fee_per_byte: u64, min_fee: u64, max_fee: u64) -> u64 {
+    fee_per_byte.min(max_fee).max(min_fee)
+}
+
+/// Lowest fee rate nodes relay by default (1 sat/vB), in millisatoshis per byte.
+const MIN_RELAY_FEE_PER_BYTE: u64 = 1_000;
+
+/// Rejects a transaction whose total fee is below the relay minimum for its virtual size.
+///
+/// Such a transaction would be accepted by the Bitcoin canister but never propagate, leaving its
+/// inputs stuck until it is dropped.
+pub fn check_relay_fee(fee: u64, vsize: u64) -> Result<(), String> {
+    let min_fee = checked_mul(vsize, MIN_RELAY_FEE_PER_BYTE)?.div_ceil(1000);
+    if fee < min_fee {
+        return Err(format!(
+            "FeeTooLow: a fee of {} satoshi is below the {} satoshi relay minimum for {} vbytes",
+            fee, min_fee, vsize
+        ));
+    }
+    Ok(())
+}
+
+/// Picks the override when set, only querying the network estimate when there is none.
+pub async fn resolve_fee_per_byte<F, Fut>(fee_override: Option<u64>, network_fee: F) -> u64
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = u64>,
+{
+    match fee_override {
+        Some(fee_per_byte) => fee_per_byte,
+        None => network_fee().await,
     }
 }
 
@@ -32,7 +32,7 @@ pub struct BitcoinContext {
 // Global, thread-local instance of the Bitcoin context.
 // This is initialized at smart contract init/upgrade time and reused across all API calls.
 thread_local! {
-    static BTC_CONTEXT: Cell<BitcoinContext> = const {
+    pub static BTC_CONTEXT: Cell<BitcoinContext> = const {
         Cell::new(BitcoinContext {
             network: Network::Testnet,
             bitcoin_network: bitcoin::Network::Testnet,
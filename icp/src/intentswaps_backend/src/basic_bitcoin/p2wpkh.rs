@@ -1,5 +1,6 @@
+use crate::amounts::checked_mul;
 use crate::basic_bitcoin::{
-    common::{build_transaction_with_fee, select_utxos_greedy, PrimaryOutput},
+    common::{build_transaction_with_fee, check_relay_fee, select_utxos_greedy, PrimaryOutput},
     ecdsa::mock_sign_with_ecdsa,
     BitcoinContext,
 };
@@ -12,7 +13,9 @@ use bitcoin::{
 use ic_cdk::bitcoin_canister::{MillisatoshiPerByte, Satoshi, Utxo};
 
 // Builds a transaction to send the given `amount` of satoshis to the
-// destination address.
+// destination address. Fails if the UTXOs cannot cover the amount plus
+// fee, if the fee computation overflows, or if the fee rate is too low for
+// the transaction to be relayed.
 pub async fn build_transaction(
     ctx: &BitcoinContext,
     own_public_key: &PublicKey,
@@ -21,7 +24,7 @@ pub async fn build_transaction(
     dst_address: &Address,
     amount: Satoshi,
     fee_per_vbyte: MillisatoshiPerByte,
-) -> (Transaction, Vec<TxOut>) {
+) -> Result<(Transaction, Vec<TxOut>), String> {
     // We have a chicken-and-egg problem where we need to know the length
     // of the transaction in order to compute its proper fee, but we need
     // to know the proper fee in order to figure out the inputs needed for
@@ -32,14 +35,13 @@ pub async fn build_transaction(
     // rebuild the transaction, until the fee is set to the correct amount.
     let mut fee = 0;
     loop {
-        let utxos_to_spend = select_utxos_greedy(own_utxos, amount, fee).unwrap();
+        let utxos_to_spend = select_utxos_greedy(own_utxos, amount, fee)?;
         let (transaction, prevouts) = build_transaction_with_fee(
             utxos_to_spend,
             own_address,
             &PrimaryOutput::Address(dst_address.clone(), amount),
             fee,
-        )
-        .unwrap();
+        )?;
 
         // Sign the transaction. In this case, we only care about the size
         // of the signed transaction, so we use a mock signer here for efficiency.
@@ -55,15 +57,53 @@ pub async fn build_transaction(
         .await;
 
         let tx_vsize = signed_transaction.vsize() as u64;
+        let required_fee = checked_mul(tx_vsize, fee_per_vbyte)? / 1000;
 
-        if (tx_vsize * fee_per_vbyte) / 1000 == fee {
-            return (transaction, prevouts);
+        if required_fee == fee {
+            check_relay_fee(fee, tx_vsize)?;
+            return Ok((transaction, prevouts));
         } else {
-            fee = (tx_vsize * fee_per_vbyte) / 1000;
+            fee = required_fee;
         }
     }
 }
 
+// Virtual size (vbytes) of the signed transaction `build_transaction` would
+// produce for these inputs. Signatures are mocked, so nothing is signed or sent.
+pub async fn estimate_transaction_vsize(
+    ctx: &BitcoinContext,
+    own_public_key: &PublicKey,
+    own_address: &Address,
+    own_utxos: &[Utxo],
+    dst_address: &Address,
+    amount: Satoshi,
+    fee_per_vbyte: MillisatoshiPerByte,
+) -> Result<u64, String> {
+    let (transaction, prevouts) = build_transaction(
+        ctx,
+        own_public_key,
+        own_address,
+        own_utxos,
+        dst_address,
+        amount,
+        fee_per_vbyte,
+    )
+    .await?;
+
+    let signed_transaction = sign_transaction(
+        ctx,
+        own_public_key,
+        own_address,
+        transaction,
+        &prevouts,
+        vec![], // mock derivation path
+        mock_sign_with_ecdsa,
+    )
+    .await;
+
+    Ok(signed_transaction.vsize() as u64)
+}
+
 // Sign a P2WPKH bitcoin transaction.
 //
 // IMPORTANT: This method is for demonstration purposes only and it only
@@ -1,5 +1,5 @@
 use crate::basic_bitcoin::{common::DerivationPath, ecdsa::get_ecdsa_public_key, BTC_CONTEXT};
-use bitcoin::{Address, CompressedPublicKey};
+use bitcoin::{Address, CompressedPublicKey, Network};
 use ic_cdk::update;
 
 /// Returns a native SegWit (P2WPKH) address for this smart contract.
@@ -17,10 +17,19 @@ pub async fn get_p2wpkh_address() -> String {
     // Get the ECDSA public key of this smart contract at the given derivation path
     let public_key = get_ecdsa_public_key(&ctx, derivation_path.to_vec_u8_path()).await;
 
+    p2wpkh_address_from_public_key(&public_key, ctx.bitcoin_network).unwrap()
+}
+
+/// Derives the P2WPKH address of a compressed secp256k1 public key.
+pub fn p2wpkh_address_from_public_key(
+    public_key: &[u8],
+    network: Network,
+) -> Result<String, String> {
     // Create a CompressedPublicKey from the raw public key bytes
-    let public_key = CompressedPublicKey::from_slice(&public_key).unwrap();
+    let public_key = CompressedPublicKey::from_slice(public_key)
+        .map_err(|e| format!("Invalid compressed public key: {}", e))?;
 
     // Generate a P2WPKH Bech32 address.
     // The network (mainnet, testnet, regtest) determines the HRP (e.g., "bc1" or "tb1").
-    Address::p2wpkh(&public_key, ctx.bitcoin_network).to_string()
+    Ok(Address::p2wpkh(&public_key, network).to_string())
 }
@@ -1,34 +1,54 @@
+use crate::amounts::checked_sum;
 use crate::basic_bitcoin::{
-    common::{get_fee_per_byte, DerivationPath},
+    common::{
+        broadcast_transaction, check_supported_script_type, get_effective_fee_per_byte,
+        spent_outpoints, BroadcastError, DerivationPath,
+    },
     ecdsa::{get_ecdsa_public_key, sign_with_ecdsa},
     p2wpkh, SendRequest, BTC_CONTEXT,
 };
+use crate::storage::{
+    consume_txid, invalidate_balance, now_nanos, record_chain_failure, record_chain_success,
+    release_utxos, reserve_utxos, unreserved_utxos,
+};
+use crate::types::Chain;
 use bitcoin::{consensus::serialize, Address, CompressedPublicKey, PublicKey};
 use ic_cdk::{
-    bitcoin_canister::{
-        bitcoin_get_utxos, bitcoin_send_transaction, GetUtxosRequest, SendTransactionRequest,
-        UtxosFilter,
-    },
+    bitcoin_canister::{bitcoin_get_utxos, GetUtxosRequest, Utxo, UtxosFilter},
     trap, update,
 };
 use std::str::FromStr;
 
 /// Sends the given amount of bitcoin from this smart contract's P2PKH address to the given address.
 /// Returns the transaction ID.
+///
+/// This address holds every order's Bitcoin deposits, so only controllers may send from it.
 #[update]
 pub async fn send_from_p2wpkh_address(request: SendRequest) -> String {
+    if !ic_cdk::api::is_controller(&ic_cdk::api::msg_caller()) {
+        trap("Only controllers can call this method");
+    }
+    try_send_from_p2wpkh_address(request)
+        .await
+        .unwrap_or_else(|e| trap(&e))
+}
+
+/// Like `send_from_p2wpkh_address`, but reports failures as errors instead of trapping,
+/// so a failed payout does not abort the swap call that triggered it.
+pub async fn try_send_from_p2wpkh_address(request: SendRequest) -> Result<String, String> {
     let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
 
     if request.amount_in_satoshi == 0 {
-        trap("Amount must be greater than 0");
+        return Err("Amount must be greater than 0".to_string());
     }
 
     // Parse and validate the destination address. The address type needs to be
     // valid for the Bitcoin network we are on.
     let dst_address = Address::from_str(&request.destination_address)
-        .unwrap()
+        .map_err(|e| format!("Invalid destination address: {}", e))?
         .require_network(ctx.bitcoin_network)
-        .unwrap();
+        .map_err(|e| format!("Destination address is for the wrong network: {}", e))?;
+    check_supported_script_type(&dst_address)?;
 
     // Unique derivation paths are used for every address type generated, to ensure
     // each address has its own unique key pair. To generate a user-specific address,
@@ -47,6 +67,11 @@ pub async fn send_from_p2wpkh_address(request: SendRequest) -> String {
     // Generate a P2WPKH address from the public key
     let own_address = Address::p2wpkh(&own_compressed_public_key, ctx.bitcoin_network);
 
+    // Read the fee rate first: UTXOs are picked and reserved with no call in between, so a
+    // concurrent payout cannot take the same ones
+    let fee_per_byte = get_effective_fee_per_byte(&ctx).await;
+    ic_cdk::println!("💵 Fee per byte: {} millisatoshi", fee_per_byte);
+
     ic_cdk::println!("📦 Fetching UTXOs for canister address: {}", own_address);
 
     // Note that pagination may have to be used to get all UTXOs for the given address.
@@ -59,28 +84,25 @@ pub async fn send_from_p2wpkh_address(request: SendRequest) -> String {
         filter: Some(UtxosFilter::MinConfirmations(0)),
     })
     .await
-    .unwrap();
+    .map_err(|e| {
+        let error = format!("Failed to get UTXOs: {:?}", e);
+        record_chain_failure(&Chain::Bitcoin, &error, now_nanos());
+        error
+    })?;
 
-    let own_utxos = utxo_response.utxos;
-    let total_balance: u64 = own_utxos.iter().map(|u| u.value).sum();
+    let own_utxos = unreserved_utxos(utxo_response.utxos);
+    let total_balance = checked_sum(own_utxos.iter().map(|u| u.value))?;
 
     ic_cdk::println!("📦 Found {} UTXOs with total balance: {} satoshis", own_utxos.len(), total_balance);
     ic_cdk::println!("💰 Attempting to send: {} satoshis to {}", request.amount_in_satoshi, dst_address);
 
-    if own_utxos.is_empty() {
-        ic_cdk::println!("❌ ERROR: No UTXOs available!");
-        trap("No UTXOs available for spending");
-    }
-
-    if total_balance < request.amount_in_satoshi {
-        ic_cdk::println!("❌ ERROR: Insufficient balance! Have {} satoshis, need {} satoshis", total_balance, request.amount_in_satoshi);
-        trap(&format!("Insufficient balance: have {} sats, need {} sats", total_balance, request.amount_in_satoshi));
+    if let Err(e) = check_spendable(&own_utxos, request.amount_in_satoshi) {
+        ic_cdk::println!("❌ ERROR: {}", e);
+        return Err(e);
     }
 
     // Build the transaction that sends `amount` to the destination address.
     ic_cdk::println!("🔨 Building transaction...");
-    let fee_per_byte = get_fee_per_byte(&ctx).await;
-    ic_cdk::println!("💵 Fee per byte: {} millisatoshi", fee_per_byte);
     let (transaction, prevouts) = p2wpkh::build_transaction(
         &ctx,
         &own_public_key,
@@ -90,7 +112,9 @@ pub async fn send_from_p2wpkh_address(request: SendRequest) -> String {
         request.amount_in_satoshi,
         fee_per_byte,
     )
-    .await;
+    .await?;
+    let outpoints = spent_outpoints(&transaction);
+    reserve_utxos(&outpoints);
 
     ic_cdk::println!("✍️ Signing transaction...");
 
@@ -108,27 +132,50 @@ pub async fn send_from_p2wpkh_address(request: SendRequest) -> String {
 
     let txid = signed_transaction.compute_txid().to_string();
     let serialized_tx = serialize(&signed_transaction);
+    // The change comes back to this address, so the transaction must never count as a deposit
+    consume_txid(&txid);
     
     ic_cdk::println!("📤 Broadcasting transaction {} to Bitcoin network...", txid);
     ic_cdk::println!("📊 Transaction size: {} bytes", serialized_tx.len());
 
-    // Send the transaction to the Bitcoin API.
-    let send_result = bitcoin_send_transaction(&SendTransactionRequest {
-        network: ctx.network,
-        transaction: serialized_tx,
-    })
-    .await;
-
-    match send_result {
+    // Send the transaction to the Bitcoin API, retrying transient failures.
+    match broadcast_transaction(ctx.network, serialized_tx).await {
         Ok(_) => {
             ic_cdk::println!("✅ Transaction {} broadcast successfully!", txid);
+            record_chain_success(&Chain::Bitcoin);
+            invalidate_balance(&own_address.to_string());
         }
         Err(e) => {
-            ic_cdk::println!("❌ Failed to broadcast transaction {}: {:?}", txid, e);
-            trap(&format!("Failed to broadcast transaction: {:?}", e));
+            ic_cdk::println!("❌ Failed to broadcast transaction {}: {}", txid, e);
+            let error = e.to_string();
+            // A rejected transaction says nothing about the Bitcoin canister's health
+            if matches!(e, BroadcastError::Transient(_)) {
+                record_chain_failure(&Chain::Bitcoin, &error, now_nanos());
+            }
+            // Nothing was spent, so the next payout may use these inputs
+            release_utxos(&outpoints);
+            return Err(error);
         }
     }
 
     // Return the transaction ID.
-    txid
+    Ok(txid)
+}
+
+/// Checks that the canister's UTXOs can cover a payout of `amount` satoshis.
+/// Fees are checked later, when the transaction is built.
+pub fn check_spendable(own_utxos: &[Utxo], amount: u64) -> Result<(), String> {
+    if own_utxos.is_empty() {
+        return Err("InsufficientBalance: no UTXOs available for spending".to_string());
+    }
+
+    let total_balance = checked_sum(own_utxos.iter().map(|u| u.value))?;
+    if total_balance < amount {
+        return Err(format!(
+            "InsufficientBalance: have {} sats, need {} sats",
+            total_balance, amount
+        ));
+    }
+
+    Ok(())
 }
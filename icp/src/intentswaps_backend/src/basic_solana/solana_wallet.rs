@@ -10,10 +10,10 @@ use crate::basic_solana::{
 };
 use candid::Principal;
 use sol_rpc_client::{
-    ed25519::{sign_message, DerivationPath},
+    ed25519::{sign_message, sign_versioned_message, DerivationPath},
     IcRuntime,
 };
-use solana_message::Message;
+use solana_message::{Message, VersionedMessage};
 use solana_pubkey::Pubkey;
 use solana_signature::Signature;
 use std::fmt::Display;
@@ -50,6 +50,17 @@ impl SolanaAccount {
         .await
         .expect("Failed to sign transaction")
     }
+
+    pub async fn sign_versioned_message(&self, message: &VersionedMessage) -> Signature {
+        sign_versioned_message(
+            &IcRuntime,
+            message,
+            read_state(|s| s.ed25519_key_name()).into(),
+            Some(&self.derivation_path),
+        )
+        .await
+        .expect("Failed to sign transaction")
+    }
 }
 
 impl AsRef<Pubkey> for SolanaAccount {
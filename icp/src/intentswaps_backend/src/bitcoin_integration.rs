@@ -1,8 +1,35 @@
 // Integration wrapper for the comprehensive Bitcoin module
+use crate::amounts::{checked_sub, checked_sum};
 use crate::basic_bitcoin::{
-    service::{get_balance, get_p2wpkh_address, get_utxos, send_from_p2wpkh_address},
-    SendRequest,
+    common::{
+        broadcast_transaction, build_transaction_with_fee, check_supported_script_type,
+        get_effective_fee_per_byte, max_payout_amount, select_consolidation_utxos, spent_outpoints,
+        DerivationPath, PrimaryOutput,
+    },
+    ecdsa::{get_ecdsa_public_key, sign_with_ecdsa},
+    p2wpkh,
+    service::{get_balance, get_p2wpkh_address, send_from_p2wpkh_address},
+    SendRequest, BTC_CONTEXT,
 };
+use crate::storage::{
+    check_chain_available, consume_txid, invalidate_balance, now_nanos, read_balance_cached,
+    record_chain_failure, record_chain_success, release_utxos, reserve_utxos, unreserved_utxos,
+};
+use crate::types::{BitcoinDepositProof, Chain, VerificationResult};
+use bitcoin::{
+    block::Header,
+    consensus::{deserialize, serialize},
+    hashes::Hash,
+    Address, CompressedPublicKey, MerkleBlock, PublicKey, ScriptBuf, Transaction, Txid,
+};
+use ic_cdk::bitcoin_canister::{
+    bitcoin_get_block_headers, bitcoin_get_utxos, GetBlockHeadersRequest, GetUtxosRequest,
+    GetUtxosResponse, Utxo, UtxosFilter,
+};
+use std::str::FromStr;
+
+// Pending transactions are accepted, so deposits need no confirmations
+pub const REQUIRED_DEPOSIT_CONFIRMATIONS: u64 = 0;
 
 /// Get canister's Bitcoin P2WPKH address
 /// This is the address where users and resolvers will deposit Bitcoin
@@ -10,36 +37,227 @@ pub async fn get_canister_btc_address() -> Result<String, String> {
     Ok(get_p2wpkh_address::get_p2wpkh_address().await)
 }
 
+/// Get the canister's compressed threshold ECDSA public key, hex encoded
+/// This is the key the P2WPKH address returned by `get_canister_btc_address` is derived from
+pub async fn get_canister_btc_public_key() -> String {
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let public_key =
+        get_ecdsa_public_key(&ctx, DerivationPath::p2wpkh(0, 0).to_vec_u8_path()).await;
+    hex::encode(public_key)
+}
+
+/// Check that a user-supplied Bitcoin address is valid for the configured network
+/// Catches e.g. a mainnet refund address given to a testnet canister before funds are locked
+pub fn validate_bitcoin_address(address: &str) -> Result<(), String> {
+    let network = BTC_CONTEXT.with(|ctx| ctx.get()).bitcoin_network;
+    check_bitcoin_address_network(address, network)
+}
+
+/// Check that `address` parses, belongs to `network` and pays a standard script type
+pub fn check_bitcoin_address_network(
+    address: &str,
+    network: bitcoin::Network,
+) -> Result<(), String> {
+    let address = Address::from_str(address)
+        .map_err(|e| {
+            format!(
+                "InvalidAddress: {} is not a Bitcoin address: {}",
+                address, e
+            )
+        })?
+        .require_network(network)
+        .map_err(|_| {
+            format!(
+                "WrongNetworkAddress: {} is not valid on Bitcoin {:?}",
+                address, network
+            )
+        })?;
+    check_supported_script_type(&address)
+}
+
 /// Verify a Bitcoin transaction exists and has the correct recipient/amount
 /// Uses UTXO verification to ensure funds were actually received
 /// Includes pending (unconfirmed) transactions for immediate swap verification
 pub async fn verify_bitcoin_transaction(
     recipient_address: String,
     expected_amount: u64,
-    _txid: String,
+    txid: String,
 ) -> Result<bool, String> {
+    check_bitcoin_transaction(recipient_address, expected_amount, txid)
+        .await
+        .map(|result| result.verified)
+}
+
+/// Like `verify_bitcoin_transaction`, but reports the observed amount, confirmations
+/// and why verification failed
+/// The recipient may be of any supported address type (P2PKH, P2SH, P2WPKH, P2WSH or P2TR):
+/// UTXOs are looked up by address, so nothing here depends on the canister's own P2WPKH script
+pub async fn check_bitcoin_transaction(
+    recipient_address: String,
+    expected_amount: u64,
+    txid: String,
+) -> Result<VerificationResult, String> {
+    validate_bitcoin_address(&recipient_address)?;
+
     // Get UTXOs for the recipient address (includes pending transactions)
-    let utxos_response = get_utxos::get_utxos(recipient_address.clone()).await;
+    let utxos_response = fetch_utxos(recipient_address.clone()).await?;
+    // Other outputs at the address belong to other deposits, or are the canister's own change
+    let utxos = transaction_utxos(&utxos_response.utxos, &txid)?;
 
     // Check if there are any UTXOs
-    if utxos_response.utxos.is_empty() {
-        ic_cdk::println!("❌ No UTXOs found for address: {}", recipient_address);
-        return Ok(false);
+    if utxos.is_empty() {
+        ic_cdk::println!(
+            "❌ No UTXOs from {} found for address: {}",
+            txid,
+            recipient_address
+        );
+        return Ok(VerificationResult::not_found("Transaction not found"));
     }
 
-    // Calculate total balance from UTXOs (including pending)
-    let total_balance: u64 = utxos_response.utxos.iter().map(|utxo| utxo.value).sum();
-
+    let result = assess_deposit_utxos(&utxos, utxos_response.tip_height, expected_amount)?;
     ic_cdk::println!(
         "✅ Bitcoin verification: Address {} has {} satoshis (expected: {}) - includes pending txs",
         recipient_address,
-        total_balance,
+        result.observed_amount,
         expected_amount
     );
+    Ok(result)
+}
+
+/// The UTXOs created by transaction `txid`
+pub fn transaction_utxos(utxos: &[Utxo], txid: &str) -> Result<Vec<Utxo>, String> {
+    let txid = Txid::from_str(txid).map_err(|e| format!("Invalid Bitcoin txid {}: {}", txid, e))?;
+    Ok(utxos
+        .iter()
+        .filter(|utxo| Txid::from_slice(&utxo.outpoint.txid).is_ok_and(|id| id == txid))
+        .cloned()
+        .collect())
+}
+
+/// Assess the UTXOs paying a deposit address, pending ones included, against the expected amount
+/// For HTLC, we check if the canister has received at least the expected amount
+pub fn assess_deposit_utxos(
+    utxos: &[Utxo],
+    tip_height: u32,
+    expected_amount: u64,
+) -> Result<VerificationResult, String> {
+    // Calculate total balance from UTXOs (including pending)
+    let total_balance = checked_sum(utxos.iter().map(|utxo| utxo.value))?;
+    // Confirmations of the most recent UTXO, which is what the balance depends on
+    let confirmations = utxos
+        .iter()
+        .map(|utxo| (tip_height + 1).saturating_sub(utxo.height) as u64)
+        .min();
+
+    Ok(VerificationResult::assess(
+        total_balance,
+        expected_amount,
+        confirmations,
+        REQUIRED_DEPOSIT_CONFIRMATIONS,
+    ))
+}
+
+/// Verify a Bitcoin deposit from a merkle inclusion proof instead of a UTXO lookup
+/// The proof's block header must match the header the Bitcoin canister holds at that height
+/// Returns the txid of the proven deposit transaction
+pub async fn verify_bitcoin_deposit_proof(
+    recipient_address: String,
+    expected_amount: u64,
+    proof: BitcoinDepositProof,
+) -> Result<String, String> {
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+
+    let recipient = Address::from_str(&recipient_address)
+        .map_err(|e| format!("Invalid recipient address: {}", e))?
+        .require_network(ctx.bitcoin_network)
+        .map_err(|e| format!("Recipient address is for the wrong network: {}", e))?;
+
+    let headers = bitcoin_get_block_headers(&GetBlockHeadersRequest {
+        start_height: proof.block_height,
+        end_height: Some(proof.block_height),
+        network: ctx.network,
+    })
+    .await
+    .map_err(|e| format!("Failed to get block header: {:?}", e))?;
+
+    let known_header = headers.block_headers.first().ok_or(format!(
+        "No block header known at height {}",
+        proof.block_height
+    ))?;
+
+    let txid = check_merkle_proof(
+        &proof,
+        known_header,
+        &recipient.script_pubkey(),
+        expected_amount,
+    )?;
+
+    ic_cdk::println!(
+        "✅ Bitcoin proof verification: {} is included in block {} and pays {}",
+        txid,
+        proof.block_height,
+        recipient_address
+    );
+
+    Ok(txid)
+}
+
+/// Check a deposit proof against a trusted serialized block header
+/// The transaction must be committed to by the header's merkle root and pay
+/// at least `expected_amount` satoshis to `recipient_script`
+pub fn check_merkle_proof(
+    proof: &BitcoinDepositProof,
+    trusted_header: &[u8],
+    recipient_script: &ScriptBuf,
+    expected_amount: u64,
+) -> Result<String, String> {
+    let trusted_header: Header =
+        deserialize(trusted_header).map_err(|e| format!("Invalid block header: {}", e))?;
 
-    // Verify the balance is sufficient
-    // For HTLC, we check if the canister has received at least the expected amount
-    Ok(total_balance >= expected_amount)
+    let merkle_block_bytes = hex::decode(&proof.merkle_block_hex)
+        .map_err(|e| format!("Invalid merkle block hex: {}", e))?;
+    let merkle_block: MerkleBlock =
+        deserialize(&merkle_block_bytes).map_err(|e| format!("Invalid merkle block: {}", e))?;
+
+    if merkle_block.header.block_hash() != trusted_header.block_hash() {
+        return Err(
+            "Proof block header does not match the canister's view of the chain".to_string(),
+        );
+    }
+
+    // Recomputes the merkle root from the partial tree and checks it against the header
+    let mut matches = Vec::new();
+    let mut indexes = Vec::new();
+    merkle_block
+        .extract_matches(&mut matches, &mut indexes)
+        .map_err(|e| format!("Invalid merkle proof: {:?}", e))?;
+
+    let transaction_bytes = hex::decode(&proof.raw_transaction_hex)
+        .map_err(|e| format!("Invalid transaction hex: {}", e))?;
+    let transaction: Transaction =
+        deserialize(&transaction_bytes).map_err(|e| format!("Invalid transaction: {}", e))?;
+
+    let txid = transaction.compute_txid();
+    if !matches.contains(&txid) {
+        return Err("Transaction is not included in the proven block".to_string());
+    }
+
+    let received = checked_sum(
+        transaction
+            .output
+            .iter()
+            .filter(|output| output.script_pubkey == *recipient_script)
+            .map(|output| output.value.to_sat()),
+    )?;
+
+    if received < expected_amount {
+        return Err(format!(
+            "Insufficient amount: transaction pays {} satoshis, expected {}",
+            received, expected_amount
+        ));
+    }
+
+    Ok(txid.to_string())
 }
 
 /// Send Bitcoin from canister to a destination address
@@ -56,15 +274,172 @@ pub async fn send_bitcoin(to_address: String, amount_satoshis: u64) -> Result<St
         amount_in_satoshi: amount_satoshis,
     };
 
-    let txid = send_from_p2wpkh_address::send_from_p2wpkh_address(request).await;
+    let txid = send_from_p2wpkh_address::try_send_from_p2wpkh_address(request).await?;
 
     ic_cdk::println!("✅ Bitcoin sent! TXID: {}", txid);
     Ok(txid)
 }
 
+/// Serialized size (vbytes) of the transaction `send_bitcoin` would build for this payout right now
+/// Uses the canister's current UTXOs and fee rate, but neither signs nor broadcasts anything
+pub async fn estimate_payout_vsize(
+    to_address: String,
+    amount_satoshis: u64,
+) -> Result<u64, String> {
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let dst_address = Address::from_str(&to_address)
+        .map_err(|e| format!("Invalid destination address: {}", e))?
+        .require_network(ctx.bitcoin_network)
+        .map_err(|e| format!("Destination address is for the wrong network: {}", e))?;
+
+    let derivation_path = DerivationPath::p2wpkh(0, 0);
+    let own_public_key = get_ecdsa_public_key(&ctx, derivation_path.to_vec_u8_path()).await;
+    let own_compressed_public_key = CompressedPublicKey::from_slice(&own_public_key)
+        .map_err(|e| format!("Invalid canister public key: {}", e))?;
+    let own_public_key = PublicKey::from_slice(&own_public_key)
+        .map_err(|e| format!("Invalid canister public key: {}", e))?;
+    let own_address = Address::p2wpkh(&own_compressed_public_key, ctx.bitcoin_network);
+
+    let own_utxos = unreserved_utxos(fetch_utxos(own_address.to_string()).await?.utxos);
+    send_from_p2wpkh_address::check_spendable(&own_utxos, amount_satoshis)?;
+
+    let fee_per_byte = get_effective_fee_per_byte(&ctx).await;
+    p2wpkh::estimate_transaction_vsize(
+        &ctx,
+        &own_public_key,
+        &own_address,
+        &own_utxos,
+        &dst_address,
+        amount_satoshis,
+        fee_per_byte,
+    )
+    .await
+}
+
+/// Most satoshis a single payout from the canister can send right now, once its fee is paid
+pub async fn max_payout_satoshis() -> Result<u64, String> {
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let own_address = get_canister_btc_address().await?;
+    let own_utxos = unreserved_utxos(fetch_utxos(own_address).await?.utxos);
+    let fee_per_byte = get_effective_fee_per_byte(&ctx).await;
+    Ok(max_payout_amount(&own_utxos, fee_per_byte)?.0)
+}
+
+/// Merge up to `max_inputs` of the canister's smallest UTXOs into one output at its own address
+/// Skipped (with an error) when the fee would exceed the value being consolidated
+pub async fn consolidate_utxos(max_inputs: u32) -> Result<String, String> {
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+
+    let derivation_path = DerivationPath::p2wpkh(0, 0);
+    let own_public_key = get_ecdsa_public_key(&ctx, derivation_path.to_vec_u8_path()).await;
+    let own_compressed_public_key = CompressedPublicKey::from_slice(&own_public_key)
+        .map_err(|e| format!("Invalid canister public key: {}", e))?;
+    let own_public_key = PublicKey::from_slice(&own_public_key)
+        .map_err(|e| format!("Invalid canister public key: {}", e))?;
+    let own_address = Address::p2wpkh(&own_compressed_public_key, ctx.bitcoin_network);
+
+    // No call between picking the UTXOs and reserving them, as in `send_bitcoin`
+    let fee_per_byte = get_effective_fee_per_byte(&ctx).await;
+    let own_utxos = unreserved_utxos(fetch_utxos(own_address.to_string()).await?.utxos);
+    let (utxos_to_spend, fee) =
+        select_consolidation_utxos(&own_utxos, max_inputs as usize, fee_per_byte)?;
+    let total = checked_sum(utxos_to_spend.iter().map(|utxo| utxo.value))?;
+
+    ic_cdk::println!(
+        "🧹 Consolidating {} UTXOs ({} satoshis) with fee {}",
+        utxos_to_spend.len(),
+        total,
+        fee
+    );
+
+    let (transaction, prevouts) = build_transaction_with_fee(
+        utxos_to_spend,
+        &own_address,
+        &PrimaryOutput::Address(own_address.clone(), checked_sub(total, fee)?),
+        fee,
+    )?;
+    let outpoints = spent_outpoints(&transaction);
+    reserve_utxos(&outpoints);
+
+    let signed_transaction = p2wpkh::sign_transaction(
+        &ctx,
+        &own_public_key,
+        &own_address,
+        transaction,
+        &prevouts,
+        derivation_path.to_vec_u8_path(),
+        sign_with_ecdsa,
+    )
+    .await;
+
+    let txid = signed_transaction.compute_txid().to_string();
+    // Pays the canister's own address, so it must never count as a deposit
+    consume_txid(&txid);
+    broadcast_transaction(ctx.network, serialize(&signed_transaction))
+        .await
+        .map_err(|e| {
+            release_utxos(&outpoints);
+            e.to_string()
+        })?;
+    invalidate_balance(&own_address.to_string());
+
+    Ok(txid)
+}
+
+/// UTXOs of `address`, including pending ones, through the Bitcoin circuit breaker
+/// Fails fast with `ChainUnavailable` while the Bitcoin canister keeps rejecting calls
+async fn fetch_utxos(address: String) -> Result<GetUtxosResponse, String> {
+    check_chain_available(&Chain::Bitcoin, now_nanos())?;
+
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let result = bitcoin_get_utxos(&GetUtxosRequest {
+        address,
+        network: ctx.network,
+        filter: Some(UtxosFilter::MinConfirmations(0)),
+    })
+    .await
+    .map_err(|e| format!("Failed to get UTXOs: {:?}", e));
+
+    match &result {
+        Ok(_) => record_chain_success(&Chain::Bitcoin),
+        Err(e) => record_chain_failure(&Chain::Bitcoin, e, now_nanos()),
+    }
+    result
+}
+
+/// Get the fee rate (millisatoshi/byte) the next Bitcoin send will use
+/// This is the operator override when one is set, otherwise the current network estimate
+pub async fn get_btc_fee_per_byte() -> u64 {
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    get_effective_fee_per_byte(&ctx).await
+}
+
+/// List the outputs (txid, satoshis) currently paying an address, including pending ones
+pub async fn get_incoming_outputs(address: String) -> Result<Vec<(String, u64)>, String> {
+    let utxos_response = fetch_utxos(address).await?;
+
+    utxos_response
+        .utxos
+        .iter()
+        .map(|utxo| {
+            let txid = Txid::from_slice(&utxo.outpoint.txid)
+                .map_err(|e| format!("Invalid UTXO txid: {}", e))?;
+            Ok((txid.to_string(), utxo.value))
+        })
+        .collect()
+}
+
+/// Get Bitcoin balance for an address in satoshis, served from the balance cache while fresh
+pub async fn get_bitcoin_balance_satoshis(address: String) -> Result<u64, String> {
+    read_balance_cached(address, now_nanos(), |address| async {
+        Ok(get_balance::get_balance(address).await)
+    })
+    .await
+}
+
 /// Get Bitcoin balance for any address
 pub async fn get_bitcoin_balance(address: String) -> Result<f64, String> {
-    let balance_satoshis = get_balance::get_balance(address).await;
+    let balance_satoshis = get_bitcoin_balance_satoshis(address).await?;
     let balance_btc = balance_satoshis as f64 / 100_000_000.0;
     Ok(balance_btc)
 }
@@ -0,0 +1,252 @@
+// Background retries of pending Bitcoin deposit confirmations, so `confirm_deposit` need not be
+// called again once the named transaction appears. Deposits nobody confirmed are never picked up:
+// every order shares the canister's deposit address, so only a claimed txid says which order paid
+use crate::amounts::checked_sum;
+use crate::bitcoin_integration;
+use crate::orders::{check_open_order_capacity, credit_creator_deposit, deposit_overpayment};
+use crate::storage::{
+    creator_deposit_deadline, deposit_claims, last_deposit_poll, open_order_count, order_assets,
+    read_config, record_deposit_polls, release_open_order, remove_deposit_claim, update_order,
+    CANISTER_BTC_ADDRESS, ORDERS,
+};
+use crate::types::{Asset, Order, OrderStatus};
+use std::future::Future;
+use std::time::Duration;
+
+// A payment into a canister deposit address seen on-chain
+#[derive(Clone, Debug, PartialEq)]
+pub struct DetectedDeposit {
+    pub asset: Asset,
+    pub txid: String,
+    pub amount: u64,
+}
+
+/// Retry pending deposit confirmations every `interval_seconds`
+/// Timers do not survive upgrades, so this is called from both `init` and `post_upgrade`
+pub fn start(interval_seconds: u64) {
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(interval_seconds), || async {
+        let advanced = watch_deposits_with(fetch_bitcoin_deposits, ic_cdk::api::time()).await;
+        if !advanced.is_empty() {
+            ic_cdk::println!(
+                "👀 Pending deposit confirmations credited orders {:?}",
+                advanced
+            );
+        }
+        let cancelled = cancel_unfunded_orders(ic_cdk::api::time());
+        if !cancelled.is_empty() {
+            ic_cdk::println!("🧹 Cancelled orders never funded in time {:?}", cancelled);
+        }
+    });
+}
+
+/// Retry the pending confirmations: fetch candidate deposits and credit the claimed ones among them
+/// Only orders whose creator named a deposit not yet seen are retried, and deposits are only
+/// fetched once one of them is due for a poll, see `next_poll_at`
+/// Returns the ids of the orders moved to `DepositReceived`
+pub async fn watch_deposits_with<F, Fut>(fetch: F, now: u64) -> Vec<u64>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<Vec<DetectedDeposit>, String>>,
+{
+    let claimed: Vec<u64> = deposit_claims()
+        .into_iter()
+        .map(|(order_id, _)| order_id)
+        .collect();
+    let awaiting: Vec<Order> = ORDERS.with(|orders| {
+        orders
+            .borrow()
+            .values()
+            .filter(|order| {
+                matches!(order.status, OrderStatus::AwaitingDeposit) && claimed.contains(&order.id)
+            })
+            .cloned()
+            .collect()
+    });
+    let (min_seconds, max_seconds) = read_config(|config| {
+        (
+            config.deposit_watch_interval_seconds.unwrap_or_default(),
+            config.deposit_poll_max_interval_seconds,
+        )
+    });
+    let due = awaiting
+        .iter()
+        .any(|order| match last_deposit_poll(order.id) {
+            Some(polled_at) => now >= next_poll_at(order, polled_at, min_seconds, max_seconds),
+            None => true,
+        });
+    if !due {
+        return Vec::new();
+    }
+    // One fetch covers every watched order, so they all count as polled
+    let order_ids: Vec<u64> = awaiting.iter().map(|order| order.id).collect();
+    record_deposit_polls(&order_ids, now);
+
+    match fetch().await {
+        Ok(deposits) => apply_detected_deposits(&deposits, now),
+        Err(e) => {
+            ic_cdk::println!("❌ Deposit watcher could not fetch deposits: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Cancel orders still awaiting the creator's deposit past their deposit deadline
+/// A dropped or rejected deposit would otherwise hold the order open until expiry. Orders with a
+/// pending deposit confirmation are left for it to settle, since their deposit may yet be seen;
+/// the rest never had a deposit credited, so the canister holds nothing of theirs to refund.
+/// Returns the ids of the cancelled orders
+pub fn cancel_unfunded_orders(now: u64) -> Vec<u64> {
+    let deposit_seconds = read_config(|config| config.creator_deposit_seconds);
+    if deposit_seconds.is_none() {
+        return Vec::new();
+    }
+
+    let claimed: Vec<u64> = deposit_claims()
+        .into_iter()
+        .map(|(order_id, _)| order_id)
+        .collect();
+    let lapsed: Vec<Order> = ORDERS.with(|orders| {
+        orders
+            .borrow()
+            .values()
+            .filter(|order| {
+                matches!(order.status, OrderStatus::AwaitingDeposit)
+                    && !order.creator_deposited
+                    && !claimed.contains(&order.id)
+                    && creator_deposit_deadline(order, deposit_seconds)
+                        .is_some_and(|deadline| now >= deadline)
+            })
+            .cloned()
+            .collect()
+    });
+
+    let mut cancelled = Vec::new();
+    for order in lapsed {
+        release_open_order(&order);
+        remove_deposit_claim(order.id);
+        update_order(order.id, |ord| {
+            ord.status = OrderStatus::Cancelled;
+            ord.cancelled_at = Some(now);
+        });
+        cancelled.push(order.id);
+    }
+    cancelled.sort();
+    cancelled
+}
+
+/// When to next check for the deposit of `order`, last checked at `polled_at`
+/// The interval grows linearly from `min_seconds` at creation to `max_seconds` at expiry, so
+/// fresh orders are picked up quickly while stale ones stop costing cycles and RPC quota
+pub fn next_poll_at(order: &Order, polled_at: u64, min_seconds: u64, max_seconds: u64) -> u64 {
+    let max_seconds = max_seconds.max(min_seconds);
+    let lifetime = order.expires_at.saturating_sub(order.created_at).max(1);
+    let age = polled_at.saturating_sub(order.created_at).min(lifetime);
+    let backoff = (max_seconds - min_seconds) as u128 * age as u128 / lifetime as u128;
+    let interval_seconds = min_seconds + backoff as u64;
+    polled_at.saturating_add(interval_seconds.saturating_mul(1_000_000_000))
+}
+
+/// Outputs currently paying the canister's Bitcoin address
+/// All orders share that address, so attribution relies on the claims in `match_deposits`
+async fn fetch_bitcoin_deposits() -> Result<Vec<DetectedDeposit>, String> {
+    let address = match CANISTER_BTC_ADDRESS.with(|addr| addr.borrow().clone()) {
+        Some(address) => address,
+        None => return Ok(Vec::new()),
+    };
+
+    let outputs = bitcoin_integration::get_incoming_outputs(address).await?;
+    Ok(outputs
+        .into_iter()
+        .map(|(txid, amount)| DetectedDeposit {
+            asset: Asset::Bitcoin,
+            txid,
+            amount,
+        })
+        .collect())
+}
+
+/// Credit matched deposits to their orders, as `confirm_deposit` would
+/// A claim that can no longer be credited, say because its txid was meanwhile credited
+/// elsewhere, is dropped; an order over the open order limits keeps its claim for later
+pub fn apply_detected_deposits(deposits: &[DetectedDeposit], now: u64) -> Vec<u64> {
+    let orders: Vec<Order> = ORDERS.with(|orders| orders.borrow().values().cloned().collect());
+    let mut advanced = Vec::new();
+
+    for (order_id, txid, received) in match_deposits(&orders, &deposit_claims(), deposits) {
+        let Some(order) = orders.iter().find(|order| order.id == order_id) else {
+            continue;
+        };
+        let within_capacity = order_assets(order).into_iter().all(|asset| {
+            let open_orders = open_order_count(asset);
+            read_config(|config| check_open_order_capacity(config, asset, open_orders)).is_ok()
+        });
+        if !within_capacity {
+            continue;
+        }
+
+        if let Err(e) = credit_creator_deposit(order_id, &txid, now) {
+            ic_cdk::println!(
+                "⚠️ Dropping deposit claim {} of order {}: {}",
+                txid,
+                order_id,
+                e
+            );
+            remove_deposit_claim(order_id);
+            continue;
+        }
+        // Bitcoin deposits are not tied to their order, so any excess is left to an operator
+        if let Some(excess) = deposit_overpayment(order.from_amount, received) {
+            update_order(order_id, |ord| {
+                ord.creator_overpayment = Some(excess);
+                ord.manual_review = Some(format!(
+                    "Overpayment of {} not refunded: Bitcoin overpayments are refunded by an operator",
+                    excess
+                ));
+            });
+        }
+        advanced.push(order_id);
+    }
+
+    advanced
+}
+
+/// Pair orders awaiting a deposit with the claimed deposit that was seen paying for them
+/// Deposits go to a shared canister address, so the amount alone says nothing about which order
+/// a deposit is for; only the txid the creator named is credited, as (order ID, txid, amount
+/// received), once its outputs cover the order's `from_amount` and it is not recorded on any order
+pub fn match_deposits(
+    orders: &[Order],
+    claims: &[(u64, String)],
+    deposits: &[DetectedDeposit],
+) -> Vec<(u64, String, u64)> {
+    let recorded_txids: Vec<&String> = orders
+        .iter()
+        .flat_map(|order| [order.creator_txid.as_ref(), order.resolver_txid.as_ref()])
+        .flatten()
+        .collect();
+
+    let mut matches = Vec::new();
+    for (order_id, txid) in claims {
+        let Some(order) = orders.iter().find(|order| order.id == *order_id) else {
+            continue;
+        };
+        if !matches!(order.status, OrderStatus::AwaitingDeposit) || recorded_txids.contains(&txid) {
+            continue;
+        }
+
+        let Ok(received) = checked_sum(
+            deposits
+                .iter()
+                .filter(|deposit| deposit.asset == order.from_asset && &deposit.txid == txid)
+                .map(|deposit| deposit.amount),
+        ) else {
+            continue;
+        };
+        if received >= order.from_amount {
+            matches.push((order.id, txid.clone(), received));
+        }
+    }
+
+    matches.sort();
+    matches
+}
@@ -1,11 +1,17 @@
 // Module declarations
+mod amounts;
 mod basic_bitcoin;
 mod basic_solana;
 mod bitcoin_integration;
+mod deposit_watcher;
 mod orders;
 mod solana_integration;
 mod storage;
 mod types;
+mod webhooks;
+
+#[cfg(test)]
+mod tests;
 
 // Re-export types for Candid interface
 pub use types::*;
@@ -16,12 +22,19 @@ use orders::*;
 // Initialization
 use basic_bitcoin::{init_bitcoin, upgrade_bitcoin};
 use basic_solana::{state::init_state, Ed25519KeyName, InitArg as SolanaInitArg, SolanaNetwork};
+use candid::Principal;
 use ic_cdk::bitcoin_canister::Network as BtcNetwork;
-use ic_cdk::{init, post_upgrade};
+use ic_cdk::management_canister::{HttpRequestResult, TransformArgs};
+use ic_cdk::{init, post_upgrade, pre_upgrade};
 use sol_rpc_types::CommitmentLevel;
 
 #[init]
-fn init() {
+fn init(swap_init: Option<SwapInitArg>) {
+    storage::init_config(swap_init.unwrap_or_default());
+    start_deposit_watcher();
+    webhooks::start();
+    start_self_test();
+
     // Initialize Bitcoin module with Testnet (change to Mainnet for production)
     init_bitcoin(BtcNetwork::Testnet);
 
@@ -39,8 +52,27 @@ fn init() {
     ic_cdk::println!("   - Solana Network: Devnet");
 }
 
+// Settings changed since `init` are otherwise lost with the heap on upgrade
+#[pre_upgrade]
+fn pre_upgrade() {
+    let config = storage::read_config(|config| config.clone());
+    ic_cdk::storage::stable_save((config,)).expect("Failed to save the swap configuration");
+}
+
 #[post_upgrade]
-fn post_upgrade() {
+fn post_upgrade(swap_init: Option<SwapInitArg>) {
+    let saved = match ic_cdk::storage::stable_restore::<(SwapConfig,)>() {
+        Ok((config,)) => Some(config),
+        Err(e) => {
+            ic_cdk::println!("⚠️ No saved swap configuration: {}", e);
+            None
+        }
+    };
+    let config = storage::upgraded_config(swap_init, saved);
+    storage::CONFIG.with(|current| *current.borrow_mut() = config);
+    start_deposit_watcher();
+    webhooks::start();
+
     // Reinitialize Bitcoin module
     upgrade_bitcoin(BtcNetwork::Testnet);
 
@@ -56,6 +88,39 @@ fn post_upgrade() {
     ic_cdk::println!("♻️ Intentional Swaps Canister upgraded!");
 }
 
+fn start_deposit_watcher() {
+    if let Some(interval) = storage::read_config(|config| config.deposit_watch_interval_seconds) {
+        deposit_watcher::start(interval);
+    }
+}
+
+// Inter-canister calls are not allowed in `init`, so the self-test runs right after it
+fn start_self_test() {
+    ic_cdk_timers::set_timer(std::time::Duration::ZERO, async {
+        let report = orders::self_test().await;
+        for line in &report.details {
+            ic_cdk::println!("🩺 Self-test {}", line);
+        }
+    });
+}
+
+// Canister clock in nanoseconds, the unit of created_at/expires_at and all other timestamps
+#[ic_cdk::query]
+fn get_time() -> u64 {
+    storage::now_nanos()
+}
+
+// Amount conversion helpers for clients
+#[ic_cdk::query]
+fn format_amount(asset: Asset, raw: u64) -> String {
+    amounts::format_amount(&asset, raw)
+}
+
+#[ic_cdk::query]
+fn parse_amount(asset: Asset, human: String) -> Result<u64, String> {
+    amounts::parse_amount(&asset, &human)
+}
+
 // Query functions from storage
 #[ic_cdk::query]
 fn get_pending_orders() -> Vec<OrderInfo> {
@@ -72,12 +137,103 @@ fn get_order(order_id: u64) -> Option<OrderInfo> {
     storage::get_order(order_id)
 }
 
+// Cheap status check for polling clients
+#[ic_cdk::query]
+fn get_order_status(order_id: u64) -> Option<OrderStatus> {
+    storage::get_order_status(order_id)
+}
+
+// Orders per status, for dashboards that only need totals
+#[ic_cdk::query]
+fn get_order_counts() -> OrderCounts {
+    storage::get_order_counts()
+}
+
+#[ic_cdk::query]
+fn get_order_timeline(order_id: u64) -> Option<OrderTimeline> {
+    storage::get_order_timeline(order_id)
+}
+
+// Time left until each of an order's deadlines, computed against the canister clock
+#[ic_cdk::query]
+fn get_order_deadlines(order_id: u64) -> Option<Deadlines> {
+    storage::get_order_deadlines(order_id, storage::now_nanos())
+}
+
+// Orders created or completed within an inclusive nanosecond range, for reporting
+#[ic_cdk::query]
+fn get_orders_in_range(
+    from_ns: u64,
+    to_ns: u64,
+    field: TimeField,
+) -> Result<Vec<OrderInfo>, String> {
+    storage::get_orders_in_range(from_ns, to_ns, field)
+}
+
+// Circuit breaker state per chain; operations on an unavailable chain fail with ChainUnavailable
+#[ic_cdk::query]
+fn get_chain_status() -> Vec<ChainStatus> {
+    storage::get_chain_statuses(storage::now_nanos())
+}
+
 #[ic_cdk::query]
 fn get_my_orders() -> Vec<OrderInfo> {
     let caller = ic_cdk::api::caller();
     storage::get_my_orders(caller)
 }
 
+// An order's transaction IDs and, once completed, its secret; only for its creator or resolver
+#[ic_cdk::query]
+fn get_my_order_detail(order_id: u64) -> Result<OrderDetail, String> {
+    let caller = ic_cdk::api::caller();
+    storage::get_my_order_detail(caller, order_id)
+}
+
+// Rebates a resolver (default: the caller) has earned for fast deposits
+#[ic_cdk::query]
+fn get_resolver_rebates(resolver: Option<Principal>) -> Vec<ResolverRebate> {
+    let resolver = resolver.unwrap_or_else(ic_cdk::api::caller);
+    storage::get_resolver_rebates(resolver)
+}
+
+// Acceptance, completion and abandonment counts for a resolver
+#[ic_cdk::query]
+fn get_resolver_stats(resolver: Principal) -> ResolverStats {
+    storage::get_resolver_stats(resolver)
+}
+
+// Pending orders the caller can accept, leaving out ones they created or already accepted
+#[ic_cdk::query]
+fn get_acceptable_orders() -> Vec<OrderInfo> {
+    let caller = ic_cdk::api::caller();
+    storage::get_acceptable_orders(caller)
+}
+
+#[ic_cdk::query]
+fn get_my_active_orders() -> Vec<OrderInfo> {
+    let caller = ic_cdk::api::caller();
+    storage::get_my_active_orders(caller)
+}
+
+// The caller's open orders with what they have to do next on each, for a to-do list
+#[ic_cdk::query]
+fn get_my_actionable_orders() -> Vec<ActionableOrder> {
+    let caller = ic_cdk::api::caller();
+    storage::get_actionable_orders(caller, ic_cdk::api::time())
+}
+
+// Orders a resolver has accepted, without the ones they created
+#[ic_cdk::query]
+fn get_orders_resolved_by(resolver: Principal) -> Vec<OrderInfo> {
+    storage::get_orders_resolved_by(resolver)
+}
+
+#[ic_cdk::query]
+fn get_my_resolutions() -> Vec<OrderInfo> {
+    let caller = ic_cdk::api::caller();
+    storage::get_orders_resolved_by(caller)
+}
+
 #[ic_cdk::query]
 fn get_orders_by_wallet(
     btc_address: Option<String>,
@@ -92,16 +248,281 @@ async fn get_canister_addresses() -> Result<CanisterAddresses, String> {
     orders::get_canister_addresses().await
 }
 
+// Threshold public keys the canister signs Bitcoin and Solana payouts and attestations with
+#[ic_cdk::update]
+async fn get_public_keys() -> Result<PublicKeys, String> {
+    orders::get_public_keys().await
+}
+
+// Fees and cycles spent settling a completed order; None until it completes
+#[ic_cdk::query]
+fn get_settlement_receipt(order_id: u64) -> Option<SettlementReceipt> {
+    storage::get_settlement_receipt(order_id)
+}
+
+// Signed receipt of a completed swap, verifiable against the attestation key from get_public_keys
+// An update because the first request for an order signs with the threshold key
+#[ic_cdk::update]
+async fn get_settlement_attestation(order_id: u64) -> Result<Attestation, String> {
+    orders::get_settlement_attestation(order_id).await
+}
+
+// Associated token account SPL deposits of the given mint must be sent to
+#[ic_cdk::update]
+async fn get_canister_token_account(mint_address: String) -> Result<String, String> {
+    orders::get_canister_token_account(mint_address).await
+}
+
+// Asset, address, exact amount and network for one party's deposit into an order
+#[ic_cdk::update]
+async fn get_deposit_instructions(
+    order_id: u64,
+    leg: DepositLeg,
+) -> Result<DepositInstructions, String> {
+    orders::get_deposit_instructions(order_id, leg).await
+}
+
+// Compare open-order liabilities with on-chain balances; an update since it queries both chains
+#[ic_cdk::update]
+async fn get_solvency_report() -> Result<SolvencyReport, String> {
+    orders::get_solvency_report().await
+}
+
+// Derive both canister addresses and read their balances to confirm the chains are reachable;
+// controllers only
+#[ic_cdk::update]
+async fn run_self_test() -> Result<SelfTestReport, String> {
+    require_controller()?;
+    Ok(orders::self_test().await)
+}
+
+// Send from the canister's pooled funds, bypassing any order; controllers only
 #[ic_cdk::update]
 async fn send_bitcoin(to_address: String, amount_satoshis: u64) -> Result<String, String> {
+    require_controller()?;
     bitcoin_integration::send_bitcoin(to_address, amount_satoshis).await
 }
 
+// Runs as an update because the network estimate needs an inter-canister call
+#[ic_cdk::update]
+async fn get_btc_fee_per_byte() -> u64 {
+    bitcoin_integration::get_btc_fee_per_byte().await
+}
+
+// Vbyte size of an order's Bitcoin payout, built but not signed or sent
+// An update for the same reason: reading UTXOs and the fee rate are inter-canister calls
+#[ic_cdk::update]
+async fn estimate_bitcoin_tx_size(order_id: u64) -> Result<u64, String> {
+    orders::estimate_bitcoin_tx_size(order_id).await
+}
+
+// Check the caller's deposit on each listed order in one call (at most 20 orders)
+// An update because the checks read both chains
+#[ic_cdk::update]
+async fn verify_deposits(order_ids: Vec<u64>) -> Result<Vec<(u64, VerificationResult)>, String> {
+    orders::verify_deposits(ic_cdk::api::caller(), order_ids).await
+}
+
+// A resolver's expected profit on an order at the feed prices, net of its deposit fees
+// An update because the Bitcoin fee rate is read from the Bitcoin canister
+#[ic_cdk::update]
+async fn evaluate_order(order_id: u64) -> Result<ResolverEvaluation, String> {
+    orders::evaluate_order(order_id).await
+}
+
+// Normalizes fee API responses so replicas reach consensus on the HTTPS outcall
+#[ic_cdk::query]
+fn transform_fee_response(args: TransformArgs) -> HttpRequestResult {
+    basic_bitcoin::common::transform_fee_response(args)
+}
+
+// Normalizes webhook responses to their status, for the same reason
+#[ic_cdk::query]
+fn transform_webhook_response(args: TransformArgs) -> HttpRequestResult {
+    webhooks::transform_webhook_response(args)
+}
+
+// Set (or with `null`, turn off) the HTTPS URL lifecycle events are POSTed to; controllers only
+#[ic_cdk::update]
+fn set_webhook_url(url: Option<String>) -> Result<(), String> {
+    require_controller()?;
+    if let Some(url) = &url {
+        webhooks::validate_webhook_url(url)?;
+    }
+    storage::set_webhook_url(url);
+    Ok(())
+}
+
+// Pin (or with `null`, unpin) the Bitcoin fee rate used for sends; controllers only
+#[ic_cdk::update]
+fn set_btc_fee_per_byte_override(fee_per_byte: Option<u64>) -> Result<(), String> {
+    require_controller()?;
+    storage::set_btc_fee_per_byte_override(fee_per_byte);
+    Ok(())
+}
+
+// Page through the audit trail of state-changing calls, oldest first; controllers only
+#[ic_cdk::query]
+fn get_audit_log(offset: u64, limit: u64) -> Result<Vec<AuditEntry>, String> {
+    require_controller()?;
+    Ok(storage::get_audit_log(offset, limit))
+}
+
+// Expire a stuck order now and refund its deposits, recording the reason; controllers only
+#[ic_cdk::update]
+async fn force_expire(order_id: u64, reason: String) -> Result<String, String> {
+    require_controller()?;
+    orders::force_expire(order_id, reason).await
+}
+
+// Send an order's overpayment, once checked to be the creator's, back to them; controllers only
+#[ic_cdk::update]
+async fn refund_overpayment(order_id: u64) -> Result<String, String> {
+    require_controller()?;
+    orders::refund_overpayment(order_id).await
+}
+
+// Send idle Bitcoin beyond what open orders hold to a treasury address; controllers only
+#[ic_cdk::update]
+async fn sweep_btc_to(address: String, amount_satoshis: u64) -> Result<String, String> {
+    require_controller()?;
+    orders::sweep_to(Asset::Bitcoin, address, amount_satoshis).await
+}
+
+// Send idle SOL beyond what open orders hold to a treasury address; controllers only
+#[ic_cdk::update]
+async fn sweep_sol_to(address: String, amount_lamports: u64) -> Result<String, String> {
+    require_controller()?;
+    orders::sweep_to(Asset::Solana, address, amount_lamports).await
+}
+
+// Merge the canister's smallest Bitcoin UTXOs into one output; controllers only
+#[ic_cdk::update]
+async fn consolidate_utxos(max_inputs: u32) -> Result<String, String> {
+    require_controller()?;
+    bitcoin_integration::consolidate_utxos(max_inputs).await
+}
+
+// Publish USD prices used to settle oracle-priced orders; controllers only
+#[ic_cdk::update]
+fn set_asset_prices(prices: Vec<AssetPrice>) -> Result<(), String> {
+    require_controller()?;
+    storage::set_asset_prices(prices);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_asset_prices() -> Vec<AssetPrice> {
+    storage::get_asset_prices()
+}
+
+// Manage the SPL mints orders may trade; controllers only
+#[ic_cdk::update]
+fn add_allowed_spl_mint(mint_address: String) -> Result<bool, String> {
+    require_controller()?;
+    solana_integration::validate_solana_address(&mint_address)?;
+    Ok(storage::add_allowed_spl_mint(mint_address))
+}
+
+#[ic_cdk::update]
+fn remove_allowed_spl_mint(mint_address: String) -> Result<bool, String> {
+    require_controller()?;
+    Ok(storage::remove_allowed_spl_mint(&mint_address))
+}
+
+// Turn test mode, and with it the development-only endpoints, on or off; controllers only
+#[ic_cdk::update]
+fn set_test_mode(enabled: bool) -> Result<(), String> {
+    require_controller()?;
+    storage::set_test_mode(enabled);
+    Ok(())
+}
+
+// Accept any SPL mint regardless of the allowlist, for testing; controllers only
+#[ic_cdk::update]
+fn set_allow_any_spl_mint(enabled: bool) -> Result<(), String> {
+    require_controller()?;
+    storage::set_allow_any_spl_mint(enabled);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_allowed_spl_mints() -> Vec<String> {
+    storage::read_config(|config| config.allowed_spl_mints.clone())
+}
+
+// Assets orders may use, so frontends can build their asset picker from the allowlist
+#[ic_cdk::query]
+fn get_supported_assets() -> Vec<SupportedAsset> {
+    orders::get_supported_assets()
+}
+
+// Manage the BTC/SOL addresses payouts are refused to; controllers only
+#[ic_cdk::update]
+fn add_blocked_address(address: String) -> Result<bool, String> {
+    require_controller()?;
+    Ok(storage::add_blocked_address(address))
+}
+
+#[ic_cdk::update]
+fn remove_blocked_address(address: String) -> Result<bool, String> {
+    require_controller()?;
+    Ok(storage::remove_blocked_address(&address))
+}
+
+#[ic_cdk::query]
+fn get_blocked_addresses() -> Result<Vec<String>, String> {
+    require_controller()?;
+    Ok(storage::read_config(|config| {
+        config.blocked_addresses.clone()
+    }))
+}
+
+// Average cycles per call of each instrumented endpoint; controllers only
+#[ic_cdk::query]
+fn get_operation_costs() -> Result<Vec<(String, u64)>, String> {
+    require_controller()?;
+    Ok(storage::get_operation_costs())
+}
+
+fn require_controller() -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::api::msg_caller()) {
+        return Err("Only controllers can call this method".to_string());
+    }
+    Ok(())
+}
+
+// Send from the canister's pooled funds, bypassing any order; controllers only
 #[ic_cdk::update]
 async fn send_solana(to_address: String, amount_lamports: u64) -> Result<String, String> {
+    require_controller()?;
     solana_integration::send_solana(to_address, amount_lamports).await
 }
 
+#[ic_cdk::update]
+async fn send_solana_with_format(
+    to_address: String,
+    amount_lamports: u64,
+    format: SolanaTransactionFormat,
+) -> Result<String, String> {
+    require_controller()?;
+    solana_integration::send_solana_with_format(to_address, amount_lamports, format).await
+}
+
+// Create the canister's Solana nonce account and switch payouts to durable nonces; controllers only
+#[ic_cdk::update]
+async fn setup_durable_nonce() -> Result<String, String> {
+    require_controller()?;
+    solana_integration::setup_durable_nonce().await
+}
+
+// Switch payouts back to recent blockhashes and close the nonce account; controllers only
+#[ic_cdk::update]
+async fn teardown_durable_nonce() -> Result<String, String> {
+    require_controller()?;
+    solana_integration::teardown_durable_nonce().await
+}
+
 #[ic_cdk::update]
 async fn verify_bitcoin_transaction(
     recipient_address: String,
@@ -116,8 +537,31 @@ async fn verify_solana_transaction(
     recipient_address: String,
     expected_amount: u64,
     txid: String,
+    memo: Option<String>,
 ) -> Result<bool, String> {
-    solana_integration::verify_solana_transaction(recipient_address, expected_amount, txid).await
+    solana_integration::verify_solana_transaction(recipient_address, expected_amount, txid, memo)
+        .await
+}
+
+// Same checks as the verify_* methods, with the observed amount and the reason for a failure
+#[ic_cdk::update]
+async fn verify_bitcoin_transaction_detailed(
+    recipient_address: String,
+    expected_amount: u64,
+    txid: String,
+) -> Result<VerificationResult, String> {
+    bitcoin_integration::check_bitcoin_transaction(recipient_address, expected_amount, txid).await
+}
+
+#[ic_cdk::update]
+async fn verify_solana_transaction_detailed(
+    recipient_address: String,
+    expected_amount: u64,
+    txid: String,
+    memo: Option<String>,
+) -> Result<VerificationResult, String> {
+    solana_integration::check_solana_transaction(recipient_address, expected_amount, txid, memo)
+        .await
 }
 
 #[ic_cdk::update]
@@ -130,35 +574,78 @@ async fn get_solana_balance(address: String) -> Result<f64, String> {
     solana_integration::get_solana_balance(address).await
 }
 
+// Test mode only, never on mainnet: pays 0.01 SOL out of the canister's funds
 #[ic_cdk::update]
 async fn test_send_sol(to_address: String) -> Result<String, String> {
     solana_integration::test_send_sol(to_address).await
 }
 
 // ============ SPL Token Functions ============
+// Send from the canister's pooled funds, bypassing any order; controllers only
 #[ic_cdk::update]
 async fn send_spl_token(
     to_address: String,
     amount: u64,
     mint_address: String,
 ) -> Result<String, String> {
+    require_controller()?;
     solana_integration::send_spl_token(to_address, amount, mint_address).await
 }
 
+#[ic_cdk::update]
+async fn send_spl_token_with_format(
+    to_address: String,
+    amount: u64,
+    mint_address: String,
+    format: SolanaTransactionFormat,
+) -> Result<String, String> {
+    require_controller()?;
+    solana_integration::send_spl_token_with_format(to_address, amount, mint_address, format).await
+}
+
+// Name, symbol and decimals of an SPL mint for display; cached after the first lookup
+#[ic_cdk::update]
+async fn get_token_metadata(mint_address: String) -> Result<TokenMetadata, String> {
+    solana_integration::get_token_metadata(mint_address).await
+}
+
 #[ic_cdk::update]
 async fn get_spl_token_balance(address: String, mint_address: String) -> Result<u64, String> {
     solana_integration::get_spl_token_balance(address, mint_address).await
 }
 
+// Every SPL and Token-2022 account an address holds, with mint and balance
+#[ic_cdk::update]
+async fn get_all_token_balances(owner_address: String) -> Result<Vec<TokenBalance>, String> {
+    solana_integration::get_all_token_balances(owner_address).await
+}
+
 #[ic_cdk::update]
 async fn verify_spl_token_transaction(
-    recipient_address: String,
+    token_account: String,
     expected_amount: u64,
     mint_address: String,
     txid: String,
 ) -> Result<bool, String> {
     solana_integration::verify_spl_token_transaction(
-        recipient_address,
+        token_account,
+        expected_amount,
+        mint_address,
+        txid,
+    )
+    .await
+}
+
+// Reports the amount actually credited to the token account, flagging fee-on-transfer mints
+#[ic_cdk::update]
+async fn verify_spl_token_transaction_detailed(
+    token_account: String,
+    expected_amount: u64,
+    mint_address: String,
+    txid: String,
+) -> Result<VerificationResult, String> {
+    solana_integration::check_spl_token_transaction(
+        token_account,
         expected_amount,
         mint_address,
         txid,
@@ -169,5 +656,9 @@ async fn verify_spl_token_transaction(
 // Legacy compatibility functions (deprecated)
 #[ic_cdk::update]
 fn deposit_funds(_chain: Chain, _amount: u64) -> Result<String, String> {
-    Err("Deprecated: Use your own Bitcoin/Solana wallet to send funds".to_string())
+    Err(orders::DEPOSIT_FUNDS_DEPRECATED.to_string())
 }
+
+// Generates the Candid interface from the endpoints above (and the basic_bitcoin ones);
+// `tests::candid_interface` checks it against the committed intentswaps_backend.did
+ic_cdk::export_candid!();
@@ -1,13 +1,27 @@
+use crate::amounts::{asset_decimals, checked_mul, checked_sum, resolve_amount};
+use crate::basic_bitcoin::{
+    common::sleep,
+    ecdsa::{get_ecdsa_public_key, sign_with_ecdsa},
+    BTC_CONTEXT,
+};
+use crate::basic_solana::{state::read_state, SolanaNetwork};
 use crate::{bitcoin_integration, solana_integration, storage::*, types::*};
+use bitcoin::secp256k1;
+use candid::Principal;
 use ic_cdk::api::time;
+use sha2::{Digest, Sha256};
+use std::time::Duration;
 
 /// Helper function to verify deposit based on asset type
 async fn verify_asset_deposit(
     asset: &Asset,
     canister_address: &str,
     amount: u64,
+    memo: Option<String>,
     txid: String,
 ) -> Result<bool, String> {
+    check_chain_available(&asset_chain(asset), time())?;
+
     match asset {
         Asset::Bitcoin => {
             bitcoin_integration::verify_bitcoin_transaction(
@@ -22,6 +36,7 @@ async fn verify_asset_deposit(
                 canister_address.to_string(),
                 amount,
                 txid,
+                memo,
             )
             .await
         }
@@ -37,8 +52,51 @@ async fn verify_asset_deposit(
     }
 }
 
+/// Like `verify_asset_deposit`, but reporting what was observed and why a deposit does not count
+async fn check_asset_deposit(deposit: &PendingDeposit) -> Result<VerificationResult, String> {
+    check_chain_available(&asset_chain(&deposit.asset), time())?;
+
+    let canister_address = get_canister_deposit_address(&deposit.asset)?;
+    match &deposit.asset {
+        Asset::Bitcoin => {
+            bitcoin_integration::check_bitcoin_transaction(
+                canister_address,
+                deposit.amount,
+                deposit.txid.clone(),
+            )
+            .await
+        }
+        Asset::Solana => {
+            solana_integration::check_solana_transaction(
+                canister_address,
+                deposit.amount,
+                deposit.txid.clone(),
+                deposit.memo.clone(),
+            )
+            .await
+        }
+        Asset::SplToken { mint_address, .. } => {
+            solana_integration::check_spl_token_transaction(
+                canister_address,
+                deposit.amount,
+                mint_address.clone(),
+                deposit.txid.clone(),
+            )
+            .await
+        }
+    }
+}
+
 /// Helper function to send asset based on type
-async fn send_asset(asset: &Asset, to_address: &str, amount: u64) -> Result<String, String> {
+async fn send_asset(
+    order_id: u64,
+    asset: &Asset,
+    to_address: &str,
+    amount: u64,
+) -> Result<String, String> {
+    check_payout_destination(order_id, to_address)?;
+    check_chain_available(&asset_chain(asset), time())?;
+
     match asset {
         Asset::Bitcoin => bitcoin_integration::send_bitcoin(to_address.to_string(), amount).await,
         Asset::Solana => solana_integration::send_solana(to_address.to_string(), amount).await,
@@ -49,6 +107,25 @@ async fn send_asset(asset: &Asset, to_address: &str, amount: u64) -> Result<Stri
     }
 }
 
+/// Refuse a payout to a blocked address, setting the order aside for manual handling
+/// The deposit stays held; an operator decides where it goes instead
+pub fn check_payout_destination(order_id: u64, to_address: &str) -> Result<(), String> {
+    if !read_config(|config| config.is_address_blocked(to_address)) {
+        return Ok(());
+    }
+    let error = format!("BlockedAddress: payouts to {} are blocked", to_address);
+    update_order(order_id, |ord| ord.manual_review = Some(error.clone()));
+    Err(error)
+}
+
+/// The chain an asset lives on
+pub fn asset_chain(asset: &Asset) -> Chain {
+    match asset {
+        Asset::Bitcoin => Chain::Bitcoin,
+        Asset::Solana | Asset::SplToken { .. } => Chain::Solana,
+    }
+}
+
 /// Get the appropriate address for receiving an asset
 fn get_receive_address(
     asset: &Asset,
@@ -63,6 +140,331 @@ fn get_receive_address(
     }
 }
 
+/// Reference SOL deposits for an order carry in their memo instruction
+/// All SOL deposits go to the same canister address, so the memo is what ties a transfer to an order
+pub fn solana_deposit_memo(order_id: u64) -> String {
+    format!("intentswaps:{}", order_id)
+}
+
+/// The memo a deposit of `asset` into the order must carry, if memos are required
+pub fn required_deposit_memo(order_id: u64, asset: &Asset) -> Option<String> {
+    let required = read_config(|config| config.require_solana_memo);
+    (required && matches!(asset, Asset::Solana)).then(|| solana_deposit_memo(order_id))
+}
+
+/// Get the canister address a deposit of the given asset must be sent to
+/// SPL tokens are held in the canister's associated token account for the mint, not in its wallet
+/// Call `resolve_asset_token_program` first so Token-2022 mints derive that account correctly
+pub fn get_canister_deposit_address(asset: &Asset) -> Result<String, String> {
+    match asset {
+        Asset::Bitcoin => CANISTER_BTC_ADDRESS
+            .with(|addr| addr.borrow().clone())
+            .ok_or_else(|| "Canister Bitcoin address not initialized".to_string()),
+        Asset::Solana => CANISTER_SOL_ADDRESS
+            .with(|addr| addr.borrow().clone())
+            .ok_or_else(|| "Canister Solana address not initialized".to_string()),
+        Asset::SplToken { mint_address, .. } => {
+            let wallet = CANISTER_SOL_ADDRESS
+                .with(|addr| addr.borrow().clone())
+                .ok_or("Canister Solana address not initialized")?;
+            solana_integration::get_associated_token_account(&wallet, mint_address)
+        }
+    }
+}
+
+/// Look up the token program of an SPL asset so its associated token accounts derive correctly
+pub async fn resolve_asset_token_program(asset: &Asset) -> Result<(), String> {
+    if let Asset::SplToken { mint_address, .. } = asset {
+        solana_integration::resolve_token_program(mint_address).await?;
+    }
+    Ok(())
+}
+
+// Upper bound on how long `deposit_and_poll` keeps re-checking a deposit
+const MAX_DEPOSIT_POLL_SECONDS: u64 = 120;
+// Upper bound on the number of verification calls a single `deposit_and_poll` makes
+pub const DEPOSIT_POLL_MAX_ATTEMPTS: u32 = 10;
+// Largest order metadata accepted, in bytes
+pub const MAX_ORDER_METADATA_BYTES: usize = 256;
+// Returned by the legacy `deposit_funds`, which never moved funds; deposits go through orders
+pub const DEPOSIT_FUNDS_DEPRECATED: &str = "Deprecated: deposit_funds no longer exists. Call create_order, send the deposit from your own wallet to the address in get_deposit_instructions, then call confirm_deposit with its transaction ID";
+// Most assets a basket order may pay the creator beyond `to_asset`
+pub const MAX_EXTRA_TO_LEGS: usize = 3;
+// Canonical mint of wrapped BTC (Wormhole wBTC) on Solana; 8 decimals like BTC itself
+pub const WBTC_MINT: &str = "3NZ9JMVBmGAqocybic2c7LQCJScmgsAZ6vQqTDzcqmJh";
+// SPL tokens an order may name in place of the mint address: (name, mint, decimals)
+// Named mints still have to be allowlisted like any other
+pub const NAMED_SPL_MINTS: &[(&str, &str, u8)] = &[("WBTC", WBTC_MINT, 8)];
+// Most orders `verify_deposits` checks in one call, bounding its RPC and cycle cost
+pub const MAX_BATCH_VERIFICATIONS: usize = 20;
+// Virtual size of a typical one-input P2WPKH transfer, used to price a resolver's Bitcoin deposit
+const RESOLVER_BTC_DEPOSIT_VSIZE: u64 = 141;
+
+/// Reject integrator metadata over `MAX_ORDER_METADATA_BYTES`
+pub fn check_order_metadata(metadata: Option<&str>) -> Result<(), String> {
+    match metadata {
+        Some(metadata) if metadata.len() > MAX_ORDER_METADATA_BYTES => Err(format!(
+            "Order metadata is {} bytes; the maximum is {}",
+            metadata.len(),
+            MAX_ORDER_METADATA_BYTES
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Validate the extra legs of a basket order
+/// Each asset may appear once across `to_asset` and the legs, since deposits are verified and
+/// held per asset; oracle pricing only covers `to_amount`, so baskets must be fixed-price
+pub fn check_basket_legs(
+    to_asset: &Asset,
+    extra_to_legs: &[(Asset, u64)],
+    pricing: Option<&OrderPricing>,
+) -> Result<(), String> {
+    if extra_to_legs.is_empty() {
+        return Ok(());
+    }
+    if extra_to_legs.len() > MAX_EXTRA_TO_LEGS {
+        return Err(format!(
+            "Basket order has {} extra legs; the maximum is {}",
+            extra_to_legs.len(),
+            MAX_EXTRA_TO_LEGS
+        ));
+    }
+    if matches!(pricing, Some(OrderPricing::Oracle { .. })) {
+        return Err("Basket orders must use fixed pricing".to_string());
+    }
+
+    let mut seen = vec![to_asset];
+    for (asset, amount) in extra_to_legs {
+        if *amount == 0 {
+            return Err(format!("Basket leg of {:?} has no amount", asset));
+        }
+        if seen.contains(&asset) {
+            return Err(format!("{:?} appears more than once in the basket", asset));
+        }
+        seen.push(asset);
+    }
+    Ok(())
+}
+
+/// Reject swapping an asset for itself, which is almost certainly a client bug
+/// SPL tokens only count as the same asset when the mint matches too
+pub fn check_distinct_assets(
+    from_asset: &Asset,
+    to_asset: &Asset,
+    extra_to_legs: &[(Asset, u64)],
+) -> Result<(), String> {
+    let to_assets = std::iter::once(to_asset).chain(extra_to_legs.iter().map(|(asset, _)| asset));
+    for asset in to_assets {
+        if asset == from_asset {
+            return Err(format!(
+                "SameAssetSwap: cannot swap {:?} for itself",
+                from_asset
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Check an order leg against the configured per-order and outstanding limits
+pub fn check_order_limits(
+    config: &SwapConfig,
+    asset: &Asset,
+    amount: u64,
+    outstanding: u64,
+) -> Result<(), String> {
+    if let Some(max_amount) = config.max_order_amount(asset) {
+        if amount > max_amount {
+            return Err(format!(
+                "OrderTooLarge: {} exceeds the maximum of {} for {:?}",
+                amount, max_amount, asset
+            ));
+        }
+    }
+
+    if let Some(max_outstanding) = config.max_outstanding_amount(asset) {
+        if outstanding.saturating_add(amount) > max_outstanding {
+            return Err(format!(
+                "OrderTooLarge: {} outstanding plus {} exceeds the limit of {} for {:?}",
+                outstanding, amount, max_outstanding, asset
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Reject a creator deposit below the configured minimum for its asset
+pub fn check_minimum_deposit(
+    config: &SwapConfig,
+    asset: &Asset,
+    amount: u64,
+) -> Result<(), String> {
+    match config.min_deposit_amount(asset) {
+        Some(min_amount) if amount < min_amount => Err(format!(
+            "BelowMinimum: {} is below the minimum deposit of {} for {:?}",
+            amount, min_amount, asset
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Check that `open_orders` (including the order being admitted) stays within the per-asset cap
+pub fn check_open_order_capacity(
+    config: &SwapConfig,
+    asset: &Asset,
+    open_orders: u64,
+) -> Result<(), String> {
+    match config.max_open_orders_per_asset {
+        Some(max_open) if open_orders > max_open => Err(format!(
+            "CapacityExceeded: {} open orders for {:?} exceeds the limit of {}",
+            open_orders, asset, max_open
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Turn an SPL token named after a registered mint (e.g. `WBTC`) into its canonical mint and decimals
+/// A registered mint given by address must carry the registered decimals
+pub fn resolve_named_mint(asset: Asset) -> Result<Asset, String> {
+    let Asset::SplToken {
+        mint_address,
+        decimals,
+    } = &asset
+    else {
+        return Ok(asset);
+    };
+    let registered = NAMED_SPL_MINTS
+        .iter()
+        .find(|(name, mint, _)| name.eq_ignore_ascii_case(mint_address) || mint == mint_address);
+    match registered {
+        None => Ok(asset),
+        Some((name, mint, registered_decimals))
+            if mint == mint_address && decimals != registered_decimals =>
+        {
+            Err(format!(
+                "MintMismatch: {} ({}) has {} decimals, not {}",
+                name, mint, registered_decimals, decimals
+            ))
+        }
+        Some((_, mint, registered_decimals)) => Ok(Asset::SplToken {
+            mint_address: mint.to_string(),
+            decimals: *registered_decimals,
+        }),
+    }
+}
+
+/// Reject SPL tokens whose mint is not on the allowlist, unless the allowlist is open
+pub fn check_asset_allowed(config: &SwapConfig, asset: &Asset) -> Result<(), String> {
+    match asset {
+        Asset::SplToken { mint_address, .. } if !config.is_spl_mint_allowed(mint_address) => {
+            Err(format!(
+                "MintNotAllowed: SPL mint {} is not allowlisted",
+                mint_address
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Check every asset of an order that is already counted as open against the per-asset cap
+fn check_order_capacity(order: &Order) -> Result<(), String> {
+    for asset in order_assets(order) {
+        let open_orders = open_order_count(asset);
+        read_config(|config| check_open_order_capacity(config, asset, open_orders))?;
+    }
+    Ok(())
+}
+
+/// Validate that an address can receive the given asset on the configured network
+pub fn validate_receive_address(asset: &Asset, address: &str) -> Result<(), String> {
+    match asset {
+        Asset::Bitcoin => bitcoin_integration::validate_bitcoin_address(address),
+        Asset::Solana => solana_integration::validate_sol_transfer_destination(address),
+        Asset::SplToken { .. } => solana_integration::validate_solana_address(address),
+    }
+}
+
+/// Rebate a resolver earns on `amount`, decaying linearly from `max_rebate_bps` for a deposit at
+/// acceptance time to nothing once `window_seconds` have passed
+pub fn resolver_rebate(
+    config: &ResolverRebateConfig,
+    amount: u64,
+    accepted_at: u64,
+    resolver_deposited_at: u64,
+) -> u64 {
+    let window = config.window_seconds.saturating_mul(1_000_000_000);
+    let latency = resolver_deposited_at.saturating_sub(accepted_at);
+    if latency >= window {
+        return 0;
+    }
+
+    let max_rebate = amount as u128 * config.max_rebate_bps.min(10_000) as u128 / 10_000;
+    (max_rebate * (window - latency) as u128 / window as u128) as u64
+}
+
+/// Accrue the resolver's rebate for a settled order, if rebates are configured
+/// There is no protocol fee pool yet, so rebates are only recorded, not paid out
+fn accrue_settlement_rebate(order: &Order) {
+    let Some(config) = read_config(|config| config.resolver_rebate.clone()) else {
+        return;
+    };
+    let (Some(resolver), Some(accepted_at), Some(resolver_deposited_at)) = (
+        order.resolver,
+        order.accepted_at,
+        order.resolver_deposited_at,
+    ) else {
+        return;
+    };
+
+    let amount = resolver_rebate(
+        &config,
+        order.from_amount,
+        accepted_at,
+        resolver_deposited_at,
+    );
+    if amount > 0 {
+        accrue_resolver_rebate(
+            resolver,
+            ResolverRebate {
+                order_id: order.id,
+                asset: order.from_asset.clone(),
+                amount,
+            },
+        );
+    }
+}
+
+/// Validate the payout/refund wallet addresses a party supplies, before any funds are locked
+pub fn validate_wallet_addresses(
+    btc_address: Option<&String>,
+    sol_address: Option<&String>,
+) -> Result<(), String> {
+    if let Some(address) = btc_address {
+        bitcoin_integration::validate_bitcoin_address(address)?;
+    }
+    if let Some(address) = sol_address {
+        solana_integration::validate_solana_address(address)?;
+    }
+    Ok(())
+}
+
+/// Get the refund destination for a leg, preferring a validated override address
+pub fn get_refund_address(
+    asset: &Asset,
+    override_address: Option<&String>,
+    btc_addr: Option<&String>,
+    sol_addr: Option<&String>,
+) -> Result<String, String> {
+    match override_address {
+        Some(address) => {
+            validate_receive_address(asset, address)?;
+            Ok(address.clone())
+        }
+        None => get_receive_address(asset, btc_addr, sol_addr),
+    }
+}
+
 /// Create a new swap order
 #[ic_cdk::update]
 pub async fn create_order(
@@ -71,19 +473,143 @@ pub async fn create_order(
     creator_sol_address: Option<String>,
 ) -> Result<(u64, CanisterAddresses), String> {
     let caller = ic_cdk::api::msg_caller();
+    let meter = CostMeter::start();
+    let result = create_order_as(caller, request, creator_btc_address, creator_sol_address).await;
+    let order_id = result.as_ref().ok().map(|(order_id, _)| *order_id);
+    record_audit(caller, order_id, "create_order", &result);
+    meter.finish("create_order");
+    result
+}
+
+async fn create_order_as(
+    caller: Principal,
+    request: OrderRequest,
+    creator_btc_address: Option<String>,
+    creator_sol_address: Option<String>,
+) -> Result<(u64, CanisterAddresses), String> {
+    let order = build_order(caller, request, creator_btc_address, creator_sol_address).await?;
+    register_with_addresses(order, get_canister_addresses, ic_cdk::api::id()).await
+}
+
+/// Register `order` once `derive` has produced the canister addresses it is funded through
+/// Nothing is stored or reserved when derivation fails, so a caller never ends up with an order
+/// they were given no address to deposit to
+pub async fn register_with_addresses<F, Fut>(
+    order: Order,
+    derive: F,
+    canister_id: Principal,
+) -> Result<(u64, CanisterAddresses), String>
+where
+    F: FnOnce() -> Fut,
+    Fut: std::future::Future<Output = Result<CanisterAddresses, String>>,
+{
+    let canister_addresses = derive().await?;
+    let order_id = register_order(order, canister_id)?;
+    Ok((order_id, canister_addresses))
+}
+
+/// Validate a request and build the order it describes, not yet registered
+async fn build_order(
+    caller: Principal,
+    mut request: OrderRequest,
+    creator_btc_address: Option<String>,
+    creator_sol_address: Option<String>,
+) -> Result<Order, String> {
     let current_time = time();
 
-    let order_id = generate_order_id();
+    validate_wallet_addresses(creator_btc_address.as_ref(), creator_sol_address.as_ref())?;
+    check_order_metadata(request.metadata.as_deref())?;
+
+    // Named mints are swapped for their canonical mint before any other check sees them
+    request.from_asset = resolve_named_mint(request.from_asset)?;
+    request.to_asset = resolve_named_mint(request.to_asset)?;
+    let extra_to_legs = request
+        .extra_to_legs
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(asset, amount)| Ok((resolve_named_mint(asset)?, amount)))
+        .collect::<Result<Vec<_>, String>>()?;
+    check_basket_legs(&request.to_asset, &extra_to_legs, request.pricing.as_ref())?;
+    check_distinct_assets(&request.from_asset, &request.to_asset, &extra_to_legs)?;
+
+    let assets: Vec<&Asset> = [&request.from_asset, &request.to_asset]
+        .into_iter()
+        .chain(extra_to_legs.iter().map(|(asset, _)| asset))
+        .collect();
+    for asset in &assets {
+        read_config(|config| check_asset_allowed(config, asset))?;
+    }
+
+    if let Some(max_timeout) = read_config(|config| config.max_timeout_seconds) {
+        if request.timeout_seconds > max_timeout {
+            return Err(format!(
+                "Timeout of {} seconds exceeds the maximum of {} seconds",
+                request.timeout_seconds, max_timeout
+            ));
+        }
+    }
+
+    let expires_at = compute_expires_at(current_time, request.timeout_seconds)?;
+
+    let from_amount = resolve_amount(
+        &request.from_asset,
+        request.from_amount,
+        request.from_amount_ui.as_deref(),
+    )?;
+    read_config(|config| check_minimum_deposit(config, &request.from_asset, from_amount))?;
+    let requested_to_amount = resolve_amount(
+        &request.to_asset,
+        request.to_amount,
+        request.to_amount_ui.as_deref(),
+    )?;
+
+    let (to_amount, pricing) = match request.pricing {
+        None => (
+            requested_to_amount,
+            OrderPricing::Fixed {
+                to_amount: requested_to_amount,
+            },
+        ),
+        Some(OrderPricing::Fixed { to_amount }) => (to_amount, OrderPricing::Fixed { to_amount }),
+        Some(OrderPricing::Oracle {
+            reference,
+            max_slippage_bps,
+        }) => {
+            if max_slippage_bps > 10_000 {
+                return Err("Maximum slippage cannot exceed 10000 basis points".to_string());
+            }
+            (
+                reference,
+                OrderPricing::Oracle {
+                    reference,
+                    max_slippage_bps,
+                },
+            )
+        }
+    };
+
+    // Cache SPL metadata up front, so order listings carry the token symbol without a lookup
+    for asset in assets {
+        if let Asset::SplToken { mint_address, .. } = asset {
+            if let Err(e) = solana_integration::get_token_metadata(mint_address.clone()).await {
+                ic_cdk::println!("⚠️ No metadata for mint {}: {}", mint_address, e);
+            }
+        }
+    }
 
+    // Assigned by `register_order`
     let order = Order {
-        id: order_id,
+        id: 0,
+        sequence: 0,
         creator: caller,
         creator_btc_address,
         creator_sol_address,
         from_asset: request.from_asset,
         to_asset: request.to_asset,
-        from_amount: request.from_amount,
-        to_amount: request.to_amount,
+        from_amount,
+        to_amount,
+        pricing,
+        extra_to_legs,
         secret_hash: request.secret_hash,
         secret: None,
         status: OrderStatus::AwaitingDeposit,
@@ -91,344 +617,2186 @@ pub async fn create_order(
         resolver_btc_address: None,
         resolver_sol_address: None,
         created_at: current_time,
-        expires_at: current_time + (request.timeout_seconds * 1_000_000_000),
+        expires_at,
         creator_txid: None,
         resolver_txid: None,
+        resolver_payout_txid: None,
+        creator_payout_txid: None,
+        extra_leg_txids: Vec::new(),
+        resolver_refund_txid: None,
+        creator_refund_amount: None,
+        resolver_refund_amount: None,
+        creator_overpayment: None,
+        overpayment_refund_txid: None,
+        settlement_receipt: None,
         creator_deposited: false,
         resolver_deposited: false,
+        require_resolver_ack: request.require_resolver_ack.unwrap_or_default(),
+        secret_revealed_by: None,
+        manual_review: None,
+        metadata: request.metadata,
+        deposit_confirmed_at: None,
+        accepted_at: None,
+        resolver_deposited_at: None,
+        resolver_acknowledged_at: None,
+        completed_at: None,
+        cancelled_at: None,
     };
 
-    ORDERS.with(|orders| {
-        orders.borrow_mut().insert(order_id, order);
-    });
-
-    let canister_addresses = get_canister_addresses().await?;
-
-    Ok((order_id, canister_addresses))
+    Ok(order)
 }
 
-/// Confirm creator's deposit
+/// Replace a creator's order with one on new terms, in a single call
+/// The old order is cancelled and its deposit refunded, unless the new order takes the same asset
+/// and amount, in which case the deposit moves over and stays held
 #[ic_cdk::update]
-pub async fn confirm_deposit(order_id: u64, txid: String) -> Result<String, String> {
-    let caller = ic_cdk::api::caller();
+pub async fn replace_order(
+    old_order_id: u64,
+    request: OrderRequest,
+) -> Result<(u64, CanisterAddresses), String> {
+    let caller = ic_cdk::api::msg_caller();
+    let meter = CostMeter::start();
+    let result = replace_order_as(caller, old_order_id, request).await;
+    record_audit(caller, Some(old_order_id), "replace_order", &result);
+    meter.finish("replace_order");
+    result
+}
 
-    let order = ORDERS
-        .with(|orders| orders.borrow().get(&order_id).cloned())
+async fn replace_order_as(
+    caller: Principal,
+    old_order_id: u64,
+    request: OrderRequest,
+) -> Result<(u64, CanisterAddresses), String> {
+    let old = ORDERS
+        .with(|orders| orders.borrow().get(&old_order_id).cloned())
         .ok_or("Order not found")?;
+    check_cancellable(&old, caller)?;
 
-    if order.creator != caller {
-        return Err("Only order creator can confirm deposit".to_string());
-    }
+    let replacement = build_order(
+        caller,
+        request,
+        old.creator_btc_address.clone(),
+        old.creator_sol_address.clone(),
+    )
+    .await?;
+    // Derived before the old order is cancelled, so a failure here leaves both untouched
+    let canister_addresses = get_canister_addresses().await?;
 
-    if order.creator_deposited {
-        return Err("Deposit already confirmed".to_string());
+    let (order_id, to_refund) =
+        swap_in_replacement(caller, old_order_id, replacement, ic_cdk::api::id(), time())?;
+
+    // The replacement exists by now, so a failed refund is left to `process_refund` after expiry
+    if let Some(old) = to_refund {
+        match process_refund_internal(&old, true, false, None, None).await {
+            Ok(refund_tx) => ic_cdk::println!(
+                "🔁 Order {} replaced by {}; {}",
+                old_order_id,
+                order_id,
+                refund_tx
+            ),
+            Err(e) => ic_cdk::println!(
+                "❌ Order {} replaced by {}, but its refund failed: {}",
+                old_order_id,
+                order_id,
+                e
+            ),
+        }
     }
 
-    let canister_address = match &order.from_asset {
-        Asset::Bitcoin => CANISTER_BTC_ADDRESS
-            .with(|addr| addr.borrow().clone())
-            .ok_or("Canister Bitcoin address not initialized")?,
-        Asset::Solana | Asset::SplToken { .. } => CANISTER_SOL_ADDRESS
-            .with(|addr| addr.borrow().clone())
-            .ok_or("Canister Solana address not initialized")?,
-    };
-
-    let verified = verify_asset_deposit(
-        &order.from_asset,
-        &canister_address,
-        order.from_amount,
-        txid.clone(),
-    )
-    .await?;
+    Ok((order_id, canister_addresses))
+}
 
-    if !verified {
-        return Err("Transaction not found or insufficient amount".to_string());
+/// Register `replacement` and cancel the order it replaces, without awaiting in between
+/// The old order is checked again, as it may have moved on while the replacement was built.
+/// The creator's refund addresses carry over; so does a confirmed deposit when the replacement
+/// takes the same asset and amount, and the old order then no longer counts it as deposited.
+/// Returns the new order's id, and the old order when its deposit still has to be refunded
+pub fn swap_in_replacement(
+    caller: Principal,
+    old_order_id: u64,
+    mut replacement: Order,
+    canister_id: Principal,
+    now: u64,
+) -> Result<(u64, Option<Order>), String> {
+    let old = ORDERS
+        .with(|orders| orders.borrow().get(&old_order_id).cloned())
+        .ok_or("Order not found")?;
+    check_cancellable(&old, caller)?;
+
+    replacement.creator_btc_address = old.creator_btc_address.clone();
+    replacement.creator_sol_address = old.creator_sol_address.clone();
+    let carry_deposit = old.creator_deposited
+        && old.from_asset == replacement.from_asset
+        && old.from_amount == replacement.from_amount;
+    if carry_deposit {
+        replacement.creator_txid = old.creator_txid.clone();
+        replacement.creator_deposited = true;
+        replacement.status = OrderStatus::DepositReceived;
+        replacement.deposit_confirmed_at = old.deposit_confirmed_at;
     }
 
-    ORDERS.with(|orders| {
-        if let Some(ord) = orders.borrow_mut().get_mut(&order_id) {
-            ord.creator_txid = Some(txid);
-            ord.creator_deposited = true;
-            ord.status = OrderStatus::DepositReceived;
+    // The old order's slot is freed first, so a creator at the open-order cap can still replace
+    release_open_order(&old);
+    let order_id = match register_order(replacement, canister_id) {
+        Ok(order_id) => order_id,
+        Err(e) => {
+            track_open_order(&old);
+            return Err(e);
+        }
+    };
+
+    record_abandonment(&old, now);
+    update_order(old_order_id, |ord| {
+        ord.status = OrderStatus::Cancelled;
+        ord.cancelled_at = Some(now);
+        if carry_deposit {
+            ord.creator_deposited = false;
         }
     });
 
-    Ok("Deposit confirmed! Order is now visible to resolvers.".to_string())
+    let to_refund = (old.creator_deposited && !carry_deposit).then_some(old);
+    Ok((order_id, to_refund))
 }
 
-/// Resolver accepts an order
+/// Run a whole swap in one call, the caller acting as both creator and resolver, for integration testing
+/// Deposits are assumed rather than verified, so the payouts come out of whatever the canister holds;
+/// rejected unless the canister was installed with `test_mode` and the caller is a controller
 #[ic_cdk::update]
-pub async fn accept_order(
-    order_id: u64,
-    resolver_btc_address: Option<String>,
-    resolver_sol_address: Option<String>,
-) -> Result<CanisterAddresses, String> {
+pub async fn execute_full_swap(
+    request: OrderRequest,
+    creator_addresses: PartyAddresses,
+    resolver_addresses: PartyAddresses,
+    secret: String,
+) -> Result<String, String> {
     let caller = ic_cdk::api::caller();
+    let meter = CostMeter::start();
+    let result = execute_full_swap_as(
+        caller,
+        request,
+        creator_addresses,
+        resolver_addresses,
+        secret,
+        &meter,
+    )
+    .await;
+    record_audit(caller, None, "execute_full_swap", &result);
+    meter.finish("execute_full_swap");
+    result
+}
+
+async fn execute_full_swap_as(
+    caller: Principal,
+    request: OrderRequest,
+    creator_addresses: PartyAddresses,
+    resolver_addresses: PartyAddresses,
+    secret: String,
+    meter: &CostMeter,
+) -> Result<String, String> {
+    let caller_is_controller = ic_cdk::api::is_controller(&caller);
+    read_config(|config| check_full_swap_access(config, caller_is_controller))?;
+    validate_wallet_addresses(
+        resolver_addresses.btc_address.as_ref(),
+        resolver_addresses.sol_address.as_ref(),
+    )?;
+
+    let order = build_order(
+        caller,
+        request,
+        creator_addresses.btc_address,
+        creator_addresses.sol_address,
+    )
+    .await?;
+    let (order_id, _) =
+        register_with_addresses(order, get_canister_addresses, ic_cdk::api::id()).await?;
+    stage_full_swap(order_id, caller, resolver_addresses, time())?;
+
+    reveal_secret_as(caller, order_id, secret, meter).await
+}
+
+/// Refuse development-only endpoints unless the canister runs in test mode
+pub fn check_test_mode(config: &SwapConfig) -> Result<(), String> {
+    if !config.test_mode {
+        return Err(
+            "TestModeDisabled: only available when the canister runs in test mode".to_string(),
+        );
+    }
+    Ok(())
+}
+
+/// Refuse `execute_full_swap` outside test mode or to anyone but a controller, since its
+/// payouts come out of the canister's pooled funds
+pub fn check_full_swap_access(
+    config: &SwapConfig,
+    caller_is_controller: bool,
+) -> Result<(), String> {
+    check_test_mode(config)?;
+    if !caller_is_controller {
+        return Err("Only controllers can call this method".to_string());
+    }
+    Ok(())
+}
 
+/// Move a newly registered order to `ResolverDeposited`, accepted by `resolver`, as if both
+/// deposits had been confirmed; the parties' addresses must still differ, as `accept_order` requires
+pub fn stage_full_swap(
+    order_id: u64,
+    resolver: Principal,
+    resolver_addresses: PartyAddresses,
+    now: u64,
+) -> Result<(), String> {
     let order = ORDERS
         .with(|orders| orders.borrow().get(&order_id).cloned())
         .ok_or("Order not found")?;
-
-    if !matches!(order.status, OrderStatus::DepositReceived) {
-        return Err("Order not ready for acceptance".to_string());
+    if order.status != OrderStatus::AwaitingDeposit {
+        return Err(format!("Order is {:?}, not awaiting deposit", order.status));
+    }
+    let shares_address = |creator: &Option<String>, resolver: &Option<String>| {
+        creator.is_some() && creator == resolver
+    };
+    if shares_address(&order.creator_btc_address, &resolver_addresses.btc_address)
+        || shares_address(&order.creator_sol_address, &resolver_addresses.sol_address)
+    {
+        return Err("Creator and resolver must use distinct addresses".to_string());
     }
 
-    // Check if resolver is trying to use the same wallet addresses as creator
-    // This prevents self-dealing while allowing the same ICP principal to resolve
-    if let Some(ref creator_btc) = order.creator_btc_address {
-        if let Some(ref resolver_btc) = resolver_btc_address {
-            if creator_btc == resolver_btc {
-                return Err("Cannot accept order with your own Bitcoin address".to_string());
-            }
-        }
+    update_order(order_id, |ord| {
+        ord.creator_deposited = true;
+        ord.deposit_confirmed_at = Some(now);
+        ord.resolver = Some(resolver);
+        ord.resolver_btc_address = resolver_addresses.btc_address;
+        ord.resolver_sol_address = resolver_addresses.sol_address;
+        ord.accepted_at = Some(now);
+        ord.resolver_deposited = true;
+        ord.resolver_deposited_at = Some(now);
+        ord.resolver_acknowledged_at = Some(now);
+        ord.status = OrderStatus::ResolverDeposited;
+    });
+    Ok(())
+}
+
+/// Check limits and capacity, then allocate the order's ID and store it
+/// Runs without awaiting, so no other call can interleave between the checks and the insert,
+/// and an order rejected by the checks never consumes an ID
+pub fn register_order(mut order: Order, canister_id: Principal) -> Result<u64, String> {
+    let legs =
+        std::iter::once((order.from_asset.clone(), order.from_amount)).chain(order.to_legs());
+    for (asset, amount) in legs {
+        let outstanding = outstanding_amount(&asset);
+        read_config(|config| check_order_limits(config, &asset, amount, outstanding))?;
     }
-    
-    if let Some(ref creator_sol) = order.creator_sol_address {
-        if let Some(ref resolver_sol) = resolver_sol_address {
-            if creator_sol == resolver_sol {
-                return Err("Cannot accept order with your own Solana address".to_string());
-            }
-        }
+
+    for asset in order_assets(&order) {
+        let open_orders = open_order_count(asset) + 1;
+        read_config(|config| check_open_order_capacity(config, asset, open_orders))?;
     }
 
-    let canister_addresses = get_canister_addresses().await?;
+    let (order_id, sequence) = generate_order_id(canister_id, order.created_at);
+    order.id = order_id;
+    order.sequence = sequence;
 
-    ORDERS.with(|orders| {
-        if let Some(ord) = orders.borrow_mut().get_mut(&order_id) {
-            ord.resolver = Some(caller);
-            ord.resolver_btc_address = resolver_btc_address;
-            ord.resolver_sol_address = resolver_sol_address;
-        }
-    });
+    track_open_order(&order);
+    insert_order(order);
 
-    Ok(canister_addresses)
+    Ok(order_id)
 }
 
-/// Resolver confirms their deposit
+/// Expiry timestamp (nanoseconds) of an order created at `current_time`
+/// Rejects timeouts that would overflow instead of wrapping to a timestamp in the past
+pub fn compute_expires_at(current_time: u64, timeout_seconds: u64) -> Result<u64, String> {
+    timeout_seconds
+        .checked_mul(1_000_000_000)
+        .and_then(|timeout| current_time.checked_add(timeout))
+        .ok_or_else(|| format!("InvalidTimeout: {} seconds is too long", timeout_seconds))
+}
+
+/// Push back the expiry of an order the resolver has not yet deposited into
 #[ic_cdk::update]
-pub async fn confirm_resolver_deposit(order_id: u64, txid: String) -> Result<String, String> {
+pub fn extend_order(order_id: u64, additional_seconds: u64) -> Result<u64, String> {
     let caller = ic_cdk::api::caller();
+    let meter = CostMeter::start();
+    let result = extend_order_as(caller, order_id, additional_seconds);
+    record_audit(caller, Some(order_id), "extend_order", &result);
+    meter.finish("extend_order");
+    result
+}
 
+fn extend_order_as(
+    caller: Principal,
+    order_id: u64,
+    additional_seconds: u64,
+) -> Result<u64, String> {
     let order = ORDERS
         .with(|orders| orders.borrow().get(&order_id).cloned())
         .ok_or("Order not found")?;
 
-    if order.resolver != Some(caller) {
-        return Err("Only resolver can confirm their deposit".to_string());
+    let max_timeout = read_config(|config| config.max_timeout_seconds);
+    let expires_at = check_extension(&order, caller, additional_seconds, max_timeout, time())?;
+
+    update_order(order_id, |ord| {
+        ord.expires_at = expires_at;
+    });
+
+    Ok(expires_at)
+}
+
+/// Validate an expiry extension and return the new `expires_at`
+/// Once the resolver has deposited, their refund timing depends on the current expiry,
+/// so extensions are no longer allowed
+pub fn check_extension(
+    order: &Order,
+    caller: Principal,
+    additional_seconds: u64,
+    max_timeout_seconds: Option<u64>,
+    current_time: u64,
+) -> Result<u64, String> {
+    if order.creator != caller {
+        return Err("Only order creator can extend the order".to_string());
     }
 
-    if order.resolver_deposited {
-        return Err("Resolver deposit already confirmed".to_string());
+    if order.status.is_terminal() {
+        return Err("Order is no longer open".to_string());
     }
 
-    let canister_address = match &order.to_asset {
-        Asset::Bitcoin => CANISTER_BTC_ADDRESS
-            .with(|addr| addr.borrow().clone())
-            .ok_or("Canister Bitcoin address not initialized")?,
-        Asset::Solana | Asset::SplToken { .. } => CANISTER_SOL_ADDRESS
-            .with(|addr| addr.borrow().clone())
-            .ok_or("Canister Solana address not initialized")?,
-    };
+    if order.resolver_deposited {
+        return Err("Cannot extend after resolver has deposited".to_string());
+    }
 
-    let verified = verify_asset_deposit(
-        &order.to_asset,
-        &canister_address,
-        order.to_amount,
-        txid.clone(),
-    )
-    .await?;
+    if current_time >= order.expires_at {
+        return Err("Order has expired".to_string());
+    }
 
-    if !verified {
-        return Err("Transaction not found or insufficient amount".to_string());
+    if additional_seconds == 0 {
+        return Err("Extension must be at least one second".to_string());
     }
 
-    ORDERS.with(|orders| {
-        if let Some(ord) = orders.borrow_mut().get_mut(&order_id) {
-            ord.resolver_txid = Some(txid);
-            ord.resolver_deposited = true;
-            ord.status = OrderStatus::ResolverDeposited;
+    let expires_at = additional_seconds
+        .checked_mul(1_000_000_000)
+        .and_then(|extension| order.expires_at.checked_add(extension))
+        .ok_or("Extension too long")?;
+
+    if let Some(max_timeout) = max_timeout_seconds {
+        let timeout = expires_at.saturating_sub(order.created_at) / 1_000_000_000;
+        if timeout > max_timeout {
+            return Err(format!(
+                "Extended timeout of {} seconds exceeds the maximum of {} seconds",
+                timeout, max_timeout
+            ));
         }
-    });
+    }
 
-    Ok("Resolver deposit confirmed!".to_string())
+    Ok(expires_at)
 }
 
-/// Reveal secret to complete the swap
+/// Confirm creator's deposit
 #[ic_cdk::update]
-pub async fn reveal_secret(order_id: u64, secret: String) -> Result<String, String> {
+pub async fn confirm_deposit(order_id: u64, txid: String) -> Result<String, String> {
     let caller = ic_cdk::api::caller();
-    let current_time = time();
+    let meter = CostMeter::start();
+    let result = confirm_deposit_as(caller, order_id, txid, 0).await;
+    record_audit(caller, Some(order_id), "confirm_deposit", &result);
+    meter.finish("confirm_deposit");
+    result
+}
+
+/// Confirm creator's deposit, re-checking the chain until it is seen or `max_wait_seconds` pass
+/// The checks are spread evenly over the wait, with a timer between them, but each is a full
+/// verification (a `bitcoin_get_utxos` call or SOL RPC HTTPS outcalls), so the cycle cost is up
+/// to `DEPOSIT_POLL_MAX_ATTEMPTS` times that of `confirm_deposit`
+#[ic_cdk::update]
+pub async fn deposit_and_poll(
+    order_id: u64,
+    txid: String,
+    max_wait_seconds: u64,
+) -> Result<String, String> {
+    let caller = ic_cdk::api::caller();
+    let meter = CostMeter::start();
+    let result = confirm_deposit_as(caller, order_id, txid, max_wait_seconds).await;
+    record_audit(caller, Some(order_id), "deposit_and_poll", &result);
+    meter.finish("deposit_and_poll");
+    result
+}
 
+async fn confirm_deposit_as(
+    caller: Principal,
+    order_id: u64,
+    txid: String,
+    max_wait_seconds: u64,
+) -> Result<String, String> {
     let order = ORDERS
         .with(|orders| orders.borrow().get(&order_id).cloned())
         .ok_or("Order not found")?;
 
     if order.creator != caller {
-        return Err("Only order creator can reveal secret".to_string());
+        return Err("Only order creator can confirm deposit".to_string());
     }
 
-    if !matches!(order.status, OrderStatus::ResolverDeposited) {
-        return Err("Resolver has not deposited funds yet".to_string());
+    check_deposit_open(&order, time())?;
+    check_txid_unused(order_id, &txid)?;
+    check_order_capacity(&order)?;
+
+    resolve_asset_token_program(&order.from_asset).await?;
+    let canister_address = get_canister_deposit_address(&order.from_asset)?;
+    let memo = required_deposit_memo(order.id, &order.from_asset);
+
+    let wait = Duration::from_secs(max_wait_seconds.min(MAX_DEPOSIT_POLL_SECONDS));
+    let verified = poll_verification(
+        time().saturating_add(wait.as_nanos() as u64),
+        DEPOSIT_POLL_MAX_ATTEMPTS,
+        wait / DEPOSIT_POLL_MAX_ATTEMPTS,
+        time,
+        sleep,
+        || {
+            verify_asset_deposit(
+                &order.from_asset,
+                &canister_address,
+                order.from_amount,
+                memo.clone(),
+                txid.clone(),
+            )
+        },
+    )
+    .await?;
+
+    if !verified {
+        let mut error = format!(
+            "Transaction not found or insufficient amount sent to {}",
+            canister_address
+        );
+        // A Bitcoin confirmation is retried in the background until the named transaction appears
+        if matches!(order.from_asset, Asset::Bitcoin)
+            && read_config(|config| config.deposit_watch_interval_seconds.is_some())
+        {
+            claim_deposit(order_id, &txid);
+            error.push_str("; it will be confirmed automatically once seen");
+        }
+        return Err(error);
     }
 
-    if current_time >= order.expires_at {
-        return Err("Order has expired".to_string());
+    // Polling may have waited long enough for the order to be cancelled or expired meanwhile
+    credit_creator_deposit(order_id, &txid, time())?;
+
+    let mut message = "Deposit confirmed! Order is now visible to resolvers.".to_string();
+    if let Some(note) = handle_overpayment(&order, &canister_address, &txid).await {
+        message.push_str(&format!(" {}", note));
+    }
+    Ok(message)
+}
+
+/// Send back whatever a confirmed creator deposit paid beyond `from_amount`
+/// Only an excess `check_overpayment_refundable` allows is sent; any other, and any refund that
+/// fails, is recorded for an operator. The deposit stays confirmed either way
+/// Returns a note for the caller, or None when the deposit was exact
+async fn handle_overpayment(order: &Order, canister_address: &str, txid: &str) -> Option<String> {
+    let received = match deposit_received_amount(&order.from_asset, canister_address, txid).await {
+        Ok(received) => received,
+        Err(e) => {
+            ic_cdk::println!(
+                "⚠️ Could not measure deposit {} of order {}: {}",
+                txid,
+                order.id,
+                e
+            );
+            return None;
+        }
+    };
+    let excess = deposit_overpayment(order.from_amount, received)?;
+
+    let asset = &order.from_asset;
+    let refund_address = check_overpayment_refundable(order, txid).and_then(|()| {
+        get_receive_address(
+            asset,
+            order.creator_btc_address.as_ref(),
+            order.creator_sol_address.as_ref(),
+        )
+    });
+    let refund = match refund_address {
+        Ok(refund_address) => {
+            let fee = overpayment_refund_fee(asset);
+            refund_overpayment_with(order.id, excess, fee, |amount| {
+                send_asset(order.id, asset, &refund_address, amount)
+            })
+            .await
+        }
+        Err(e) => Err(e),
+    };
+
+    match refund {
+        Ok(refund_txid) => Some(format!(
+            "Overpayment of {} refunded in {}.",
+            excess, refund_txid
+        )),
+        Err(e) => {
+            let reason = format!("Overpayment of {} not refunded: {}", excess, e);
+            update_order(order.id, |ord| {
+                ord.creator_overpayment = Some(excess);
+                ord.manual_review = Some(reason.clone());
+            });
+            Some(reason)
+        }
     }
+}
 
-    let secret_hash = format!("{:x}", md5::compute(&secret));
-    if secret_hash != order.secret_hash {
-        return Err("Secret does not match hash".to_string());
+/// Whether the excess of deposit `txid` may be sent back without an operator
+/// Deposits share the canister's addresses, so only one that had to carry its order's memo is
+/// provably the creator's; refunding any other could pay someone else's deposit to the creator
+pub fn check_overpayment_refundable(order: &Order, txid: &str) -> Result<(), String> {
+    if required_deposit_memo(order.id, &order.from_asset).is_none() {
+        return Err(match order.from_asset {
+            Asset::Bitcoin => "Bitcoin overpayments are refunded by an operator".to_string(),
+            _ => "the deposit is not bound to the order by a memo, so an operator refunds it"
+                .to_string(),
+        });
     }
+    if txid_recorded_on_other_order(order.id, txid) {
+        return Err(format!("transaction {} is recorded on another order", txid));
+    }
+    Ok(())
+}
 
-    ic_cdk::println!("🔓 Secret verified for order {}. Starting atomic swap...", order_id);
+/// Amount the deposit transaction `txid` itself paid to `canister_address`
+async fn deposit_received_amount(
+    asset: &Asset,
+    canister_address: &str,
+    txid: &str,
+) -> Result<u64, String> {
+    match asset {
+        Asset::Bitcoin => {
+            let outputs =
+                bitcoin_integration::get_incoming_outputs(canister_address.to_string()).await?;
+            checked_sum(
+                outputs
+                    .into_iter()
+                    .filter(|(output_txid, _)| output_txid == txid)
+                    .map(|(_, amount)| amount),
+            )
+        }
+        Asset::Solana => {
+            solana_integration::get_lamports_credit(canister_address.to_string(), txid.to_string())
+                .await
+        }
+        Asset::SplToken { mint_address, .. } => solana_integration::check_spl_token_transaction(
+            canister_address.to_string(),
+            0,
+            mint_address.clone(),
+            txid.to_string(),
+        )
+        .await
+        .map(|result| result.observed_amount),
+    }
+}
 
-    // Execute the atomic swap
-    let resolver_address = get_receive_address(
-        &order.from_asset,
-        order.resolver_btc_address.as_ref(),
-        order.resolver_sol_address.as_ref(),
-    )?;
+/// A deposit txid funds at most one order, and is never one the canister sent itself
+fn check_txid_unused(order_id: u64, txid: &str) -> Result<(), String> {
+    if txid_recorded_on_other_order(order_id, txid) {
+        return Err(format!(
+            "Transaction {} is already recorded on another order",
+            txid
+        ));
+    }
+    if is_txid_consumed(txid) {
+        return Err(format!(
+            "Transaction {} was already credited or sent by the canister",
+            txid
+        ));
+    }
+    Ok(())
+}
 
-    ic_cdk::println!("💸 Sending {:?} (amount: {}) to resolver at {}", order.from_asset, order.from_amount, resolver_address);
-    let resolver_tx = send_asset(&order.from_asset, &resolver_address, order.from_amount).await?;
-    ic_cdk::println!("✅ Resolver payment sent successfully! TXID: {}", resolver_tx);
+/// Whether some order other than `order_id` already holds `txid` as a deposit
+fn txid_recorded_on_other_order(order_id: u64, txid: &str) -> bool {
+    ORDERS.with(|orders| {
+        orders.borrow().values().any(|order| {
+            order.id != order_id
+                && [&order.creator_txid, &order.resolver_txid]
+                    .into_iter()
+                    .any(|recorded| recorded.as_deref() == Some(txid))
+        })
+    })
+}
 
-    let creator_address = get_receive_address(
-        &order.to_asset,
+/// How much a deposit of `received` paid beyond the `from_amount` the order asked for
+pub fn deposit_overpayment(from_amount: u64, received: u64) -> Option<u64> {
+    received
+        .checked_sub(from_amount)
+        .filter(|excess| *excess > 0)
+}
+
+/// Record an overpayment of `excess` on the order and send it back less the network `fee`
+/// `send` pays the given amount to the creator's refund address and returns the transaction id
+pub async fn refund_overpayment_with<F, Fut>(
+    order_id: u64,
+    excess: u64,
+    fee: u64,
+    send: F,
+) -> Result<String, String>
+where
+    F: FnOnce(u64) -> Fut,
+    Fut: std::future::Future<Output = Result<String, String>>,
+{
+    update_order(order_id, |ord| ord.creator_overpayment = Some(excess));
+
+    let amount = excess
+        .checked_sub(fee)
+        .filter(|amount| *amount > 0)
+        .ok_or_else(|| {
+            format!(
+                "UneconomicalSettlement: the {} overpaid does not cover the {} network fee",
+                excess, fee
+            )
+        })?;
+    let txid = send(amount).await?;
+
+    update_order(order_id, |ord| {
+        ord.overpayment_refund_txid = Some(txid.clone())
+    });
+    Ok(txid)
+}
+
+/// Network fee withheld from an overpayment refund in `asset`
+/// SPL transfers pay their fee in SOL, so the whole token excess goes back
+fn overpayment_refund_fee(asset: &Asset) -> u64 {
+    match asset {
+        Asset::Solana => solana_integration::SOLANA_TX_FEE_LAMPORTS,
+        _ => 0,
+    }
+}
+
+/// Send an overpayment left for an operator back to the order's creator
+/// Controllers only (checked by the endpoint). Deposits share the canister's addresses, so the
+/// operator must first make sure the excess really came from the creator
+pub async fn refund_overpayment(order_id: u64) -> Result<String, String> {
+    let caller = ic_cdk::api::caller();
+    let meter = CostMeter::start();
+    let result = refund_overpayment_as(order_id).await;
+    record_audit(caller, Some(order_id), "refund_overpayment", &result);
+    meter.finish("refund_overpayment");
+    result
+}
+
+async fn refund_overpayment_as(order_id: u64) -> Result<String, String> {
+    let order = ORDERS
+        .with(|orders| orders.borrow().get(&order_id).cloned())
+        .ok_or("Order not found")?;
+    // Taken before the first await, so a concurrent call cannot send the excess twice
+    let _lock = PayoutLock::acquire(order_id)?;
+
+    let asset = &order.from_asset;
+    let refund_address = get_receive_address(
+        asset,
         order.creator_btc_address.as_ref(),
         order.creator_sol_address.as_ref(),
     )?;
+    check_payout_destination(order_id, &refund_address)?;
 
-    ic_cdk::println!("💸 Sending {:?} (amount: {}) to creator at {}", order.to_asset, order.to_amount, creator_address);
-    let creator_tx = send_asset(&order.to_asset, &creator_address, order.to_amount).await?;
-    ic_cdk::println!("✅ Creator payment sent successfully! TXID: {}", creator_tx);
+    refund_flagged_overpayment_with(&order, overpayment_refund_fee(asset), |amount| {
+        send_asset(order.id, asset, &refund_address, amount)
+    })
+    .await
+}
 
-    ORDERS.with(|orders| {
-        if let Some(ord) = orders.borrow_mut().get_mut(&order_id) {
-            ord.secret = Some(secret);
-            ord.status = OrderStatus::Completed;
+/// Refund the recorded `creator_overpayment` of `order` less the network `fee` with `send`,
+/// clearing the manual review it was flagged with
+pub async fn refund_flagged_overpayment_with<F, Fut>(
+    order: &Order,
+    fee: u64,
+    send: F,
+) -> Result<String, String>
+where
+    F: FnOnce(u64) -> Fut,
+    Fut: std::future::Future<Output = Result<String, String>>,
+{
+    if let Some(txid) = &order.overpayment_refund_txid {
+        return Err(format!("Overpayment already refunded in {}", txid));
+    }
+    let excess = order
+        .creator_overpayment
+        .ok_or("Order has no overpayment to refund")?;
+
+    let txid = refund_overpayment_with(order.id, excess, fee, send).await?;
+    update_order(order.id, |ord| {
+        if ord
+            .manual_review
+            .as_ref()
+            .is_some_and(|reason| reason.starts_with("Overpayment of"))
+        {
+            ord.manual_review = None;
         }
     });
+    Ok(txid)
+}
 
-    Ok(format!(
-        "Swap completed! Transactions: Resolver: {}, Creator: {}",
-        resolver_tx, creator_tx
-    ))
+/// Run `verify` until it sees the deposit, `deadline` passes or `max_attempts` checks were made
+/// Failed checks are retried after `sleep`ing for `interval`; the last outcome is returned if the
+/// deposit is never seen
+pub async fn poll_verification<F, Fut, S, SFut>(
+    deadline: u64,
+    max_attempts: u32,
+    interval: Duration,
+    now: impl Fn() -> u64,
+    mut sleep: S,
+    mut verify: F,
+) -> Result<bool, String>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<bool, String>>,
+    S: FnMut(Duration) -> SFut,
+    SFut: std::future::Future<Output = ()>,
+{
+    let mut attempts = 0;
+    loop {
+        let result = verify().await;
+        attempts += 1;
+        if matches!(result, Ok(true)) || attempts >= max_attempts || now() >= deadline {
+            return result;
+        }
+        sleep(interval).await;
+    }
 }
 
-/// Cancel an order and process refunds
+/// Confirm creator's Bitcoin deposit with a merkle inclusion proof
+/// Alternative to `confirm_deposit` that verifies against a block header instead of re-querying UTXOs
 #[ic_cdk::update]
-pub async fn cancel_order(order_id: u64) -> Result<String, String> {
+pub async fn confirm_deposit_with_proof(
+    order_id: u64,
+    proof: BitcoinDepositProof,
+) -> Result<String, String> {
     let caller = ic_cdk::api::caller();
+    let meter = CostMeter::start();
+    let result = confirm_deposit_with_proof_as(caller, order_id, proof).await;
+    record_audit(
+        caller,
+        Some(order_id),
+        "confirm_deposit_with_proof",
+        &result,
+    );
+    meter.finish("confirm_deposit_with_proof");
+    result
+}
 
+async fn confirm_deposit_with_proof_as(
+    caller: Principal,
+    order_id: u64,
+    proof: BitcoinDepositProof,
+) -> Result<String, String> {
     let order = ORDERS
         .with(|orders| orders.borrow().get(&order_id).cloned())
         .ok_or("Order not found")?;
 
     if order.creator != caller {
-        return Err("Only order creator can cancel the order".to_string());
+        return Err("Only order creator can confirm deposit".to_string());
     }
 
-    match order.status {
-        OrderStatus::Completed => {
-            return Err("Cannot cancel completed order".to_string());
-        }
-        OrderStatus::Cancelled => {
-            return Err("Order already cancelled".to_string());
-        }
-        _ => {}
-    }
+    check_deposit_open(&order, time())?;
+    check_order_capacity(&order)?;
 
-    if order.resolver_deposited {
-        return Err(
-            "Cannot cancel after resolver has deposited. Wait for expiry to process refund."
-                .to_string(),
-        );
+    if !matches!(order.from_asset, Asset::Bitcoin) {
+        return Err("Deposit proofs are only supported for Bitcoin deposits".to_string());
     }
 
-    ORDERS.with(|orders| {
-        if let Some(ord) = orders.borrow_mut().get_mut(&order_id) {
-            ord.status = OrderStatus::Cancelled;
-        }
-    });
+    let canister_address = CANISTER_BTC_ADDRESS
+        .with(|addr| addr.borrow().clone())
+        .ok_or("Canister Bitcoin address not initialized")?;
+
+    let txid = bitcoin_integration::verify_bitcoin_deposit_proof(
+        canister_address,
+        order.from_amount,
+        proof,
+    )
+    .await?;
+
+    credit_creator_deposit(order_id, &txid, time())?;
 
+    Ok("Deposit confirmed! Order is now visible to resolvers.".to_string())
+}
+
+/// A creator deposit is only credited to an unexpired order still awaiting it
+pub fn check_deposit_open(order: &Order, current_time: u64) -> Result<(), String> {
     if order.creator_deposited {
-        let refund_tx = process_refund_internal(&order, true, false).await?;
-        return Ok(format!(
-            "Order cancelled. Refund transaction: {}",
-            refund_tx
+        return Err("Deposit already confirmed".to_string());
+    }
+    if order.status != OrderStatus::AwaitingDeposit {
+        return Err(format!(
+            "Order is {:?}, not awaiting a deposit",
+            order.status
         ));
     }
+    if current_time >= order.expires_at {
+        return Err(format!(
+            "Expired: order {} expired at {}",
+            order.id, order.expires_at
+        ));
+    }
+    Ok(())
+}
 
-    Ok("Order cancelled successfully. No deposits to refund.".to_string())
+/// Record a verified creator deposit, re-reading the order since verification awaited the chain
+/// Fails if the order stopped awaiting the deposit meanwhile, or if `txid` was already credited
+/// or sent by the canister, so one payment can never be credited twice
+pub fn credit_creator_deposit(order_id: u64, txid: &str, current_time: u64) -> Result<(), String> {
+    let order = ORDERS
+        .with(|orders| orders.borrow().get(&order_id).cloned())
+        .ok_or("Order not found")?;
+    check_deposit_open(&order, current_time)?;
+    check_txid_unused(order_id, txid)?;
+
+    consume_txid(txid);
+    remove_deposit_claim(order_id);
+    update_order(order_id, |ord| {
+        ord.creator_txid = Some(txid.to_string());
+        ord.creator_deposited = true;
+        ord.status = OrderStatus::DepositReceived;
+        ord.deposit_confirmed_at = Some(current_time);
+    });
+    Ok(())
 }
 
-/// Process refund for an expired or cancelled order
+/// Resolver accepts an order
 #[ic_cdk::update]
-pub async fn process_refund(order_id: u64) -> Result<String, String> {
-    let current_time = time();
+pub async fn accept_order(
+    order_id: u64,
+    resolver_btc_address: Option<String>,
+    resolver_sol_address: Option<String>,
+) -> Result<CanisterAddresses, String> {
+    let caller = ic_cdk::api::caller();
+    let meter = CostMeter::start();
+    let result =
+        accept_order_as(caller, order_id, resolver_btc_address, resolver_sol_address).await;
+    record_audit(caller, Some(order_id), "accept_order", &result);
+    meter.finish("accept_order");
+    result
+}
 
+async fn accept_order_as(
+    caller: Principal,
+    order_id: u64,
+    resolver_btc_address: Option<String>,
+    resolver_sol_address: Option<String>,
+) -> Result<CanisterAddresses, String> {
     let order = ORDERS
         .with(|orders| orders.borrow().get(&order_id).cloned())
         .ok_or("Order not found")?;
 
-    if current_time < order.expires_at {
-        return Err("Order has not expired yet. Cannot process refund.".to_string());
+    check_acceptance(&order, time())?;
+
+    validate_wallet_addresses(resolver_btc_address.as_ref(), resolver_sol_address.as_ref())?;
+
+    // The allowlist may have shrunk since the order was created
+    for asset in order_assets(&order) {
+        read_config(|config| check_asset_allowed(config, asset))?;
     }
 
-    match order.status {
-        OrderStatus::Completed => {
-            return Err("Order completed successfully. No refund needed.".to_string());
+    // Check if resolver is trying to use the same wallet addresses as creator
+    // This prevents self-dealing while allowing the same ICP principal to resolve
+    if let Some(ref creator_btc) = order.creator_btc_address {
+        if let Some(ref resolver_btc) = resolver_btc_address {
+            if creator_btc == resolver_btc {
+                return Err("Cannot accept order with your own Bitcoin address".to_string());
+            }
         }
-        OrderStatus::Cancelled => {}
-        _ => {}
     }
-
-    let refund_creator = order.creator_deposited;
-    let refund_resolver = order.resolver_deposited;
-
-    if !refund_creator && !refund_resolver {
-        return Err("No deposits to refund".to_string());
+    
+    if let Some(ref creator_sol) = order.creator_sol_address {
+        if let Some(ref resolver_sol) = resolver_sol_address {
+            if creator_sol == resolver_sol {
+                return Err("Cannot accept order with your own Solana address".to_string());
+            }
+        }
     }
 
-    let refund_message = process_refund_internal(&order, refund_creator, refund_resolver).await?;
+    let to_amount = priced_to_amount(
+        &order,
+        get_asset_price(&order.from_asset),
+        get_asset_price(&order.to_asset),
+    )?;
+
+    let canister_addresses = get_canister_addresses().await?;
 
-    ORDERS.with(|orders| {
-        if let Some(ord) = orders.borrow_mut().get_mut(&order_id) {
-            ord.status = OrderStatus::Cancelled;
-        }
+    update_order(order_id, |ord| {
+        ord.to_amount = to_amount;
+        ord.resolver = Some(caller);
+        ord.resolver_btc_address = resolver_btc_address;
+        ord.resolver_sol_address = resolver_sol_address;
+        ord.accepted_at = Some(time());
     });
+    record_resolver_accepted(caller);
 
-    Ok(format!("Refund processed: {}", refund_message))
+    Ok(canister_addresses)
 }
 
-/// Internal function to process refunds
-async fn process_refund_internal(
+/// Check that `order` can still be accepted
+/// `get_pending_orders` already hides expired orders, but a resolver may act on a stale listing;
+/// an expired order could never settle, leaving the resolver's deposit waiting for a refund
+pub fn check_acceptance(order: &Order, current_time: u64) -> Result<(), String> {
+    if !matches!(order.status, OrderStatus::DepositReceived) {
+        return Err("Order not ready for acceptance".to_string());
+    }
+    if current_time >= order.expires_at {
+        return Err(format!(
+            "Expired: order {} expired at {}",
+            order.id, order.expires_at
+        ));
+    }
+    Ok(())
+}
+
+/// The amount the resolver must deliver if they accept `order` now
+/// Fixed orders keep their amount; oracle orders convert `from_amount` at the feed prices
+pub fn priced_to_amount(
     order: &Order,
-    refund_creator: bool,
-    refund_resolver: bool,
-) -> Result<String, String> {
-    let mut refund_txs = Vec::new();
+    from_price: Option<u64>,
+    to_price: Option<u64>,
+) -> Result<u64, String> {
+    let (reference, max_slippage_bps) = match order.pricing {
+        OrderPricing::Fixed { .. } => return Ok(order.to_amount),
+        OrderPricing::Oracle {
+            reference,
+            max_slippage_bps,
+        } => (reference, max_slippage_bps),
+    };
 
-    if refund_creator {
-        let creator_address = get_receive_address(
+    let from_price = from_price.ok_or(format!("No price available for {:?}", order.from_asset))?;
+    let to_price = to_price.ok_or(format!("No price available for {:?}", order.to_asset))?;
+    if to_price == 0 {
+        return Err(format!("No price available for {:?}", order.to_asset));
+    }
+
+    let too_large = || "Amount too large".to_string();
+    let value = (order.from_amount as u128)
+        .checked_mul(from_price as u128)
+        .and_then(|value| value.checked_mul(10u128.pow(asset_decimals(&order.to_asset) as u32)))
+        .ok_or_else(too_large)?;
+    let divisor = 10u128.pow(asset_decimals(&order.from_asset) as u32) * to_price as u128;
+    let market: u64 = (value / divisor).try_into().map_err(|_| too_large())?;
+
+    let minimum = reference as u128 * (10_000 - max_slippage_bps.min(10_000) as u128) / 10_000;
+    if (market as u128) < minimum {
+        return Err(format!(
+            "Market amount {} is more than {} bps below the reference amount {}",
+            market, max_slippage_bps, reference
+        ));
+    }
+
+    Ok(market)
+}
+
+/// Resolver confirms their deposit
+#[ic_cdk::update]
+pub async fn confirm_resolver_deposit(order_id: u64, txid: String) -> Result<String, String> {
+    let caller = ic_cdk::api::caller();
+    let meter = CostMeter::start();
+    let result = confirm_resolver_deposit_as(caller, order_id, txid, Vec::new()).await;
+    record_audit(caller, Some(order_id), "confirm_resolver_deposit", &result);
+    meter.finish("confirm_resolver_deposit");
+    result
+}
+
+/// Resolver confirms their deposit of a basket order
+/// `txid` covers `to_asset`, and `leg_txids` each of the extra legs, in order
+#[ic_cdk::update]
+pub async fn confirm_resolver_basket_deposit(
+    order_id: u64,
+    txid: String,
+    leg_txids: Vec<String>,
+) -> Result<String, String> {
+    let caller = ic_cdk::api::caller();
+    let meter = CostMeter::start();
+    let result = confirm_resolver_deposit_as(caller, order_id, txid, leg_txids).await;
+    record_audit(
+        caller,
+        Some(order_id),
+        "confirm_resolver_basket_deposit",
+        &result,
+    );
+    meter.finish("confirm_resolver_basket_deposit");
+    result
+}
+
+async fn confirm_resolver_deposit_as(
+    caller: Principal,
+    order_id: u64,
+    txid: String,
+    leg_txids: Vec<String>,
+) -> Result<String, String> {
+    let order = ORDERS
+        .with(|orders| orders.borrow().get(&order_id).cloned())
+        .ok_or("Order not found")?;
+
+    if order.resolver != Some(caller) {
+        return Err("Only resolver can confirm their deposit".to_string());
+    }
+
+    if order.resolver_deposited {
+        return Err("Resolver deposit already confirmed".to_string());
+    }
+
+    check_leg_txids(&order, &leg_txids)?;
+    for leg_txid in std::iter::once(&txid).chain(leg_txids.iter()) {
+        check_txid_unused(order_id, leg_txid)?;
+    }
+
+    // Every leg must verify before the deposit counts, so a basket is never half-deposited
+    let txids = std::iter::once(&txid).chain(leg_txids.iter());
+    for ((asset, amount), leg_txid) in order.to_legs().into_iter().zip(txids) {
+        resolve_asset_token_program(&asset).await?;
+        let canister_address = get_canister_deposit_address(&asset)?;
+
+        let verified = verify_asset_deposit(
+            &asset,
+            &canister_address,
+            amount,
+            required_deposit_memo(order.id, &asset),
+            leg_txid.clone(),
+        )
+        .await?;
+
+        if !verified {
+            return Err(format!(
+                "Transaction not found or insufficient amount sent to {}",
+                canister_address
+            ));
+        }
+    }
+
+    // Verification awaited the chain, so another call may have credited the deposit meanwhile
+    let current = ORDERS
+        .with(|orders| orders.borrow().get(&order_id).cloned())
+        .ok_or("Order not found")?;
+    if current.resolver_deposited {
+        return Err("Resolver deposit already confirmed".to_string());
+    }
+    for leg_txid in std::iter::once(&txid).chain(leg_txids.iter()) {
+        check_txid_unused(order_id, leg_txid)?;
+    }
+    for leg_txid in std::iter::once(&txid).chain(leg_txids.iter()) {
+        consume_txid(leg_txid);
+    }
+    update_order(order_id, |ord| {
+        ord.resolver_txid = Some(txid);
+        ord.resolver_deposited = true;
+        ord.status = OrderStatus::ResolverDeposited;
+        ord.resolver_deposited_at = Some(time());
+    });
+
+    let latency = time().saturating_sub(order.accepted_at.unwrap_or(order.created_at));
+    record_resolver_deposited(caller, latency);
+
+    Ok("Resolver deposit confirmed!".to_string())
+}
+
+/// A basket order's resolver deposit needs one transaction per extra leg, besides the one for `to_asset`
+pub fn check_leg_txids(order: &Order, leg_txids: &[String]) -> Result<(), String> {
+    if leg_txids.len() != order.extra_to_legs.len() {
+        return Err(format!(
+            "Order has {} extra legs but {} leg transactions were given",
+            order.extra_to_legs.len(),
+            leg_txids.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Reveal secret to complete the swap
+/// The resolver may call this too once they know the secret, so the creator cannot stall settlement
+#[ic_cdk::update]
+pub async fn reveal_secret(order_id: u64, secret: String) -> Result<String, String> {
+    let caller = ic_cdk::api::caller();
+    let meter = CostMeter::start();
+    let result = reveal_secret_as(caller, order_id, secret, &meter).await;
+    record_audit(caller, Some(order_id), "reveal_secret", &result);
+    meter.finish("reveal_secret");
+    result
+}
+
+async fn reveal_secret_as(
+    caller: Principal,
+    order_id: u64,
+    secret: String,
+    meter: &CostMeter,
+) -> Result<String, String> {
+    let current_time = time();
+
+    let order = ORDERS
+        .with(|orders| orders.borrow().get(&order_id).cloned())
+        .ok_or("Order not found")?;
+
+    check_secret_reveal(&order, caller, &secret, current_time)?;
+    // Taken before the first await, so a concurrent reveal cannot pay out a second time
+    let _lock = PayoutLock::acquire(order_id)?;
+
+    // A retry after the resolver was paid must be allowed to finish
+    let guard = read_config(|config| config.guard_settlement_economics)
+        && order.resolver_payout_txid.is_none();
+    let fees = match estimate_payout_fees(&order).await {
+        Ok(fees) => fees,
+        // Without the guard the estimate only feeds the receipt, which then goes without fees
+        Err(e) if !guard => {
+            ic_cdk::println!("⚠️ No fee estimate for order {}: {}", order_id, e);
+            Vec::new()
+        }
+        Err(e) => return Err(e),
+    };
+    if guard {
+        check_settlement_solvency(&order, &fees).await?;
+    }
+
+    ic_cdk::println!("🔓 Secret verified for order {}. Starting atomic swap...", order_id);
+
+    // Execute the atomic swap
+    let resolver_address = get_receive_address(
+        &order.from_asset,
+        order.resolver_btc_address.as_ref(),
+        order.resolver_sol_address.as_ref(),
+    )?;
+    let creator_address = get_receive_address(
+        &order.to_asset,
+        order.creator_btc_address.as_ref(),
+        order.creator_sol_address.as_ref(),
+    )?;
+
+    // Neither leg is paid when either destination is blocked
+    if order.resolver_payout_txid.is_none() {
+        check_payout_destination(order_id, &resolver_address)?;
+    }
+    check_payout_destination(order_id, &creator_address)?;
+    for (asset, _) in unsent_extra_legs(&order) {
+        let leg_address = get_receive_address(
+            asset,
+            order.creator_btc_address.as_ref(),
+            order.creator_sol_address.as_ref(),
+        )?;
+        check_payout_destination(order_id, &leg_address)?;
+    }
+
+    // A retry after a failed settlement must not pay the resolver twice
+    let resolver_paid = order.resolver_payout_txid.is_some();
+    if !resolver_paid {
+        ic_cdk::println!(
+            "💸 Sending {:?} (amount: {}) to resolver at {}",
+            order.from_asset,
+            order.from_amount,
+            resolver_address
+        );
+    }
+    let resolver_tx = pay_resolver(&order, || {
+        send_asset(
+            order.id,
+            &order.from_asset,
+            &resolver_address,
+            order.from_amount,
+        )
+    })
+    .await
+    .inspect_err(|_| mark_settlement_failed(order_id, caller, &secret, None))?;
+    if !resolver_paid {
+        ic_cdk::println!(
+            "✅ Resolver payment sent successfully! TXID: {}",
+            resolver_tx
+        );
+    }
+
+    // Basket legs go first; `to_asset` is paid last, so its txid marks the creator as fully paid
+    let leg_txids = send_extra_legs(
+        &order,
+        order.creator_btc_address.as_ref(),
+        order.creator_sol_address.as_ref(),
+    )
+    .await
+    .inspect_err(|_| mark_settlement_failed(order_id, caller, &secret, Some(&resolver_tx)))?;
+
+    ic_cdk::println!("💸 Sending {:?} (amount: {}) to creator at {}", order.to_asset, order.to_amount, creator_address);
+    let creator_tx = send_asset(order.id, &order.to_asset, &creator_address, order.to_amount)
+        .await
+        .inspect_err(|_| mark_settlement_failed(order_id, caller, &secret, Some(&resolver_tx)))?;
+    ic_cdk::println!("✅ Creator payment sent successfully! TXID: {}", creator_tx);
+
+    mark_settled(order_id, caller, secret, &resolver_tx, &creator_tx, time());
+    record_settlement_receipt(order_id, settlement_receipt(&order, &fees, meter.spent()));
+
+    accrue_settlement_rebate(&order);
+    if let Some(resolver) = order.resolver {
+        record_resolver_completed(resolver);
+    }
+
+    let mut message = format!(
+        "Swap completed! Transactions: Resolver: {}, Creator: {}",
+        resolver_tx, creator_tx
+    );
+    if !leg_txids.is_empty() {
+        message.push_str(&format!(", Basket legs: {}", leg_txids.join(", ")));
+    }
+    Ok(message)
+}
+
+/// Pay the resolver with `send` unless an earlier attempt already did
+/// The txid is recorded as soon as the payment is sent, so a retry skips it even if the
+/// attempt that sent it never got to record the settlement
+pub async fn pay_resolver<F, Fut>(order: &Order, send: F) -> Result<String, String>
+where
+    F: FnOnce() -> Fut,
+    Fut: std::future::Future<Output = Result<String, String>>,
+{
+    if let Some(txid) = &order.resolver_payout_txid {
+        return Ok(txid.clone());
+    }
+    let txid = send().await?;
+    update_order(order.id, |ord| {
+        ord.resolver_payout_txid = Some(txid.clone())
+    });
+    Ok(txid)
+}
+
+/// Extra basket legs that have not been paid out or refunded yet
+pub fn unsent_extra_legs(order: &Order) -> &[(Asset, u64)] {
+    let sent = order.extra_leg_txids.len().min(order.extra_to_legs.len());
+    &order.extra_to_legs[sent..]
+}
+
+/// Send the order's unsent extra basket legs, each to whichever of the addresses suits its asset
+/// Every txid is recorded as soon as its leg is sent, so a retry resumes with the first unsent leg
+/// Returns the txids of all extra legs, including those sent by earlier attempts
+async fn send_extra_legs(
+    order: &Order,
+    btc_address: Option<&String>,
+    sol_address: Option<&String>,
+) -> Result<Vec<String>, String> {
+    let mut txids = order.extra_leg_txids.clone();
+    for (asset, amount) in unsent_extra_legs(order) {
+        let address = get_receive_address(asset, btc_address, sol_address)?;
+        ic_cdk::println!(
+            "💸 Sending basket leg {:?} (amount: {}) to {}",
+            asset,
+            amount,
+            address
+        );
+        let txid = send_asset(order.id, asset, &address, *amount).await?;
+        update_order(order.id, |ord| ord.extra_leg_txids.push(txid.clone()));
+        txids.push(txid);
+    }
+    Ok(txids)
+}
+
+/// Cancel an order and process refunds
+#[ic_cdk::update]
+pub async fn cancel_order(order_id: u64, refund_address: Option<String>) -> Result<String, String> {
+    let caller = ic_cdk::api::caller();
+    let meter = CostMeter::start();
+    let result = cancel_order_as(caller, order_id, refund_address).await;
+    record_audit(caller, Some(order_id), "cancel_order", &result);
+    meter.finish("cancel_order");
+    result
+}
+
+/// Check that `caller` may settle the order with `secret`
+/// Either party can reveal: the creator chose the secret, and a resolver who learned it
+/// out-of-band proves so by matching the hash
+pub fn check_secret_reveal(
+    order: &Order,
+    caller: Principal,
+    secret: &str,
+    current_time: u64,
+) -> Result<(), String> {
+    if order.creator != caller && order.resolver != Some(caller) {
+        return Err("Only the order creator or resolver can reveal the secret".to_string());
+    }
+
+    check_settleable(order, current_time)?;
+
+    let secret_hash = format!("{:x}", md5::compute(secret));
+    if secret_hash != order.secret_hash {
+        return Err("Secret does not match hash".to_string());
+    }
+
+    Ok(())
+}
+
+/// Check that an order's swap can be settled now
+/// A failed settlement can be retried even after expiry, since the secret is already public
+pub fn check_settleable(order: &Order, current_time: u64) -> Result<(), String> {
+    match order.status {
+        OrderStatus::SettlementFailed => Ok(()),
+        OrderStatus::ResolverDeposited if current_time >= order.expires_at => {
+            Err("Order has expired".to_string())
+        }
+        OrderStatus::ResolverDeposited
+            if order.require_resolver_ack && order.resolver_acknowledged_at.is_none() =>
+        {
+            Err("Waiting for the resolver to acknowledge the order".to_string())
+        }
+        OrderStatus::ResolverDeposited => Ok(()),
+        _ => Err("Resolver has not deposited funds yet".to_string()),
+    }
+}
+
+/// Network fee of each of the order's payouts, as (fee asset, amount)
+/// SPL payouts pay their fee in SOL
+async fn estimate_payout_fees(order: &Order) -> Result<Vec<(Asset, u64)>, String> {
+    let resolver_address = get_receive_address(
+        &order.from_asset,
+        order.resolver_btc_address.as_ref(),
+        order.resolver_sol_address.as_ref(),
+    )?;
+    let mut payouts = vec![(
+        order.from_asset.clone(),
+        resolver_address,
+        order.from_amount,
+    )];
+    for (asset, amount) in order.to_legs() {
+        let creator_address = get_receive_address(
+            &asset,
+            order.creator_btc_address.as_ref(),
+            order.creator_sol_address.as_ref(),
+        )?;
+        payouts.push((asset, creator_address, amount));
+    }
+
+    let mut fees = Vec::new();
+    for (asset, address, amount) in payouts {
+        let fee = match asset {
+            Asset::Bitcoin => {
+                let vsize = bitcoin_integration::estimate_payout_vsize(address, amount).await?;
+                let fee_per_byte = bitcoin_integration::get_btc_fee_per_byte().await;
+                (Asset::Bitcoin, checked_mul(vsize, fee_per_byte)? / 1000)
+            }
+            // Assume the priority fee is at its cap, so the estimate never falls short
+            Asset::Solana | Asset::SplToken { .. } => (
+                Asset::Solana,
+                solana_integration::SOLANA_TX_FEE_LAMPORTS
+                    + solana_integration::max_priority_fee_lamports(),
+            ),
+        };
+        fees.push(fee);
+    }
+    Ok(fees)
+}
+
+/// Make sure settling `order` would not eat into other orders' deposits to pay its `fees`
+async fn check_settlement_solvency(order: &Order, fees: &[(Asset, u64)]) -> Result<(), String> {
+    // What the canister holds beyond the deposits of open orders (this one included)
+    let surplus: Vec<(Asset, u64)> = get_solvency_report()
+        .await?
+        .assets
+        .into_iter()
+        .map(|held| {
+            (
+                held.asset,
+                held.actual_held.saturating_sub(held.expected_held),
+            )
+        })
+        .collect();
+
+    check_settlement_economics(order, fees, &surplus)
+}
+
+/// Receipt for a settled order: its payouts and what they cost in network fees and cycles
+pub fn settlement_receipt(
+    order: &Order,
+    fees: &[(Asset, u64)],
+    cycles_spent: u64,
+) -> SettlementReceipt {
+    let fee_in = |asset: Asset| {
+        fees.iter()
+            .filter(|(fee_asset, _)| *fee_asset == asset)
+            .fold(0u64, |total, (_, fee)| total.saturating_add(*fee))
+    };
+    SettlementReceipt {
+        btc_fee: fee_in(Asset::Bitcoin),
+        sol_fee: fee_in(Asset::Solana),
+        cycles_spent,
+        payout_amounts: std::iter::once((order.from_asset.clone(), order.from_amount))
+            .chain(order.to_legs())
+            .collect(),
+    }
+}
+
+/// Attach the receipt of a completed order
+pub fn record_settlement_receipt(order_id: u64, receipt: SettlementReceipt) {
+    update_order(order_id, |ord| ord.settlement_receipt = Some(receipt));
+}
+
+/// Per asset, the order's deposits plus the canister's surplus must cover its payouts plus fees
+/// Each leg is deposited once and paid out once, so this comes down to the surplus covering the fees;
+/// otherwise they would be paid out of other orders' deposits
+pub fn check_settlement_economics(
+    order: &Order,
+    fees: &[(Asset, u64)],
+    surplus: &[(Asset, u64)],
+) -> Result<(), String> {
+    let amount_of = |entries: &[(Asset, u64)], asset: &Asset| {
+        checked_sum(
+            entries
+                .iter()
+                .filter(|(entry_asset, _)| entry_asset == asset)
+                .map(|(_, amount)| *amount),
+        )
+    };
+
+    let mut assets: Vec<&Asset> = Vec::new();
+    for (asset, _) in fees {
+        if !assets.contains(&asset) {
+            assets.push(asset);
+        }
+    }
+
+    for asset in assets {
+        let fee = amount_of(fees, asset)?;
+        let spare = amount_of(surplus, asset)?;
+        if fee > spare {
+            return Err(format!(
+                "UneconomicalSettlement: order {} needs {} in {:?} fees but the canister only holds {} beyond open deposits",
+                order.id, fee, asset, spare
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Resolver confirms, after depositing, that they are ready for the order to settle
+/// Required before `reveal_secret` on orders created with `require_resolver_ack`
+#[ic_cdk::update]
+pub fn resolver_acknowledge(order_id: u64) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    let meter = CostMeter::start();
+    let result = resolver_acknowledge_as(caller, order_id);
+    record_audit(caller, Some(order_id), "resolver_acknowledge", &result);
+    meter.finish("resolver_acknowledge");
+    result
+}
+
+fn resolver_acknowledge_as(caller: Principal, order_id: u64) -> Result<(), String> {
+    let order = ORDERS
+        .with(|orders| orders.borrow().get(&order_id).cloned())
+        .ok_or("Order not found")?;
+
+    check_resolver_acknowledgement(&order, caller)?;
+
+    update_order(order_id, |ord| {
+        ord.resolver_acknowledged_at = Some(time());
+    });
+
+    Ok(())
+}
+
+/// Validate a resolver acknowledgement: only the resolver, only once their deposit is confirmed
+pub fn check_resolver_acknowledgement(order: &Order, caller: Principal) -> Result<(), String> {
+    if order.resolver != Some(caller) {
+        return Err("Only the order's resolver can acknowledge it".to_string());
+    }
+    if !matches!(order.status, OrderStatus::ResolverDeposited) {
+        return Err("Resolver deposit has not been confirmed".to_string());
+    }
+    if order.resolver_acknowledged_at.is_some() {
+        return Err("Order already acknowledged".to_string());
+    }
+    Ok(())
+}
+
+/// Complete an order once both payouts went through; only now is the secret stored
+pub fn mark_settled(
+    order_id: u64,
+    revealed_by: Principal,
+    secret: String,
+    resolver_payout_txid: &str,
+    creator_payout_txid: &str,
+    now: u64,
+) {
+    update_order(order_id, |ord| {
+        release_open_order(ord);
+        ord.secret = Some(secret);
+        ord.secret_revealed_by = Some(revealed_by);
+        ord.resolver_payout_txid = Some(resolver_payout_txid.to_string());
+        ord.creator_payout_txid = Some(creator_payout_txid.to_string());
+        ord.status = OrderStatus::Completed;
+        ord.completed_at = Some(now);
+    });
+}
+
+/// Keep a payout failure recoverable: the funds stay held and `reveal_secret` can be retried
+/// With `defer_secret_until_settled` the secret is not kept, so it stays unreadable until the
+/// retry that completes both legs
+pub fn mark_settlement_failed(
+    order_id: u64,
+    revealed_by: Principal,
+    secret: &str,
+    resolver_payout_txid: Option<&String>,
+) {
+    let store_secret = !read_config(|config| config.defer_secret_until_settled);
+    update_order(order_id, |ord| {
+        if store_secret {
+            ord.secret = Some(secret.to_string());
+        }
+        ord.secret_revealed_by = Some(revealed_by);
+        ord.status = OrderStatus::SettlementFailed;
+        if let Some(txid) = resolver_payout_txid {
+            ord.resolver_payout_txid = Some(txid.clone());
+        }
+    });
+}
+
+/// Point the resolver's payout at a new address, e.g. after settlement keeps failing on the old one
+#[ic_cdk::update]
+pub fn update_resolver_payout_address(order_id: u64, new_address: String) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    let meter = CostMeter::start();
+    let result = update_resolver_payout_address_as(caller, order_id, new_address);
+    record_audit(
+        caller,
+        Some(order_id),
+        "update_resolver_payout_address",
+        &result,
+    );
+    meter.finish("update_resolver_payout_address");
+    result
+}
+
+fn update_resolver_payout_address_as(
+    caller: Principal,
+    order_id: u64,
+    new_address: String,
+) -> Result<(), String> {
+    let order = ORDERS
+        .with(|orders| orders.borrow().get(&order_id).cloned())
+        .ok_or("Order not found")?;
+
+    let updated = with_resolver_payout_address(&order, caller, new_address)?;
+
+    update_order(order_id, |ord| {
+        ord.resolver_btc_address = updated.resolver_btc_address;
+        ord.resolver_sol_address = updated.resolver_sol_address;
+    });
+
+    Ok(())
+}
+
+/// `order` with the resolver paid out to `new_address` instead
+/// Only allowed while the resolver's deposit is held and their payout has not been sent: before the
+/// secret is revealed, or after a failed settlement left the resolver leg unpaid
+pub fn with_resolver_payout_address(
+    order: &Order,
+    caller: Principal,
+    new_address: String,
+) -> Result<Order, String> {
+    if order.resolver != Some(caller) {
+        return Err("Only the order's resolver can change their payout address".to_string());
+    }
+    if !matches!(
+        order.status,
+        OrderStatus::ResolverDeposited | OrderStatus::SettlementFailed
+    ) {
+        return Err(
+            "Payout address can only change while the resolver's deposit is held".to_string(),
+        );
+    }
+    if order.resolver_payout_txid.is_some() {
+        return Err("Resolver has already been paid".to_string());
+    }
+
+    // The resolver is paid in the asset the creator deposited
+    validate_receive_address(&order.from_asset, &new_address)?;
+
+    let mut updated = order.clone();
+    match order.from_asset {
+        Asset::Bitcoin => {
+            if order.creator_btc_address.as_ref() == Some(&new_address) {
+                return Err("Cannot use the creator's Bitcoin address".to_string());
+            }
+            updated.resolver_btc_address = Some(new_address);
+        }
+        Asset::Solana | Asset::SplToken { .. } => {
+            if order.creator_sol_address.as_ref() == Some(&new_address) {
+                return Err("Cannot use the creator's Solana address".to_string());
+            }
+            updated.resolver_sol_address = Some(new_address);
+        }
+    }
+    Ok(updated)
+}
+
+/// Cancel every open order the caller created, reporting the outcome per order
+/// Orders that cannot be cancelled (e.g. the resolver already deposited) are reported as errors
+#[ic_cdk::update]
+pub async fn cancel_my_cancellable_orders() -> Vec<(u64, Result<String, String>)> {
+    let caller = ic_cdk::api::caller();
+    let meter = CostMeter::start();
+    let mut results = Vec::new();
+
+    for (order_id, check) in cancellable_orders(caller) {
+        let result = match check {
+            Ok(()) => cancel_order_as(caller, order_id, None).await,
+            Err(e) => Err(e),
+        };
+        record_audit(caller, Some(order_id), "cancel_order", &result);
+        results.push((order_id, result));
+    }
+
+    meter.finish("cancel_my_cancellable_orders");
+    results
+}
+
+/// The open orders created by `caller`, each with whether it can be cancelled right now
+pub fn cancellable_orders(caller: Principal) -> Vec<(u64, Result<(), String>)> {
+    let mut orders: Vec<(u64, u64, Result<(), String>)> = ORDERS.with(|orders| {
+        orders
+            .borrow()
+            .values()
+            .filter(|order| order.creator == caller && !order.status.is_terminal())
+            .map(|order| (order.sequence, order.id, check_cancellable(order, caller)))
+            .collect()
+    });
+    orders.sort_by_key(|(sequence, _, _)| *sequence);
+    orders
+        .into_iter()
+        .map(|(_, order_id, result)| (order_id, result))
+        .collect()
+}
+
+/// Guards shared by single and bulk cancellation
+pub fn check_cancellable(order: &Order, caller: Principal) -> Result<(), String> {
+    if order.creator != caller {
+        return Err("Only order creator can cancel the order".to_string());
+    }
+
+    match order.status {
+        OrderStatus::Completed => {
+            return Err("Cannot cancel completed order".to_string());
+        }
+        OrderStatus::Cancelled => {
+            return Err("Order already cancelled".to_string());
+        }
+        _ => {}
+    }
+
+    if order.resolver_deposited {
+        return Err(
+            "Cannot cancel after resolver has deposited. Wait for expiry to process refund."
+                .to_string(),
+        );
+    }
+
+    Ok(())
+}
+
+/// Marks an order's settlement or refund as in flight while its payouts are awaited
+/// Status only changes once the payouts are sent, so without this a second call made meanwhile
+/// would pass the same checks and pay again. Released when dropped, which ic-cdk also does when
+/// the call traps, so a failed payout leaves the order free for a retry
+pub struct PayoutLock {
+    order_id: u64,
+}
+
+impl PayoutLock {
+    pub fn acquire(order_id: u64) -> Result<Self, String> {
+        let acquired = PAYOUTS_IN_FLIGHT.with(|orders| orders.borrow_mut().insert(order_id));
+        if !acquired {
+            return Err(format!(
+                "InProgress: a payout of order {} is already in progress",
+                order_id
+            ));
+        }
+        Ok(PayoutLock { order_id })
+    }
+}
+
+impl Drop for PayoutLock {
+    fn drop(&mut self) {
+        PAYOUTS_IN_FLIGHT.with(|orders| orders.borrow_mut().remove(&self.order_id));
+    }
+}
+
+/// Measures the cycles an update call spends, as the drop in the canister's balance
+/// Only recorded when `track_operation_costs` is enabled; cycles received during the call
+/// (e.g. refunds of unused outcall cycles) make the measured cost an underestimate
+pub struct CostMeter {
+    starting_balance: u128,
+}
+
+impl CostMeter {
+    pub fn start() -> Self {
+        Self::starting_at(ic_cdk::api::canister_cycle_balance())
+    }
+
+    pub fn starting_at(starting_balance: u128) -> Self {
+        CostMeter { starting_balance }
+    }
+
+    /// Cycles spent since the meter started, measured whether or not cost tracking is enabled
+    pub fn spent(&self) -> u64 {
+        let spent = self
+            .starting_balance
+            .saturating_sub(ic_cdk::api::canister_cycle_balance());
+        u64::try_from(spent).unwrap_or(u64::MAX)
+    }
+
+    pub fn finish(self, operation: &str) {
+        if read_config(|config| config.track_operation_costs) {
+            self.finish_at(operation, ic_cdk::api::canister_cycle_balance());
+        }
+    }
+
+    pub fn finish_at(self, operation: &str, balance: u128) {
+        record_operation_cost(operation, self.starting_balance.saturating_sub(balance));
+    }
+}
+
+/// Append the outcome of a state-changing call to the audit log
+fn record_audit<T>(
+    caller: Principal,
+    order_id: Option<u64>,
+    action: &str,
+    result: &Result<T, String>,
+) {
+    append_audit_entry(AuditEntry {
+        id: 0,
+        timestamp: time(),
+        caller,
+        order_id,
+        action: action.to_string(),
+        result: result.as_ref().map(|_| ()).map_err(Clone::clone),
+    });
+}
+
+async fn cancel_order_as(
+    caller: Principal,
+    order_id: u64,
+    refund_address: Option<String>,
+) -> Result<String, String> {
+    let order = ORDERS
+        .with(|orders| orders.borrow().get(&order_id).cloned())
+        .ok_or("Order not found")?;
+
+    check_cancellable(&order, caller)?;
+
+    if let Some(ref address) = refund_address {
+        validate_receive_address(&order.from_asset, address)?;
+    }
+
+    record_abandonment(&order, time());
+
+    release_open_order(&order);
+    update_order(order_id, |ord| {
+        ord.status = OrderStatus::Cancelled;
+        ord.cancelled_at = Some(time());
+    });
+
+    if order.creator_deposited {
+        let refund_tx =
+            process_refund_internal(&order, true, false, refund_address.as_ref(), None).await?;
+        return Ok(format!(
+            "Order cancelled. Refund transaction: {}",
+            refund_tx
+        ));
+    }
+
+    Ok("Order cancelled successfully. No deposits to refund.".to_string())
+}
+
+/// Process refund for an expired or cancelled order
+/// An optional refund address overrides the stored one for the caller's own leg
+/// (the creator leg when the caller is on both sides)
+#[ic_cdk::update]
+pub async fn process_refund(
+    order_id: u64,
+    refund_address: Option<String>,
+) -> Result<String, String> {
+    let caller = ic_cdk::api::caller();
+    let meter = CostMeter::start();
+    let result = process_refund_as(caller, order_id, refund_address).await;
+    record_audit(caller, Some(order_id), "process_refund", &result);
+    meter.finish("process_refund");
+    result
+}
+
+async fn process_refund_as(
+    caller: Principal,
+    order_id: u64,
+    refund_address: Option<String>,
+) -> Result<String, String> {
+    let current_time = time();
+
+    let order = ORDERS
+        .with(|orders| orders.borrow().get(&order_id).cloned())
+        .ok_or("Order not found")?;
+
+    let grace_seconds = read_config(|config| config.refund_grace_seconds);
+    check_refund_window(order.expires_at, grace_seconds, current_time)?;
+
+    // Cancelled and expired orders may still hold a deposit whose refund failed; `refund_legs`
+    // leaves out every leg already refunded
+    if order.status == OrderStatus::Completed {
+        return Err("Order completed successfully. No refund needed.".to_string());
+    }
+
+    if order.resolver_payout_txid.is_some() {
+        return Err(
+            "Settlement already paid the resolver. Retry reveal_secret to finish it.".to_string(),
+        );
+    }
+
+    let (refund_creator, refund_resolver) = refund_legs(&order);
+
+    if !refund_creator && !refund_resolver {
+        return Err("No deposits to refund".to_string());
+    }
+
+    let is_creator = caller == order.creator;
+    let is_resolver = order.resolver == Some(caller);
+    if refund_address.is_some() && !is_creator && !is_resolver {
+        return Err("Only the creator or resolver can override a refund address".to_string());
+    }
+    let creator_override = refund_address.as_ref().filter(|_| is_creator);
+    let resolver_override = refund_address
+        .as_ref()
+        .filter(|_| is_resolver && !is_creator);
+
+    let refund_message = process_refund_internal(
+        &order,
+        refund_creator,
+        refund_resolver,
+        creator_override,
+        resolver_override,
+    )
+    .await?;
+
+    record_abandonment(&order, current_time);
+
+    release_open_order(&order);
+    update_order(order_id, |ord| {
+        ord.status = OrderStatus::Cancelled;
+        ord.cancelled_at = Some(time());
+    });
+
+    Ok(format!("Refund processed: {}", refund_message))
+}
+
+/// Resolver takes back their own deposit once the order has expired, whatever the creator does
+/// The creator's deposit stays held until the creator (or anyone) calls `process_refund`
+#[ic_cdk::update]
+pub async fn claim_refund(order_id: u64) -> Result<String, String> {
+    let caller = ic_cdk::api::caller();
+    let meter = CostMeter::start();
+    let result = claim_refund_as(caller, order_id).await;
+    record_audit(caller, Some(order_id), "claim_refund", &result);
+    meter.finish("claim_refund");
+    result
+}
+
+async fn claim_refund_as(caller: Principal, order_id: u64) -> Result<String, String> {
+    let order = ORDERS
+        .with(|orders| orders.borrow().get(&order_id).cloned())
+        .ok_or("Order not found")?;
+
+    check_resolver_refund(&order, caller, time())?;
+    let _lock = PayoutLock::acquire(order_id)?;
+
+    let leg_txids = send_extra_legs(
+        &order,
+        order.resolver_btc_address.as_ref(),
+        order.resolver_sol_address.as_ref(),
+    )
+    .await?;
+
+    let resolver_address = get_refund_address(
+        &order.to_asset,
+        None,
+        order.resolver_btc_address.as_ref(),
+        order.resolver_sol_address.as_ref(),
+    )?;
+    let (refund_tx, refunded) = send_refund(
+        order.id,
+        &order.to_asset,
+        &resolver_address,
+        order.to_amount,
+    )
+    .await?;
+
+    record_refund(order_id, &DepositLeg::Resolver, refunded);
+    mark_resolver_refunded(order_id, &refund_tx, time());
+
+    if leg_txids.is_empty() {
+        return Ok(format!("Resolver refund: {}", refund_tx));
+    }
+    Ok(format!(
+        "Resolver refund: {}, Basket legs: {}",
+        refund_tx,
+        leg_txids.join(", ")
+    ))
+}
+
+/// Refunds open `grace_seconds` after `expires_at`
+/// `reveal_secret` is refused from `expires_at` on, so no moment allows both a reveal and a refund
+pub fn check_refund_window(
+    expires_at: u64,
+    grace_seconds: u64,
+    current_time: u64,
+) -> Result<(), String> {
+    if current_time < expires_at {
+        return Err("Order has not expired yet. Cannot process refund.".to_string());
+    }
+    let refundable_at = expires_at.saturating_add(grace_seconds.saturating_mul(1_000_000_000));
+    if current_time < refundable_at {
+        return Err(format!(
+            "Order has expired, but refunds open {} seconds after expiry",
+            grace_seconds
+        ));
+    }
+    Ok(())
+}
+
+/// Guards for `claim_refund`: the resolver's deposit is held, unpaid and the order has expired
+pub fn check_resolver_refund(
+    order: &Order,
+    caller: Principal,
+    current_time: u64,
+) -> Result<(), String> {
+    if order.resolver != Some(caller) {
+        return Err("Only the resolver can claim this refund".to_string());
+    }
+    if order.status.is_terminal() {
+        return Err(format!("Order is already {:?}", order.status));
+    }
+    let grace_seconds = read_config(|config| config.refund_grace_seconds);
+    check_refund_window(order.expires_at, grace_seconds, current_time)?;
+    if order.resolver_payout_txid.is_some() {
+        return Err(
+            "Settlement already paid the resolver. Retry reveal_secret to finish it.".to_string(),
+        );
+    }
+    if !refund_legs(order).1 {
+        return Err("No resolver deposit to refund".to_string());
+    }
+    Ok(())
+}
+
+/// Resolver backs out of a swap the creator never settled, refunding both deposits
+/// Available once the resolver's deposit has waited `resolver_protection_seconds` for the secret,
+/// which comes before `expires_at`; both sides get their funds back, so only the trade is lost
+#[ic_cdk::update]
+pub async fn resolver_cancel(order_id: u64) -> Result<String, String> {
+    let caller = ic_cdk::api::caller();
+    let meter = CostMeter::start();
+    let result = resolver_cancel_as(caller, order_id).await;
+    record_audit(caller, Some(order_id), "resolver_cancel", &result);
+    meter.finish("resolver_cancel");
+    result
+}
+
+async fn resolver_cancel_as(caller: Principal, order_id: u64) -> Result<String, String> {
+    let current_time = time();
+
+    let order = ORDERS
+        .with(|orders| orders.borrow().get(&order_id).cloned())
+        .ok_or("Order not found")?;
+
+    let protection_seconds = read_config(|config| config.resolver_protection_seconds);
+    check_resolver_cancel(&order, caller, protection_seconds, current_time)?;
+
+    // Closed before any refund is sent, so a reveal arriving meanwhile finds the order cancelled
+    release_open_order(&order);
+    update_order(order_id, |ord| {
+        ord.status = OrderStatus::Cancelled;
+        ord.cancelled_at = Some(current_time);
+    });
+
+    let (refund_creator, refund_resolver) = refund_legs(&order);
+    let refund_message =
+        process_refund_internal(&order, refund_creator, refund_resolver, None, None).await?;
+
+    Ok(format!("Order cancelled by resolver. {}", refund_message))
+}
+
+/// Guards for `resolver_cancel`: the caller is the resolver, both deposits are in, nothing has
+/// been settled and the resolver's protection period has run out
+pub fn check_resolver_cancel(
+    order: &Order,
+    caller: Principal,
+    protection_seconds: u64,
+    current_time: u64,
+) -> Result<(), String> {
+    if order.resolver != Some(caller) {
+        return Err("Only the resolver can cancel this order".to_string());
+    }
+    if order.status != OrderStatus::ResolverDeposited {
+        return Err(format!(
+            "Order is {:?}; the resolver can only cancel while awaiting the secret",
+            order.status
+        ));
+    }
+    if order.secret_revealed_by.is_some() || order.resolver_payout_txid.is_some() {
+        return Err("Settlement has started. Retry reveal_secret to finish it.".to_string());
+    }
+
+    let deposited_at = order
+        .resolver_deposited_at
+        .ok_or("Resolver deposit time was not recorded")?;
+    let unlocks_at = deposited_at.saturating_add(protection_seconds.saturating_mul(1_000_000_000));
+    if current_time < unlocks_at {
+        return Err(format!(
+            "The creator can still reveal the secret until {}",
+            unlocks_at
+        ));
+    }
+    Ok(())
+}
+
+/// Record the resolver's refund, closing the order when no creator deposit is left to return
+pub fn mark_resolver_refunded(order_id: u64, refund_txid: &str, now: u64) {
+    update_order(order_id, |ord| {
+        ord.resolver_refund_txid = Some(refund_txid.to_string());
+        if !refund_legs(ord).0 {
+            release_open_order(ord);
+            ord.status = OrderStatus::Cancelled;
+            ord.cancelled_at = Some(now);
+        }
+    });
+}
+
+/// Whether the resolver let an open order run out without depositing
+pub fn is_abandoned(order: &Order, current_time: u64) -> bool {
+    order.resolver.is_some()
+        && !order.resolver_deposited
+        && !order.status.is_terminal()
+        && current_time >= order.expires_at
+}
+
+/// Count an abandonment against the resolver when an order is closed after they let it lapse
+fn record_abandonment(order: &Order, current_time: u64) {
+    if let (true, Some(resolver)) = (is_abandoned(order, current_time), order.resolver) {
+        record_resolver_abandoned(resolver);
+    }
+}
+
+/// Expire a wedged order right away, regardless of `expires_at`, refunding whatever was deposited
+/// Controllers only (checked by the endpoint); the reason is kept in the audit log
+pub async fn force_expire(order_id: u64, reason: String) -> Result<String, String> {
+    let caller = ic_cdk::api::caller();
+    let meter = CostMeter::start();
+    let result = force_expire_as(order_id).await;
+    record_audit(
+        caller,
+        Some(order_id),
+        &format!("force_expire: {}", reason),
+        &result,
+    );
+    meter.finish("force_expire");
+    result
+}
+
+async fn force_expire_as(order_id: u64) -> Result<String, String> {
+    let order = ORDERS
+        .with(|orders| orders.borrow().get(&order_id).cloned())
+        .ok_or("Order not found")?;
+
+    let (refund_creator, refund_resolver) = check_force_expirable(&order)?;
+
+    let refund_message = if refund_creator || refund_resolver {
+        process_refund_internal(&order, refund_creator, refund_resolver, None, None).await?
+    } else {
+        "No deposits to refund".to_string()
+    };
+
+    mark_expired(order_id);
+
+    Ok(format!("Order force-expired. {}", refund_message))
+}
+
+/// Which legs (creator, resolver) a force-expiry must refund
+/// Completed and otherwise closed orders are off limits, as are settlements that already paid out
+pub fn check_force_expirable(order: &Order) -> Result<(bool, bool), String> {
+    if order.status == OrderStatus::Completed {
+        return Err("Completed orders cannot be force-expired".to_string());
+    }
+    if order.status.is_terminal() {
+        return Err(format!("Order is already {:?}", order.status));
+    }
+    if order.resolver_payout_txid.is_some() {
+        return Err(
+            "Settlement already paid the resolver. Retry reveal_secret to finish it.".to_string(),
+        );
+    }
+    Ok(refund_legs(order))
+}
+
+/// Which deposits (creator, resolver) the canister still holds for a refund
+/// A leg with a recorded refund, such as a resolver's `claim_refund`, is not refunded again
+pub fn refund_legs(order: &Order) -> (bool, bool) {
+    (
+        order.creator_deposited && order.creator_refund_amount.is_none(),
+        order.resolver_deposited
+            && order.resolver_refund_txid.is_none()
+            && order.resolver_refund_amount.is_none(),
+    )
+}
+
+/// Close an order as `Expired`, releasing its open-order slots
+pub fn mark_expired(order_id: u64) {
+    update_order(order_id, |ord| {
+        release_open_order(ord);
+        ord.status = OrderStatus::Expired;
+    });
+}
+
+/// Internal function to process refunds
+/// The order is re-read under its payout lock, so a leg refunded since the caller read it is
+/// skipped instead of being sent twice
+async fn process_refund_internal(
+    order: &Order,
+    refund_creator: bool,
+    refund_resolver: bool,
+    creator_refund_address: Option<&String>,
+    resolver_refund_address: Option<&String>,
+) -> Result<String, String> {
+    let _lock = PayoutLock::acquire(order.id)?;
+    let order = &ORDERS
+        .with(|orders| orders.borrow().get(&order.id).cloned())
+        .ok_or("Order not found")?;
+    let (creator_held, resolver_held) = refund_legs(order);
+    let refund_creator = refund_creator && creator_held;
+    let refund_resolver = refund_resolver && resolver_held;
+    let mut refund_txs = Vec::new();
+
+    if refund_creator {
+        let creator_address = get_refund_address(
             &order.from_asset,
+            creator_refund_address,
             order.creator_btc_address.as_ref(),
             order.creator_sol_address.as_ref(),
         )?;
-        let creator_refund_tx =
-            send_asset(&order.from_asset, &creator_address, order.from_amount).await?;
+        let (creator_refund_tx, refunded) = send_refund(
+            order.id,
+            &order.from_asset,
+            &creator_address,
+            order.from_amount,
+        )
+        .await?;
+        record_refund(order.id, &DepositLeg::Creator, refunded);
         refund_txs.push(format!("Creator refund: {}", creator_refund_tx));
     }
 
     if refund_resolver {
-        let resolver_address = get_receive_address(
+        // Extra basket legs go back to the resolver's own addresses; an override is for `to_asset`
+        let leg_txids = send_extra_legs(
+            order,
+            order.resolver_btc_address.as_ref(),
+            order.resolver_sol_address.as_ref(),
+        )
+        .await?;
+        if !leg_txids.is_empty() {
+            refund_txs.push(format!("Basket leg refunds: {}", leg_txids.join(", ")));
+        }
+
+        let resolver_address = get_refund_address(
             &order.to_asset,
+            resolver_refund_address,
             order.resolver_btc_address.as_ref(),
             order.resolver_sol_address.as_ref(),
         )?;
-        let resolver_refund_tx =
-            send_asset(&order.to_asset, &resolver_address, order.to_amount).await?;
+        let (resolver_refund_tx, refunded) = send_refund(
+            order.id,
+            &order.to_asset,
+            &resolver_address,
+            order.to_amount,
+        )
+        .await?;
+        record_refund(order.id, &DepositLeg::Resolver, refunded);
         refund_txs.push(format!("Resolver refund: {}", resolver_refund_tx));
     }
 
@@ -439,9 +2807,130 @@ async fn process_refund_internal(
     Ok(refund_txs.join(", "))
 }
 
+/// Send a refund of `deposit`, or the most the canister can still send when fees already taken
+/// from the held funds leave too little for the whole deposit
+/// Returns the transaction ID and the amount actually refunded
+async fn send_refund(
+    order_id: u64,
+    asset: &Asset,
+    to_address: &str,
+    deposit: u64,
+) -> Result<(String, u64), String> {
+    let error = match send_asset(order_id, asset, to_address, deposit).await {
+        Ok(txid) => return Ok((txid, deposit)),
+        Err(error) if is_insufficient_balance(&error) => error,
+        Err(error) => return Err(error),
+    };
+
+    let spendable = spendable_balance(asset).await?;
+    let amount = partial_refund_amount(deposit, spendable).ok_or(error)?;
+    ic_cdk::println!(
+        "⚠️ Order {} refunds {} of its {} deposit, the most the canister can still send",
+        order_id,
+        amount,
+        deposit
+    );
+    let txid = send_asset(order_id, asset, to_address, amount).await?;
+    Ok((txid, amount))
+}
+
+/// Whether a payout failed because the canister holds too little to cover it and its fee
+pub fn is_insufficient_balance(error: &str) -> bool {
+    // Bitcoin reports `InsufficientBalance`, Solana "insufficient funds"/"insufficient lamports"
+    error.to_lowercase().contains("insufficient")
+}
+
+/// Amount a refund falls back to when the canister can send only `spendable` of a `deposit`
+/// None when a partial refund would not help: the deposit is coverable, or nothing is spendable
+pub fn partial_refund_amount(deposit: u64, spendable: u64) -> Option<u64> {
+    (spendable > 0 && spendable < deposit).then_some(spendable)
+}
+
+/// Most of `asset` a single payout from the canister can send, after the network fee
+async fn spendable_balance(asset: &Asset) -> Result<u64, String> {
+    match asset {
+        Asset::Bitcoin => bitcoin_integration::max_payout_satoshis().await,
+        Asset::Solana => {
+            let balance =
+                solana_integration::get_solana_balance_lamports(canister_sol_address().await?)
+                    .await?;
+            Ok(balance
+                .saturating_sub(solana_integration::SOLANA_TX_FEE_LAMPORTS)
+                .saturating_sub(solana_integration::SOLANA_RENT_EXEMPT_MINIMUM_LAMPORTS))
+        }
+        // The transaction fee is paid in SOL, so the whole token balance can be sent
+        Asset::SplToken { mint_address, .. } => {
+            solana_integration::get_spl_token_balance(
+                canister_sol_address().await?,
+                mint_address.clone(),
+            )
+            .await
+        }
+    }
+}
+
+/// Send idle canister funds to a treasury address
+/// Controllers only (checked by the endpoint); funds held for open orders are never touched
+pub async fn sweep_to(asset: Asset, to_address: String, amount: u64) -> Result<String, String> {
+    let caller = ic_cdk::api::caller();
+    let meter = CostMeter::start();
+    let result = sweep_to_as(&asset, &to_address, amount).await;
+    record_audit(
+        caller,
+        None,
+        &format!("sweep: {} to {}", amount, to_address),
+        &result,
+    );
+    meter.finish("sweep");
+    result
+}
+
+async fn sweep_to_as(asset: &Asset, to_address: &str, amount: u64) -> Result<String, String> {
+    check_chain_available(&asset_chain(asset), time())?;
+
+    let spendable = spendable_balance(asset).await?;
+    let reserved = expected_held_amounts()
+        .into_iter()
+        .find(|(held_asset, _)| held_asset == asset)
+        .map(|(_, amount)| amount)
+        .unwrap_or(0);
+    check_sweep(amount, spendable, reserved)?;
+
+    match asset {
+        Asset::Bitcoin => bitcoin_integration::send_bitcoin(to_address.to_string(), amount).await,
+        Asset::Solana => solana_integration::send_solana(to_address.to_string(), amount).await,
+        Asset::SplToken { mint_address, .. } => {
+            solana_integration::send_spl_token(to_address.to_string(), amount, mint_address.clone())
+                .await
+        }
+    }
+}
+
+/// A sweep may only send what is spendable beyond the deposits held for open orders
+pub fn check_sweep(amount: u64, spendable: u64, reserved: u64) -> Result<(), String> {
+    if amount == 0 {
+        return Err("Sweep amount must be greater than zero".to_string());
+    }
+    let idle = spendable.saturating_sub(reserved);
+    if amount > idle {
+        return Err(format!(
+            "InsufficientBalance: only {} is idle beyond the {} held for open orders",
+            idle, reserved
+        ));
+    }
+    Ok(())
+}
+
+/// Record how much of a leg's deposit was refunded
+pub fn record_refund(order_id: u64, leg: &DepositLeg, amount: u64) {
+    update_order(order_id, |ord| match leg {
+        DepositLeg::Creator => ord.creator_refund_amount = Some(amount),
+        DepositLeg::Resolver => ord.resolver_refund_amount = Some(amount),
+    });
+}
+
 pub async fn get_canister_addresses() -> Result<CanisterAddresses, String> {
     let btc_address = CANISTER_BTC_ADDRESS.with(|addr| addr.borrow().clone());
-    let sol_address = CANISTER_SOL_ADDRESS.with(|addr| addr.borrow().clone());
 
     let btc_address = if let Some(addr) = btc_address {
         addr
@@ -451,17 +2940,588 @@ pub async fn get_canister_addresses() -> Result<CanisterAddresses, String> {
         addr
     };
 
-    let sol_address = if let Some(addr) = sol_address {
-        addr
-    } else {
-        let principal = ic_cdk::api::id();
-        let addr = solana_integration::get_canister_sol_address(principal).await?;
-        CANISTER_SOL_ADDRESS.with(|a| *a.borrow_mut() = Some(addr.clone()));
-        addr
-    };
+    let sol_address = canister_sol_address().await?;
 
     Ok(CanisterAddresses {
         bitcoin_address: btc_address,
         solana_address: sol_address,
     })
 }
+
+/// The threshold public keys behind the canister's addresses, so counterparties can verify
+/// that payouts are signed by this canister. The Solana address is the base58 ed25519 key itself.
+pub async fn get_public_keys() -> Result<PublicKeys, String> {
+    Ok(PublicKeys {
+        btc_ecdsa_pubkey_hex: bitcoin_integration::get_canister_btc_public_key().await,
+        sol_ed25519_pubkey_base58: canister_sol_address().await?,
+        attestation_ecdsa_pubkey_hex: attestation_public_key_hex().await,
+    })
+}
+
+/// Threshold ECDSA derivation path settlement attestations are signed under
+/// Its single non-4-byte segment can never equal a Bitcoin address path, so the key holding
+/// deposits never signs a digest it did not build itself
+pub fn attestation_derivation_path() -> Vec<Vec<u8>> {
+    vec![b"settlement-attestation".to_vec()]
+}
+
+async fn attestation_public_key_hex() -> String {
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    hex::encode(get_ecdsa_public_key(&ctx, attestation_derivation_path()).await)
+}
+
+/// Canister-signed receipt binding a completed order to its settlement txids and amounts
+/// Signed once per order with the attestation key `get_public_keys` reports
+pub async fn get_settlement_attestation(order_id: u64) -> Result<Attestation, String> {
+    if let Some(attestation) = ATTESTATIONS.with(|cache| cache.borrow().get(&order_id).cloned()) {
+        return Ok(attestation);
+    }
+
+    let order = ORDERS
+        .with(|orders| orders.borrow().get(&order_id).cloned())
+        .ok_or("Order not found")?;
+    let message = attestation_message(&order)?;
+
+    let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+    let signature = sign_with_ecdsa(
+        ctx.key_name.to_string(),
+        attestation_derivation_path(),
+        attestation_digest(&message).to_vec(),
+    )
+    .await;
+    let attestation = Attestation {
+        order_id,
+        message,
+        signature_hex: hex::encode(signature.serialize_compact()),
+    };
+
+    // Only hand out what integrators will be able to verify
+    let public_key_hex = attestation_public_key_hex().await;
+    verify_attestation(&attestation, &public_key_hex)?;
+
+    ATTESTATIONS.with(|cache| cache.borrow_mut().insert(order_id, attestation.clone()));
+    Ok(attestation)
+}
+
+/// The text a settlement attestation signs; amounts are in each asset's smallest unit
+/// Only completed orders with both payouts recorded can be attested
+pub fn attestation_message(order: &Order) -> Result<String, String> {
+    if order.status != OrderStatus::Completed {
+        return Err(format!(
+            "Order is {:?}; only completed orders can be attested",
+            order.status
+        ));
+    }
+    let resolver_txid = order
+        .resolver_payout_txid
+        .as_ref()
+        .ok_or("Resolver payout was not recorded")?;
+    let creator_txid = order
+        .creator_payout_txid
+        .as_ref()
+        .ok_or("Creator payout was not recorded")?;
+
+    let mut message = format!(
+        "intentswaps settlement v1\n\
+         order_id: {}\n\
+         from_asset: {}\n\
+         from_amount: {}\n\
+         to_asset: {}\n\
+         to_amount: {}\n\
+         resolver_payout_txid: {}\n\
+         creator_payout_txid: {}",
+        order.id,
+        attested_asset(&order.from_asset),
+        order.from_amount,
+        attested_asset(&order.to_asset),
+        order.to_amount,
+        resolver_txid,
+        creator_txid
+    );
+    // Only basket orders carry these lines, so plain attestations keep their v1 text
+    for ((asset, amount), txid) in order.extra_to_legs.iter().zip(&order.extra_leg_txids) {
+        message.push_str(&format!(
+            "\nextra_to_leg: {} {} {}",
+            attested_asset(asset),
+            amount,
+            txid
+        ));
+    }
+    Ok(message)
+}
+
+fn attested_asset(asset: &Asset) -> String {
+    match asset {
+        Asset::Bitcoin => "Bitcoin".to_string(),
+        Asset::Solana => "Solana".to_string(),
+        Asset::SplToken { mint_address, .. } => format!("SplToken:{}", mint_address),
+    }
+}
+
+/// The 32-byte hash an attestation's signature covers
+pub fn attestation_digest(message: &str) -> [u8; 32] {
+    Sha256::digest(message.as_bytes()).into()
+}
+
+/// Check an attestation against a hex-encoded secp256k1 public key
+pub fn verify_attestation(attestation: &Attestation, public_key_hex: &str) -> Result<(), String> {
+    let public_key = hex::decode(public_key_hex)
+        .ok()
+        .and_then(|bytes| secp256k1::PublicKey::from_slice(&bytes).ok())
+        .ok_or("Invalid public key")?;
+    let mut signature = hex::decode(&attestation.signature_hex)
+        .ok()
+        .and_then(|bytes| secp256k1::ecdsa::Signature::from_compact(&bytes).ok())
+        .ok_or("Invalid attestation signature encoding")?;
+    signature.normalize_s();
+
+    let digest = secp256k1::Message::from_digest(attestation_digest(&attestation.message));
+    secp256k1::Secp256k1::verification_only()
+        .verify_ecdsa(&digest, &signature, &public_key)
+        .map_err(|e| format!("Attestation does not verify: {}", e))
+}
+
+/// Size in vbytes of the Bitcoin payout an order will make, built from the canister's current UTXOs
+pub async fn estimate_bitcoin_tx_size(order_id: u64) -> Result<u64, String> {
+    let order = ORDERS
+        .with(|orders| orders.borrow().get(&order_id).cloned())
+        .ok_or("Order not found")?;
+    let (to_address, amount) = bitcoin_payout(&order)?;
+    check_chain_available(&Chain::Bitcoin, time())?;
+    bitcoin_integration::estimate_payout_vsize(to_address, amount).await
+}
+
+/// What a resolver would make filling an order at the current feed prices
+pub async fn evaluate_order(order_id: u64) -> Result<ResolverEvaluation, String> {
+    let order = ORDERS
+        .with(|orders| orders.borrow().get(&order_id).cloned())
+        .ok_or("Order not found")?;
+    let pays_bitcoin = order
+        .to_legs()
+        .iter()
+        .any(|(asset, _)| *asset == Asset::Bitcoin);
+    let btc_fee_per_byte = if pays_bitcoin {
+        bitcoin_integration::get_btc_fee_per_byte().await
+    } else {
+        0
+    };
+    let fees = resolver_deposit_fees(&order, btc_fee_per_byte);
+    evaluate_resolver_profit(&order, &fees, get_asset_price)
+}
+
+/// Network fees a resolver pays depositing each to leg of `order`, as (fee asset, amount)
+/// The resolver's wallet is unknown, so a Bitcoin deposit is sized as a typical P2WPKH transfer
+pub fn resolver_deposit_fees(order: &Order, btc_fee_per_byte: u64) -> Vec<(Asset, u64)> {
+    order
+        .to_legs()
+        .into_iter()
+        .map(|(asset, _)| match asset {
+            Asset::Bitcoin => (
+                Asset::Bitcoin,
+                RESOLVER_BTC_DEPOSIT_VSIZE.saturating_mul(btc_fee_per_byte) / 1000,
+            ),
+            Asset::Solana | Asset::SplToken { .. } => {
+                (Asset::Solana, solana_integration::SOLANA_TX_FEE_LAMPORTS)
+            }
+        })
+        .collect()
+}
+
+/// Value the resolver's side of `order` and its `fees` at `price` (USD micros per whole unit)
+/// Oracle orders not yet accepted are valued at the amount acceptance would fix right now
+pub fn evaluate_resolver_profit(
+    order: &Order,
+    fees: &[(Asset, u64)],
+    price: impl Fn(&Asset) -> Option<u64>,
+) -> Result<ResolverEvaluation, String> {
+    let usd_value = |asset: &Asset, amount: u64| -> Result<i128, String> {
+        let unit_price = price(asset).ok_or(format!("No price available for {:?}", asset))?;
+        Ok(amount as i128 * unit_price as i128 / 10i128.pow(asset_decimals(asset) as u32))
+    };
+
+    let mut to_legs = order.to_legs();
+    to_legs[0].1 = priced_to_amount(order, price(&order.from_asset), price(&order.to_asset))?;
+
+    let received = usd_value(&order.from_asset, order.from_amount)?;
+    let mut paid = 0;
+    for (asset, amount) in &to_legs {
+        paid += usd_value(asset, *amount)?;
+    }
+    let mut fee_value = 0;
+    for (asset, amount) in fees {
+        fee_value += usd_value(asset, *amount)?;
+    }
+
+    let too_large = |_| "Amount too large".to_string();
+    let gross_spread = received - paid;
+    let net_profit = gross_spread - fee_value;
+    Ok(ResolverEvaluation {
+        gross_spread: gross_spread.try_into().map_err(too_large)?,
+        estimated_fees: fee_value.try_into().map_err(too_large)?,
+        net_profit: net_profit.try_into().map_err(too_large)?,
+        profitable: net_profit > 0,
+    })
+}
+
+/// Recipient and amount of an order's Bitcoin payout on settlement
+/// A Bitcoin `from_asset` pays the resolver, a Bitcoin `to_asset` or basket leg pays the creator
+pub fn bitcoin_payout(order: &Order) -> Result<(String, u64), String> {
+    let creator_bitcoin = order
+        .to_legs()
+        .into_iter()
+        .find(|(asset, _)| *asset == Asset::Bitcoin);
+    if order.from_asset == Asset::Bitcoin {
+        let address = order
+            .resolver_btc_address
+            .clone()
+            .ok_or("Resolver Bitcoin address not known until the order is accepted")?;
+        Ok((address, order.from_amount))
+    } else if let Some((_, amount)) = creator_bitcoin {
+        let address = order
+            .creator_btc_address
+            .clone()
+            .ok_or("Bitcoin address not provided")?;
+        Ok((address, amount))
+    } else {
+        Err("Order has no Bitcoin leg".to_string())
+    }
+}
+
+async fn canister_sol_address() -> Result<String, String> {
+    if let Some(addr) = CANISTER_SOL_ADDRESS.with(|addr| addr.borrow().clone()) {
+        return Ok(addr);
+    }
+    let principal = ic_cdk::api::id();
+    let addr = solana_integration::get_canister_sol_address(principal).await?;
+    CANISTER_SOL_ADDRESS.with(|a| *a.borrow_mut() = Some(addr.clone()));
+    Ok(addr)
+}
+
+/// The canister's associated token account for a mint, where SPL deposits of that mint must go
+pub async fn get_canister_token_account(mint_address: String) -> Result<String, String> {
+    solana_integration::validate_solana_address(&mint_address)?;
+    let wallet = canister_sol_address().await?;
+    let token_program = solana_integration::resolve_token_program(&mint_address).await?;
+    solana_integration::get_associated_token_account_for_program(
+        &wallet,
+        &mint_address,
+        &token_program,
+    )
+}
+
+/// Where, how much and on which network `leg` of an order must be deposited
+pub async fn get_deposit_instructions(
+    order_id: u64,
+    leg: DepositLeg,
+) -> Result<DepositInstructions, String> {
+    // Make sure the canister addresses are cached before reading them
+    get_canister_addresses().await?;
+    let order = ORDERS
+        .with(|orders| orders.borrow().get(&order_id).cloned())
+        .ok_or("Order not found")?;
+    resolve_asset_token_program(&order.from_asset).await?;
+    resolve_asset_token_program(&order.to_asset).await?;
+    deposit_instructions(&order, &leg)
+}
+
+/// Deposit instructions for one leg of `order`, using the cached canister addresses
+pub fn deposit_instructions(
+    order: &Order,
+    leg: &DepositLeg,
+) -> Result<DepositInstructions, String> {
+    let (asset, amount) = match leg {
+        DepositLeg::Creator => (&order.from_asset, order.from_amount),
+        DepositLeg::Resolver => {
+            if matches!(order.pricing, OrderPricing::Oracle { .. }) && order.resolver.is_none() {
+                return Err("Resolver amount is fixed when the order is accepted".to_string());
+            }
+            (&order.to_asset, order.to_amount)
+        }
+    };
+
+    let (network, confirmations_required) = match asset {
+        Asset::Bitcoin => (
+            bitcoin_network_name(),
+            bitcoin_integration::REQUIRED_DEPOSIT_CONFIRMATIONS,
+        ),
+        // Solana deposits are checked at `Confirmed` commitment rather than by confirmation count
+        Asset::Solana | Asset::SplToken { .. } => (solana_network_name(), 0),
+    };
+
+    Ok(DepositInstructions {
+        asset: asset.clone(),
+        address_or_ata: get_canister_deposit_address(asset)?,
+        amount,
+        decimals: asset_decimals(asset) as u8,
+        network,
+        confirmations_required,
+        memo: required_deposit_memo(order.id, asset),
+    })
+}
+
+/// A deposit leg that has a recorded transaction but is not confirmed yet
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingDeposit {
+    pub asset: Asset,
+    pub amount: u64,
+    pub memo: Option<String>,
+    pub txid: String,
+}
+
+/// What `verify_deposits` knows about an order's deposit before going on-chain
+#[derive(Clone, Debug, PartialEq)]
+pub enum DepositCheck {
+    /// Answered from the order itself: already confirmed, no txid yet, or not the caller's order
+    Resolved(VerificationResult),
+    OnChain(PendingDeposit),
+}
+
+/// Verify the caller's own deposit leg on each of `order_ids`: the resolver leg on orders they
+/// accepted, otherwise the creator leg
+pub async fn verify_deposits(
+    caller: Principal,
+    order_ids: Vec<u64>,
+) -> Result<Vec<(u64, VerificationResult)>, String> {
+    verify_deposits_with(caller, order_ids, |deposit| {
+        let deposit = deposit.clone();
+        async move { check_asset_deposit(&deposit).await }
+    })
+    .await
+}
+
+/// Check the deposits of `order_ids` with `check`, at most `MAX_BATCH_VERIFICATIONS` of them
+/// Confirmed deposits are answered without an RPC call, and a deposit listed twice is checked once
+pub async fn verify_deposits_with<F, Fut>(
+    caller: Principal,
+    order_ids: Vec<u64>,
+    mut check: F,
+) -> Result<Vec<(u64, VerificationResult)>, String>
+where
+    F: FnMut(&PendingDeposit) -> Fut,
+    Fut: std::future::Future<Output = Result<VerificationResult, String>>,
+{
+    if order_ids.len() > MAX_BATCH_VERIFICATIONS {
+        return Err(format!(
+            "Too many orders: at most {} can be verified per call",
+            MAX_BATCH_VERIFICATIONS
+        ));
+    }
+
+    let mut checked: Vec<(PendingDeposit, VerificationResult)> = Vec::new();
+    let mut results = Vec::new();
+    for order_id in order_ids {
+        let order = ORDERS.with(|orders| orders.borrow().get(&order_id).cloned());
+        let result = match order.map(|order| deposit_check(&order, caller)) {
+            None => VerificationResult::not_found("Order not found"),
+            Some(DepositCheck::Resolved(result)) => result,
+            Some(DepositCheck::OnChain(deposit)) => {
+                match checked.iter().find(|(known, _)| *known == deposit) {
+                    Some((_, result)) => result.clone(),
+                    None => {
+                        let result = check(&deposit)
+                            .await
+                            .unwrap_or_else(|e| VerificationResult::not_found(&e));
+                        checked.push((deposit, result.clone()));
+                        result
+                    }
+                }
+            }
+        };
+        results.push((order_id, result));
+    }
+    Ok(results)
+}
+
+/// The deposit leg of `order` that `caller` is responsible for, and whether it needs an on-chain check
+pub fn deposit_check(order: &Order, caller: Principal) -> DepositCheck {
+    let (asset, amount, txid, deposited) = if order.resolver == Some(caller) {
+        (
+            &order.to_asset,
+            order.to_amount,
+            &order.resolver_txid,
+            order.resolver_deposited,
+        )
+    } else if order.creator == caller {
+        (
+            &order.from_asset,
+            order.from_amount,
+            &order.creator_txid,
+            order.creator_deposited,
+        )
+    } else {
+        return DepositCheck::Resolved(VerificationResult::not_found(
+            "Only the order creator or resolver can verify its deposits",
+        ));
+    };
+
+    if deposited {
+        return DepositCheck::Resolved(VerificationResult::assess(amount, amount, None, 0));
+    }
+    match txid {
+        Some(txid) => DepositCheck::OnChain(PendingDeposit {
+            asset: asset.clone(),
+            amount,
+            memo: required_deposit_memo(order.id, asset),
+            txid: txid.clone(),
+        }),
+        None => DepositCheck::Resolved(VerificationResult::not_found(
+            "No deposit transaction recorded",
+        )),
+    }
+}
+
+/// The Bitcoin network the canister runs on, e.g. "Bitcoin Testnet"
+fn bitcoin_network_name() -> String {
+    let network = BTC_CONTEXT.with(|ctx| ctx.get()).bitcoin_network;
+    format!("Bitcoin {:?}", network)
+}
+
+/// The Solana network the canister runs on, e.g. "Solana Devnet"
+fn solana_network_name() -> String {
+    let network = match read_state(|state| state.solana_network().clone()) {
+        SolanaNetwork::Mainnet => "Mainnet",
+        SolanaNetwork::Devnet => "Devnet",
+        SolanaNetwork::Custom(_) => "Custom",
+    };
+    format!("Solana {}", network)
+}
+
+/// BTC and SOL, then each allowlisted SPL mint with whatever metadata is cached for it
+/// Mints only reachable through `allow_any_spl_mint` cannot be enumerated and are not listed
+pub fn get_supported_assets() -> Vec<SupportedAsset> {
+    let native = [Asset::Bitcoin, Asset::Solana].into_iter().map(|asset| {
+        let info = asset_info(&asset);
+        let network = match asset {
+            Asset::Bitcoin => bitcoin_network_name(),
+            _ => solana_network_name(),
+        };
+        SupportedAsset {
+            name: Some(info.kind.clone()),
+            kind: info.kind,
+            mint: None,
+            decimals: Some(info.decimals),
+            symbol: info.symbol,
+            network,
+        }
+    });
+
+    let solana_network = solana_network_name();
+    let mints = read_config(|config| config.allowed_spl_mints.clone());
+    let spl = mints.into_iter().map(|mint_address| {
+        let metadata = get_cached_token_metadata(&mint_address);
+        SupportedAsset {
+            kind: "SplToken".to_string(),
+            decimals: metadata.as_ref().map(|metadata| metadata.decimals),
+            symbol: metadata
+                .as_ref()
+                .and_then(|metadata| metadata.symbol.clone()),
+            name: metadata.and_then(|metadata| metadata.name),
+            mint: Some(mint_address),
+            network: solana_network.clone(),
+        }
+    });
+
+    native.chain(spl).collect()
+}
+
+/// Derive the canister's address on each chain and read its balance, so an unreachable or
+/// misconfigured chain shows up before a user's swap runs into it
+pub async fn self_test() -> SelfTestReport {
+    self_test_with(bitcoin_self_check, solana_self_check).await
+}
+
+/// Run both chain checks and report on them; each check describes what it read
+pub async fn self_test_with<B, BFut, S, SFut>(check_btc: B, check_sol: S) -> SelfTestReport
+where
+    B: FnOnce() -> BFut,
+    BFut: std::future::Future<Output = Result<String, String>>,
+    S: FnOnce() -> SFut,
+    SFut: std::future::Future<Output = Result<String, String>>,
+{
+    let btc = check_btc().await;
+    let sol = check_sol().await;
+
+    let details = [("Bitcoin", &btc), ("Solana", &sol)]
+        .into_iter()
+        .map(|(chain, result)| match result {
+            Ok(read) => format!("{}: ok, {}", chain, read),
+            Err(e) => format!("{}: failed, {}", chain, e),
+        })
+        .collect();
+
+    SelfTestReport {
+        btc_ok: btc.is_ok(),
+        sol_ok: sol.is_ok(),
+        details,
+    }
+}
+
+async fn bitcoin_self_check() -> Result<String, String> {
+    let address = bitcoin_integration::get_canister_btc_address().await?;
+    let balance = bitcoin_integration::get_bitcoin_balance_satoshis(address.clone()).await?;
+    Ok(format!("{} holds {} satoshis", address, balance))
+}
+
+async fn solana_self_check() -> Result<String, String> {
+    let address = canister_sol_address().await?;
+    let balance = solana_integration::get_solana_balance_lamports(address.clone()).await?;
+    Ok(format!("{} holds {} lamports", address, balance))
+}
+
+/// Compare what the canister should hold for open orders against its on-chain balances
+pub async fn get_solvency_report() -> Result<SolvencyReport, String> {
+    let addresses = get_canister_addresses().await?;
+    let expected_held = expected_held_amounts();
+
+    let mut actual_held = Vec::with_capacity(expected_held.len());
+    for (asset, _) in &expected_held {
+        let balance = match asset {
+            Asset::Bitcoin => {
+                bitcoin_integration::get_bitcoin_balance_satoshis(addresses.bitcoin_address.clone())
+                    .await?
+            }
+            Asset::Solana => {
+                solana_integration::get_solana_balance_lamports(addresses.solana_address.clone())
+                    .await?
+            }
+            Asset::SplToken { mint_address, .. } => {
+                solana_integration::get_spl_token_balance(
+                    addresses.solana_address.clone(),
+                    mint_address.clone(),
+                )
+                .await?
+            }
+        };
+        actual_held.push((asset.clone(), balance));
+    }
+
+    Ok(assess_solvency(expected_held, &actual_held))
+}
+
+/// Build a solvency report from expected and actual per-asset holdings
+pub fn assess_solvency(
+    expected_held: Vec<(Asset, u64)>,
+    actual_held: &[(Asset, u64)],
+) -> SolvencyReport {
+    let assets: Vec<AssetSolvency> = expected_held
+        .into_iter()
+        .map(|(asset, expected_held)| {
+            let actual_held = actual_held
+                .iter()
+                .find(|(held_asset, _)| held_asset == &asset)
+                .map(|(_, amount)| *amount)
+                .unwrap_or(0);
+            AssetSolvency {
+                asset,
+                expected_held,
+                actual_held,
+                is_solvent: actual_held >= expected_held,
+            }
+        })
+        .collect();
+
+    SolvencyReport {
+        is_solvent: assets.iter().all(|asset| asset.is_solvent),
+        assets,
+    }
+}
@@ -1,26 +1,213 @@
-use crate::basic_solana::{client, solana_wallet::SolanaWallet};
+use crate::basic_solana::{
+    client,
+    solana_wallet::{SolanaAccount, SolanaWallet},
+    state::read_state,
+    SolanaNetwork,
+};
+use crate::storage::{
+    cache_compute_unit_price, cache_mint_token_program, cache_token_metadata,
+    cached_compute_unit_price, check_chain_available, get_cached_mint_token_program,
+    get_cached_token_metadata, invalidate_balance, now_nanos, read_balance_cached, read_config,
+    record_chain_failure, record_chain_success, set_use_durable_nonce, SOLANA_ACCOUNTS,
+};
+use crate::types::{
+    Chain, SolanaTransactionFormat, TokenBalance, TokenMetadata, VerificationResult,
+};
 use candid::Principal;
-use sol_rpc_types::{CommitmentLevel, GetBalanceParams, GetTransactionParams, Signature};
-use solana_message::Message as SolanaMessage;
+use sol_rpc_client::nonce::nonce_from_account;
+use sol_rpc_types::{
+    CommitmentLevel, GetAccountInfoEncoding, GetBalanceParams, GetTransactionParams,
+    MultiRpcResult, PrioritizationFee, RpcError, RpcResult, Signature, TransactionStatusMeta,
+    TransactionTokenBalance,
+};
+use solana_hash::Hash;
+use solana_instruction::Instruction;
+use solana_message::{v0, AddressLookupTableAccount, Message as SolanaMessage, VersionedMessage};
 use solana_pubkey::Pubkey as SolanaAddress;
-use solana_transaction::Transaction as SolanaTransaction;
+use solana_transaction::{versioned::VersionedTransaction, Transaction as SolanaTransaction};
+use std::fmt::Debug;
+use std::future::Future;
 use std::str::FromStr;
 
+const ADDRESS_LOOKUP_TABLE_PROGRAM_ID: &str = "AddressLookupTab1e1111111111111111111111111";
+
+// Base fee of a single-signature Solana transaction, paid in SOL by the canister on every payout
+pub const SOLANA_TX_FEE_LAMPORTS: u64 = 5_000;
+
+// Balance a data-less account must keep to stay rent exempt; transfers may not go below it
+pub const SOLANA_RENT_EXEMPT_MINIMUM_LAMPORTS: u64 = 890_880;
+
+// Rent-exempt balance funding the canister's nonce account (80 bytes of nonce state)
+const NONCE_ACCOUNT_LAMPORTS: u64 = 1_500_000;
+
+// Lookup table accounts start with a fixed-size metadata header followed by the addresses
+const LOOKUP_TABLE_META_SIZE: usize = 56;
+
+pub const SPL_TOKEN_PROGRAM_ID: &str = "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA";
+pub const TOKEN_2022_PROGRAM_ID: &str = "TokenzQdBNbLqP5VEhdkAS6EPFLC1PeJt27Kw9ZhoHd6hLx";
+const METAPLEX_METADATA_PROGRAM_ID: &str = "metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s";
+// SPL Memo program, and its deprecated v1 deployment still used by some wallets
+pub const MEMO_PROGRAM_ID: &str = "MemoSq4gqABAXKb96qnH8TysNcWxMyWCqXgDLGmfcHr";
+const MEMO_V1_PROGRAM_ID: &str = "Memo1UhkJRfHyvLMcVucJwxXeuD728EqVDDwQDxFMNo";
+
+const COMPUTE_BUDGET_PROGRAM_ID: &str = "ComputeBudget111111111111111111111111111111";
+// Compute units a payout may use: a SOL transfer needs a few hundred, an SPL transfer that
+// creates the recipient's token account a few tens of thousands
+const PAYOUT_COMPUTE_UNIT_LIMIT: u32 = 60_000;
+// Payouts bid this percentile of recent prioritization fees
+const COMPUTE_UNIT_PRICE_PERCENTILE: usize = 75;
+// A prioritization fee sample is reused for this long
+const COMPUTE_UNIT_PRICE_CACHE_SECONDS: u64 = 10;
+
+// Mint accounts are 82 bytes: authority option (36), supply (8), decimals (1), ...
+const MINT_DECIMALS_OFFSET: usize = 44;
+const MINT_SIZE: usize = 82;
+// Token-2022 extensions start after the account padded to 165 bytes and an account type byte
+const TOKEN_2022_EXTENSIONS_OFFSET: usize = 166;
+const TOKEN_2022_METADATA_EXTENSION: u16 = 19;
+
 /// Get canister's Solana address
 /// This uses the SolanaWallet with the canister's principal for deterministic address generation
 pub async fn get_canister_sol_address(canister_principal: Principal) -> Result<String, String> {
-    let wallet = SolanaWallet::new(canister_principal).await;
-    let account = wallet.solana_account();
+    let account = solana_account_for(canister_principal).await;
     Ok(account.to_string())
 }
 
+/// Get the Solana account of `owner`, deriving its public key only on first use
+/// Derivation is deterministic, so the account is memoized per principal; signing still goes
+/// through threshold EdDSA every time
+pub async fn solana_account_for(owner: Principal) -> SolanaAccount {
+    cached_solana_account(owner, || async move {
+        SolanaWallet::new(owner).await.solana_account()
+    })
+    .await
+}
+
+/// Return the cached account of `owner`, or derive it with `derive` and cache it
+pub async fn cached_solana_account<F, Fut>(owner: Principal, derive: F) -> SolanaAccount
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = SolanaAccount>,
+{
+    if let Some(account) = SOLANA_ACCOUNTS.with(|accounts| accounts.borrow().get(&owner).cloned()) {
+        return account;
+    }
+
+    let account = derive().await;
+    SOLANA_ACCOUNTS.with(|accounts| accounts.borrow_mut().insert(owner, account.clone()));
+    account
+}
+
+/// Check that a user-supplied Solana address is a valid base58 public key
+/// Solana addresses carry no network prefix, so the same key is valid on every cluster
+pub fn validate_solana_address(address: &str) -> Result<(), String> {
+    SolanaAddress::from_str(address)
+        .map_err(|e| format!("InvalidAddress: {} is not a Solana address: {}", address, e))?;
+    Ok(())
+}
+
+/// Check that native SOL sent to `address` can be spent again
+/// Addresses off the ed25519 curve (program-derived addresses) have no private key; a plain
+/// transfer to one that is not a program-owned account strands the lamports
+pub fn validate_sol_transfer_destination(address: &str) -> Result<(), String> {
+    let pubkey = SolanaAddress::from_str(address)
+        .map_err(|e| format!("InvalidAddress: {} is not a Solana address: {}", address, e))?;
+    if !pubkey.is_on_curve() {
+        return Err(format!(
+            "InvalidAddress: {} is off-curve and cannot receive SOL payouts",
+            address
+        ));
+    }
+    Ok(())
+}
+
+/// Await a multi-provider RPC read, re-issuing it up to the configured number of times while the
+/// providers disagree instead of trapping on the first inconsistent response
+/// Reads go through the Solana circuit breaker: they fail fast while it is tripped, and a rejected
+/// sol-rpc call or failed outcall counts towards tripping it. JSON-RPC errors mean a provider
+/// answered, so they do not.
+async fn consistent_rpc<T, E, F, Fut>(mut request: F) -> RpcResult<T>
+where
+    T: Debug,
+    E: Debug,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<MultiRpcResult<T>, E>>,
+{
+    check_chain_available(&Chain::Solana, now_nanos()).map_err(RpcError::ValidationError)?;
+
+    let retries = read_config(|config| config.solana_rpc_retries);
+    let result = retry_inconsistent(retries, || {
+        let response = request();
+        async move {
+            response.await.unwrap_or_else(|e| {
+                MultiRpcResult::Consistent(Err(RpcError::ValidationError(format!(
+                    "sol-rpc call rejected: {:?}",
+                    e
+                ))))
+            })
+        }
+    })
+    .await;
+
+    match &result {
+        Err(e) if !matches!(e, RpcError::JsonRpcError(_)) => {
+            record_chain_failure(&Chain::Solana, &format!("{:?}", e), now_nanos())
+        }
+        _ => record_chain_success(&Chain::Solana),
+    }
+    result
+}
+
+/// Retry `request` while it comes back inconsistent, at most `retries` more times
+/// There is no sleeping inside a call; each attempt is a fresh round of outcalls to every provider,
+/// which already spaces retries several consensus rounds apart
+pub async fn retry_inconsistent<T, F, Fut>(retries: u32, mut request: F) -> RpcResult<T>
+where
+    T: Debug,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = MultiRpcResult<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        match request().await {
+            MultiRpcResult::Consistent(result) => return result,
+            MultiRpcResult::Inconsistent(results) => {
+                if attempt >= retries {
+                    return Err(RpcError::ValidationError(format!(
+                        "Inconsistent responses after {} attempts: {:?}",
+                        attempt + 1,
+                        results
+                    )));
+                }
+                attempt += 1;
+                ic_cdk::println!("⚠️ Inconsistent Solana RPC responses, retry {}", attempt);
+            }
+        }
+    }
+}
+
 /// Verify a Solana transaction exists and has the correct recipient/amount
 /// Uses both transaction verification and balance checking for HTLC security
+/// With `expected_memo`, the transaction must also carry that memo to be credited
 pub async fn verify_solana_transaction(
     recipient_address: String,
     expected_amount: u64,
     txid: String,
+    expected_memo: Option<String>,
 ) -> Result<bool, String> {
+    check_solana_transaction(recipient_address, expected_amount, txid, expected_memo)
+        .await
+        .map(|result| result.verified)
+}
+
+/// Like `verify_solana_transaction`, but reports the observed balance and why verification failed
+/// Transactions are fetched at `Confirmed` commitment, so confirmations are not tracked
+pub async fn check_solana_transaction(
+    recipient_address: String,
+    expected_amount: u64,
+    txid: String,
+    expected_memo: Option<String>,
+) -> Result<VerificationResult, String> {
     ic_cdk::println!("🔍 Verifying Solana transaction: {}", txid);
 
     // First, verify the transaction exists and was successful
@@ -36,34 +223,46 @@ pub async fn verify_solana_transaction(
         max_supported_transaction_version: Some(0),
     };
 
-    let tx = client
-        .get_transaction(params)
-        .send()
+    let tx = consistent_rpc(|| client.get_transaction(params.clone()).try_send())
         .await
-        .expect_consistent()
         .map_err(|e| format!("Failed to get transaction: {:?}", e))?;
 
     // Check if transaction exists and was successful
-    let tx_valid = if let Some(tx) = tx {
+    let failure = if let Some(tx) = &tx {
         if let Some(meta) = &tx.transaction.meta {
             if meta.err.is_none() {
                 ic_cdk::println!("✅ Transaction found and successful");
-                true
+                None
             } else {
                 ic_cdk::println!("❌ Transaction found but failed: {:?}", meta.err);
-                false
+                Some("Transaction failed")
             }
         } else {
             ic_cdk::println!("❌ Transaction found but no metadata");
-            false
+            Some("Transaction has no metadata")
         }
     } else {
         ic_cdk::println!("❌ Transaction not found");
-        false
+        Some("Transaction not found")
     };
 
-    if !tx_valid {
-        return Ok(false);
+    if let Some(reason) = failure {
+        return Ok(VerificationResult::not_found(reason));
+    }
+
+    // All SOL deposits share one address, so only the memo ties this transfer to an order
+    if let (Some(expected), Some(tx)) = (&expected_memo, &tx) {
+        let transaction = tx
+            .transaction
+            .transaction
+            .decode()
+            .ok_or("Failed to decode transaction")?;
+        if !has_memo(&transaction.message, expected) {
+            ic_cdk::println!("❌ Transaction memo does not match {}", expected);
+            return Ok(VerificationResult::not_found(
+                "Memo does not match the order reference",
+            ));
+        }
     }
 
     // Additionally verify the balance to ensure funds are available
@@ -76,69 +275,596 @@ pub async fn verify_solana_transaction(
         expected_amount
     );
 
-    Ok(balance >= expected_amount)
+    Ok(VerificationResult::assess(
+        balance,
+        expected_amount,
+        None,
+        0,
+    ))
+}
+
+/// Whether `message` contains a memo instruction whose text is exactly `expected`
+/// Memo program ids are never loaded from lookup tables, so the static keys suffice
+pub fn has_memo(message: &VersionedMessage, expected: &str) -> bool {
+    let account_keys = message.static_account_keys();
+    message.instructions().iter().any(|instruction| {
+        let is_memo = account_keys
+            .get(instruction.program_id_index as usize)
+            .map(|program_id| {
+                let program_id = program_id.to_string();
+                program_id == MEMO_PROGRAM_ID || program_id == MEMO_V1_PROGRAM_ID
+            })
+            .unwrap_or(false);
+        is_memo && instruction.data == expected.as_bytes()
+    })
 }
+
 /// Send Solana from canister to a destination address
 /// Uses the SolanaWallet for proper key management and signing
 pub async fn send_solana(to_address: String, amount_lamports: u64) -> Result<String, String> {
+    send_solana_with_format(to_address, amount_lamports, default_transaction_format()).await
+}
+
+/// Format used by `send_solana`/`send_spl_token`: durable nonce when enabled, legacy otherwise
+fn default_transaction_format() -> SolanaTransactionFormat {
+    if read_config(|config| config.use_durable_nonce) {
+        SolanaTransactionFormat::DurableNonce
+    } else {
+        SolanaTransactionFormat::Legacy
+    }
+}
+
+/// Send Solana using the given transaction format (legacy or v0 with lookup tables)
+pub async fn send_solana_with_format(
+    to_address: String,
+    amount_lamports: u64,
+    format: SolanaTransactionFormat,
+) -> Result<String, String> {
     ic_cdk::println!(
         "🔄 Sending {} lamports to Solana address: {}",
         amount_lamports,
         to_address
     );
 
-    let canister_principal = ic_cdk::api::id();
-    let wallet = SolanaWallet::new(canister_principal).await;
-    let from_account = wallet.solana_account();
+    let from_account = solana_account_for(ic_cdk::api::id()).await;
     let from_pubkey = from_account.ed25519_public_key;
 
+    validate_sol_transfer_destination(&to_address)?;
     let to_pubkey = SolanaAddress::from_str(&to_address)
         .map_err(|e| format!("Invalid destination Solana address: {}", e))?;
 
-    let client = client();
-
     // Create transfer instruction
     use solana_system_interface::instruction::transfer;
     let instruction = transfer(&from_pubkey, &to_pubkey, amount_lamports);
 
-    // Get recent blockhash
-    let recent_blockhash = client
-        .estimate_recent_blockhash()
-        .send()
+    let tx_signature = sign_and_send_transaction(&from_account, &[instruction], &format)
         .await
-        .map_err(|e| format!("Failed to get recent blockhash: {:?}", e))?;
+        .map_err(|e| format!("Failed to send Solana transaction: {}", e))?;
+
+    ic_cdk::println!("✅ Solana sent! TX: {}", tx_signature);
+    Ok(tx_signature)
+}
+
+/// Build, sign and send a transaction whose fees are paid by `from_account`
+async fn sign_and_send_transaction(
+    from_account: &SolanaAccount,
+    instructions: &[Instruction],
+    format: &SolanaTransactionFormat,
+) -> Result<String, String> {
+    let from_pubkey = from_account.ed25519_public_key;
+    let client = client();
+
+    let unit_price = current_compute_unit_price(&from_pubkey).await;
+    let instructions = &[
+        compute_budget_instructions(unit_price),
+        instructions.to_vec(),
+    ]
+    .concat();
+
+    let simulate = read_config(|config| config.simulate_solana_sends);
+    let send_error = |e: RpcError| format!("{:?}", e);
 
     // Build and sign message using the wallet
-    let message =
-        SolanaMessage::new_with_blockhash(&[instruction], Some(&from_pubkey), &recent_blockhash);
+    let tx_signature = match format {
+        SolanaTransactionFormat::Legacy => {
+            let recent_blockhash = get_recent_blockhash().await?;
+            let message = SolanaMessage::new_with_blockhash(
+                instructions,
+                Some(&from_pubkey),
+                &recent_blockhash,
+            );
+
+            let signature = from_account.sign_message(&message).await;
+
+            let transaction = SolanaTransaction {
+                message,
+                signatures: vec![signature],
+            };
+
+            let encoded = encode_for_simulation(&transaction)?;
+            send_after_preflight(
+                simulate,
+                || simulate_transaction(encoded),
+                || async {
+                    client
+                        .send_transaction(transaction)
+                        .send()
+                        .await
+                        .expect_consistent()
+                        .map_err(send_error)
+                },
+            )
+            .await
+        }
+        SolanaTransactionFormat::V0 {
+            address_lookup_tables,
+        } => {
+            let recent_blockhash = get_recent_blockhash().await?;
+            let lookup_tables = fetch_address_lookup_tables(address_lookup_tables).await?;
+            let message =
+                build_v0_message(&from_pubkey, instructions, &lookup_tables, recent_blockhash)?;
+
+            let signature = from_account.sign_versioned_message(&message).await;
+
+            let transaction = VersionedTransaction {
+                signatures: vec![signature],
+                message,
+            };
+
+            let encoded = encode_for_simulation(&transaction)?;
+            send_after_preflight(
+                simulate,
+                || simulate_transaction(encoded),
+                || async {
+                    client
+                        .send_transaction(transaction)
+                        .send()
+                        .await
+                        .expect_consistent()
+                        .map_err(send_error)
+                },
+            )
+            .await
+        }
+        SolanaTransactionFormat::DurableNonce => {
+            let nonce_account = canister_nonce_account().await;
+            let nonce = get_durable_nonce(nonce_account).await?;
+            let message =
+                build_durable_nonce_message(&from_pubkey, &nonce_account, instructions, nonce);
+
+            let signature = from_account.sign_message(&message).await;
+
+            let transaction = SolanaTransaction {
+                message,
+                signatures: vec![signature],
+            };
+
+            let encoded = encode_for_simulation(&transaction)?;
+            send_after_preflight(
+                simulate,
+                || simulate_transaction(encoded),
+                || async {
+                    client
+                        .send_transaction(transaction)
+                        .send()
+                        .await
+                        .expect_consistent()
+                        .map_err(send_error)
+                },
+            )
+            .await
+        }
+    }?;
+
+    // The payer's balance changed (amount and fee), so the next read must go to the chain
+    invalidate_balance(&from_pubkey.to_string());
+    Ok(tx_signature.to_string())
+}
+
+/// Broadcast with `send` once the `simulate` preflight has passed, or straight away when
+/// `simulate` is off; a transaction that would fail is never broadcast, so it costs no fee
+pub async fn send_after_preflight<T, P, PFut, S, SFut>(
+    simulate: bool,
+    preflight: P,
+    send: S,
+) -> Result<T, String>
+where
+    P: FnOnce() -> PFut,
+    PFut: Future<Output = MultiRpcResult<String>>,
+    S: FnOnce() -> SFut,
+    SFut: Future<Output = Result<T, String>>,
+{
+    if simulate {
+        simulation_outcome(preflight().await)?;
+    }
+    send().await
+}
+
+/// Serialize a signed transaction the way `simulateTransaction` takes it with base58 encoding
+fn encode_for_simulation(transaction: &impl serde::Serialize) -> Result<String, String> {
+    let serialized = bincode::serialize(transaction)
+        .map_err(|e| format!("Transaction serialization failed: {}", e))?;
+    Ok(bs58::encode(serialized).into_string())
+}
+
+/// Run a signed transaction through `simulateTransaction` against current state
+async fn simulate_transaction(encoded: String) -> MultiRpcResult<String> {
+    let request = serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "simulateTransaction",
+        "params": [
+            encoded,
+            { "encoding": "base58", "commitment": "confirmed", "sigVerify": true }
+        ]
+    });
+    client().json_request(request).send().await
+}
+
+/// Whether a `simulateTransaction` preflight says the transaction would fail
+/// Providers that could not be reached do not count; the preflight only blocks the send when
+/// most of those that answered report an error, so one flaky provider cannot stall payouts
+pub fn simulation_outcome(response: MultiRpcResult<String>) -> Result<(), String> {
+    let results = match response {
+        MultiRpcResult::Consistent(result) => vec![result],
+        MultiRpcResult::Inconsistent(results) => {
+            results.into_iter().map(|(_, result)| result).collect()
+        }
+    };
+    let answered: Vec<Result<(), String>> = results
+        .into_iter()
+        .filter_map(Result::ok)
+        .map(|json| check_simulation(&json))
+        .collect();
+    let failures: Vec<&String> = answered
+        .iter()
+        .filter_map(|outcome| outcome.as_ref().err())
+        .collect();
+
+    match failures.first() {
+        Some(error) if failures.len() * 2 > answered.len() => Err((*error).clone()),
+        _ => Ok(()),
+    }
+}
 
-    let signature = from_account.sign_message(&message).await;
+/// The simulation error in a `simulateTransaction` response, with the last program log line
+pub fn check_simulation(json: &str) -> Result<(), String> {
+    let response: serde_json::Value = serde_json::from_str(json)
+        .map_err(|e| format!("SimulationFailed: invalid response: {}", e))?;
+    if let Some(error) = response.get("error") {
+        return Err(format!("SimulationFailed: {}", error));
+    }
+
+    let value = &response["result"]["value"];
+    if value["err"].is_null() {
+        return Ok(());
+    }
+    let last_log = value["logs"]
+        .as_array()
+        .and_then(|logs| logs.last())
+        .and_then(|log| log.as_str());
+    Err(match last_log {
+        Some(log) => format!("SimulationFailed: {} ({})", value["err"], log),
+        None => format!("SimulationFailed: {}", value["err"]),
+    })
+}
 
-    let transaction = SolanaTransaction {
-        message,
-        signatures: vec![signature],
+/// Compute unit price (micro-lamports) for the next transaction, following recent prioritization
+/// fees up to `max_compute_unit_price`; samples are cached briefly, and a failed sample means no
+/// priority fee rather than a failed payout
+async fn current_compute_unit_price(payer: &SolanaAddress) -> u64 {
+    let max_price = read_config(|config| config.max_compute_unit_price);
+    if max_price == 0 {
+        return 0;
+    }
+    let now = now_nanos();
+    if let Some(price) = cached_compute_unit_price(now, COMPUTE_UNIT_PRICE_CACHE_SECONDS) {
+        return price;
+    }
+
+    let response = match client().get_recent_prioritization_fees([payer]) {
+        Ok(request) => request.send().await,
+        Err(e) => MultiRpcResult::Consistent(Err(e)),
     };
+    match sampled_prioritization_fees(response) {
+        Ok(samples) => {
+            let price = compute_unit_price(&samples, max_price);
+            cache_compute_unit_price(price, now);
+            price
+        }
+        Err(e) => {
+            ic_cdk::println!(
+                "⚠️ No prioritization fee sample, sending without one: {}",
+                e
+            );
+            0
+        }
+    }
+}
 
-    // Send transaction
-    let tx_signature = client
-        .send_transaction(transaction)
+/// Every fee any provider reported; providers sample different slots, so their lists rarely
+/// agree exactly, and all of them are useful samples
+pub fn sampled_prioritization_fees(
+    response: MultiRpcResult<Vec<PrioritizationFee>>,
+) -> Result<Vec<u64>, String> {
+    let results = match response {
+        MultiRpcResult::Consistent(result) => vec![result],
+        MultiRpcResult::Inconsistent(results) => {
+            results.into_iter().map(|(_, result)| result).collect()
+        }
+    };
+
+    let mut samples = Vec::new();
+    let mut error = None;
+    for result in results {
+        match result {
+            Ok(fees) => samples.extend(fees.into_iter().map(|fee| fee.prioritization_fee)),
+            Err(e) => error = Some(format!("{:?}", e)),
+        }
+    }
+    match error {
+        Some(error) if samples.is_empty() => Err(error),
+        _ => Ok(samples),
+    }
+}
+
+/// The `COMPUTE_UNIT_PRICE_PERCENTILE`th percentile of the sampled fees, capped at `max_price`
+/// Recent slots are mostly fee-free on an idle network, which makes the price 0
+pub fn compute_unit_price(samples: &[u64], max_price: u64) -> u64 {
+    let mut sorted = samples.to_vec();
+    sorted.sort_unstable();
+    match sorted.len() {
+        0 => 0,
+        len => sorted[(len - 1) * COMPUTE_UNIT_PRICE_PERCENTILE / 100].min(max_price),
+    }
+}
+
+/// Most a payout can pay in priority fees, in lamports, with the price at its configured cap
+pub fn max_priority_fee_lamports() -> u64 {
+    let max_price = read_config(|config| config.max_compute_unit_price);
+    max_price.saturating_mul(PAYOUT_COMPUTE_UNIT_LIMIT as u64) / 1_000_000
+}
+
+/// SetComputeUnitLimit and SetComputeUnitPrice instructions for a payout bidding `unit_price`
+/// None at all for a price of 0, leaving the transaction at the base fee
+pub fn compute_budget_instructions(unit_price: u64) -> Vec<Instruction> {
+    if unit_price == 0 {
+        return Vec::new();
+    }
+    let program_id = SolanaAddress::from_str(COMPUTE_BUDGET_PROGRAM_ID)
+        .expect("Compute budget program ID is a valid address");
+
+    // Instruction tags 2 and 3 of the compute budget program, followed by little-endian values
+    let mut set_limit = vec![2];
+    set_limit.extend_from_slice(&PAYOUT_COMPUTE_UNIT_LIMIT.to_le_bytes());
+    let mut set_price = vec![3];
+    set_price.extend_from_slice(&unit_price.to_le_bytes());
+
+    vec![
+        Instruction::new_with_bytes(program_id, &set_limit, Vec::new()),
+        Instruction::new_with_bytes(program_id, &set_price, Vec::new()),
+    ]
+}
+
+async fn get_recent_blockhash() -> Result<Hash, String> {
+    client()
+        .estimate_recent_blockhash()
         .send()
         .await
-        .expect_consistent()
-        .map_err(|e| format!("Failed to send Solana transaction: {:?}", e))?;
+        .map_err(|e| format!("Failed to get recent blockhash: {:?}", e))
+}
 
-    ic_cdk::println!("✅ Solana sent! TX: {}", tx_signature.to_string());
-    Ok(tx_signature.to_string())
+/// Build a legacy message that uses a durable nonce in place of a recent blockhash
+/// The nonce is advanced as the first instruction, with the payer as nonce authority
+pub fn build_durable_nonce_message(
+    payer: &SolanaAddress,
+    nonce_account: &SolanaAddress,
+    instructions: &[Instruction],
+    nonce: Hash,
+) -> SolanaMessage {
+    use solana_system_interface::instruction::advance_nonce_account;
+
+    let mut nonce_instructions = Vec::with_capacity(instructions.len() + 1);
+    nonce_instructions.push(advance_nonce_account(nonce_account, payer));
+    nonce_instructions.extend_from_slice(instructions);
+
+    SolanaMessage::new_with_blockhash(&nonce_instructions, Some(payer), &nonce)
+}
+
+/// Address of the canister's nonce account, derived deterministically from its principal
+async fn canister_nonce_account() -> SolanaAddress {
+    let wallet = SolanaWallet::new(ic_cdk::api::id()).await;
+    *wallet.derived_nonce_account().as_ref()
+}
+
+/// Read the nonce currently stored in a nonce account
+async fn get_durable_nonce(nonce_account: SolanaAddress) -> Result<Hash, String> {
+    let client = client();
+    let account = consistent_rpc(|| {
+        client
+            .get_account_info(nonce_account)
+            .with_encoding(GetAccountInfoEncoding::Base64)
+            .try_send()
+    })
+    .await
+    .map_err(|e| format!("Failed to get nonce account: {:?}", e))?
+    .ok_or(format!(
+        "Nonce account {} not found, run setup_durable_nonce first",
+        nonce_account
+    ))?;
+
+    nonce_from_account(&account).map_err(|e| format!("Failed to read durable nonce: {:?}", e))
+}
+
+/// Create the canister's nonce account (if needed) and send payouts with durable nonces
+pub async fn setup_durable_nonce() -> Result<String, String> {
+    let wallet = SolanaWallet::new(ic_cdk::api::id()).await;
+    let payer = wallet.solana_account();
+    let nonce_account = wallet.derived_nonce_account();
+    let client = client();
+
+    let existing = consistent_rpc(|| client.get_account_info(*nonce_account.as_ref()).try_send())
+        .await
+        .map_err(|e| format!("Failed to get nonce account: {:?}", e))?;
+
+    if existing.is_none() {
+        use solana_system_interface::instruction::create_nonce_account;
+
+        let instructions = create_nonce_account(
+            payer.as_ref(),
+            nonce_account.as_ref(),
+            payer.as_ref(),
+            NONCE_ACCOUNT_LAMPORTS,
+        );
+        let message = SolanaMessage::new_with_blockhash(
+            &instructions,
+            Some(payer.as_ref()),
+            &get_recent_blockhash().await?,
+        );
+
+        // The new account has to sign its own creation
+        let signatures = vec![
+            payer.sign_message(&message).await,
+            nonce_account.sign_message(&message).await,
+        ];
+        let transaction = SolanaTransaction {
+            message,
+            signatures,
+        };
+
+        client
+            .send_transaction(transaction)
+            .send()
+            .await
+            .expect_consistent()
+            .map_err(|e| format!("Failed to create nonce account: {:?}", e))?;
+
+        ic_cdk::println!("✅ Created nonce account {}", nonce_account);
+    }
+
+    set_use_durable_nonce(true);
+    Ok(nonce_account.to_string())
+}
+
+/// Stop using durable nonces and withdraw the nonce account's balance, which closes it
+pub async fn teardown_durable_nonce() -> Result<String, String> {
+    // Switch back first so no payout races against the account being closed
+    set_use_durable_nonce(false);
+
+    let wallet = SolanaWallet::new(ic_cdk::api::id()).await;
+    let payer = wallet.solana_account();
+    let nonce_account = wallet.derived_nonce_account();
+
+    let balance = get_solana_balance_internal(nonce_account.to_string()).await?;
+    if balance == 0 {
+        return Err(format!("Nonce account {} does not exist", nonce_account));
+    }
+
+    use solana_system_interface::instruction::withdraw_nonce_account;
+    let instruction = withdraw_nonce_account(
+        nonce_account.as_ref(),
+        payer.as_ref(),
+        payer.as_ref(),
+        balance,
+    );
+
+    let tx_signature =
+        sign_and_send_transaction(&payer, &[instruction], &SolanaTransactionFormat::Legacy)
+            .await
+            .map_err(|e| format!("Failed to close nonce account: {}", e))?;
+
+    ic_cdk::println!(
+        "✅ Closed nonce account {}! TX: {}",
+        nonce_account,
+        tx_signature
+    );
+    Ok(tx_signature)
+}
+
+/// Compile a v0 message, moving accounts found in the lookup tables out of the static keys
+pub fn build_v0_message(
+    payer: &SolanaAddress,
+    instructions: &[Instruction],
+    lookup_tables: &[AddressLookupTableAccount],
+    recent_blockhash: Hash,
+) -> Result<VersionedMessage, String> {
+    let message = v0::Message::try_compile(payer, instructions, lookup_tables, recent_blockhash)
+        .map_err(|e| format!("Failed to compile v0 message: {:?}", e))?;
+    Ok(VersionedMessage::V0(message))
+}
+
+/// Fetch and decode the given address lookup table accounts
+async fn fetch_address_lookup_tables(
+    addresses: &[String],
+) -> Result<Vec<AddressLookupTableAccount>, String> {
+    let client = client();
+    let mut lookup_tables = Vec::with_capacity(addresses.len());
+
+    for address in addresses {
+        let key = SolanaAddress::from_str(address)
+            .map_err(|e| format!("Invalid lookup table address: {}", e))?;
+
+        let account = consistent_rpc(|| {
+            client
+                .get_account_info(key)
+                .with_encoding(GetAccountInfoEncoding::Base64)
+                .try_send()
+        })
+        .await
+        .map_err(|e| format!("Failed to get lookup table {}: {:?}", address, e))?
+        .ok_or(format!("Lookup table {} not found", address))?;
+
+        if account.owner != ADDRESS_LOOKUP_TABLE_PROGRAM_ID {
+            return Err(format!(
+                "Account {} is not an address lookup table",
+                address
+            ));
+        }
+
+        let data = account.data.decode().ok_or(format!(
+            "Unsupported data encoding for lookup table {}",
+            address
+        ))?;
+
+        lookup_tables.push(parse_address_lookup_table(key, &data)?);
+    }
+
+    Ok(lookup_tables)
+}
+
+/// Decode the addresses stored in an address lookup table account
+pub fn parse_address_lookup_table(
+    key: SolanaAddress,
+    data: &[u8],
+) -> Result<AddressLookupTableAccount, String> {
+    if data.len() < LOOKUP_TABLE_META_SIZE || (data.len() - LOOKUP_TABLE_META_SIZE) % 32 != 0 {
+        return Err(format!(
+            "Invalid lookup table data length: {} bytes",
+            data.len()
+        ));
+    }
+
+    let addresses = data[LOOKUP_TABLE_META_SIZE..]
+        .chunks_exact(32)
+        .map(|chunk| SolanaAddress::try_from(chunk).expect("chunk is 32 bytes"))
+        .collect();
+
+    Ok(AddressLookupTableAccount { key, addresses })
 }
 
 /// Get Solana balance (public interface)
 pub async fn get_solana_balance(address: String) -> Result<f64, String> {
-    let balance_lamports = get_solana_balance_internal(address).await?;
+    let balance_lamports = get_solana_balance_lamports(address).await?;
     let balance_sol = balance_lamports as f64 / 1_000_000_000.0;
     Ok(balance_sol)
 }
 
-/// Internal function to get balance in lamports
+/// Get Solana balance in lamports, served from the balance cache while fresh
+pub async fn get_solana_balance_lamports(address: String) -> Result<u64, String> {
+    read_balance_cached(address, now_nanos(), get_solana_balance_internal).await
+}
+
+/// Internal function to get balance in lamports, always read from the chain
 async fn get_solana_balance_internal(address: String) -> Result<u64, String> {
     let pubkey =
         SolanaAddress::from_str(&address).map_err(|e| format!("Invalid Solana address: {}", e))?;
@@ -150,20 +876,21 @@ async fn get_solana_balance_internal(address: String) -> Result<u64, String> {
     };
 
     let client = client();
-    let balance_lamports = client
-        .get_balance(params)
-        .send()
+    let balance_lamports = consistent_rpc(|| client.get_balance(params.clone()).try_send())
         .await
-        .expect_consistent()
         .map_err(|e| format!("Failed to get balance: {:?}", e))?;
 
     Ok(balance_lamports)
 }
 
 /// Test function: Send 0.01 SOL to a specified address
+/// It pays out of the canister's own funds, so it only runs in test mode and never on mainnet
 pub async fn test_send_sol(to_address: String) -> Result<String, String> {
     const TEST_AMOUNT: u64 = 10_000_000; // 0.01 SOL in lamports
 
+    let network = read_state(|state| state.solana_network().clone());
+    check_test_send_allowed(read_config(|config| config.test_mode), &network)?;
+
     ic_cdk::println!("🧪 Test: Sending 0.01 SOL to {}", to_address);
 
     let result = send_solana(to_address.clone(), TEST_AMOUNT).await?;
@@ -172,12 +899,44 @@ pub async fn test_send_sol(to_address: String) -> Result<String, String> {
     Ok(result)
 }
 
+/// Allow `test_send_sol` only in test mode, and not on Solana mainnet even then
+pub fn check_test_send_allowed(test_mode: bool, network: &SolanaNetwork) -> Result<(), String> {
+    if !test_mode {
+        return Err(
+            "TestModeDisabled: test_send_sol is only available when the canister runs in test mode"
+                .to_string(),
+        );
+    }
+    if matches!(network, SolanaNetwork::Mainnet) {
+        return Err(
+            "TestModeDisabled: test_send_sol is not available on Solana mainnet".to_string(),
+        );
+    }
+    Ok(())
+}
+
 /// Send SPL token from canister to a destination address
 /// Uses the SolanaWallet for proper key management and signing
 pub async fn send_spl_token(
     to_address: String,
     amount: u64,
     mint_address: String,
+) -> Result<String, String> {
+    send_spl_token_with_format(
+        to_address,
+        amount,
+        mint_address,
+        default_transaction_format(),
+    )
+    .await
+}
+
+/// Send SPL token using the given transaction format (legacy or v0 with lookup tables)
+pub async fn send_spl_token_with_format(
+    to_address: String,
+    amount: u64,
+    mint_address: String,
+    format: SolanaTransactionFormat,
 ) -> Result<String, String> {
     ic_cdk::println!(
         "🔄 Sending {} tokens (mint: {}) to Solana address: {}",
@@ -186,64 +945,55 @@ pub async fn send_spl_token(
         to_address
     );
 
-    let canister_principal = ic_cdk::api::id();
-    let wallet = SolanaWallet::new(canister_principal).await;
-    let from_account = wallet.solana_account();
-    let from_pubkey = from_account.ed25519_public_key;
+    let from_account = solana_account_for(ic_cdk::api::id()).await;
 
-    let to_pubkey = SolanaAddress::from_str(&to_address)
-        .map_err(|e| format!("Invalid destination Solana address: {}", e))?;
+    // Token-2022 mints use their own program for both the transfer and the ATA derivation
+    let token_program = resolve_token_program(&mint_address).await?;
 
-    let mint_pubkey = SolanaAddress::from_str(&mint_address)
-        .map_err(|e| format!("Invalid mint address: {}", e))?;
-
-    let client = client();
-
-    // Get or create associated token accounts
-    let from_ata = get_associated_token_address(&from_pubkey, &mint_pubkey);
-    let to_ata = get_associated_token_address(&to_pubkey, &mint_pubkey);
-
-    // Create SPL token transfer instruction
-    use crate::basic_solana::spl::transfer_instruction_with_program_id;
-    const SPL_TOKEN_PROGRAM_ID: &str = "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA";
-    let token_program = SolanaAddress::from_str(SPL_TOKEN_PROGRAM_ID).unwrap();
-
-    let instruction = transfer_instruction_with_program_id(
-        &from_ata,
-        &to_ata,
-        &from_pubkey,
+    let instruction = spl_transfer_instruction(
+        &from_account.ed25519_public_key,
+        &to_address,
+        &mint_address,
         amount,
         &token_program,
-    );
+    )?;
 
-    // Get recent blockhash
-    let recent_blockhash = client
-        .estimate_recent_blockhash()
-        .send()
+    let tx_signature = sign_and_send_transaction(&from_account, &[instruction], &format)
         .await
-        .map_err(|e| format!("Failed to get recent blockhash: {:?}", e))?;
+        .map_err(|e| format!("Failed to send SPL token transaction: {}", e))?;
 
-    // Build and sign message using the wallet
-    let message =
-        SolanaMessage::new_with_blockhash(&[instruction], Some(&from_pubkey), &recent_blockhash);
+    ic_cdk::println!("✅ SPL tokens sent! TX: {}", tx_signature);
+    Ok(tx_signature)
+}
 
-    let signature = from_account.sign_message(&message).await;
+/// Transfer of `amount` of `mint_address` from `from_pubkey`'s associated token account to
+/// `to_address`'s, under `token_program`
+pub fn spl_transfer_instruction(
+    from_pubkey: &SolanaAddress,
+    to_address: &str,
+    mint_address: &str,
+    amount: u64,
+    token_program: &str,
+) -> Result<Instruction, String> {
+    use crate::basic_solana::spl::transfer_instruction_with_program_id;
 
-    let transaction = SolanaTransaction {
-        message,
-        signatures: vec![signature],
-    };
+    let to_pubkey = SolanaAddress::from_str(to_address)
+        .map_err(|e| format!("Invalid destination Solana address: {}", e))?;
+    let mint_pubkey = SolanaAddress::from_str(mint_address)
+        .map_err(|e| format!("Invalid mint address: {}", e))?;
+    let token_program = SolanaAddress::from_str(token_program)
+        .map_err(|e| format!("Invalid token program: {}", e))?;
 
-    // Send transaction
-    let tx_signature = client
-        .send_transaction(transaction)
-        .send()
-        .await
-        .expect_consistent()
-        .map_err(|e| format!("Failed to send SPL token transaction: {:?}", e))?;
+    let from_ata = get_associated_token_address(from_pubkey, &mint_pubkey, &token_program);
+    let to_ata = get_associated_token_address(&to_pubkey, &mint_pubkey, &token_program);
 
-    ic_cdk::println!("✅ SPL tokens sent! TX: {}", tx_signature.to_string());
-    Ok(tx_signature.to_string())
+    Ok(transfer_instruction_with_program_id(
+        &from_ata,
+        &to_ata,
+        from_pubkey,
+        amount,
+        &token_program,
+    ))
 }
 
 /// Get SPL token balance for an address
@@ -257,7 +1007,9 @@ pub async fn get_spl_token_balance(address: String, mint_address: String) -> Res
         .map_err(|e| format!("Invalid mint address: {}", e))?;
 
     // Get associated token account address
-    let ata = get_associated_token_address(&owner_pubkey, &mint_pubkey);
+    let token_program = SolanaAddress::from_str(&resolve_token_program(&mint_address).await?)
+        .map_err(|e| format!("Invalid token program: {}", e))?;
+    let ata = get_associated_token_address(&owner_pubkey, &mint_pubkey, &token_program);
 
     let params = GetTokenAccountBalanceParams {
         pubkey: ata.into(),
@@ -265,12 +1017,10 @@ pub async fn get_spl_token_balance(address: String, mint_address: String) -> Res
     };
 
     let client = client();
-    let balance_response = client
-        .get_token_account_balance(params)
-        .send()
-        .await
-        .expect_consistent()
-        .map_err(|e| format!("Failed to get token balance: {:?}", e))?;
+    let balance_response =
+        consistent_rpc(|| client.get_token_account_balance(params.clone()).try_send())
+            .await
+            .map_err(|e| format!("Failed to get token balance: {:?}", e))?;
 
     // Parse amount from ui_amount_string or amount
     let amount = balance_response
@@ -281,15 +1031,204 @@ pub async fn get_spl_token_balance(address: String, mint_address: String) -> Res
     Ok(amount)
 }
 
+/// Every token account `owner_address` holds under the legacy SPL Token and Token-2022 programs
+/// Owners without token accounts get an empty list
+pub async fn get_all_token_balances(owner_address: String) -> Result<Vec<TokenBalance>, String> {
+    validate_solana_address(&owner_address)?;
+
+    let client = client();
+    let mut balances = Vec::new();
+    // getTokenAccountsByOwner is not paginated, but it only lists one token program per call
+    for token_program in [SPL_TOKEN_PROGRAM_ID, TOKEN_2022_PROGRAM_ID] {
+        let request = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "getTokenAccountsByOwner",
+            "params": [
+                owner_address,
+                { "programId": token_program },
+                { "encoding": "jsonParsed", "commitment": "confirmed" }
+            ]
+        });
+        let accounts = consistent_rpc(|| {
+            let response = client.json_request(request.clone()).try_send();
+            async move { response.await.map(reconcile_token_accounts) }
+        })
+        .await
+        .map_err(|e| format!("Failed to list token accounts: {:?}", e))?;
+        balances.extend(accounts);
+    }
+
+    balances.sort_by(|a, b| {
+        (&a.mint_address, &a.token_account).cmp(&(&b.mint_address, &b.token_account))
+    });
+    Ok(balances)
+}
+
+/// Parse each provider's getTokenAccountsByOwner response and compare the accounts they list
+/// Providers answer at different slots, so their raw JSON rarely matches byte for byte even when
+/// the accounts do; the response counts as consistent when a majority report the same accounts
+pub fn reconcile_token_accounts(
+    response: MultiRpcResult<String>,
+) -> MultiRpcResult<Vec<TokenBalance>> {
+    let parse = |result: RpcResult<String>| {
+        result.and_then(|json| parse_token_accounts(&json).map_err(RpcError::ValidationError))
+    };
+
+    match response {
+        MultiRpcResult::Consistent(result) => MultiRpcResult::Consistent(parse(result)),
+        MultiRpcResult::Inconsistent(results) => {
+            let results: Vec<_> = results
+                .into_iter()
+                .map(|(source, result)| (source, parse(result)))
+                .collect();
+            let majority = results.iter().find_map(|(_, result)| {
+                let accounts = result.as_ref().ok()?;
+                let agreeing = results
+                    .iter()
+                    .filter(|(_, other)| matches!(other, Ok(other) if other == accounts))
+                    .count();
+                (agreeing * 2 > results.len()).then(|| accounts.clone())
+            });
+            match majority {
+                Some(accounts) => MultiRpcResult::Consistent(Ok(accounts)),
+                None => MultiRpcResult::Inconsistent(results),
+            }
+        }
+    }
+}
+
+/// Token accounts in a jsonParsed getTokenAccountsByOwner response
+pub fn parse_token_accounts(json: &str) -> Result<Vec<TokenBalance>, String> {
+    let response: serde_json::Value = serde_json::from_str(json)
+        .map_err(|e| format!("Invalid token accounts response: {}", e))?;
+    if let Some(error) = response.get("error") {
+        return Err(format!("getTokenAccountsByOwner failed: {}", error));
+    }
+    let accounts = response["result"]["value"]
+        .as_array()
+        .ok_or("Token accounts response has no result")?;
+
+    accounts
+        .iter()
+        .map(|entry| {
+            let info = &entry["account"]["data"]["parsed"]["info"];
+            let token_amount = &info["tokenAmount"];
+            let field = |value: &serde_json::Value, name: &str| {
+                value
+                    .as_str()
+                    .map(str::to_string)
+                    .ok_or(format!("Token account is missing {}", name))
+            };
+            Ok(TokenBalance {
+                token_account: field(&entry["pubkey"], "pubkey")?,
+                mint_address: field(&info["mint"], "mint")?,
+                token_program: field(&entry["account"]["owner"], "owner")?,
+                amount: field(&token_amount["amount"], "amount")?
+                    .parse::<u64>()
+                    .map_err(|e| format!("Failed to parse token amount: {}", e))?,
+                decimals: token_amount["decimals"]
+                    .as_u64()
+                    .and_then(|decimals| u8::try_from(decimals).ok())
+                    .ok_or("Token account is missing decimals")?,
+            })
+        })
+        .collect()
+}
+
+/// Lamports a successful transaction credited to `recipient_address`
+/// Unlike the balance check in `check_solana_transaction`, this counts only this transaction
+pub async fn get_lamports_credit(recipient_address: String, txid: String) -> Result<u64, String> {
+    let recipient = SolanaAddress::from_str(&recipient_address)
+        .map_err(|e| format!("Invalid recipient address: {}", e))?;
+    let signature = Signature::from_str(&txid).map_err(|e| format!("Invalid signature: {}", e))?;
+
+    let client = client();
+
+    use sol_rpc_types::GetTransactionEncoding;
+    let params = GetTransactionParams {
+        signature,
+        encoding: Some(GetTransactionEncoding::Base64),
+        commitment: Some(CommitmentLevel::Confirmed),
+        max_supported_transaction_version: Some(0),
+    };
+
+    let tx = consistent_rpc(|| client.get_transaction(params.clone()).try_send())
+        .await
+        .map_err(|e| format!("Failed to get transaction: {:?}", e))?
+        .ok_or("Transaction not found")?;
+    let meta = tx
+        .transaction
+        .meta
+        .clone()
+        .ok_or("Transaction has no metadata")?;
+    if meta.err.is_some() {
+        return Err("Transaction failed".to_string());
+    }
+    let meta = TransactionStatusMeta::try_from(meta)
+        .map_err(|e| format!("Failed to parse transaction metadata: {:?}", e))?;
+
+    // Balances are listed in account order: static keys, then addresses loaded from lookup tables
+    let transaction = tx
+        .transaction
+        .transaction
+        .decode()
+        .ok_or("Failed to decode transaction")?;
+    let mut account_keys = transaction.message.static_account_keys().to_vec();
+    if let Some(loaded) = meta.loaded_addresses {
+        account_keys.extend(loaded.writable.into_iter().map(SolanaAddress::from));
+        account_keys.extend(loaded.readonly.into_iter().map(SolanaAddress::from));
+    }
+
+    Ok(lamports_credit(
+        &account_keys,
+        &recipient,
+        &meta.pre_balances,
+        &meta.post_balances,
+    ))
+}
+
+/// Net lamports a transaction credited to `recipient`, given its per-account balances
+pub fn lamports_credit(
+    account_keys: &[SolanaAddress],
+    recipient: &SolanaAddress,
+    pre_balances: &[u64],
+    post_balances: &[u64],
+) -> u64 {
+    let Some(index) = account_keys.iter().position(|key| key == recipient) else {
+        return 0;
+    };
+    let balance_of = |balances: &[u64]| balances.get(index).copied().unwrap_or(0);
+    balance_of(post_balances).saturating_sub(balance_of(pre_balances))
+}
+
 /// Verify SPL token transaction
+/// `token_account` must be the exact token account that should have been credited (for deposits to the
+/// canister, its associated token account for the mint), not the wallet that owns it
 pub async fn verify_spl_token_transaction(
-    recipient_address: String,
+    token_account: String,
     expected_amount: u64,
     mint_address: String,
     txid: String,
 ) -> Result<bool, String> {
+    check_spl_token_transaction(token_account, expected_amount, mint_address, txid)
+        .await
+        .map(|result| result.verified)
+}
+
+/// Verify an SPL token deposit, reporting the amount actually credited to `token_account`
+/// Tokens that charge a transfer fee credit less than the sender sent; such deposits are rejected
+pub async fn check_spl_token_transaction(
+    token_account: String,
+    expected_amount: u64,
+    mint_address: String,
+    txid: String,
+) -> Result<VerificationResult, String> {
     ic_cdk::println!("🔍 Verifying SPL token transaction: {}", txid);
 
+    let token_account_pubkey = SolanaAddress::from_str(&token_account)
+        .map_err(|e| format!("Invalid token account address: {}", e))?;
+
     // First, verify the transaction exists and was successful
     let signature = Signature::from_str(&txid).map_err(|e| format!("Invalid signature: {}", e))?;
 
@@ -303,52 +1242,199 @@ pub async fn verify_spl_token_transaction(
         max_supported_transaction_version: Some(0),
     };
 
-    let tx = client
-        .get_transaction(params)
-        .send()
+    let tx = consistent_rpc(|| client.get_transaction(params.clone()).try_send())
         .await
-        .expect_consistent()
         .map_err(|e| format!("Failed to get transaction: {:?}", e))?;
 
-    // Check if transaction exists and was successful
-    let tx_valid = if let Some(tx) = tx {
-        if let Some(meta) = &tx.transaction.meta {
-            if meta.err.is_none() {
-                ic_cdk::println!("✅ Transaction found and successful");
-                true
-            } else {
-                ic_cdk::println!("❌ Transaction found but failed: {:?}", meta.err);
-                false
-            }
-        } else {
-            ic_cdk::println!("❌ Transaction found but no metadata");
-            false
-        }
-    } else {
+    let Some(tx) = tx else {
         ic_cdk::println!("❌ Transaction not found");
-        false
+        return Ok(VerificationResult::not_found("Transaction not found"));
+    };
+
+    let Some(meta) = tx.transaction.meta.clone() else {
+        ic_cdk::println!("❌ Transaction found but no metadata");
+        return Ok(VerificationResult::not_found("Transaction has no metadata"));
     };
 
-    if !tx_valid {
-        return Ok(false);
+    if meta.err.is_some() {
+        ic_cdk::println!("❌ Transaction found but failed: {:?}", meta.err);
+        return Ok(VerificationResult::not_found("Transaction failed"));
+    }
+
+    let meta = TransactionStatusMeta::try_from(meta)
+        .map_err(|e| format!("Failed to parse transaction metadata: {:?}", e))?;
+
+    // Token balances reference accounts by index, so rebuild the full account list:
+    // static keys followed by any addresses loaded from lookup tables
+    let transaction = tx
+        .transaction
+        .transaction
+        .decode()
+        .ok_or("Failed to decode transaction")?;
+    let mut account_keys = transaction.message.static_account_keys().to_vec();
+    if let Some(loaded) = meta.loaded_addresses {
+        account_keys.extend(loaded.writable.into_iter().map(SolanaAddress::from));
+        account_keys.extend(loaded.readonly.into_iter().map(SolanaAddress::from));
     }
 
-    // Verify token balance
-    let balance = get_spl_token_balance(recipient_address.clone(), mint_address).await?;
+    let pre_balances = meta.pre_token_balances.unwrap_or_default();
+    let post_balances = meta.post_token_balances.unwrap_or_default();
+    let credited = token_account_credit(
+        &account_keys,
+        &token_account_pubkey,
+        &mint_address,
+        &pre_balances,
+        &post_balances,
+    )?;
+    let debited = token_mint_debit(&mint_address, &pre_balances, &post_balances)?;
 
     ic_cdk::println!(
-        "✅ SPL token verification: Address {} has {} tokens (expected: {})",
-        recipient_address,
-        balance,
+        "✅ SPL token verification: Token account {} credited {} tokens (expected: {})",
+        token_account,
+        credited,
         expected_amount
     );
 
-    Ok(balance >= expected_amount)
+    Ok(assess_spl_deposit(credited, debited, expected_amount))
+}
+
+/// Judge an SPL deposit by what reached the canister, not by what the sender sent
+/// A sender debited at least `expected_amount` while less was credited points to a transfer fee
+pub fn assess_spl_deposit(credited: u64, debited: u64, expected_amount: u64) -> VerificationResult {
+    if credited < expected_amount && debited >= expected_amount {
+        return VerificationResult {
+            verified: false,
+            observed_amount: credited,
+            confirmations: None,
+            reason: Some(format!(
+                "FeeOnTransfer: {} sent but only {} credited, expected {}",
+                debited, credited, expected_amount
+            )),
+        };
+    }
+    VerificationResult::assess(credited, expected_amount, None, 0)
+}
+
+/// Total amount of `mint` tokens a transaction took out of the accounts it debited
+pub fn token_mint_debit(
+    mint: &str,
+    pre_balances: &[TransactionTokenBalance],
+    post_balances: &[TransactionTokenBalance],
+) -> Result<u64, String> {
+    let parse = |balance: &TransactionTokenBalance| -> Result<u64, String> {
+        balance
+            .ui_token_amount
+            .amount
+            .parse::<u64>()
+            .map_err(|e| format!("Failed to parse token amount: {}", e))
+    };
+
+    let mut debited: u64 = 0;
+    for pre in pre_balances.iter().filter(|balance| balance.mint == mint) {
+        let post = match post_balances
+            .iter()
+            .find(|post| post.account_index == pre.account_index && post.mint == mint)
+        {
+            Some(post) => parse(post)?,
+            None => 0,
+        };
+        debited = debited.saturating_add(parse(pre)?.saturating_sub(post));
+    }
+    Ok(debited)
+}
+
+/// Net amount of `mint` tokens a transaction credited to `token_account`
+/// `account_keys` is the transaction's full account list, which token balance `account_index` values refer to
+pub fn token_account_credit(
+    account_keys: &[SolanaAddress],
+    token_account: &SolanaAddress,
+    mint: &str,
+    pre_balances: &[TransactionTokenBalance],
+    post_balances: &[TransactionTokenBalance],
+) -> Result<u64, String> {
+    let Some(index) = account_keys.iter().position(|key| key == token_account) else {
+        return Ok(0);
+    };
+
+    let balance_of = |balances: &[TransactionTokenBalance]| -> Result<u64, String> {
+        match balances
+            .iter()
+            .find(|balance| balance.account_index as usize == index && balance.mint == mint)
+        {
+            Some(balance) => balance
+                .ui_token_amount
+                .amount
+                .parse::<u64>()
+                .map_err(|e| format!("Failed to parse token amount: {}", e)),
+            None => Ok(0),
+        }
+    };
+
+    Ok(balance_of(post_balances)?.saturating_sub(balance_of(pre_balances)?))
+}
+
+/// Get the associated token account a wallet holds `mint` in
+/// Deposits of SPL tokens must be sent here rather than to the wallet address itself
+/// Uses the mint's token program once `resolve_token_program` has looked it up, legacy SPL Token before
+pub fn get_associated_token_account(owner: &str, mint_address: &str) -> Result<String, String> {
+    get_associated_token_account_for_program(owner, mint_address, &mint_token_program(mint_address))
+}
+
+/// Associated token account of `owner` for `mint` under an explicit token program
+pub fn get_associated_token_account_for_program(
+    owner: &str,
+    mint_address: &str,
+    token_program: &str,
+) -> Result<String, String> {
+    let owner_pubkey =
+        SolanaAddress::from_str(owner).map_err(|e| format!("Invalid owner address: {}", e))?;
+    let mint_pubkey = SolanaAddress::from_str(mint_address)
+        .map_err(|e| format!("Invalid mint address: {}", e))?;
+    let token_program = SolanaAddress::from_str(check_token_program(token_program)?)
+        .map_err(|e| format!("Invalid token program: {}", e))?;
+
+    Ok(get_associated_token_address(&owner_pubkey, &mint_pubkey, &token_program).to_string())
+}
+
+/// Accept only the legacy SPL Token and Token-2022 programs as owners of a mint
+pub fn check_token_program(program_id: &str) -> Result<&str, String> {
+    if program_id == SPL_TOKEN_PROGRAM_ID || program_id == TOKEN_2022_PROGRAM_ID {
+        Ok(program_id)
+    } else {
+        Err(format!("Unsupported token program {}", program_id))
+    }
+}
+
+/// Token program owning `mint_address` as cached by `resolve_token_program`, legacy SPL Token if unknown
+pub fn mint_token_program(mint_address: &str) -> String {
+    get_cached_mint_token_program(mint_address).unwrap_or_else(|| SPL_TOKEN_PROGRAM_ID.to_string())
+}
+
+/// Look up (and cache) the token program that owns a mint account
+pub async fn resolve_token_program(mint_address: &str) -> Result<String, String> {
+    if let Some(token_program) = get_cached_mint_token_program(mint_address) {
+        return Ok(token_program);
+    }
+
+    let mint = SolanaAddress::from_str(mint_address)
+        .map_err(|e| format!("Invalid mint address: {}", e))?;
+    let (owner, _) = fetch_account_data(mint)
+        .await?
+        .ok_or(format!("Mint {} not found", mint_address))?;
+    check_token_program(&owner)
+        .map_err(|_| format!("Account {} is not an SPL token mint", mint_address))?;
+
+    cache_mint_token_program(mint_address, &owner);
+    Ok(owner)
 }
 
 /// Helper function to derive associated token address
 /// This follows the SPL Associated Token Account Program derivation
-fn get_associated_token_address(owner: &SolanaAddress, mint: &SolanaAddress) -> SolanaAddress {
+fn get_associated_token_address(
+    owner: &SolanaAddress,
+    mint: &SolanaAddress,
+    token_program: &SolanaAddress,
+) -> SolanaAddress {
     const SPL_ASSOCIATED_TOKEN_ACCOUNT_PROGRAM_ID: &str =
         "ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA8knL";
     let associated_token_program =
@@ -356,11 +1442,141 @@ fn get_associated_token_address(owner: &SolanaAddress, mint: &SolanaAddress) ->
 
     // Find program address for associated token account
     // Seeds: [owner, token_program_id, mint]
-    const SPL_TOKEN_PROGRAM_ID: &str = "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA";
-    let token_program = SolanaAddress::from_str(SPL_TOKEN_PROGRAM_ID).unwrap();
-
     let seeds = &[owner.as_ref(), token_program.as_ref(), mint.as_ref()];
 
     let (address, _bump) = SolanaAddress::find_program_address(seeds, &associated_token_program);
     address
 }
+
+/// Symbol, name and decimals of an SPL mint, cached per mint after the first lookup
+/// Mints without a Metaplex metadata account or Token-2022 metadata extension have no name/symbol
+pub async fn get_token_metadata(mint_address: String) -> Result<TokenMetadata, String> {
+    let mint = SolanaAddress::from_str(&mint_address)
+        .map_err(|e| format!("Invalid mint address: {}", e))?;
+    if let Some(metadata) = get_cached_token_metadata(&mint_address) {
+        return Ok(metadata);
+    }
+
+    let mint_account = fetch_account_data(mint)
+        .await?
+        .ok_or(format!("Mint {} not found", mint_address))?;
+    if check_token_program(&mint_account.0).is_err() {
+        return Err(format!("Account {} is not an SPL token mint", mint_address));
+    }
+    cache_mint_token_program(&mint_address, &mint_account.0);
+    let metaplex_account = fetch_account_data(metaplex_metadata_address(&mint)).await?;
+
+    let metadata = token_metadata(
+        &mint_address,
+        &mint_account.1,
+        metaplex_account.as_ref().map(|(_, data)| data.as_slice()),
+    )?;
+    cache_token_metadata(metadata.clone());
+    Ok(metadata)
+}
+
+/// Owner program and decoded data of an account, or `None` if it does not exist
+async fn fetch_account_data(address: SolanaAddress) -> Result<Option<(String, Vec<u8>)>, String> {
+    let client = client();
+    let account = consistent_rpc(|| {
+        client
+            .get_account_info(address)
+            .with_encoding(GetAccountInfoEncoding::Base64)
+            .try_send()
+    })
+    .await
+    .map_err(|e| format!("Failed to get account {}: {:?}", address, e))?;
+
+    match account {
+        Some(account) => {
+            let data = account
+                .data
+                .decode()
+                .ok_or(format!("Unsupported data encoding for account {}", address))?;
+            Ok(Some((account.owner, data)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Metaplex metadata PDA of a mint: seeds ["metadata", metadata program, mint]
+pub fn metaplex_metadata_address(mint: &SolanaAddress) -> SolanaAddress {
+    let metadata_program = SolanaAddress::from_str(METAPLEX_METADATA_PROGRAM_ID).unwrap();
+    let seeds = &[
+        b"metadata".as_ref(),
+        metadata_program.as_ref(),
+        mint.as_ref(),
+    ];
+    let (address, _bump) = SolanaAddress::find_program_address(seeds, &metadata_program);
+    address
+}
+
+/// Assemble a mint's metadata, preferring the Token-2022 extension over a Metaplex account
+pub fn token_metadata(
+    mint_address: &str,
+    mint_data: &[u8],
+    metaplex_data: Option<&[u8]>,
+) -> Result<TokenMetadata, String> {
+    if mint_data.len() < MINT_SIZE {
+        return Err(format!("Account {} is not an SPL token mint", mint_address));
+    }
+
+    let names = parse_token_2022_metadata(mint_data)
+        .or_else(|| metaplex_data.and_then(parse_metaplex_metadata));
+    let (name, symbol) = match names {
+        Some((name, symbol)) => (Some(name), Some(symbol)),
+        None => (None, None),
+    };
+
+    Ok(TokenMetadata {
+        mint_address: mint_address.to_string(),
+        name,
+        symbol,
+        decimals: mint_data[MINT_DECIMALS_OFFSET],
+    })
+}
+
+/// Name and symbol from a Metaplex metadata account (key, update authority, mint, name, symbol, ...)
+pub fn parse_metaplex_metadata(data: &[u8]) -> Option<(String, String)> {
+    const METADATA_V1_KEY: u8 = 4;
+    if data.first() != Some(&METADATA_V1_KEY) {
+        return None;
+    }
+    let mut offset = 1 + 32 + 32;
+    let name = read_borsh_string(data, &mut offset)?;
+    let symbol = read_borsh_string(data, &mut offset)?;
+    Some((name, symbol))
+}
+
+/// Name and symbol from a Token-2022 mint's metadata extension, if it has one
+pub fn parse_token_2022_metadata(mint_data: &[u8]) -> Option<(String, String)> {
+    let mut offset = TOKEN_2022_EXTENSIONS_OFFSET;
+    while offset + 4 <= mint_data.len() {
+        let extension_type = u16::from_le_bytes([mint_data[offset], mint_data[offset + 1]]);
+        let length = u16::from_le_bytes([mint_data[offset + 2], mint_data[offset + 3]]) as usize;
+        let value = mint_data.get(offset + 4..offset + 4 + length)?;
+        if extension_type == TOKEN_2022_METADATA_EXTENSION {
+            // Update authority and mint precede the name and symbol
+            let mut value_offset = 32 + 32;
+            let name = read_borsh_string(value, &mut value_offset)?;
+            let symbol = read_borsh_string(value, &mut value_offset)?;
+            return Some((name, symbol));
+        }
+        offset += 4 + length;
+    }
+    None
+}
+
+/// Read a length-prefixed string, dropping the NUL padding Metaplex stores names with
+fn read_borsh_string(data: &[u8], offset: &mut usize) -> Option<String> {
+    let length_bytes = data.get(*offset..*offset + 4)?;
+    let length = u32::from_le_bytes(length_bytes.try_into().ok()?) as usize;
+    let bytes = data.get(*offset + 4..(*offset + 4).checked_add(length)?)?;
+    *offset += 4 + length;
+    Some(
+        String::from_utf8_lossy(bytes)
+            .trim_end_matches('\0')
+            .trim()
+            .to_string(),
+    )
+}
@@ -1,49 +1,973 @@
-use crate::types::{Chain, Order, OrderInfo};
+use crate::basic_solana::solana_wallet::SolanaAccount;
+use crate::types::{
+    ActionableOrder, Asset, AssetInfo, AssetPrice, Attestation, AuditEntry, Chain, ChainStatus,
+    Deadlines, DepositLeg, Order, OrderAction, OrderCounts, OrderDetail, OrderInfo, OrderStatus,
+    OrderTimeline, ResolverRebate, ResolverStats, SwapConfig, SwapInitArg, TimeField,
+    TokenMetadata, WebhookEvent, WebhookEventKind,
+};
 use candid::Principal;
 use ic_cdk::api::time;
+use ic_cdk::bitcoin_canister::Utxo;
+use sha2::{Digest, Sha256};
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+// Oldest audit entries are dropped once this many are held
+pub const AUDIT_LOG_CAPACITY: usize = 1_000;
+
+// Oldest undelivered webhook events are dropped once this many are queued
+pub const WEBHOOK_QUEUE_CAPACITY: usize = 1_000;
+
+// `get_pending_orders` returns at most this many orders per call
+pub const MAX_PENDING_SCAN: usize = 1_000;
+
+// Consecutive failed calls after which a chain's operations fail fast
+pub const CHAIN_FAILURE_THRESHOLD: u32 = 3;
+// How long a tripped chain fails fast before a call is let through to probe it again
+pub const CHAIN_RETRY_AFTER_NANOS: u64 = 60 * 1_000_000_000;
 
 // Storage
 thread_local! {
     pub static ORDERS: RefCell<HashMap<u64, Order>> = RefCell::new(HashMap::new());
+    // Kept in step with ORDERS by `insert_order` and `update_order`
+    pub static ORDER_INDEXES: RefCell<OrderIndexes> = RefCell::new(OrderIndexes::default());
     pub static NEXT_ORDER_ID: RefCell<u64> = RefCell::new(1);
     pub static CANISTER_BTC_ADDRESS: RefCell<Option<String>> = RefCell::new(None);
     pub static CANISTER_SOL_ADDRESS: RefCell<Option<String>> = RefCell::new(None);
+    pub static CONFIG: RefCell<SwapConfig> = RefCell::new(SwapConfig::default());
+    pub static SOLANA_ACCOUNTS: RefCell<HashMap<Principal, SolanaAccount>> =
+        RefCell::new(HashMap::new());
+    pub static RESOLVER_STATS: RefCell<HashMap<Principal, ResolverStats>> =
+        RefCell::new(HashMap::new());
+    // Non-terminal orders involving each asset, kept in step with status changes
+    pub static OPEN_ORDER_COUNTS: RefCell<Vec<(Asset, u64)>> = RefCell::new(Vec::new());
+    pub static PRICE_FEED: RefCell<Vec<AssetPrice>> = RefCell::new(Vec::new());
+    // Mint address -> metadata; a mint's name, symbol and decimals do not change in practice
+    pub static TOKEN_METADATA: RefCell<HashMap<String, TokenMetadata>> =
+        RefCell::new(HashMap::new());
+    // Mint address -> owning token program (legacy SPL Token or Token-2022); fixed at mint creation
+    pub static MINT_TOKEN_PROGRAMS: RefCell<HashMap<String, String>> =
+        RefCell::new(HashMap::new());
+    // Operation name -> (calls measured, total cycles spent)
+    pub static OPERATION_COSTS: RefCell<HashMap<String, (u64, u128)>> =
+        RefCell::new(HashMap::new());
+    pub static AUDIT_LOG: RefCell<VecDeque<AuditEntry>> = RefCell::new(VecDeque::new());
+    pub static NEXT_AUDIT_ID: RefCell<u64> = RefCell::new(0);
+    pub static RESOLVER_REBATES: RefCell<HashMap<Principal, Vec<ResolverRebate>>> =
+        RefCell::new(HashMap::new());
+    // Order ID -> signed settlement receipt; signing is costly, so each order is signed once
+    pub static ATTESTATIONS: RefCell<HashMap<u64, Attestation>> = RefCell::new(HashMap::new());
+    // Address -> (balance, read at) of SOL/BTC balance reads, served within the configured TTL
+    pub static BALANCE_CACHE: RefCell<HashMap<String, (u64, u64)>> = RefCell::new(HashMap::new());
+    // (compute unit price, sampled at) of the last recent-prioritization-fees sample
+    pub static COMPUTE_UNIT_PRICE: RefCell<Option<(u64, u64)>> = RefCell::new(None);
+    // Circuit breaker per chain, created on the first recorded call
+    pub static CHAIN_HEALTH: RefCell<Vec<ChainStatus>> = RefCell::new(Vec::new());
+    // Outpoints (txid, vout) of canister UTXOs already spent by a payout, until that spend is mined
+    pub static RESERVED_UTXOS: RefCell<BTreeSet<(Vec<u8>, u32)>> = RefCell::new(BTreeSet::new());
+    // Order ID -> when the deposit watcher last checked for its deposit, for awaiting orders only
+    pub static DEPOSIT_POLLS: RefCell<HashMap<u64, u64>> = RefCell::new(HashMap::new());
+    // Order ID -> txid of a pending Bitcoin confirm_deposit, retried until the deposit is seen
+    pub static DEPOSIT_CLAIMS: RefCell<HashMap<u64, String>> = RefCell::new(HashMap::new());
+    // Txids credited as a deposit or broadcast by the canister itself; never credited again
+    pub static CONSUMED_TXIDS: RefCell<BTreeSet<String>> = RefCell::new(BTreeSet::new());
+    // Orders with a settlement or refund in flight, see `PayoutLock`
+    pub static PAYOUTS_IN_FLIGHT: RefCell<BTreeSet<u64>> = RefCell::new(BTreeSet::new());
+    // Webhook events waiting to be sent, with the number of failed attempts so far
+    pub static WEBHOOK_QUEUE: RefCell<VecDeque<(WebhookEvent, u32)>> = RefCell::new(VecDeque::new());
+}
+
+pub fn init_config(init_arg: SwapInitArg) {
+    CONFIG.with(|config| *config.borrow_mut() = SwapConfig::from(init_arg));
+}
+
+/// Configuration to run with after an upgrade
+/// An upgrade argument replaces the configuration; without one, the `saved` configuration in
+/// force before the upgrade is kept, including anything changed since by the admin endpoints
+pub fn upgraded_config(init_arg: Option<SwapInitArg>, saved: Option<SwapConfig>) -> SwapConfig {
+    match (init_arg, saved) {
+        (Some(init_arg), _) => SwapConfig::from(init_arg),
+        (None, Some(saved)) => saved,
+        (None, None) => SwapConfig::from(SwapInitArg::default()),
+    }
+}
+
+pub fn set_btc_fee_per_byte_override(fee_per_byte: Option<u64>) {
+    CONFIG.with(|config| config.borrow_mut().btc_fee_per_byte_override = fee_per_byte);
+}
+
+pub fn set_webhook_url(url: Option<String>) {
+    CONFIG.with(|config| config.borrow_mut().webhook_url = url);
+}
+
+pub fn set_use_durable_nonce(enabled: bool) {
+    CONFIG.with(|config| config.borrow_mut().use_durable_nonce = enabled);
+}
+
+/// Add `mint_address` to the SPL allowlist; returns false if it was already listed
+pub fn add_allowed_spl_mint(mint_address: String) -> bool {
+    CONFIG.with(|config| {
+        let mints = &mut config.borrow_mut().allowed_spl_mints;
+        if mints.contains(&mint_address) {
+            return false;
+        }
+        mints.push(mint_address);
+        true
+    })
+}
+
+/// Remove `mint_address` from the SPL allowlist; returns false if it was not listed
+pub fn remove_allowed_spl_mint(mint_address: &str) -> bool {
+    CONFIG.with(|config| {
+        let mints = &mut config.borrow_mut().allowed_spl_mints;
+        let before = mints.len();
+        mints.retain(|mint| mint != mint_address);
+        mints.len() != before
+    })
+}
+
+/// Balance of `address` read less than `balance_cache_ttl_seconds` before `now`, if any
+pub fn cached_balance(address: &str, now: u64) -> Option<u64> {
+    let ttl = read_config(|config| config.balance_cache_ttl_seconds).saturating_mul(1_000_000_000);
+    BALANCE_CACHE.with(|cache| {
+        cache
+            .borrow()
+            .get(address)
+            .filter(|(_, read_at)| now < read_at.saturating_add(ttl))
+            .map(|(balance, _)| *balance)
+    })
+}
+
+pub fn cache_balance(address: &str, balance: u64, now: u64) {
+    BALANCE_CACHE.with(|cache| {
+        cache
+            .borrow_mut()
+            .insert(address.to_string(), (balance, now));
+    });
+}
+
+/// Compute unit price sampled less than `ttl_seconds` before `now`, if any
+pub fn cached_compute_unit_price(now: u64, ttl_seconds: u64) -> Option<u64> {
+    let ttl = ttl_seconds.saturating_mul(1_000_000_000);
+    let cached = COMPUTE_UNIT_PRICE.with(|cache| *cache.borrow());
+    cached
+        .filter(|(_, sampled_at)| now < sampled_at.saturating_add(ttl))
+        .map(|(price, _)| price)
+}
+
+pub fn cache_compute_unit_price(price: u64, now: u64) {
+    COMPUTE_UNIT_PRICE.with(|cache| *cache.borrow_mut() = Some((price, now)));
+}
+
+/// Drop the cached balance of an address the canister has just sent from
+pub fn invalidate_balance(address: &str) {
+    BALANCE_CACHE.with(|cache| {
+        cache.borrow_mut().remove(address);
+    });
+}
+
+/// Serve a balance from the cache, or read it with `fetch` and cache it
+/// Deposit verification calls `fetch` directly, since it needs the chain's current answer
+pub async fn read_balance_cached<F, Fut>(address: String, now: u64, fetch: F) -> Result<u64, String>
+where
+    F: FnOnce(String) -> Fut,
+    Fut: std::future::Future<Output = Result<u64, String>>,
+{
+    if let Some(balance) = cached_balance(&address, now) {
+        return Ok(balance);
+    }
+    let balance = fetch(address.clone()).await?;
+    cache_balance(&address, balance, now);
+    Ok(balance)
+}
+
+/// The canister UTXOs no other payout has claimed, so concurrent payouts spend disjoint inputs
+/// The Bitcoin canister keeps listing an output until its spend is mined; reservations of
+/// outputs that have left `utxos` are dropped here
+pub fn unreserved_utxos(utxos: Vec<Utxo>) -> Vec<Utxo> {
+    RESERVED_UTXOS.with(|reserved| {
+        let mut reserved = reserved.borrow_mut();
+        let listed: BTreeSet<(Vec<u8>, u32)> = utxos.iter().map(utxo_outpoint).collect();
+        reserved.retain(|outpoint| listed.contains(outpoint));
+        utxos
+            .into_iter()
+            .filter(|utxo| !reserved.contains(&utxo_outpoint(utxo)))
+            .collect()
+    })
+}
+
+fn utxo_outpoint(utxo: &Utxo) -> (Vec<u8>, u32) {
+    (utxo.outpoint.txid.clone(), utxo.outpoint.vout)
+}
+
+/// Claim the outpoints a payout is about to spend; done before its first await
+pub fn reserve_utxos(outpoints: &[(Vec<u8>, u32)]) {
+    RESERVED_UTXOS.with(|reserved| reserved.borrow_mut().extend(outpoints.iter().cloned()));
+}
+
+/// Hand back the outpoints of a payout that was never broadcast
+pub fn release_utxos(outpoints: &[(Vec<u8>, u32)]) {
+    RESERVED_UTXOS.with(|reserved| {
+        let mut reserved = reserved.borrow_mut();
+        for outpoint in outpoints {
+            reserved.remove(outpoint);
+        }
+    });
+}
+
+/// Block payouts to `address`; returns false if it was already blocked
+pub fn add_blocked_address(address: String) -> bool {
+    CONFIG.with(|config| {
+        let blocked = &mut config.borrow_mut().blocked_addresses;
+        if blocked.contains(&address) {
+            return false;
+        }
+        blocked.push(address);
+        true
+    })
+}
+
+/// Allow payouts to `address` again; returns false if it was not blocked
+pub fn remove_blocked_address(address: &str) -> bool {
+    CONFIG.with(|config| {
+        let blocked = &mut config.borrow_mut().blocked_addresses;
+        let before = blocked.len();
+        blocked.retain(|entry| entry != address);
+        blocked.len() != before
+    })
+}
+
+pub fn set_test_mode(enabled: bool) {
+    CONFIG.with(|config| config.borrow_mut().test_mode = enabled);
+}
+
+pub fn set_allow_any_spl_mint(enabled: bool) {
+    CONFIG.with(|config| config.borrow_mut().allow_any_spl_mint = enabled);
+}
+
+pub fn read_config<R>(f: impl FnOnce(&SwapConfig) -> R) -> R {
+    CONFIG.with(|config| f(&config.borrow()))
+}
+
+/// Current canister time in nanoseconds since the Unix epoch, the unit of every stored timestamp
+/// Outside a canister (unit tests) the system clock stands in for the IC's
+pub fn now_nanos() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        time()
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64)
+    }
+}
+
+/// Secondary indexes over ORDERS, so the order book queries read a few entries instead of scanning
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OrderIndexes {
+    // Orders awaiting a resolver (`DepositReceived`)
+    pub pending: BTreeSet<u64>,
+    // Creator or resolver -> the orders they are part of
+    pub by_principal: HashMap<Principal, BTreeSet<u64>>,
+    // (expires_at, id) of orders that were neither completed nor cancelled
+    pub by_expiry: BTreeSet<(u64, u64)>,
+    // Orders per status
+    pub counts: OrderCounts,
+}
+
+impl OrderIndexes {
+    pub fn insert(&mut self, order: &Order) {
+        if order.status == OrderStatus::DepositReceived {
+            self.pending.insert(order.id);
+        }
+        for principal in order_principals(order) {
+            self.by_principal
+                .entry(principal)
+                .or_default()
+                .insert(order.id);
+        }
+        if awaits_expiry(order) {
+            self.by_expiry.insert((order.expires_at, order.id));
+        }
+        *status_count(&mut self.counts, &order.status) += 1;
+    }
+
+    pub fn remove(&mut self, order: &Order) {
+        self.pending.remove(&order.id);
+        for principal in order_principals(order) {
+            if let Some(ids) = self.by_principal.get_mut(&principal) {
+                ids.remove(&order.id);
+                if ids.is_empty() {
+                    self.by_principal.remove(&principal);
+                }
+            }
+        }
+        self.by_expiry.remove(&(order.expires_at, order.id));
+        let count = status_count(&mut self.counts, &order.status);
+        *count = count.saturating_sub(1);
+    }
+}
+
+fn status_count<'a>(counts: &'a mut OrderCounts, status: &OrderStatus) -> &'a mut u64 {
+    match status {
+        OrderStatus::AwaitingDeposit => &mut counts.awaiting_deposit,
+        OrderStatus::DepositReceived => &mut counts.deposit_received,
+        OrderStatus::ResolverDeposited => &mut counts.resolver_deposited,
+        OrderStatus::SettlementFailed => &mut counts.settlement_failed,
+        OrderStatus::Completed => &mut counts.completed,
+        OrderStatus::Cancelled => &mut counts.cancelled,
+        OrderStatus::Expired => &mut counts.expired,
+    }
+}
+
+fn order_principals(order: &Order) -> impl Iterator<Item = Principal> {
+    std::iter::once(order.creator).chain(order.resolver)
+}
+
+// Completed and cancelled orders are settled; anything else may still need a refund once expired
+fn awaits_expiry(order: &Order) -> bool {
+    !matches!(
+        order.status,
+        OrderStatus::Completed | OrderStatus::Cancelled
+    )
+}
+
+/// Store a new order and index it
+pub fn insert_order(order: Order) {
+    ORDER_INDEXES.with(|indexes| indexes.borrow_mut().insert(&order));
+    ORDERS.with(|orders| {
+        orders.borrow_mut().insert(order.id, order);
+    });
+}
+
+/// Apply `change` to a stored order and re-index it; every change to an order goes through here
+/// Lifecycle events the change causes are queued for the webhook
+pub fn update_order(order_id: u64, change: impl FnOnce(&mut Order)) {
+    let events = ORDERS.with(|orders| {
+        let mut orders = orders.borrow_mut();
+        let ord = orders.get_mut(&order_id)?;
+        let before = ord.clone();
+        ORDER_INDEXES.with(|indexes| {
+            let mut indexes = indexes.borrow_mut();
+            indexes.remove(ord);
+            change(ord);
+            indexes.insert(ord);
+        });
+        Some(order_events(&before, ord, now_nanos()))
+    });
+    queue_webhook_events(events.unwrap_or_default());
+}
+
+/// Deposit, settlement and refund events between two versions of an order
+pub fn order_events(before: &Order, after: &Order, now: u64) -> Vec<WebhookEvent> {
+    let mut kinds = Vec::new();
+    if !before.creator_deposited && after.creator_deposited {
+        kinds.push(WebhookEventKind::DepositConfirmed(DepositLeg::Creator));
+    }
+    if !before.resolver_deposited && after.resolver_deposited {
+        kinds.push(WebhookEventKind::DepositConfirmed(DepositLeg::Resolver));
+    }
+    if before.status != OrderStatus::Completed && after.status == OrderStatus::Completed {
+        kinds.push(WebhookEventKind::Settled);
+    }
+    if before.creator_refund_amount.is_none() && after.creator_refund_amount.is_some() {
+        kinds.push(WebhookEventKind::Refunded(DepositLeg::Creator));
+    }
+    if before.resolver_refund_amount.is_none() && after.resolver_refund_amount.is_some() {
+        kinds.push(WebhookEventKind::Refunded(DepositLeg::Resolver));
+    }
+
+    kinds
+        .into_iter()
+        .map(|kind| WebhookEvent {
+            order_id: after.id,
+            kind,
+            status: after.status.clone(),
+            at: now,
+        })
+        .collect()
+}
+
+/// Queue events for delivery, unless no webhook is configured
+fn queue_webhook_events(events: Vec<WebhookEvent>) {
+    if events.is_empty() || read_config(|config| config.webhook_url.is_none()) {
+        return;
+    }
+    for event in events {
+        requeue_webhook_event(event, 0);
+    }
+}
+
+/// Put an event (back) at the end of the webhook queue after `attempts` failed deliveries
+pub fn requeue_webhook_event(event: WebhookEvent, attempts: u32) {
+    WEBHOOK_QUEUE.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        if queue.len() >= WEBHOOK_QUEUE_CAPACITY {
+            queue.pop_front();
+        }
+        queue.push_back((event, attempts));
+    });
+}
+
+/// Take up to `limit` events off the front of the webhook queue
+pub fn take_webhook_events(limit: usize) -> Vec<(WebhookEvent, u32)> {
+    WEBHOOK_QUEUE.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        let count = limit.min(queue.len());
+        queue.drain(..count).collect()
+    })
+}
+
+/// Stored orders with the given IDs, skipping any that are not stored
+fn orders_by_id(ids: impl IntoIterator<Item = u64>) -> Vec<Order> {
+    ORDERS.with(|orders| {
+        let orders = orders.borrow();
+        ids.into_iter()
+            .filter_map(|id| orders.get(&id).cloned())
+            .collect()
+    })
+}
+
+/// IDs of up to `MAX_PENDING_SCAN` pending orders that have not expired, soonest to expire kept
+/// The expiry index is walked from `current_time` on, so pending orders left to expire never
+/// use up the limit and hide live ones
+pub fn pending_order_ids(current_time: u64) -> Vec<u64> {
+    let mut pending: Vec<u64> = ORDER_INDEXES.with(|indexes| {
+        let indexes = indexes.borrow();
+        indexes
+            .by_expiry
+            .range((current_time, u64::MAX)..)
+            .filter(|(expires_at, id)| current_time < *expires_at && indexes.pending.contains(id))
+            .map(|(_, id)| *id)
+            .take(MAX_PENDING_SCAN)
+            .collect()
+    });
+    pending.sort_unstable();
+    pending
+}
+
+/// IDs of the orders `principal` created or accepted
+pub fn principal_order_ids(principal: Principal) -> Vec<u64> {
+    ORDER_INDEXES.with(|indexes| {
+        indexes
+            .borrow()
+            .by_principal
+            .get(&principal)
+            .map(|ids| ids.iter().copied().collect())
+            .unwrap_or_default()
+    })
+}
+
+/// IDs of expired, unsettled orders still holding a deposit, soonest expiry first
+pub fn expired_order_ids(current_time: u64) -> Vec<u64> {
+    let expired: Vec<u64> = ORDER_INDEXES.with(|indexes| {
+        indexes
+            .borrow()
+            .by_expiry
+            .range(..=(current_time, u64::MAX))
+            .map(|(_, id)| *id)
+            .collect()
+    });
+    orders_by_id(expired)
+        .into_iter()
+        .filter(|order| order.creator_deposited || order.resolver_deposited)
+        .map(|order| order.id)
+        .collect()
 }
 
 // Helper functions
-pub fn generate_order_id() -> u64 {
-    NEXT_ORDER_ID.with(|id| {
+/// Allocate the `(id, sequence)` of a new order
+/// The sequence comes from the monotonic counter; the ID equals it unless `opaque_order_ids`
+/// is set, in which case it is hashed so IDs reveal neither order volume nor creation order
+pub fn generate_order_id(canister_id: Principal, created_at: u64) -> (u64, u64) {
+    let sequence = NEXT_ORDER_ID.with(|id| {
         let current = *id.borrow();
         *id.borrow_mut() = current + 1;
         current
-    })
+    });
+
+    if !read_config(|config| config.opaque_order_ids) {
+        return (sequence, sequence);
+    }
+
+    let mut attempt = 0;
+    loop {
+        let id = opaque_order_id(sequence, canister_id.as_slice(), created_at, attempt);
+        let taken = id == 0 || ORDERS.with(|orders| orders.borrow().contains_key(&id));
+        if !taken {
+            return (id, sequence);
+        }
+        attempt += 1;
+    }
 }
 
-/// Get all orders awaiting resolver acceptance
-pub fn get_pending_orders() -> Vec<OrderInfo> {
-    let current_time = time();
-    let canister_btc = CANISTER_BTC_ADDRESS
-        .with(|addr| addr.borrow().clone())
-        .unwrap_or_default();
-    let canister_sol = CANISTER_SOL_ADDRESS
-        .with(|addr| addr.borrow().clone())
-        .unwrap_or_default();
+/// Opaque order ID: the first 8 bytes of SHA-256 over the counter, canister ID and creation time
+/// `attempt` is bumped to re-hash on the (unlikely) collision with an existing ID
+pub fn opaque_order_id(sequence: u64, canister_id: &[u8], created_at: u64, attempt: u32) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(sequence.to_be_bytes());
+    hasher.update(canister_id);
+    hasher.update(created_at.to_be_bytes());
+    hasher.update(attempt.to_be_bytes());
+    let digest = hasher.finalize();
+    u64::from_be_bytes(digest[..8].try_into().expect("SHA-256 digest is 32 bytes"))
+}
 
+/// Total amount of `asset` held or expected across all open orders
+pub fn outstanding_amount(asset: &Asset) -> u64 {
     ORDERS.with(|orders| {
         orders
             .borrow()
             .values()
-            .filter(|order| {
-                matches!(order.status, crate::types::OrderStatus::DepositReceived)
-                    && current_time < order.expires_at
+            .filter(|order| !order.status.is_terminal())
+            .map(|order| {
+                let mut amount: u64 = 0;
+                if &order.from_asset == asset {
+                    amount = amount.saturating_add(order.from_amount);
+                }
+                for (leg_asset, leg_amount) in order.to_legs() {
+                    if &leg_asset == asset {
+                        amount = amount.saturating_add(leg_amount);
+                    }
+                }
+                amount
             })
-            .map(|order| order_to_info(order, &canister_btc, &canister_sol))
+            .fold(0, u64::saturating_add)
+    })
+}
+
+/// Assets a swap involves, each listed once
+pub fn order_assets(order: &Order) -> Vec<&Asset> {
+    let mut assets = vec![&order.from_asset];
+    let to_assets =
+        std::iter::once(&order.to_asset).chain(order.extra_to_legs.iter().map(|(asset, _)| asset));
+    for asset in to_assets {
+        if !assets.contains(&asset) {
+            assets.push(asset);
+        }
+    }
+    assets
+}
+
+/// Number of non-terminal orders involving `asset`
+pub fn open_order_count(asset: &Asset) -> u64 {
+    OPEN_ORDER_COUNTS.with(|counts| {
+        counts
+            .borrow()
+            .iter()
+            .find(|(counted, _)| counted == asset)
+            .map_or(0, |(_, count)| *count)
+    })
+}
+
+/// Count a newly created order against each of its assets
+pub fn track_open_order(order: &Order) {
+    OPEN_ORDER_COUNTS.with(|counts| {
+        let mut counts = counts.borrow_mut();
+        for asset in order_assets(order) {
+            match counts.iter_mut().find(|(counted, _)| counted == asset) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((asset.clone(), 1)),
+            }
+        }
+    });
+}
+
+/// Stop counting an order that is about to move to a terminal status
+/// Orders that are already terminal were released before, so they are ignored
+pub fn release_open_order(order: &Order) {
+    if order.status.is_terminal() {
+        return;
+    }
+    OPEN_ORDER_COUNTS.with(|counts| {
+        let mut counts = counts.borrow_mut();
+        for asset in order_assets(order) {
+            if let Some((_, count)) = counts.iter_mut().find(|(counted, _)| counted == asset) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    });
+}
+
+/// Amount of each asset the canister should currently hold for confirmed deposits on open orders
+/// Bitcoin and Solana are always listed; SPL tokens only when some deposit of that mint is held
+pub fn expected_held_amounts() -> Vec<(Asset, u64)> {
+    let mut held = vec![(Asset::Bitcoin, 0), (Asset::Solana, 0)];
+
+    ORDERS.with(|orders| {
+        for order in orders.borrow().values() {
+            if order.status.is_terminal() {
+                continue;
+            }
+
+            let mut deposits = Vec::new();
+            if order.creator_deposited {
+                deposits.push((order.from_asset.clone(), order.from_amount));
+            }
+            if order.resolver_deposited {
+                deposits.extend(order.to_legs());
+            }
+            for (asset, amount) in deposits {
+                match held.iter_mut().find(|(held_asset, _)| *held_asset == asset) {
+                    Some((_, total)) => *total = total.saturating_add(amount),
+                    None => held.push((asset, amount)),
+                }
+            }
+        }
+    });
+
+    held
+}
+
+pub fn cache_token_metadata(metadata: TokenMetadata) {
+    TOKEN_METADATA.with(|cache| {
+        cache
+            .borrow_mut()
+            .insert(metadata.mint_address.clone(), metadata);
+    });
+}
+
+pub fn get_cached_token_metadata(mint_address: &str) -> Option<TokenMetadata> {
+    TOKEN_METADATA.with(|cache| cache.borrow().get(mint_address).cloned())
+}
+
+pub fn cache_mint_token_program(mint_address: &str, token_program: &str) {
+    MINT_TOKEN_PROGRAMS.with(|cache| {
+        cache
+            .borrow_mut()
+            .insert(mint_address.to_string(), token_program.to_string());
+    });
+}
+
+pub fn get_cached_mint_token_program(mint_address: &str) -> Option<String> {
+    MINT_TOKEN_PROGRAMS.with(|cache| cache.borrow().get(mint_address).cloned())
+}
+
+fn with_chain_status<R>(chain: &Chain, f: impl FnOnce(&mut ChainStatus) -> R) -> R {
+    CHAIN_HEALTH.with(|health| {
+        let mut health = health.borrow_mut();
+        let index = match health.iter().position(|status| &status.chain == chain) {
+            Some(index) => index,
+            None => {
+                health.push(ChainStatus {
+                    chain: chain.clone(),
+                    available: true,
+                    consecutive_failures: 0,
+                    tripped_at: None,
+                    last_error: None,
+                });
+                health.len() - 1
+            }
+        };
+        f(&mut health[index])
+    })
+}
+
+/// A successful call closes the chain's breaker again
+pub fn record_chain_success(chain: &Chain) {
+    with_chain_status(chain, |status| {
+        status.consecutive_failures = 0;
+        status.tripped_at = None;
+        status.last_error = None;
+    });
+}
+
+/// Count a failed call; reaching the threshold (again, for a failed probe) trips the breaker
+pub fn record_chain_failure(chain: &Chain, error: &str, now: u64) {
+    with_chain_status(chain, |status| {
+        status.consecutive_failures = status.consecutive_failures.saturating_add(1);
+        status.last_error = Some(error.to_string());
+        if status.consecutive_failures >= CHAIN_FAILURE_THRESHOLD {
+            status.tripped_at = Some(now);
+        }
+    });
+}
+
+/// Breaker state of `chain` at `now`; a tripped chain becomes available for a probe after
+/// `CHAIN_RETRY_AFTER_NANOS`
+pub fn get_chain_status(chain: &Chain, now: u64) -> ChainStatus {
+    with_chain_status(chain, |status| {
+        let mut status = status.clone();
+        status.available = match status.tripped_at {
+            Some(tripped_at) => now >= tripped_at.saturating_add(CHAIN_RETRY_AFTER_NANOS),
+            None => true,
+        };
+        status
+    })
+}
+
+pub fn get_chain_statuses(now: u64) -> Vec<ChainStatus> {
+    [Chain::Bitcoin, Chain::Solana]
+        .iter()
+        .map(|chain| get_chain_status(chain, now))
+        .collect()
+}
+
+/// Fail fast with `ChainUnavailable` while `chain`'s breaker is tripped
+pub fn check_chain_available(chain: &Chain, now: u64) -> Result<(), String> {
+    let status = get_chain_status(chain, now);
+    if status.available {
+        return Ok(());
+    }
+    Err(format!(
+        "ChainUnavailable: {:?} failed {} consecutive calls (last error: {})",
+        chain,
+        status.consecutive_failures,
+        status.last_error.unwrap_or_default()
+    ))
+}
+
+/// Insert or replace the feed prices of the given assets
+pub fn set_asset_prices(prices: Vec<AssetPrice>) {
+    PRICE_FEED.with(|feed| {
+        let mut feed = feed.borrow_mut();
+        for price in prices {
+            match feed.iter_mut().find(|known| known.asset == price.asset) {
+                Some(known) => *known = price,
+                None => feed.push(price),
+            }
+        }
+    });
+}
+
+pub fn get_asset_prices() -> Vec<AssetPrice> {
+    PRICE_FEED.with(|feed| feed.borrow().clone())
+}
+
+pub fn get_asset_price(asset: &Asset) -> Option<u64> {
+    PRICE_FEED.with(|feed| {
+        feed.borrow()
+            .iter()
+            .find(|price| &price.asset == asset)
+            .map(|price| price.usd_micros)
+    })
+}
+
+pub fn record_operation_cost(operation: &str, cycles: u128) {
+    OPERATION_COSTS.with(|costs| {
+        let mut costs = costs.borrow_mut();
+        let (calls, total) = costs.entry(operation.to_string()).or_insert((0, 0));
+        *calls += 1;
+        *total = total.saturating_add(cycles);
+    });
+}
+
+/// Average cycles spent per measured call of each operation, sorted by operation name
+pub fn get_operation_costs() -> Vec<(String, u64)> {
+    let mut averages: Vec<(String, u64)> = OPERATION_COSTS.with(|costs| {
+        costs
+            .borrow()
+            .iter()
+            .map(|(operation, (calls, total))| {
+                let average = (total / *calls as u128).try_into().unwrap_or(u64::MAX);
+                (operation.clone(), average)
+            })
+            .collect()
+    });
+    averages.sort();
+    averages
+}
+
+/// Append an entry to the audit log, assigning its id and evicting the oldest entry when full
+pub fn append_audit_entry(mut entry: AuditEntry) {
+    entry.id = NEXT_AUDIT_ID.with(|id| {
+        let current = *id.borrow();
+        *id.borrow_mut() = current + 1;
+        current
+    });
+    AUDIT_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        if log.len() >= AUDIT_LOG_CAPACITY {
+            log.pop_front();
+        }
+        log.push_back(entry);
+    });
+}
+
+/// When the deposit watcher last checked for the deposit of an order
+pub fn last_deposit_poll(order_id: u64) -> Option<u64> {
+    DEPOSIT_POLLS.with(|polls| polls.borrow().get(&order_id).copied())
+}
+
+/// Record that the deposits of `order_ids` were checked at `now`
+/// Orders not listed are no longer awaiting a deposit, so their entries are dropped
+pub fn record_deposit_polls(order_ids: &[u64], now: u64) {
+    DEPOSIT_POLLS.with(|polls| {
+        *polls.borrow_mut() = order_ids.iter().map(|&order_id| (order_id, now)).collect();
+    });
+}
+
+/// Leave `txid` for the deposit watcher to credit to `order_id` once it is seen
+/// A later claim for the same order replaces the earlier one
+pub fn claim_deposit(order_id: u64, txid: &str) {
+    DEPOSIT_CLAIMS.with(|claims| claims.borrow_mut().insert(order_id, txid.to_string()));
+}
+
+pub fn remove_deposit_claim(order_id: u64) {
+    DEPOSIT_CLAIMS.with(|claims| claims.borrow_mut().remove(&order_id));
+}
+
+/// Claimed deposits as (order ID, txid), in order ID order
+pub fn deposit_claims() -> Vec<(u64, String)> {
+    let mut claims: Vec<(u64, String)> = DEPOSIT_CLAIMS.with(|claims| {
+        claims
+            .borrow()
+            .iter()
+            .map(|(order_id, txid)| (*order_id, txid.clone()))
+            .collect()
+    });
+    claims.sort();
+    claims
+}
+
+/// Mark `txid` as spoken for; returns false if it already was
+/// Deposits are credited this way, and the canister's own transactions are marked before they
+/// are broadcast, so their change outputs can never pass for a deposit
+pub fn consume_txid(txid: &str) -> bool {
+    CONSUMED_TXIDS.with(|txids| txids.borrow_mut().insert(txid.to_string()))
+}
+
+pub fn is_txid_consumed(txid: &str) -> bool {
+    CONSUMED_TXIDS.with(|txids| txids.borrow().contains(txid))
+}
+
+/// Page through the retained audit entries, oldest first
+pub fn get_audit_log(offset: u64, limit: u64) -> Vec<AuditEntry> {
+    AUDIT_LOG.with(|log| {
+        log.borrow()
+            .iter()
+            .skip(offset.try_into().unwrap_or(usize::MAX))
+            .take(limit.try_into().unwrap_or(usize::MAX))
+            .cloned()
             .collect()
     })
 }
 
+fn update_resolver_stats(resolver: Principal, f: impl FnOnce(&mut ResolverStats)) {
+    RESOLVER_STATS.with(|stats| f(stats.borrow_mut().entry(resolver).or_default()));
+}
+
+pub fn record_resolver_accepted(resolver: Principal) {
+    update_resolver_stats(resolver, |stats| stats.accepted += 1);
+}
+
+/// Count a deposit and fold its latency (nanoseconds since acceptance) into the running average
+pub fn record_resolver_deposited(resolver: Principal, latency: u64) {
+    update_resolver_stats(resolver, |stats| {
+        let total = stats.avg_deposit_latency as u128 * stats.deposited as u128 + latency as u128;
+        stats.deposited += 1;
+        stats.avg_deposit_latency = (total / stats.deposited as u128) as u64;
+    });
+}
+
+pub fn record_resolver_completed(resolver: Principal) {
+    update_resolver_stats(resolver, |stats| stats.completed += 1);
+}
+
+pub fn record_resolver_abandoned(resolver: Principal) {
+    update_resolver_stats(resolver, |stats| stats.abandoned += 1);
+}
+
+/// Get a resolver's track record (all zero for unknown resolvers)
+pub fn get_resolver_stats(resolver: Principal) -> ResolverStats {
+    RESOLVER_STATS.with(|stats| stats.borrow().get(&resolver).cloned().unwrap_or_default())
+}
+
+/// Record a rebate earned by a resolver
+pub fn accrue_resolver_rebate(resolver: Principal, rebate: ResolverRebate) {
+    RESOLVER_REBATES.with(|rebates| {
+        rebates
+            .borrow_mut()
+            .entry(resolver)
+            .or_default()
+            .push(rebate)
+    });
+}
+
+/// Get the rebates a resolver has accrued
+pub fn get_resolver_rebates(resolver: Principal) -> Vec<ResolverRebate> {
+    RESOLVER_REBATES.with(|rebates| rebates.borrow().get(&resolver).cloned().unwrap_or_default())
+}
+
+/// Get all orders awaiting resolver acceptance
+pub fn get_pending_orders() -> Vec<OrderInfo> {
+    orders_to_info(pending_order_ids(time()))
+}
+
+/// Pending orders `caller` could take on as resolver: not their own, and not already
+/// accepted by them (an accepted order stays `DepositReceived` until the resolver deposits)
+pub fn get_acceptable_orders(caller: Principal) -> Vec<OrderInfo> {
+    let current_time = time();
+    get_pending_orders()
+        .into_iter()
+        .filter(|order| is_acceptable_by(order, caller, current_time))
+        .collect()
+}
+
+pub fn is_acceptable_by(order: &OrderInfo, caller: Principal, current_time: u64) -> bool {
+    matches!(order.status, crate::types::OrderStatus::DepositReceived)
+        && current_time < order.expires_at
+        && order.creator != caller
+        && order.resolver != Some(caller)
+}
+
+/// The caller's open orders, each with the next action they have to take in their role
+/// An order the caller both created and accepted is listed once per role
+pub fn get_actionable_orders(caller: Principal, current_time: u64) -> Vec<ActionableOrder> {
+    let mut open: Vec<Order> = orders_by_id(principal_order_ids(caller))
+        .into_iter()
+        .filter(|order| !order.status.is_terminal())
+        .collect();
+    open.sort_by_key(|order| order.sequence);
+
+    open.iter()
+        .flat_map(|order| {
+            let roles = [
+                (order.creator == caller).then_some(DepositLeg::Creator),
+                (order.resolver == Some(caller)).then_some(DepositLeg::Resolver),
+            ];
+            roles.into_iter().flatten().map(|role| ActionableOrder {
+                order_id: order.id,
+                action: next_action(order, &role, current_time),
+                role,
+            })
+        })
+        .collect()
+}
+
+/// Next action for the party holding `role` on `order`
+pub fn next_action(order: &Order, role: &DepositLeg, current_time: u64) -> OrderAction {
+    let expired = current_time >= order.expires_at;
+    let awaiting_ack = order.require_resolver_ack && order.resolver_acknowledged_at.is_none();
+
+    match (role, &order.status) {
+        (_, status) if status.is_terminal() => OrderAction::None,
+        (DepositLeg::Creator, OrderStatus::AwaitingDeposit) if expired => OrderAction::None,
+        (DepositLeg::Creator, OrderStatus::AwaitingDeposit) => OrderAction::Deposit,
+        (DepositLeg::Creator, OrderStatus::DepositReceived) if expired => OrderAction::ClaimRefund,
+        (DepositLeg::Creator, OrderStatus::DepositReceived) => OrderAction::WaitForResolver,
+        (DepositLeg::Creator, OrderStatus::ResolverDeposited) if expired => {
+            OrderAction::ClaimRefund
+        }
+        (DepositLeg::Creator, OrderStatus::ResolverDeposited) if awaiting_ack => {
+            OrderAction::WaitForResolver
+        }
+        (DepositLeg::Creator, OrderStatus::ResolverDeposited) => OrderAction::RevealSecret,
+        (DepositLeg::Creator, OrderStatus::SettlementFailed) => OrderAction::RevealSecret,
+        (DepositLeg::Resolver, OrderStatus::DepositReceived) if expired => OrderAction::None,
+        (DepositLeg::Resolver, OrderStatus::DepositReceived) => OrderAction::Deposit,
+        (DepositLeg::Resolver, OrderStatus::ResolverDeposited) if expired => {
+            OrderAction::ClaimRefund
+        }
+        (DepositLeg::Resolver, OrderStatus::ResolverDeposited) if awaiting_ack => {
+            OrderAction::Acknowledge
+        }
+        _ => OrderAction::None,
+    }
+}
+
 /// Get order details
 pub fn get_order(order_id: u64) -> Option<OrderInfo> {
     let canister_btc = CANISTER_BTC_ADDRESS
@@ -61,8 +985,8 @@ pub fn get_order(order_id: u64) -> Option<OrderInfo> {
     })
 }
 
-/// Get all orders created by the caller
-pub fn get_my_orders(caller: Principal) -> Vec<OrderInfo> {
+/// Get an order with its transaction IDs, for its creator or resolver only
+pub fn get_my_order_detail(caller: Principal, order_id: u64) -> Result<OrderDetail, String> {
     let canister_btc = CANISTER_BTC_ADDRESS
         .with(|addr| addr.borrow().clone())
         .unwrap_or_default();
@@ -70,16 +994,144 @@ pub fn get_my_orders(caller: Principal) -> Vec<OrderInfo> {
         .with(|addr| addr.borrow().clone())
         .unwrap_or_default();
 
+    let order = ORDERS
+        .with(|orders| orders.borrow().get(&order_id).cloned())
+        .ok_or("Order not found")?;
+    if order.creator != caller && order.resolver != Some(caller) {
+        return Err("Only the order creator or resolver can view its details".to_string());
+    }
+
+    let completed = order.status == OrderStatus::Completed;
+    Ok(OrderDetail {
+        info: order_to_info(&order, &canister_btc, &canister_sol),
+        creator_txid: order.creator_txid,
+        resolver_txid: order.resolver_txid,
+        resolver_payout_txid: order.resolver_payout_txid,
+        creator_payout_txid: order.creator_payout_txid,
+        extra_leg_txids: order.extra_leg_txids,
+        resolver_refund_txid: order.resolver_refund_txid,
+        secret: order.secret.filter(|_| completed),
+    })
+}
+
+/// Number of orders in each status, kept up to date by the order indexes rather than a scan
+pub fn get_order_counts() -> OrderCounts {
+    ORDER_INDEXES.with(|indexes| indexes.borrow().counts.clone())
+}
+
+/// Get just the status of an order, without building the full `OrderInfo`
+pub fn get_order_status(order_id: u64) -> Option<OrderStatus> {
     ORDERS.with(|orders| {
         orders
             .borrow()
-            .values()
-            .filter(|order| order.creator == caller || order.resolver == Some(caller))
-            .map(|order| order_to_info(order, &canister_btc, &canister_sol))
-            .collect()
+            .get(&order_id)
+            .map(|order| order.status.clone())
+    })
+}
+
+/// Get the fees and cycles the settlement of a completed order cost
+pub fn get_settlement_receipt(order_id: u64) -> Option<SettlementReceipt> {
+    ORDERS.with(|orders| {
+        orders
+            .borrow()
+            .get(&order_id)
+            .and_then(|order| order.settlement_receipt.clone())
     })
 }
 
+/// Get the lifecycle timestamps of an order
+pub fn get_order_timeline(order_id: u64) -> Option<OrderTimeline> {
+    ORDERS.with(|orders| orders.borrow().get(&order_id).map(order_timeline))
+}
+
+/// Build the timeline view of an order
+pub fn order_timeline(order: &Order) -> OrderTimeline {
+    OrderTimeline {
+        order_id: order.id,
+        status: order.status.clone(),
+        created_at: order.created_at,
+        expires_at: order.expires_at,
+        deposit_confirmed_at: order.deposit_confirmed_at,
+        accepted_at: order.accepted_at,
+        resolver_deposited_at: order.resolver_deposited_at,
+        completed_at: order.completed_at,
+        cancelled_at: order.cancelled_at,
+    }
+}
+
+/// Get the deadlines of an order as of `now`, with the configured refund grace period and
+/// creator deposit window
+pub fn get_order_deadlines(order_id: u64, now: u64) -> Option<Deadlines> {
+    let (grace_seconds, deposit_seconds) =
+        read_config(|config| (config.refund_grace_seconds, config.creator_deposit_seconds));
+    ORDERS.with(|orders| {
+        orders
+            .borrow()
+            .get(&order_id)
+            .map(|order| order_deadlines(order, grace_seconds, deposit_seconds, now))
+    })
+}
+
+/// Deadlines of an order as of `now`, so clients need not do timestamp arithmetic themselves
+pub fn order_deadlines(
+    order: &Order,
+    refund_grace_seconds: u64,
+    creator_deposit_seconds: Option<u64>,
+    now: u64,
+) -> Deadlines {
+    let resolver_refund_at = order
+        .expires_at
+        .saturating_add(refund_grace_seconds.saturating_mul(1_000_000_000));
+    let seconds_until = |deadline: u64| deadline.saturating_sub(now) / 1_000_000_000;
+    Deadlines {
+        now,
+        expires_at: order.expires_at,
+        creator_deposit_deadline: creator_deposit_deadline(order, creator_deposit_seconds),
+        acceptance_deadline: order.expires_at,
+        resolver_refund_at,
+        seconds_to_expiry: seconds_until(order.expires_at),
+        seconds_to_acceptance_deadline: seconds_until(order.expires_at),
+        seconds_to_resolver_refund: seconds_until(resolver_refund_at),
+    }
+}
+
+/// When an order the creator never funded is cancelled, given the configured deposit window
+/// Capped at expiry, so it never falls after the acceptance deadline
+pub fn creator_deposit_deadline(
+    order: &Order,
+    creator_deposit_seconds: Option<u64>,
+) -> Option<u64> {
+    creator_deposit_seconds.map(|seconds| {
+        order
+            .created_at
+            .saturating_add(seconds.saturating_mul(1_000_000_000))
+            .min(order.expires_at)
+    })
+}
+
+/// Get all orders created by the caller
+pub fn get_my_orders(caller: Principal) -> Vec<OrderInfo> {
+    orders_to_info(principal_order_ids(caller))
+}
+
+/// Get the caller's orders that have not reached a terminal status
+pub fn get_my_active_orders(caller: Principal) -> Vec<OrderInfo> {
+    let active = orders_by_id(principal_order_ids(caller))
+        .into_iter()
+        .filter(|order| !order.status.is_terminal())
+        .map(|order| order.id);
+    orders_to_info(active)
+}
+
+/// Get the orders `resolver` has accepted, leaving out ones they only created
+pub fn get_orders_resolved_by(resolver: Principal) -> Vec<OrderInfo> {
+    let resolved = orders_by_id(principal_order_ids(resolver))
+        .into_iter()
+        .filter(|order| order.resolver == Some(resolver))
+        .map(|order| order.id);
+    orders_to_info(resolved)
+}
+
 /// Get all orders associated with a Bitcoin or Solana wallet address
 pub fn get_orders_by_wallet(
     btc_address: Option<String>,
@@ -93,8 +1145,8 @@ pub fn get_orders_by_wallet(
         .unwrap_or_default();
 
     ORDERS.with(|orders| {
-        orders
-            .borrow()
+        let orders = orders.borrow();
+        let mut matching: Vec<&Order> = orders
             .values()
             .filter(|order| {
                 // Check if the wallet address matches either creator or resolver addresses
@@ -110,14 +1162,29 @@ pub fn get_orders_by_wallet(
 
                 btc_match || sol_match
             })
+            .collect();
+        matching.sort_by_key(|order| listing_key(order));
+        matching
+            .into_iter()
             .map(|order| order_to_info(order, &canister_btc, &canister_sol))
             .collect()
     })
 }
 
-/// Get all expired orders that need refunds
-pub fn get_expired_orders() -> Vec<OrderInfo> {
-    let current_time = time();
+/// Orders whose `field` timestamp falls within `from_ns..=to_ns`, oldest first
+/// Orders that never completed have no `completed_at` and never match on it
+pub fn get_orders_in_range(
+    from_ns: u64,
+    to_ns: u64,
+    field: TimeField,
+) -> Result<Vec<OrderInfo>, String> {
+    if from_ns > to_ns {
+        return Err(format!(
+            "Invalid range: start {} is after end {}",
+            from_ns, to_ns
+        ));
+    }
+
     let canister_btc = CANISTER_BTC_ADDRESS
         .with(|addr| addr.borrow().clone())
         .unwrap_or_default();
@@ -125,24 +1192,81 @@ pub fn get_expired_orders() -> Vec<OrderInfo> {
         .with(|addr| addr.borrow().clone())
         .unwrap_or_default();
 
+    let timestamp = |order: &Order| match field {
+        TimeField::CreatedAt => Some(order.created_at),
+        TimeField::CompletedAt => order.completed_at,
+    };
+
     ORDERS.with(|orders| {
-        orders
-            .borrow()
+        let orders = orders.borrow();
+        let mut matching: Vec<&Order> = orders
             .values()
-            .filter(|order| {
-                current_time >= order.expires_at
-                    && !matches!(
-                        order.status,
-                        crate::types::OrderStatus::Completed | crate::types::OrderStatus::Cancelled
-                    )
-                    && (order.creator_deposited || order.resolver_deposited)
-            })
+            .filter(|order| timestamp(order).is_some_and(|at| (from_ns..=to_ns).contains(&at)))
+            .collect();
+        matching.sort_by_key(|order| (timestamp(order), order.sequence));
+        Ok(matching
+            .into_iter()
             .map(|order| order_to_info(order, &canister_btc, &canister_sol))
-            .collect()
+            .collect())
     })
 }
 
+/// Get all expired orders that need refunds
+pub fn get_expired_orders() -> Vec<OrderInfo> {
+    orders_to_info(expired_order_ids(time()))
+}
+
+/// `OrderInfo` of the given orders, in listing order
+fn orders_to_info(ids: impl IntoIterator<Item = u64>) -> Vec<OrderInfo> {
+    let canister_btc = CANISTER_BTC_ADDRESS
+        .with(|addr| addr.borrow().clone())
+        .unwrap_or_default();
+    let canister_sol = CANISTER_SOL_ADDRESS
+        .with(|addr| addr.borrow().clone())
+        .unwrap_or_default();
+
+    let mut orders = orders_by_id(ids);
+    orders.sort_by_key(listing_key);
+    orders
+        .iter()
+        .map(|order| order_to_info(order, &canister_btc, &canister_sol))
+        .collect()
+}
+
+/// Order queries list oldest first, then by ID, so repeated polls return the same sequence
+/// Opaque order IDs are random, so sorting by ID alone would not follow creation
+fn listing_key(order: &Order) -> (u64, u64) {
+    (order.created_at, order.id)
+}
+
 // Helper to convert Order to OrderInfo
+/// Flattened view of an asset; an SPL token's symbol comes from the metadata cache
+pub fn asset_info(asset: &Asset) -> AssetInfo {
+    match asset {
+        Asset::Bitcoin => AssetInfo {
+            kind: "Bitcoin".to_string(),
+            mint: None,
+            decimals: 8,
+            symbol: Some("BTC".to_string()),
+        },
+        Asset::Solana => AssetInfo {
+            kind: "Solana".to_string(),
+            mint: None,
+            decimals: 9,
+            symbol: Some("SOL".to_string()),
+        },
+        Asset::SplToken {
+            mint_address,
+            decimals,
+        } => AssetInfo {
+            kind: "SplToken".to_string(),
+            mint: Some(mint_address.clone()),
+            decimals: *decimals,
+            symbol: get_cached_token_metadata(mint_address).and_then(|metadata| metadata.symbol),
+        },
+    }
+}
+
 fn order_to_info(order: &Order, canister_btc: &str, canister_sol: &str) -> OrderInfo {
     OrderInfo {
         id: order.id,
@@ -151,8 +1275,11 @@ fn order_to_info(order: &Order, canister_btc: &str, canister_sol: &str) -> Order
         creator_sol_address: order.creator_sol_address.clone(),
         from_asset: order.from_asset.clone(),
         to_asset: order.to_asset.clone(),
+        from_asset_info: asset_info(&order.from_asset),
+        to_asset_info: asset_info(&order.to_asset),
         from_amount: order.from_amount,
         to_amount: order.to_amount,
+        pricing: order.pricing.clone(),
         secret_hash: order.secret_hash.clone(),
         status: order.status.clone(),
         resolver: order.resolver,
@@ -164,5 +1291,15 @@ fn order_to_info(order: &Order, canister_btc: &str, canister_sol: &str) -> Order
         canister_sol_address: canister_sol.to_string(),
         creator_deposited: order.creator_deposited,
         resolver_deposited: order.resolver_deposited,
+        require_resolver_ack: order.require_resolver_ack,
+        resolver_acknowledged: order.resolver_acknowledged_at.is_some(),
+        secret_revealed_by: order.secret_revealed_by,
+        manual_review: order.manual_review.clone(),
+        creator_refund_amount: order.creator_refund_amount,
+        resolver_refund_amount: order.resolver_refund_amount,
+        metadata: order.metadata.clone(),
+        creator_overpayment: order.creator_overpayment,
+        overpayment_refund_txid: order.overpayment_refund_txid.clone(),
+        extra_to_legs: order.extra_to_legs.clone(),
     }
 }
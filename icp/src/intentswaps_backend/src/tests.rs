@@ -0,0 +1,5569 @@
+use crate::storage::{insert_order, OrderIndexes, ORDERS, ORDER_INDEXES};
+use crate::types::{Asset, Order, OrderPricing, OrderStatus};
+use candid::Principal;
+use ic_cdk::bitcoin_canister::{Outpoint, Utxo};
+use std::future::Future;
+use std::pin::pin;
+use std::task::{Context, Poll, Waker};
+
+fn principal(id: u8) -> Principal {
+    Principal::from_slice(&[id; 29])
+}
+
+// A canister UTXO whose txid is `txid_byte` repeated
+fn utxo(txid_byte: u8, vout: u32, value: u64, height: u32) -> Utxo {
+    Utxo {
+        outpoint: Outpoint {
+            txid: vec![txid_byte; 32],
+            vout,
+        },
+        value,
+        height,
+    }
+}
+
+// A BTC -> SOL order with no resolver yet
+fn order(id: u64, creator: Principal, status: OrderStatus) -> Order {
+    Order {
+        id,
+        sequence: id,
+        creator,
+        creator_btc_address: Some("tb1qcreator".to_string()),
+        creator_sol_address: Some("CreatorSolAddress".to_string()),
+        from_asset: Asset::Bitcoin,
+        to_asset: Asset::Solana,
+        from_amount: 100_000,
+        to_amount: 1_000_000_000,
+        pricing: OrderPricing::Fixed {
+            to_amount: 1_000_000_000,
+        },
+        extra_to_legs: Vec::new(),
+        secret_hash: format!("{:x}", md5::compute("secret")),
+        secret: None,
+        status,
+        resolver: None,
+        resolver_btc_address: None,
+        resolver_sol_address: None,
+        created_at: 1_000,
+        expires_at: 1_000 + 3_600_000_000_000,
+        creator_txid: None,
+        resolver_txid: None,
+        resolver_payout_txid: None,
+        creator_payout_txid: None,
+        extra_leg_txids: Vec::new(),
+        resolver_refund_txid: None,
+        creator_refund_amount: None,
+        resolver_refund_amount: None,
+        creator_overpayment: None,
+        overpayment_refund_txid: None,
+        settlement_receipt: None,
+        creator_deposited: false,
+        resolver_deposited: false,
+        require_resolver_ack: false,
+        secret_revealed_by: None,
+        manual_review: None,
+        metadata: None,
+        deposit_confirmed_at: None,
+        accepted_at: None,
+        resolver_deposited_at: None,
+        resolver_acknowledged_at: None,
+        completed_at: None,
+        cancelled_at: None,
+    }
+}
+
+// The futures under test never suspend, so a single poll completes them
+fn poll_once<F: Future>(future: F) -> F::Output {
+    match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+        Poll::Ready(output) => output,
+        Poll::Pending => panic!("future did not complete"),
+    }
+}
+
+fn insert_orders(orders: Vec<Order>) {
+    ORDERS.with(|stored| stored.borrow_mut().clear());
+    ORDER_INDEXES.with(|indexes| *indexes.borrow_mut() = OrderIndexes::default());
+    for order in orders {
+        insert_order(order);
+    }
+}
+
+mod deposit_proof {
+    use super::{insert_orders, order, principal};
+    use crate::bitcoin_integration::check_merkle_proof;
+    use crate::orders::credit_creator_deposit;
+    use crate::storage::ORDERS;
+    use crate::types::{BitcoinDepositProof, OrderStatus};
+    use bitcoin::{
+        absolute::LockTime,
+        block::{Header, Version as BlockVersion},
+        consensus::{deserialize, serialize},
+        hashes::Hash,
+        transaction::Version,
+        Amount, Block, BlockHash, CompactTarget, MerkleBlock, OutPoint, ScriptBuf, Sequence,
+        Transaction, TxIn, TxMerkleNode, TxOut, Txid, WPubkeyHash, Witness,
+    };
+
+    fn payment(script: &ScriptBuf, value: u64, vout: u32) -> Transaction {
+        Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: Txid::all_zeros(),
+                    vout,
+                },
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(value),
+                script_pubkey: script.clone(),
+            }],
+        }
+    }
+
+    // Returns a proof for a 50_000 sat deposit, the trusted header and the recipient script.
+    fn proof_fixture() -> (BitcoinDepositProof, Vec<u8>, ScriptBuf) {
+        let recipient = ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([7; 20]));
+        let other = ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([9; 20]));
+        let deposit = payment(&recipient, 50_000, 0);
+
+        let mut block = Block {
+            header: Header {
+                version: BlockVersion::ONE,
+                prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 1_700_000_000,
+                bits: CompactTarget::from_consensus(0x207fffff),
+                nonce: 0,
+            },
+            txdata: vec![
+                payment(&other, 1_000, 1),
+                deposit.clone(),
+                payment(&other, 2_000, 2),
+            ],
+        };
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+
+        let deposit_txid = deposit.compute_txid();
+        let merkle_block =
+            MerkleBlock::from_block_with_predicate(&block, |txid| *txid == deposit_txid);
+
+        let proof = BitcoinDepositProof {
+            raw_transaction_hex: hex::encode(serialize(&deposit)),
+            merkle_block_hex: hex::encode(serialize(&merkle_block)),
+            block_height: 100,
+        };
+        (proof, serialize(&block.header), recipient)
+    }
+
+    #[test]
+    fn should_accept_valid_proof() {
+        let (proof, header, recipient) = proof_fixture();
+        let deposit: Transaction =
+            deserialize(&hex::decode(&proof.raw_transaction_hex).unwrap()).unwrap();
+
+        assert_eq!(
+            check_merkle_proof(&proof, &header, &recipient, 50_000),
+            Ok(deposit.compute_txid().to_string())
+        );
+    }
+
+    #[test]
+    fn should_reject_tampered_proof() {
+        let (mut proof, header, recipient) = proof_fixture();
+
+        // Inflating the output changes the txid, so it is no longer committed to by the block.
+        let mut deposit: Transaction =
+            deserialize(&hex::decode(&proof.raw_transaction_hex).unwrap()).unwrap();
+        deposit.output[0].value = Amount::from_sat(5_000_000);
+        proof.raw_transaction_hex = hex::encode(serialize(&deposit));
+
+        assert!(check_merkle_proof(&proof, &header, &recipient, 50_000).is_err());
+    }
+
+    #[test]
+    fn should_reject_proof_for_unknown_header() {
+        let (proof, mut header, recipient) = proof_fixture();
+        header[79] ^= 1; // nonce
+
+        assert!(check_merkle_proof(&proof, &header, &recipient, 50_000).is_err());
+    }
+
+    #[test]
+    fn should_not_credit_same_proof_to_second_order() {
+        let (proof, header, recipient) = proof_fixture();
+        let txid = check_merkle_proof(&proof, &header, &recipient, 50_000).unwrap();
+        insert_orders(vec![
+            order(1, principal(1), OrderStatus::AwaitingDeposit),
+            order(2, principal(1), OrderStatus::AwaitingDeposit),
+        ]);
+
+        assert_eq!(credit_creator_deposit(1, &txid, 2_000), Ok(()));
+        assert!(credit_creator_deposit(2, &txid, 2_000)
+            .unwrap_err()
+            .contains("already recorded on another order"));
+
+        let second = ORDERS.with(|orders| orders.borrow()[&2].clone());
+        assert!(!second.creator_deposited);
+        assert_eq!(second.status, OrderStatus::AwaitingDeposit);
+    }
+
+    #[test]
+    fn should_not_revive_closed_orders() {
+        let (proof, header, recipient) = proof_fixture();
+        let txid = check_merkle_proof(&proof, &header, &recipient, 50_000).unwrap();
+        let expires_at = order(0, principal(1), OrderStatus::AwaitingDeposit).expires_at;
+        insert_orders(vec![
+            order(1, principal(1), OrderStatus::Cancelled),
+            order(2, principal(1), OrderStatus::Expired),
+            order(3, principal(1), OrderStatus::AwaitingDeposit),
+        ]);
+
+        assert!(credit_creator_deposit(1, &txid, 2_000).is_err());
+        assert!(credit_creator_deposit(2, &txid, 2_000).is_err());
+        assert!(credit_creator_deposit(3, &txid, expires_at)
+            .unwrap_err()
+            .starts_with("Expired:"));
+        ORDERS.with(|orders| {
+            assert!(orders
+                .borrow()
+                .values()
+                .all(|order| !order.creator_deposited));
+        });
+    }
+}
+
+mod versioned_transactions {
+    use crate::solana_integration::{build_v0_message, parse_address_lookup_table};
+    use solana_hash::Hash;
+    use solana_keypair::Keypair;
+    use solana_pubkey::Pubkey;
+    use solana_signer::Signer;
+    use solana_system_interface::instruction::transfer;
+    use solana_transaction::versioned::VersionedTransaction;
+
+    #[test]
+    fn should_build_and_sign_v0_message_with_lookup_table() {
+        let payer = Keypair::new();
+        let recipients: Vec<Pubkey> = (1..=4).map(|i| Pubkey::new_from_array([i; 32])).collect();
+
+        // 56-byte lookup table metadata header followed by the stored addresses
+        let mut table_data = vec![0; 56];
+        for recipient in &recipients {
+            table_data.extend_from_slice(recipient.as_ref());
+        }
+        let table =
+            parse_address_lookup_table(Pubkey::new_from_array([9; 32]), &table_data).unwrap();
+        assert_eq!(table.addresses, recipients);
+
+        let instructions: Vec<_> = recipients
+            .iter()
+            .map(|recipient| transfer(&payer.pubkey(), recipient, 1_000))
+            .collect();
+        let message = build_v0_message(
+            &payer.pubkey(),
+            &instructions,
+            &[table],
+            Hash::new_from_array([1; 32]),
+        )
+        .unwrap();
+
+        // Recipients are loaded through the lookup table instead of the static keys
+        assert_eq!(
+            message.address_table_lookups().map(|lookups| lookups.len()),
+            Some(1)
+        );
+        assert!(recipients
+            .iter()
+            .all(|recipient| !message.static_account_keys().contains(recipient)));
+
+        // The signed bytes carry the v0 version prefix
+        let serialized = message.serialize();
+        assert_eq!(serialized[0], 0x80);
+
+        let transaction = VersionedTransaction {
+            signatures: vec![payer.sign_message(&serialized)],
+            message,
+        };
+        assert_eq!(transaction.signatures.len(), 1);
+        assert_eq!(transaction.message.static_account_keys()[0], payer.pubkey());
+    }
+
+    #[test]
+    fn should_reject_malformed_lookup_table_data() {
+        assert!(parse_address_lookup_table(Pubkey::new_from_array([9; 32]), &[0; 70]).is_err());
+    }
+}
+
+mod durable_nonce {
+    use crate::solana_integration::build_durable_nonce_message;
+    use solana_hash::Hash;
+    use solana_pubkey::Pubkey;
+    use solana_system_interface::instruction::{advance_nonce_account, transfer};
+
+    #[test]
+    fn should_build_transaction_with_durable_nonce() {
+        let payer = Pubkey::new_from_array([1; 32]);
+        let nonce_account = Pubkey::new_from_array([2; 32]);
+        let recipient = Pubkey::new_from_array([3; 32]);
+        let nonce = Hash::new_from_array([4; 32]);
+
+        let message = build_durable_nonce_message(
+            &payer,
+            &nonce_account,
+            &[transfer(&payer, &recipient, 1_000)],
+            nonce,
+        );
+
+        // The stored nonce replaces the recent blockhash
+        assert_eq!(message.recent_blockhash, nonce);
+        assert_eq!(message.account_keys[0], payer);
+        assert_eq!(message.instructions.len(), 2);
+
+        // Advancing the nonce has to be the first instruction
+        let advance = advance_nonce_account(&nonce_account, &payer);
+        let first = &message.instructions[0];
+        assert_eq!(
+            message.account_keys[first.program_id_index as usize],
+            advance.program_id
+        );
+        assert_eq!(first.data, advance.data);
+    }
+}
+
+mod order_limits {
+    use crate::orders::check_order_limits;
+    use crate::types::{Asset, AssetLimit, SwapConfig, SwapInitArg};
+
+    fn config() -> SwapConfig {
+        SwapConfig::from(SwapInitArg {
+            max_order_amounts: Some(vec![AssetLimit {
+                asset: Asset::Bitcoin,
+                amount: 1_000_000,
+            }]),
+            max_outstanding_amounts: Some(vec![AssetLimit {
+                asset: Asset::Bitcoin,
+                amount: 5_000_000,
+            }]),
+            btc_fee_per_byte_override: None,
+            use_durable_nonce: None,
+            resolver_rebate: None,
+            max_timeout_seconds: None,
+            max_open_orders_per_asset: None,
+            deposit_watch_interval_seconds: None,
+            track_operation_costs: None,
+            allowed_spl_mints: None,
+            allow_any_spl_mint: None,
+            solana_rpc_retries: None,
+            btc_fee_api_url: None,
+            require_solana_memo: None,
+            opaque_order_ids: None,
+            defer_secret_until_settled: None,
+            guard_settlement_economics: None,
+            blocked_addresses: None,
+            resolver_protection_seconds: None,
+            balance_cache_ttl_seconds: None,
+            refund_grace_seconds: None,
+            test_mode: None,
+            max_compute_unit_price: None,
+            deposit_poll_max_interval_seconds: None,
+            creator_deposit_seconds: None,
+            min_deposit_amounts: None,
+            simulate_solana_sends: None,
+            btc_min_fee_per_byte: None,
+            btc_max_fee_per_byte: None,
+            webhook_url: None,
+        })
+    }
+
+    #[test]
+    fn should_accept_order_at_cap() {
+        assert_eq!(
+            check_order_limits(&config(), &Asset::Bitcoin, 1_000_000, 0),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn should_reject_order_above_cap() {
+        let error = check_order_limits(&config(), &Asset::Bitcoin, 1_000_001, 0).unwrap_err();
+        assert!(error.starts_with("OrderTooLarge:"), "{}", error);
+    }
+
+    #[test]
+    fn should_reject_order_exceeding_outstanding_limit() {
+        assert_eq!(
+            check_order_limits(&config(), &Asset::Bitcoin, 1_000_000, 4_000_000),
+            Ok(())
+        );
+        let error =
+            check_order_limits(&config(), &Asset::Bitcoin, 1_000_000, 4_000_001).unwrap_err();
+        assert!(error.starts_with("OrderTooLarge:"), "{}", error);
+    }
+
+    #[test]
+    fn should_not_limit_unconfigured_assets() {
+        assert_eq!(
+            check_order_limits(&config(), &Asset::Solana, u64::MAX, u64::MAX),
+            Ok(())
+        );
+    }
+}
+
+mod active_orders {
+    use super::{insert_orders, order, principal};
+    use crate::storage::{get_my_active_orders, get_my_orders};
+    use crate::types::OrderStatus;
+
+    #[test]
+    fn should_exclude_cancelled_order_from_active_orders() {
+        let creator = principal(1);
+        insert_orders(vec![
+            order(1, creator, OrderStatus::DepositReceived),
+            order(2, creator, OrderStatus::Cancelled),
+            order(3, principal(2), OrderStatus::AwaitingDeposit),
+        ]);
+
+        let mut history: Vec<u64> = get_my_orders(creator).iter().map(|o| o.id).collect();
+        history.sort();
+        assert_eq!(history, vec![1, 2]);
+
+        let active: Vec<u64> = get_my_active_orders(creator).iter().map(|o| o.id).collect();
+        assert_eq!(active, vec![1]);
+    }
+}
+
+mod solvency {
+    use super::{insert_orders, order, principal};
+    use crate::orders::assess_solvency;
+    use crate::storage::expected_held_amounts;
+    use crate::types::{Asset, OrderStatus};
+
+    #[test]
+    fn should_flag_undercollateralized_asset() {
+        let mut deposited = order(1, principal(1), OrderStatus::ResolverDeposited);
+        deposited.creator_deposited = true;
+        deposited.resolver_deposited = true;
+        let mut awaiting_resolver = order(2, principal(1), OrderStatus::DepositReceived);
+        awaiting_resolver.creator_deposited = true;
+        let mut settled = order(3, principal(1), OrderStatus::Completed);
+        settled.creator_deposited = true;
+        settled.resolver_deposited = true;
+        insert_orders(vec![deposited, awaiting_resolver, settled]);
+
+        // Two BTC deposits and one SOL deposit are still held; the completed order is settled
+        let expected = expected_held_amounts();
+        assert_eq!(
+            expected,
+            vec![(Asset::Bitcoin, 200_000), (Asset::Solana, 1_000_000_000)]
+        );
+
+        // Mocked on-chain balances: enough Bitcoin, one lamport short on Solana
+        let actual = vec![(Asset::Bitcoin, 200_500), (Asset::Solana, 999_999_999)];
+        let report = assess_solvency(expected, &actual);
+
+        assert!(report.assets[0].is_solvent);
+        assert!(!report.assets[1].is_solvent);
+        assert!(!report.is_solvent);
+    }
+
+    #[test]
+    fn should_be_solvent_without_open_deposits() {
+        insert_orders(vec![order(1, principal(1), OrderStatus::AwaitingDeposit)]);
+
+        let report = assess_solvency(expected_held_amounts(), &[]);
+        assert!(report.is_solvent);
+        assert!(report.assets.iter().all(|asset| asset.expected_held == 0));
+    }
+}
+
+mod resolver_rebate {
+    use crate::orders::resolver_rebate;
+    use crate::types::ResolverRebateConfig;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn config() -> ResolverRebateConfig {
+        ResolverRebateConfig {
+            window_seconds: 600,
+            max_rebate_bps: 50,
+        }
+    }
+
+    #[test]
+    fn should_pay_larger_rebate_for_fast_fill() {
+        let accepted_at = 1_000 * SECOND;
+
+        let immediate = resolver_rebate(&config(), 1_000_000, accepted_at, accepted_at);
+        let fast = resolver_rebate(&config(), 1_000_000, accepted_at, accepted_at + 60 * SECOND);
+        let slow = resolver_rebate(
+            &config(),
+            1_000_000,
+            accepted_at,
+            accepted_at + 540 * SECOND,
+        );
+
+        assert_eq!(immediate, 5_000);
+        assert_eq!(fast, 4_500);
+        assert_eq!(slow, 500);
+    }
+
+    #[test]
+    fn should_pay_nothing_outside_window() {
+        let accepted_at = 1_000 * SECOND;
+
+        assert_eq!(
+            resolver_rebate(
+                &config(),
+                1_000_000,
+                accepted_at,
+                accepted_at + 600 * SECOND
+            ),
+            0
+        );
+        assert_eq!(
+            resolver_rebate(
+                &config(),
+                1_000_000,
+                accepted_at,
+                accepted_at + 3_600 * SECOND
+            ),
+            0
+        );
+    }
+}
+
+mod order_status {
+    use super::{insert_orders, order, principal};
+    use crate::storage::get_order_status;
+    use crate::types::OrderStatus;
+
+    #[test]
+    fn should_return_status_of_known_order() {
+        insert_orders(vec![
+            order(1, principal(1), OrderStatus::ResolverDeposited),
+            order(2, principal(1), OrderStatus::Expired),
+        ]);
+
+        assert_eq!(get_order_status(1), Some(OrderStatus::ResolverDeposited));
+        assert_eq!(get_order_status(2), Some(OrderStatus::Expired));
+    }
+
+    #[test]
+    fn should_return_none_for_unknown_order() {
+        insert_orders(vec![order(1, principal(1), OrderStatus::AwaitingDeposit)]);
+
+        assert_eq!(get_order_status(42), None);
+    }
+}
+
+mod bulk_cancel {
+    use super::{insert_orders, order, principal};
+    use crate::orders::cancellable_orders;
+    use crate::types::OrderStatus;
+
+    #[test]
+    fn should_report_outcome_per_order() {
+        let creator = principal(1);
+        let mut resolver_deposited = order(3, creator, OrderStatus::ResolverDeposited);
+        resolver_deposited.resolver = Some(principal(2));
+        resolver_deposited.creator_deposited = true;
+        resolver_deposited.resolver_deposited = true;
+        let mut deposit_received = order(2, creator, OrderStatus::DepositReceived);
+        deposit_received.creator_deposited = true;
+
+        insert_orders(vec![
+            order(1, creator, OrderStatus::AwaitingDeposit),
+            deposit_received,
+            resolver_deposited,
+            order(4, creator, OrderStatus::Completed),
+            order(5, creator, OrderStatus::Cancelled),
+            order(6, principal(2), OrderStatus::AwaitingDeposit),
+        ]);
+
+        let outcomes = cancellable_orders(creator);
+
+        // Settled orders and other users' orders are not attempted at all
+        let ids: Vec<u64> = outcomes.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+
+        assert_eq!(outcomes[0].1, Ok(()));
+        assert_eq!(outcomes[1].1, Ok(()));
+        assert!(outcomes[2]
+            .1
+            .as_ref()
+            .unwrap_err()
+            .starts_with("Cannot cancel after resolver has deposited"));
+    }
+}
+
+mod address_network {
+    use crate::bitcoin_integration::check_bitcoin_address_network;
+    use bitcoin::Network;
+
+    const MAINNET_ADDRESS: &str = "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq";
+    const TESTNET_ADDRESS: &str = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx";
+
+    #[test]
+    fn should_reject_mainnet_address_on_testnet() {
+        let error = check_bitcoin_address_network(MAINNET_ADDRESS, Network::Testnet).unwrap_err();
+        assert!(error.starts_with("WrongNetworkAddress:"), "{}", error);
+    }
+
+    #[test]
+    fn should_accept_address_for_configured_network() {
+        assert_eq!(
+            check_bitcoin_address_network(TESTNET_ADDRESS, Network::Testnet),
+            Ok(())
+        );
+        assert_eq!(
+            check_bitcoin_address_network(MAINNET_ADDRESS, Network::Bitcoin),
+            Ok(())
+        );
+    }
+}
+
+mod wallet_addresses {
+    use crate::orders::validate_wallet_addresses;
+
+    const BTC_ADDRESS: &str = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx";
+    const SOL_ADDRESS: &str = "4zMMC9srt5Ri5X14GAgXhaHii3GnPAEERYPJgZJDncDU";
+
+    #[test]
+    fn should_accept_valid_addresses() {
+        let btc = BTC_ADDRESS.to_string();
+        let sol = SOL_ADDRESS.to_string();
+
+        assert_eq!(validate_wallet_addresses(Some(&btc), Some(&sol)), Ok(()));
+        assert_eq!(validate_wallet_addresses(None, None), Ok(()));
+    }
+
+    #[test]
+    fn should_reject_malformed_bitcoin_address() {
+        let btc = "tb1qnotanaddress".to_string();
+        let sol = SOL_ADDRESS.to_string();
+
+        let error = validate_wallet_addresses(Some(&btc), Some(&sol)).unwrap_err();
+        assert!(error.starts_with("InvalidAddress:"), "{}", error);
+    }
+
+    #[test]
+    fn should_reject_malformed_solana_address() {
+        let btc = BTC_ADDRESS.to_string();
+
+        for sol in [
+            "CreatorSolAddress0OIl",
+            "4zMMC9srt5Ri5X14GAgXhaHii3GnPAEERYPJgZJD",
+            "",
+        ] {
+            let error = validate_wallet_addresses(Some(&btc), Some(&sol.to_string())).unwrap_err();
+            assert!(error.starts_with("InvalidAddress:"), "{}", error);
+        }
+    }
+}
+
+mod extend_order {
+    use super::{order, principal};
+    use crate::orders::check_extension;
+    use crate::types::OrderStatus;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    #[test]
+    fn should_extend_order_before_resolver_deposit() {
+        let creator = principal(1);
+        let order = order(1, creator, OrderStatus::AwaitingDeposit);
+
+        assert_eq!(
+            check_extension(&order, creator, 600, None, 2_000),
+            Ok(order.expires_at + 600 * SECOND)
+        );
+
+        // The extended order stays within a 2 hour maximum timeout, but not a 1 hour one
+        assert!(check_extension(&order, creator, 600, Some(7_200), 2_000).is_ok());
+        assert!(check_extension(&order, creator, 600, Some(3_600), 2_000).is_err());
+    }
+
+    #[test]
+    fn should_reject_extension_after_resolver_deposit() {
+        let creator = principal(1);
+        let mut order = order(1, creator, OrderStatus::ResolverDeposited);
+        order.resolver = Some(principal(2));
+        order.creator_deposited = true;
+        order.resolver_deposited = true;
+
+        assert_eq!(
+            check_extension(&order, creator, 600, None, 2_000),
+            Err("Cannot extend after resolver has deposited".to_string())
+        );
+    }
+
+    #[test]
+    fn should_reject_extension_by_non_creator() {
+        let order = order(1, principal(1), OrderStatus::AwaitingDeposit);
+
+        assert!(check_extension(&order, principal(2), 600, None, 2_000).is_err());
+    }
+}
+
+mod timeline {
+    use super::{order, principal};
+    use crate::storage::order_timeline;
+    use crate::types::OrderStatus;
+
+    #[test]
+    fn should_report_transition_timestamps() {
+        let mut order = order(1, principal(1), OrderStatus::AwaitingDeposit);
+        let timeline = order_timeline(&order);
+        assert_eq!(timeline.created_at, 1_000);
+        assert_eq!(timeline.deposit_confirmed_at, None);
+
+        order.status = OrderStatus::Completed;
+        order.deposit_confirmed_at = Some(2_000);
+        order.accepted_at = Some(3_000);
+        order.resolver_deposited_at = Some(4_000);
+        order.completed_at = Some(5_000);
+
+        let timeline = order_timeline(&order);
+        assert_eq!(timeline.deposit_confirmed_at, Some(2_000));
+        assert_eq!(timeline.accepted_at, Some(3_000));
+        assert_eq!(timeline.resolver_deposited_at, Some(4_000));
+        assert_eq!(timeline.completed_at, Some(5_000));
+        assert_eq!(timeline.cancelled_at, None);
+    }
+}
+
+mod refund_address {
+    use crate::orders::get_refund_address;
+    use crate::types::Asset;
+
+    const STORED_BTC: &str = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx";
+    const OVERRIDE_BTC: &str = "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7";
+
+    #[test]
+    fn should_refund_to_override_address() {
+        let stored = STORED_BTC.to_string();
+        let override_address = OVERRIDE_BTC.to_string();
+
+        assert_eq!(
+            get_refund_address(
+                &Asset::Bitcoin,
+                Some(&override_address),
+                Some(&stored),
+                None
+            ),
+            Ok(OVERRIDE_BTC.to_string())
+        );
+        assert_eq!(
+            get_refund_address(&Asset::Bitcoin, None, Some(&stored), None),
+            Ok(STORED_BTC.to_string())
+        );
+    }
+
+    #[test]
+    fn should_reject_override_for_wrong_asset_type() {
+        let stored = "11111111111111111111111111111111".to_string();
+        let override_address = OVERRIDE_BTC.to_string();
+
+        assert!(
+            get_refund_address(&Asset::Solana, Some(&override_address), None, Some(&stored))
+                .is_err()
+        );
+    }
+}
+
+mod spl_deposit {
+    use crate::solana_integration::{
+        assess_spl_deposit, get_associated_token_account, token_account_credit, token_mint_debit,
+    };
+    use sol_rpc_types::{TokenAmount, TransactionTokenBalance};
+    use solana_pubkey::Pubkey;
+    use std::str::FromStr;
+
+    const MINT: &str = "4zMMC9srt5Ri5X14GAgXhaHii3GnPAEERYPJgZJDncDU";
+
+    fn token_balance(account_index: u8, amount: u64) -> TransactionTokenBalance {
+        TransactionTokenBalance {
+            account_index,
+            mint: MINT.to_string(),
+            ui_token_amount: TokenAmount {
+                ui_amount: None,
+                decimals: 6,
+                amount: amount.to_string(),
+                ui_amount_string: amount.to_string(),
+            },
+            owner: None,
+            program_id: None,
+        }
+    }
+
+    fn canister_accounts() -> (Pubkey, Pubkey) {
+        let wallet = Pubkey::new_from_array([7; 32]);
+        let ata = get_associated_token_account(&wallet.to_string(), MINT).unwrap();
+        (wallet, Pubkey::from_str(&ata).unwrap())
+    }
+
+    #[test]
+    fn should_accept_deposit_to_canister_ata() {
+        let (wallet, ata) = canister_accounts();
+        let sender = Pubkey::new_from_array([1; 32]);
+        let account_keys = [sender, ata, wallet];
+
+        let pre = [token_balance(0, 5_000), token_balance(1, 0)];
+        let post = [token_balance(0, 4_000), token_balance(1, 1_000)];
+
+        assert_eq!(
+            token_account_credit(&account_keys, &ata, MINT, &pre, &post),
+            Ok(1_000)
+        );
+    }
+
+    #[test]
+    fn should_reject_deposit_to_canister_wallet() {
+        let (wallet, ata) = canister_accounts();
+        let sender = Pubkey::new_from_array([1; 32]);
+        let account_keys = [sender, wallet];
+
+        // Tokens went to the wallet address itself, which the ATA never appears in
+        let pre = [token_balance(0, 5_000), token_balance(1, 0)];
+        let post = [token_balance(0, 4_000), token_balance(1, 1_000)];
+
+        assert_eq!(
+            token_account_credit(&account_keys, &ata, MINT, &pre, &post),
+            Ok(0)
+        );
+        assert_ne!(wallet, ata);
+    }
+
+    #[test]
+    fn should_ignore_balances_for_other_mints() {
+        let (_, ata) = canister_accounts();
+        let account_keys = [Pubkey::new_from_array([1; 32]), ata];
+
+        let mut other_mint = token_balance(1, 1_000);
+        other_mint.mint = "So11111111111111111111111111111111111111112".to_string();
+
+        assert_eq!(
+            token_account_credit(&account_keys, &ata, MINT, &[], &[other_mint]),
+            Ok(0)
+        );
+    }
+
+    #[test]
+    fn should_reject_deposit_short_by_transfer_fee() {
+        let (wallet, ata) = canister_accounts();
+        let sender = Pubkey::new_from_array([1; 32]);
+        let account_keys = [sender, ata, wallet];
+
+        // The sender parts with 1_000 but the mint withholds a 1% fee from the canister's credit
+        let pre = [token_balance(0, 5_000), token_balance(1, 0)];
+        let post = [token_balance(0, 4_000), token_balance(1, 990)];
+
+        let credited = token_account_credit(&account_keys, &ata, MINT, &pre, &post).unwrap();
+        let debited = token_mint_debit(MINT, &pre, &post).unwrap();
+        assert_eq!((credited, debited), (990, 1_000));
+
+        let result = assess_spl_deposit(credited, debited, 1_000);
+        assert!(!result.verified);
+        assert_eq!(result.observed_amount, 990);
+        let reason = result.reason.unwrap();
+        assert!(reason.starts_with("FeeOnTransfer"), "{}", reason);
+    }
+
+    #[test]
+    fn should_verify_deposit_by_credited_amount() {
+        assert!(assess_spl_deposit(1_000, 1_000, 1_000).verified);
+
+        // An underpayment without a fee is reported as such, not as a transfer fee
+        let reason = assess_spl_deposit(990, 990, 1_000).reason.unwrap();
+        assert!(reason.starts_with("Insufficient amount"), "{}", reason);
+    }
+}
+
+mod token_metadata {
+    use super::poll_once;
+    use crate::solana_integration::{get_token_metadata, token_metadata};
+    use crate::storage::cache_token_metadata;
+    use crate::types::TokenMetadata;
+
+    const MINT: &str = "4zMMC9srt5Ri5X14GAgXhaHii3GnPAEERYPJgZJDncDU";
+
+    fn borsh_string(value: &str, padded_len: usize) -> Vec<u8> {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.resize(padded_len.max(bytes.len()), 0);
+        let mut encoded = (bytes.len() as u32).to_le_bytes().to_vec();
+        encoded.extend(bytes);
+        encoded
+    }
+
+    fn mint_data(decimals: u8) -> Vec<u8> {
+        let mut data = vec![0; 82];
+        data[44] = decimals;
+        data
+    }
+
+    // Metaplex pads names to 32 bytes and symbols to 10 with NULs
+    fn metaplex_account(name: &str, symbol: &str) -> Vec<u8> {
+        let mut data = vec![4];
+        data.extend([0; 64]);
+        data.extend(borsh_string(name, 32));
+        data.extend(borsh_string(symbol, 10));
+        data.extend(borsh_string("https://example.com/token.json", 200));
+        data
+    }
+
+    #[test]
+    fn should_read_name_and_symbol_from_metaplex_account() {
+        let metaplex = metaplex_account("USD Coin", "USDC");
+        let metadata = token_metadata(MINT, &mint_data(6), Some(&metaplex)).unwrap();
+        assert_eq!(
+            metadata,
+            TokenMetadata {
+                mint_address: MINT.to_string(),
+                name: Some("USD Coin".to_string()),
+                symbol: Some("USDC".to_string()),
+                decimals: 6,
+            }
+        );
+    }
+
+    #[test]
+    fn should_read_token_2022_metadata_extension() {
+        let mut value = vec![0; 64];
+        value.extend(borsh_string("Wrapped Thing", 0));
+        value.extend(borsh_string("WTH", 0));
+        value.extend(borsh_string("", 0));
+        value.extend(0u32.to_le_bytes());
+
+        let mut data = mint_data(9);
+        data.resize(165, 0);
+        data.push(1); // Mint account type
+        data.extend(19u16.to_le_bytes());
+        data.extend((value.len() as u16).to_le_bytes());
+        data.extend(value);
+
+        let metadata = token_metadata(MINT, &data, None).unwrap();
+        assert_eq!(metadata.name.as_deref(), Some("Wrapped Thing"));
+        assert_eq!(metadata.symbol.as_deref(), Some("WTH"));
+        assert_eq!(metadata.decimals, 9);
+    }
+
+    #[test]
+    fn should_return_decimals_only_for_mint_without_metadata() {
+        let metadata = token_metadata(MINT, &mint_data(6), None).unwrap();
+        assert_eq!(
+            (metadata.name, metadata.symbol, metadata.decimals),
+            (None, None, 6)
+        );
+
+        // Not a Metaplex metadata account
+        let metadata = token_metadata(MINT, &mint_data(6), Some(&[0; 10])).unwrap();
+        assert_eq!(metadata.symbol, None);
+
+        assert!(token_metadata(MINT, &[0; 10], None).is_err());
+    }
+
+    #[test]
+    fn should_serve_cached_metadata_without_rpc() {
+        let metadata = token_metadata(MINT, &mint_data(6), None).unwrap();
+        cache_token_metadata(metadata.clone());
+        assert_eq!(
+            poll_once(get_token_metadata(MINT.to_string())),
+            Ok(metadata)
+        );
+    }
+}
+
+mod fee_override {
+    use super::poll_once;
+    use crate::basic_bitcoin::common::resolve_fee_per_byte;
+
+    #[test]
+    fn should_use_override_when_set() {
+        let fee = poll_once(resolve_fee_per_byte(Some(10_000), || async {
+            panic!("network fee should not be queried")
+        }));
+        assert_eq!(fee, 10_000);
+    }
+
+    #[test]
+    fn should_use_network_fee_without_override() {
+        let fee = poll_once(resolve_fee_per_byte(None, || async { 2_000 }));
+        assert_eq!(fee, 2_000);
+    }
+}
+
+mod fee_fallback {
+    use super::poll_once;
+    use crate::basic_bitcoin::common::{
+        fallback_fee_per_byte, parse_recommended_fee, transform_fee_response,
+    };
+    use ic_cdk::management_canister::{HttpHeader, HttpRequestResult, TransformArgs};
+
+    const FEE_API: &str = "https://mempool.space/testnet/api/v1/fees/recommended";
+    const RESPONSE: &str =
+        r#"{"fastestFee":25,"halfHourFee":12,"hourFee":8,"economyFee":3,"minimumFee":1}"#;
+
+    // Stands in for the HTTPS outcall, returning the mocked fee API body
+    async fn mock_fee_api(url: String) -> Result<u64, String> {
+        assert_eq!(url, FEE_API);
+        parse_recommended_fee(RESPONSE.as_bytes())
+    }
+
+    #[test]
+    fn should_fall_back_to_fee_api_without_native_estimate() {
+        let fee = poll_once(fallback_fee_per_byte(
+            None,
+            Some(FEE_API.to_string()),
+            mock_fee_api,
+        ));
+        assert_eq!(fee, 12_000);
+    }
+
+    #[test]
+    fn should_prefer_native_estimate() {
+        let fee = poll_once(fallback_fee_per_byte(
+            Some(5_000),
+            Some(FEE_API.to_string()),
+            |_| async { panic!("fee API should not be called") },
+        ));
+        assert_eq!(fee, 5_000);
+    }
+
+    #[test]
+    fn should_use_static_rate_when_fee_api_fails() {
+        let fee = poll_once(fallback_fee_per_byte(
+            None,
+            Some(FEE_API.to_string()),
+            |_| async { Err("timeout".to_string()) },
+        ));
+        assert_eq!(fee, 2_000);
+        assert_eq!(
+            poll_once(fallback_fee_per_byte(None, None, mock_fee_api)),
+            2_000
+        );
+    }
+
+    #[test]
+    fn should_strip_fee_response_to_half_hour_fee() {
+        let response = HttpRequestResult {
+            status: 200u32.into(),
+            headers: vec![HttpHeader {
+                name: "date".to_string(),
+                value: "Thu, 15 Oct 2026 10:00:00 GMT".to_string(),
+            }],
+            body: RESPONSE.as_bytes().to_vec(),
+        };
+
+        let transformed = transform_fee_response(TransformArgs {
+            response,
+            context: vec![],
+        });
+        assert!(transformed.headers.is_empty());
+        assert_eq!(transformed.body, br#"{"halfHourFee":12}"#);
+        assert_eq!(parse_recommended_fee(&transformed.body), Ok(12_000));
+    }
+}
+
+mod solana_account_cache {
+    use super::{poll_once, principal};
+    use crate::basic_solana::solana_wallet::SolanaAccount;
+    use crate::solana_integration::cached_solana_account;
+    use solana_pubkey::Pubkey;
+    use std::cell::Cell;
+
+    fn account(byte: u8) -> SolanaAccount {
+        SolanaAccount {
+            ed25519_public_key: Pubkey::new_from_array([byte; 32]),
+            derivation_path: Default::default(),
+        }
+    }
+
+    #[test]
+    fn should_reuse_cached_account_across_sends() {
+        let derivations = Cell::new(0);
+        let derive = |byte| {
+            let derivations = &derivations;
+            move || async move {
+                derivations.set(derivations.get() + 1);
+                account(byte)
+            }
+        };
+
+        let first = poll_once(cached_solana_account(principal(1), derive(1)));
+        let second = poll_once(cached_solana_account(principal(1), derive(2)));
+
+        assert_eq!(derivations.get(), 1);
+        assert_eq!(first.ed25519_public_key, second.ed25519_public_key);
+
+        // Other principals get their own entry
+        let other = poll_once(cached_solana_account(principal(2), derive(3)));
+        assert_eq!(derivations.get(), 2);
+        assert_eq!(other.ed25519_public_key, Pubkey::new_from_array([3; 32]));
+    }
+}
+
+mod amounts {
+    use crate::amounts::{format_amount, parse_amount, resolve_amount};
+    use crate::types::Asset;
+
+    fn usdc() -> Asset {
+        Asset::SplToken {
+            mint_address: "4zMMC9srt5Ri5X14GAgXhaHii3GnPAEERYPJgZJDncDU".to_string(),
+            decimals: 6,
+        }
+    }
+
+    #[test]
+    fn should_format_amounts_with_asset_decimals() {
+        assert_eq!(format_amount(&Asset::Bitcoin, 0), "0");
+        assert_eq!(format_amount(&Asset::Bitcoin, 1), "0.00000001");
+        assert_eq!(format_amount(&Asset::Bitcoin, 150_000_000), "1.5");
+        assert_eq!(format_amount(&Asset::Solana, 1_000_000_000), "1");
+        assert_eq!(
+            format_amount(&Asset::Solana, u64::MAX),
+            "18446744073.709551615"
+        );
+        assert_eq!(format_amount(&usdc(), 1_234_567), "1.234567");
+    }
+
+    #[test]
+    fn should_parse_amounts_with_asset_decimals() {
+        assert_eq!(parse_amount(&Asset::Bitcoin, "0.00000001"), Ok(1));
+        assert_eq!(parse_amount(&Asset::Bitcoin, "1.5"), Ok(150_000_000));
+        assert_eq!(parse_amount(&Asset::Solana, ".5"), Ok(500_000_000));
+        assert_eq!(
+            parse_amount(&Asset::Solana, "18446744073.709551615"),
+            Ok(u64::MAX)
+        );
+        assert_eq!(parse_amount(&usdc(), "1.234567"), Ok(1_234_567));
+        assert_eq!(parse_amount(&usdc(), "1.2345670"), Ok(1_234_567));
+    }
+
+    #[test]
+    fn should_reject_invalid_amounts() {
+        // Would need rounding
+        assert!(parse_amount(&Asset::Bitcoin, "0.000000001").is_err());
+        assert!(parse_amount(&usdc(), "1.2345678").is_err());
+        // Overflows u64
+        assert!(parse_amount(&Asset::Solana, "18446744073.709551616").is_err());
+        // Malformed
+        for input in ["", ".", "-1", "1.2.3", "1e9", "abc"] {
+            assert!(parse_amount(&Asset::Bitcoin, input).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn should_resolve_display_amounts_with_asset_decimals() {
+        assert_eq!(resolve_amount(&usdc(), 0, Some("2.5")), Ok(2_500_000));
+        assert_eq!(
+            resolve_amount(&usdc(), 2_500_000, Some("2.5")),
+            Ok(2_500_000)
+        );
+        assert_eq!(resolve_amount(&usdc(), 2_500_000, None), Ok(2_500_000));
+        assert_eq!(
+            resolve_amount(&Asset::Bitcoin, 0, Some("0.001")),
+            Ok(100_000)
+        );
+    }
+
+    #[test]
+    fn should_reject_raw_amount_scaled_with_wrong_decimals() {
+        // 2.5 USDC pre-scaled as if the mint had 9 decimals
+        let error = resolve_amount(&usdc(), 2_500_000_000, Some("2.5")).unwrap_err();
+        assert!(error.starts_with("Amount mismatch"), "{}", error);
+    }
+}
+
+mod resolver_stats {
+    use super::{order, principal};
+    use crate::orders::is_abandoned;
+    use crate::storage::{
+        get_resolver_stats, record_resolver_abandoned, record_resolver_accepted,
+        record_resolver_completed, record_resolver_deposited,
+    };
+    use crate::types::{OrderStatus, ResolverStats};
+
+    #[test]
+    fn should_track_completed_and_abandoned_orders() {
+        let resolver = principal(1);
+        assert_eq!(get_resolver_stats(resolver), ResolverStats::default());
+
+        // One swap seen through to completion, another left to lapse
+        record_resolver_accepted(resolver);
+        record_resolver_deposited(resolver, 10);
+        record_resolver_completed(resolver);
+        record_resolver_accepted(resolver);
+        record_resolver_abandoned(resolver);
+        record_resolver_accepted(resolver);
+        record_resolver_deposited(resolver, 21);
+
+        assert_eq!(
+            get_resolver_stats(resolver),
+            ResolverStats {
+                accepted: 3,
+                deposited: 2,
+                completed: 1,
+                abandoned: 1,
+                avg_deposit_latency: 15,
+            }
+        );
+        assert_eq!(get_resolver_stats(principal(2)), ResolverStats::default());
+    }
+
+    #[test]
+    fn should_only_count_lapsed_undeposited_orders_as_abandoned() {
+        let mut accepted = order(1, principal(1), OrderStatus::DepositReceived);
+        accepted.resolver = Some(principal(2));
+        let expiry = accepted.expires_at;
+
+        assert!(!is_abandoned(&accepted, expiry - 1));
+        assert!(is_abandoned(&accepted, expiry));
+
+        let mut deposited = accepted.clone();
+        deposited.resolver_deposited = true;
+        deposited.status = OrderStatus::ResolverDeposited;
+        assert!(!is_abandoned(&deposited, expiry));
+
+        let mut cancelled = accepted.clone();
+        cancelled.status = OrderStatus::Cancelled;
+        assert!(!is_abandoned(&cancelled, expiry));
+
+        let unaccepted = order(2, principal(1), OrderStatus::DepositReceived);
+        assert!(!is_abandoned(&unaccepted, expiry));
+    }
+}
+
+mod open_order_capacity {
+    use super::{order, principal};
+    use crate::orders::check_open_order_capacity;
+    use crate::storage::{open_order_count, release_open_order, track_open_order};
+    use crate::types::{Asset, OrderStatus, SwapConfig};
+
+    fn config(max_open_orders_per_asset: Option<u64>) -> SwapConfig {
+        SwapConfig {
+            max_open_orders_per_asset,
+            ..SwapConfig::default()
+        }
+    }
+
+    #[test]
+    fn should_accept_order_at_capacity() {
+        assert_eq!(
+            check_open_order_capacity(&config(Some(2)), &Asset::Bitcoin, 2),
+            Ok(())
+        );
+        assert_eq!(
+            check_open_order_capacity(&config(None), &Asset::Bitcoin, u64::MAX),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn should_reject_order_above_capacity() {
+        let error = check_open_order_capacity(&config(Some(2)), &Asset::Bitcoin, 3).unwrap_err();
+        assert!(error.starts_with("CapacityExceeded"), "{}", error);
+    }
+
+    #[test]
+    fn should_count_open_orders_incrementally() {
+        let first = order(1, principal(1), OrderStatus::AwaitingDeposit);
+        let second = order(2, principal(1), OrderStatus::DepositReceived);
+        track_open_order(&first);
+        track_open_order(&second);
+        assert_eq!(open_order_count(&Asset::Bitcoin), 2);
+        assert_eq!(open_order_count(&Asset::Solana), 2);
+
+        release_open_order(&first);
+        assert_eq!(open_order_count(&Asset::Bitcoin), 1);
+
+        // Releasing an order that is already terminal must not double count
+        let mut cancelled = second.clone();
+        cancelled.status = OrderStatus::Cancelled;
+        release_open_order(&cancelled);
+        assert_eq!(open_order_count(&Asset::Bitcoin), 1);
+
+        release_open_order(&second);
+        assert_eq!(open_order_count(&Asset::Bitcoin), 0);
+        assert_eq!(open_order_count(&Asset::Solana), 0);
+    }
+}
+
+mod audit_log {
+    use super::principal;
+    use crate::storage::{append_audit_entry, get_audit_log, AUDIT_LOG_CAPACITY};
+    use crate::types::AuditEntry;
+
+    fn entry(action: &str, result: Result<(), String>) -> AuditEntry {
+        AuditEntry {
+            id: 0,
+            timestamp: 1_000,
+            caller: principal(1),
+            order_id: Some(7),
+            action: action.to_string(),
+            result,
+        }
+    }
+
+    #[test]
+    fn should_record_completed_swap_in_order() {
+        let swap = [
+            "create_order",
+            "confirm_deposit",
+            "accept_order",
+            "confirm_resolver_deposit",
+            "reveal_secret",
+        ];
+        append_audit_entry(entry("create_order", Ok(())));
+        append_audit_entry(entry("confirm_deposit", Ok(())));
+        append_audit_entry(entry("accept_order", Ok(())));
+        append_audit_entry(entry(
+            "reveal_secret",
+            Err("Resolver has not deposited yet".to_string()),
+        ));
+        append_audit_entry(entry("confirm_resolver_deposit", Ok(())));
+        append_audit_entry(entry("reveal_secret", Ok(())));
+
+        let log = get_audit_log(0, 100);
+        let succeeded: Vec<&str> = log
+            .iter()
+            .filter(|entry| entry.result.is_ok())
+            .map(|entry| entry.action.as_str())
+            .collect();
+        assert_eq!(succeeded, swap);
+        assert_eq!(log.len(), 6);
+        assert!(log.iter().all(|entry| entry.order_id == Some(7)));
+        assert_eq!(
+            log.iter().map(|entry| entry.id).collect::<Vec<_>>(),
+            [0, 1, 2, 3, 4, 5]
+        );
+
+        // Paging
+        let page = get_audit_log(4, 10);
+        assert_eq!(page.len(), 2);
+        assert_eq!(page[0].action, "confirm_resolver_deposit");
+        assert!(get_audit_log(6, 10).is_empty());
+    }
+
+    #[test]
+    fn should_evict_oldest_entries_at_capacity() {
+        for _ in 0..AUDIT_LOG_CAPACITY + 2 {
+            append_audit_entry(entry("extend_order", Ok(())));
+        }
+
+        let log = get_audit_log(0, u64::MAX);
+        assert_eq!(log.len(), AUDIT_LOG_CAPACITY);
+        assert_eq!(log[0].id, 2);
+        assert_eq!(
+            log[AUDIT_LOG_CAPACITY - 1].id,
+            AUDIT_LOG_CAPACITY as u64 + 1
+        );
+    }
+}
+
+mod deposit_polling {
+    use super::{insert_orders, order, poll_once, principal};
+    use crate::orders::{credit_creator_deposit, poll_verification};
+    use crate::storage::{update_order, ORDERS};
+    use crate::types::OrderStatus;
+    use std::cell::{Cell, RefCell};
+    use std::time::Duration;
+
+    async fn no_sleep(_: Duration) {}
+
+    #[test]
+    fn should_confirm_on_second_poll() {
+        let polls = Cell::new(0);
+        let verify = || {
+            polls.set(polls.get() + 1);
+            let seen = polls.get() >= 2;
+            async move { Ok(seen) }
+        };
+
+        assert_eq!(
+            poll_once(poll_verification(
+                100,
+                10,
+                Duration::ZERO,
+                || 0,
+                no_sleep,
+                verify,
+            )),
+            Ok(true)
+        );
+        assert_eq!(polls.get(), 2);
+    }
+
+    #[test]
+    fn should_give_up_after_deadline_or_attempts() {
+        let polls = Cell::new(0);
+        let verify = || {
+            polls.set(polls.get() + 1);
+            async { Err::<bool, _>("Transaction not found".to_string()) }
+        };
+        assert_eq!(
+            poll_once(poll_verification(
+                100,
+                3,
+                Duration::ZERO,
+                || 0,
+                no_sleep,
+                verify,
+            )),
+            Err("Transaction not found".to_string())
+        );
+        assert_eq!(polls.get(), 3);
+
+        // Without a wait, a single check is made
+        let polls = Cell::new(0);
+        let verify = || {
+            polls.set(polls.get() + 1);
+            async { Ok(false) }
+        };
+        assert_eq!(
+            poll_once(poll_verification(
+                0,
+                10,
+                Duration::ZERO,
+                || 0,
+                no_sleep,
+                verify,
+            )),
+            Ok(false)
+        );
+        assert_eq!(polls.get(), 1);
+    }
+
+    #[test]
+    fn should_wait_between_attempts() {
+        let clock = Cell::new(0);
+        let checked_at = RefCell::new(Vec::new());
+        let verify = || {
+            checked_at.borrow_mut().push(clock.get());
+            async { Ok(false) }
+        };
+        let sleep = |delay: Duration| {
+            clock.set(clock.get() + delay.as_nanos() as u64);
+            async {}
+        };
+
+        let outcome = poll_once(poll_verification(
+            30_000_000_000,
+            10,
+            Duration::from_secs(10),
+            || clock.get(),
+            sleep,
+            verify,
+        ));
+        assert_eq!(outcome, Ok(false));
+        // Ten seconds apart, until the check made at the deadline
+        assert_eq!(
+            *checked_at.borrow(),
+            vec![0, 10_000_000_000, 20_000_000_000, 30_000_000_000]
+        );
+    }
+
+    #[test]
+    fn should_not_credit_order_cancelled_while_polling() {
+        insert_orders(vec![order(1, principal(1), OrderStatus::AwaitingDeposit)]);
+        let verify = || {
+            // The creator cancels from another call while the deposit is being looked up
+            update_order(1, |ord| ord.status = OrderStatus::Cancelled);
+            async { Ok(true) }
+        };
+
+        assert_eq!(
+            poll_once(poll_verification(
+                0,
+                10,
+                Duration::ZERO,
+                || 0,
+                no_sleep,
+                verify,
+            )),
+            Ok(true)
+        );
+        assert!(credit_creator_deposit(1, "deposit-tx", 2_000).is_err());
+
+        let order = ORDERS.with(|orders| orders.borrow()[&1].clone());
+        assert_eq!(order.status, OrderStatus::Cancelled);
+        assert!(!order.creator_deposited);
+        assert_eq!(order.creator_txid, None);
+    }
+}
+
+mod oracle_pricing {
+    use super::{order, principal};
+    use crate::orders::priced_to_amount;
+    use crate::types::{OrderPricing, OrderStatus};
+
+    // 1 BTC = $60,000 and 1 SOL = $150, so 100_000 sats (0.001 BTC) is worth 0.4 SOL
+    const BTC_PRICE: u64 = 60_000_000_000;
+    const SOL_PRICE: u64 = 150_000_000;
+
+    #[test]
+    fn should_keep_fixed_amount_regardless_of_prices() {
+        let fixed = order(1, principal(1), OrderStatus::DepositReceived);
+        assert_eq!(priced_to_amount(&fixed, None, None), Ok(1_000_000_000));
+        assert_eq!(
+            priced_to_amount(&fixed, Some(BTC_PRICE), Some(SOL_PRICE)),
+            Ok(1_000_000_000)
+        );
+    }
+
+    #[test]
+    fn should_price_oracle_order_at_acceptance() {
+        let mut oracle = order(1, principal(1), OrderStatus::DepositReceived);
+        oracle.pricing = OrderPricing::Oracle {
+            reference: 402_000_000,
+            max_slippage_bps: 50,
+        };
+
+        assert_eq!(
+            priced_to_amount(&oracle, Some(BTC_PRICE), Some(SOL_PRICE)),
+            Ok(400_000_000)
+        );
+        assert!(priced_to_amount(&oracle, None, Some(SOL_PRICE)).is_err());
+    }
+
+    #[test]
+    fn should_reject_oracle_order_beyond_slippage() {
+        let mut oracle = order(1, principal(1), OrderStatus::DepositReceived);
+        oracle.pricing = OrderPricing::Oracle {
+            reference: 403_000_000,
+            max_slippage_bps: 50,
+        };
+
+        assert!(priced_to_amount(&oracle, Some(BTC_PRICE), Some(SOL_PRICE)).is_err());
+    }
+}
+
+mod canister_token_account {
+    use crate::solana_integration::{
+        get_associated_token_account, get_associated_token_account_for_program,
+        TOKEN_2022_PROGRAM_ID,
+    };
+    use crate::storage::cache_mint_token_program;
+    use solana_pubkey::Pubkey;
+    use spl_associated_token_account_interface::address::get_associated_token_address_with_program_id;
+    use std::str::FromStr;
+
+    const OWNER: &str = "9WzDXwBbmkg8ZTbNMqUxvQRAyrZzDsGYdLVL9zYtAWWM";
+    const MINT: &str = "EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v";
+    const TOKEN_PROGRAM: &str = "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA";
+
+    #[test]
+    fn should_match_spl_derivation() {
+        let expected = get_associated_token_address_with_program_id(
+            &Pubkey::from_str(OWNER).unwrap(),
+            &Pubkey::from_str(MINT).unwrap(),
+            &Pubkey::from_str(TOKEN_PROGRAM).unwrap(),
+        );
+
+        assert_eq!(
+            get_associated_token_account(OWNER, MINT),
+            Ok(expected.to_string())
+        );
+    }
+
+    #[test]
+    fn should_reject_invalid_mint() {
+        assert!(get_associated_token_account(OWNER, "not-a-mint").is_err());
+    }
+
+    #[test]
+    fn should_derive_token_2022_ata() {
+        const TOKEN_2022_MINT: &str = "2b1kV6DkPAnxd5ixfnxCpjxmKwqjjaYmCZfHsFu24GXo";
+        let expected = get_associated_token_address_with_program_id(
+            &Pubkey::from_str(OWNER).unwrap(),
+            &Pubkey::from_str(TOKEN_2022_MINT).unwrap(),
+            &Pubkey::from_str(TOKEN_2022_PROGRAM_ID).unwrap(),
+        );
+
+        assert_eq!(
+            get_associated_token_account_for_program(OWNER, TOKEN_2022_MINT, TOKEN_2022_PROGRAM_ID),
+            Ok(expected.to_string())
+        );
+        assert_ne!(
+            get_associated_token_account(OWNER, TOKEN_2022_MINT),
+            Ok(expected.to_string())
+        );
+
+        // Once the mint's owner program is known, the default derivation follows it
+        cache_mint_token_program(TOKEN_2022_MINT, TOKEN_2022_PROGRAM_ID);
+        assert_eq!(
+            get_associated_token_account(OWNER, TOKEN_2022_MINT),
+            Ok(expected.to_string())
+        );
+    }
+
+    #[test]
+    fn should_reject_unsupported_token_program() {
+        assert!(get_associated_token_account_for_program(OWNER, MINT, OWNER).is_err());
+    }
+}
+
+mod order_expiry {
+    use crate::orders::compute_expires_at;
+
+    #[test]
+    fn should_compute_expiry_in_nanoseconds() {
+        assert_eq!(
+            compute_expires_at(1_000, 3_600),
+            Ok(1_000 + 3_600_000_000_000)
+        );
+    }
+
+    #[test]
+    fn should_reject_overflowing_timeout() {
+        let error = compute_expires_at(1_000, u64::MAX).unwrap_err();
+        assert!(error.starts_with("InvalidTimeout"), "{}", error);
+        // The multiplication fits but adding the creation time does not
+        assert!(compute_expires_at(u64::MAX - 1, 1).is_err());
+    }
+}
+
+mod deposit_watcher {
+    use super::{insert_orders, order, poll_once, principal};
+    use crate::deposit_watcher::{match_deposits, watch_deposits_with, DetectedDeposit};
+    use crate::storage::{claim_deposit, consume_txid, deposit_claims, get_order_status, ORDERS};
+    use crate::types::{Asset, OrderStatus};
+
+    fn deposit(txid: &str, amount: u64) -> DetectedDeposit {
+        DetectedDeposit {
+            asset: Asset::Bitcoin,
+            txid: txid.to_string(),
+            amount,
+        }
+    }
+
+    #[test]
+    fn should_credit_claimed_deposit_once_it_appears() {
+        insert_orders(vec![order(1, principal(1), OrderStatus::AwaitingDeposit)]);
+        claim_deposit(1, "deposit-tx");
+
+        let advanced = poll_once(watch_deposits_with(|| async { Ok(vec![]) }, 2_000));
+        assert!(advanced.is_empty());
+        assert_eq!(get_order_status(1), Some(OrderStatus::AwaitingDeposit));
+
+        let advanced = poll_once(watch_deposits_with(
+            || async { Ok(vec![deposit("deposit-tx", 100_000)]) },
+            3_000,
+        ));
+        assert_eq!(advanced, vec![1]);
+        assert_eq!(get_order_status(1), Some(OrderStatus::DepositReceived));
+        ORDERS.with(|orders| {
+            let orders = orders.borrow();
+            let order = orders.get(&1).unwrap();
+            assert_eq!(order.creator_txid, Some("deposit-tx".to_string()));
+            assert!(order.creator_deposited);
+            assert_eq!(order.deposit_confirmed_at, Some(3_000));
+            assert_eq!(order.creator_overpayment, None);
+        });
+        assert!(deposit_claims().is_empty());
+    }
+
+    #[test]
+    fn should_not_credit_deposit_on_amount_alone() {
+        insert_orders(vec![order(1, principal(1), OrderStatus::AwaitingDeposit)]);
+
+        let fetched = std::cell::Cell::new(false);
+        let advanced = poll_once(watch_deposits_with(
+            || async {
+                fetched.set(true);
+                Ok(vec![deposit("someone-elses-tx", 100_000)])
+            },
+            2_000,
+        ));
+        assert!(advanced.is_empty());
+        // With no claim there is nothing to watch for
+        assert!(!fetched.get());
+        assert_eq!(get_order_status(1), Some(OrderStatus::AwaitingDeposit));
+    }
+
+    #[test]
+    fn should_only_match_claimed_transactions() {
+        let mut short = order(2, principal(2), OrderStatus::AwaitingDeposit);
+        short.from_amount = 250_000;
+        let mut confirmed = order(3, principal(3), OrderStatus::DepositReceived);
+        confirmed.creator_txid = Some("used-tx".to_string());
+        let orders = vec![
+            order(1, principal(1), OrderStatus::AwaitingDeposit),
+            short,
+            confirmed,
+            order(4, principal(4), OrderStatus::AwaitingDeposit),
+        ];
+        let claims = [
+            (1, "split-tx".to_string()),
+            (2, "partial-tx".to_string()),
+            (3, "late-tx".to_string()),
+            (4, "used-tx".to_string()),
+        ];
+
+        let deposits = [
+            // Two outputs of one transaction add up to the order
+            deposit("split-tx", 60_000),
+            deposit("split-tx", 45_000),
+            deposit("partial-tx", 249_999),
+            deposit("late-tx", 100_000),
+            deposit("used-tx", 100_000),
+            deposit("unclaimed-tx", 250_000),
+        ];
+        assert_eq!(
+            match_deposits(&orders, &claims, &deposits),
+            vec![(1, "split-tx".to_string(), 105_000)]
+        );
+    }
+
+    #[test]
+    fn should_not_credit_canister_transactions() {
+        insert_orders(vec![order(1, principal(1), OrderStatus::AwaitingDeposit)]);
+        // A payout whose change output came back to the canister address
+        consume_txid("payout-tx");
+        claim_deposit(1, "payout-tx");
+
+        let advanced = poll_once(watch_deposits_with(
+            || async { Ok(vec![deposit("payout-tx", 100_000)]) },
+            2_000,
+        ));
+        assert!(advanced.is_empty());
+        assert_eq!(get_order_status(1), Some(OrderStatus::AwaitingDeposit));
+        assert!(deposit_claims().is_empty());
+    }
+
+    #[test]
+    fn should_leave_overpayment_to_an_operator() {
+        insert_orders(vec![order(1, principal(1), OrderStatus::AwaitingDeposit)]);
+        claim_deposit(1, "deposit-tx");
+
+        let advanced = poll_once(watch_deposits_with(
+            || async { Ok(vec![deposit("deposit-tx", 120_000)]) },
+            2_000,
+        ));
+        assert_eq!(advanced, vec![1]);
+        ORDERS.with(|orders| {
+            let order = orders.borrow()[&1].clone();
+            assert_eq!(order.creator_overpayment, Some(20_000));
+            assert!(order.manual_review.is_some());
+        });
+    }
+}
+
+mod operation_costs {
+    use crate::orders::CostMeter;
+    use crate::storage::get_operation_costs;
+
+    #[test]
+    fn should_record_average_cost_per_operation() {
+        assert!(get_operation_costs().is_empty());
+
+        CostMeter::starting_at(10_000_000).finish_at("reveal_secret", 9_000_000);
+        CostMeter::starting_at(9_000_000).finish_at("reveal_secret", 6_000_000);
+        CostMeter::starting_at(6_000_000).finish_at("accept_order", 5_500_000);
+
+        assert_eq!(
+            get_operation_costs(),
+            vec![
+                ("accept_order".to_string(), 500_000),
+                ("reveal_secret".to_string(), 2_000_000),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_not_underflow_when_balance_grows() {
+        CostMeter::starting_at(1_000).finish_at("process_refund", 2_000);
+        assert_eq!(
+            get_operation_costs(),
+            vec![("process_refund".to_string(), 0)]
+        );
+    }
+}
+
+mod settlement_failure {
+    use super::{insert_orders, order, poll_once, principal, utxo};
+    use crate::basic_bitcoin::service::send_from_p2wpkh_address::check_spendable;
+    use crate::orders::{check_settleable, pay_resolver};
+    use crate::storage::ORDERS;
+    use crate::types::OrderStatus;
+
+    #[test]
+    fn should_report_empty_utxo_set_as_insufficient_balance() {
+        let error = check_spendable(&[], 10_000).unwrap_err();
+        assert!(error.starts_with("InsufficientBalance"), "{}", error);
+
+        let utxo = utxo(0, 0, 5_000, 100);
+        assert!(check_spendable(std::slice::from_ref(&utxo), 10_000).is_err());
+        assert_eq!(check_spendable(&[utxo], 5_000), Ok(()));
+    }
+
+    #[test]
+    fn should_allow_retrying_failed_settlement_after_expiry() {
+        let mut failed = order(1, principal(1), OrderStatus::SettlementFailed);
+        let expiry = failed.expires_at;
+        assert!(!failed.status.is_terminal());
+        assert_eq!(check_settleable(&failed, expiry + 1), Ok(()));
+
+        failed.status = OrderStatus::ResolverDeposited;
+        assert_eq!(check_settleable(&failed, expiry - 1), Ok(()));
+        assert!(check_settleable(&failed, expiry).is_err());
+
+        failed.status = OrderStatus::DepositReceived;
+        assert!(check_settleable(&failed, expiry - 1).is_err());
+    }
+
+    #[test]
+    fn should_skip_resolver_payout_on_retry_after_it_was_sent() {
+        insert_orders(vec![order(1, principal(1), OrderStatus::ResolverDeposited)]);
+        let stored = || ORDERS.with(|orders| orders.borrow().get(&1).cloned().unwrap());
+
+        // The creator leg fails after this, before the settlement is recorded
+        let txid = poll_once(pay_resolver(&stored(), || async {
+            Ok("resolver-tx".to_string())
+        }));
+        assert_eq!(txid, Ok("resolver-tx".to_string()));
+        assert_eq!(
+            stored().resolver_payout_txid,
+            Some("resolver-tx".to_string())
+        );
+
+        let retried = poll_once(pay_resolver(&stored(), || async {
+            panic!("resolver must not be paid twice")
+        }));
+        assert_eq!(retried, Ok("resolver-tx".to_string()));
+    }
+}
+
+mod resolver_payout_address {
+    use super::{order, principal};
+    use crate::orders::{check_settleable, with_resolver_payout_address};
+    use crate::types::OrderStatus;
+
+    const CORRECTED_ADDRESS: &str = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx";
+
+    #[test]
+    fn should_settle_with_corrected_payout_address() {
+        let resolver = principal(2);
+        let mut failed = order(1, principal(1), OrderStatus::SettlementFailed);
+        failed.resolver = Some(resolver);
+        failed.resolver_btc_address = Some("tb1qfrozen".to_string());
+
+        let corrected =
+            with_resolver_payout_address(&failed, resolver, CORRECTED_ADDRESS.to_string()).unwrap();
+        assert_eq!(
+            corrected.resolver_btc_address.as_deref(),
+            Some(CORRECTED_ADDRESS)
+        );
+        assert_eq!(corrected.resolver_sol_address, failed.resolver_sol_address);
+        assert_eq!(check_settleable(&corrected, failed.expires_at + 1), Ok(()));
+    }
+
+    #[test]
+    fn should_reject_payout_address_change_by_others_or_once_paid() {
+        let resolver = principal(2);
+        let mut failed = order(1, principal(1), OrderStatus::SettlementFailed);
+        failed.resolver = Some(resolver);
+
+        let address = CORRECTED_ADDRESS.to_string();
+        assert!(with_resolver_payout_address(&failed, principal(3), address.clone()).is_err());
+        assert!(
+            with_resolver_payout_address(&failed, resolver, "not-an-address".to_string()).is_err()
+        );
+
+        failed.resolver_payout_txid = Some("paid".to_string());
+        assert!(with_resolver_payout_address(&failed, resolver, address.clone()).is_err());
+
+        failed.resolver_payout_txid = None;
+        failed.status = OrderStatus::Completed;
+        assert!(with_resolver_payout_address(&failed, resolver, address).is_err());
+    }
+}
+
+mod orders_in_range {
+    use super::{insert_orders, order, principal};
+    use crate::storage::get_orders_in_range;
+    use crate::types::{OrderStatus, TimeField};
+
+    const HOUR: u64 = 3_600_000_000_000;
+
+    fn ids(field: TimeField, from_ns: u64, to_ns: u64) -> Vec<u64> {
+        get_orders_in_range(from_ns, to_ns, field)
+            .unwrap()
+            .iter()
+            .map(|info| info.id)
+            .collect()
+    }
+
+    #[test]
+    fn should_filter_orders_to_sub_range() {
+        let orders = (1..=5)
+            .map(|id| {
+                let mut order = order(id, principal(1), OrderStatus::Completed);
+                order.created_at = id * HOUR;
+                order.completed_at = Some(id * HOUR + HOUR / 2);
+                order
+            })
+            .chain([order(6, principal(1), OrderStatus::AwaitingDeposit)])
+            .collect();
+        insert_orders(orders);
+
+        assert_eq!(ids(TimeField::CreatedAt, 2 * HOUR, 4 * HOUR), [2, 3, 4]);
+        // Order 6 was created at 1_000ns but has not completed
+        assert_eq!(ids(TimeField::CreatedAt, 0, HOUR), [6, 1]);
+        assert_eq!(ids(TimeField::CompletedAt, 2 * HOUR, 4 * HOUR), [2, 3]);
+        assert!(ids(TimeField::CompletedAt, 0, HOUR).is_empty());
+    }
+
+    #[test]
+    fn should_reject_inverted_range() {
+        assert!(get_orders_in_range(2 * HOUR, HOUR, TimeField::CreatedAt).is_err());
+    }
+}
+
+mod resolver_acknowledgement {
+    use super::{order, principal};
+    use crate::orders::{check_resolver_acknowledgement, check_settleable};
+    use crate::types::OrderStatus;
+
+    #[test]
+    fn should_settle_after_resolver_acknowledges() {
+        let resolver = principal(2);
+        let mut order = order(1, principal(1), OrderStatus::ResolverDeposited);
+        order.resolver = Some(resolver);
+        order.require_resolver_ack = true;
+        let now = order.created_at + 1;
+
+        assert!(check_settleable(&order, now).is_err());
+        assert!(check_resolver_acknowledgement(&order, principal(1)).is_err());
+
+        assert_eq!(check_resolver_acknowledgement(&order, resolver), Ok(()));
+        order.resolver_acknowledged_at = Some(now);
+        assert_eq!(check_settleable(&order, now), Ok(()));
+        assert!(check_resolver_acknowledgement(&order, resolver).is_err());
+    }
+
+    #[test]
+    fn should_settle_without_acknowledgement_unless_required() {
+        let mut order = order(1, principal(1), OrderStatus::ResolverDeposited);
+        order.resolver = Some(principal(2));
+        assert_eq!(check_settleable(&order, order.created_at + 1), Ok(()));
+
+        // Acknowledging before the resolver's deposit is confirmed is rejected
+        order.status = OrderStatus::DepositReceived;
+        assert!(check_resolver_acknowledgement(&order, principal(2)).is_err());
+    }
+}
+
+mod verification_result {
+    use crate::types::VerificationResult;
+
+    #[test]
+    fn should_explain_missing_transaction() {
+        let result = VerificationResult::not_found("Transaction not found");
+        assert!(!result.verified);
+        assert_eq!(result.observed_amount, 0);
+        assert_eq!(result.reason, Some("Transaction not found".to_string()));
+    }
+
+    #[test]
+    fn should_explain_insufficient_amount() {
+        let result = VerificationResult::assess(90_000, 100_000, Some(3), 0);
+        assert!(!result.verified);
+        assert_eq!(result.observed_amount, 90_000);
+        assert_eq!(
+            result.reason,
+            Some("Insufficient amount: observed 90000, expected 100000".to_string())
+        );
+    }
+
+    #[test]
+    fn should_wait_for_required_confirmations() {
+        let waiting = VerificationResult::assess(100_000, 100_000, Some(0), 1);
+        assert!(!waiting.verified);
+        assert_eq!(
+            waiting.reason,
+            Some("Seen with 0 confirmations, waiting for 1".to_string())
+        );
+
+        let verified = VerificationResult::assess(100_000, 100_000, Some(1), 1);
+        assert!(verified.verified);
+        assert_eq!(verified.reason, None);
+    }
+}
+
+mod utxo_consolidation {
+    use super::utxo;
+    use crate::basic_bitcoin::common::select_consolidation_utxos;
+
+    #[test]
+    fn should_consolidate_smallest_utxos() {
+        let utxos = [
+            utxo(1, 0, 5_000, 100),
+            utxo(2, 0, 1_000_000, 100),
+            utxo(3, 0, 2_000, 100),
+            utxo(4, 0, 3_000, 100),
+            utxo(5, 0, 4_000, 100),
+        ];
+
+        // 2 sat/vB: (11 + 3 * 68 + 31) vbytes * 2 = 492 satoshi
+        let (selected, fee) = select_consolidation_utxos(&utxos, 3, 2_000).unwrap();
+        let values: Vec<u64> = selected.iter().map(|utxo| utxo.value).collect();
+        assert_eq!(values, [2_000, 3_000, 4_000]);
+        assert_eq!(fee, 492);
+    }
+
+    #[test]
+    fn should_skip_consolidation_when_fee_exceeds_value() {
+        let utxos = [utxo(1, 0, 300, 100), utxo(2, 0, 400, 100)];
+        // 2 sat/vB: (11 + 2 * 68 + 31) vbytes * 2 = 356 satoshi for 700 satoshi of value
+        assert!(select_consolidation_utxos(&utxos, 2, 2_000).is_ok());
+        // 10 sat/vB: 1780 satoshi for 700 satoshi of value
+        assert!(select_consolidation_utxos(&utxos, 2, 10_000).is_err());
+        // A single UTXO cannot be consolidated
+        assert!(select_consolidation_utxos(&utxos, 1, 2_000).is_err());
+    }
+}
+
+mod canister_time {
+    use crate::storage::now_nanos;
+
+    #[test]
+    fn should_report_monotonic_nanoseconds() {
+        let first = now_nanos();
+        let second = now_nanos();
+        assert!(second >= first);
+        // Nanoseconds since epoch are past 2020-01-01 (1.577e18), unlike milliseconds (1.577e12)
+        assert!(first > 1_577_836_800_000_000_000);
+    }
+}
+
+mod spl_mint_allowlist {
+    use crate::orders::check_asset_allowed;
+    use crate::types::{Asset, SwapConfig};
+
+    const USDC: &str = "4zMMC9srt5Ri5X14GAgXhaHii3GnPAEERYPJgZJDncDU";
+    const UNKNOWN: &str = "So11111111111111111111111111111111111111112";
+
+    fn spl(mint_address: &str) -> Asset {
+        Asset::SplToken {
+            mint_address: mint_address.to_string(),
+            decimals: 6,
+        }
+    }
+
+    fn config(allow_any_spl_mint: bool) -> SwapConfig {
+        SwapConfig {
+            allowed_spl_mints: vec![USDC.to_string()],
+            allow_any_spl_mint,
+            ..SwapConfig::default()
+        }
+    }
+
+    #[test]
+    fn should_accept_allowlisted_mint() {
+        assert_eq!(check_asset_allowed(&config(false), &spl(USDC)), Ok(()));
+        assert_eq!(check_asset_allowed(&config(false), &Asset::Bitcoin), Ok(()));
+        assert_eq!(check_asset_allowed(&config(false), &Asset::Solana), Ok(()));
+    }
+
+    #[test]
+    fn should_reject_mint_not_on_allowlist() {
+        let error = check_asset_allowed(&config(false), &spl(UNKNOWN)).unwrap_err();
+        assert!(error.starts_with("MintNotAllowed"), "{}", error);
+    }
+
+    #[test]
+    fn should_accept_any_mint_in_open_mode() {
+        assert_eq!(check_asset_allowed(&config(true), &spl(UNKNOWN)), Ok(()));
+    }
+}
+
+mod deposit_instructions {
+    use super::{order, principal};
+    use crate::orders::deposit_instructions;
+    use crate::solana_integration::get_associated_token_account;
+    use crate::storage::{CANISTER_BTC_ADDRESS, CANISTER_SOL_ADDRESS};
+    use crate::types::{Asset, DepositLeg, OrderStatus};
+    use solana_pubkey::Pubkey;
+
+    const CANISTER_BTC: &str = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx";
+    const MINT: &str = "4zMMC9srt5Ri5X14GAgXhaHii3GnPAEERYPJgZJDncDU";
+
+    fn set_canister_addresses() -> String {
+        let canister_sol = Pubkey::new_from_array([7; 32]).to_string();
+        CANISTER_BTC_ADDRESS.with(|addr| *addr.borrow_mut() = Some(CANISTER_BTC.to_string()));
+        CANISTER_SOL_ADDRESS.with(|addr| *addr.borrow_mut() = Some(canister_sol.clone()));
+        canister_sol
+    }
+
+    #[test]
+    fn should_instruct_bitcoin_creator_deposit() {
+        set_canister_addresses();
+        let order = order(1, principal(1), OrderStatus::AwaitingDeposit);
+
+        let instructions = deposit_instructions(&order, &DepositLeg::Creator).unwrap();
+        assert_eq!(instructions.asset, Asset::Bitcoin);
+        assert_eq!(instructions.address_or_ata, CANISTER_BTC);
+        assert_eq!(instructions.amount, 100_000);
+        assert_eq!(instructions.decimals, 8);
+        assert_eq!(instructions.network, "Bitcoin Testnet");
+        assert_eq!(instructions.confirmations_required, 0);
+    }
+
+    #[test]
+    fn should_instruct_spl_resolver_deposit_to_ata() {
+        let canister_sol = set_canister_addresses();
+        let mut order = order(1, principal(1), OrderStatus::DepositReceived);
+        order.to_asset = Asset::SplToken {
+            mint_address: MINT.to_string(),
+            decimals: 6,
+        };
+        order.to_amount = 25_000_000;
+
+        let instructions = deposit_instructions(&order, &DepositLeg::Resolver).unwrap();
+        assert_eq!(
+            instructions.address_or_ata,
+            get_associated_token_account(&canister_sol, MINT).unwrap()
+        );
+        assert_ne!(instructions.address_or_ata, canister_sol);
+        assert_eq!(instructions.amount, 25_000_000);
+        assert_eq!(instructions.decimals, 6);
+        assert_eq!(instructions.network, "Solana Devnet");
+    }
+}
+
+mod solana_rpc_retry {
+    use super::poll_once;
+    use crate::solana_integration::retry_inconsistent;
+    use sol_rpc_types::{MultiRpcResult, RpcError, RpcSource, SupportedRpcProviderId};
+
+    fn inconsistent() -> MultiRpcResult<u64> {
+        MultiRpcResult::Inconsistent(vec![
+            (
+                RpcSource::Supported(SupportedRpcProviderId::AlchemyDevnet),
+                Ok(1_000),
+            ),
+            (
+                RpcSource::Supported(SupportedRpcProviderId::HeliusDevnet),
+                Ok(2_000),
+            ),
+        ])
+    }
+
+    #[test]
+    fn should_retry_after_inconsistent_response() {
+        let mut responses = vec![MultiRpcResult::Consistent(Ok(2_000)), inconsistent()];
+        let result = poll_once(retry_inconsistent(2, || {
+            let response = responses.pop().unwrap();
+            async move { response }
+        }));
+        assert_eq!(result, Ok(2_000));
+        assert!(responses.is_empty());
+    }
+
+    #[test]
+    fn should_give_up_after_configured_retries() {
+        let mut attempts = 0;
+        let result = poll_once(retry_inconsistent(2, || {
+            attempts += 1;
+            async { inconsistent() }
+        }));
+        assert!(matches!(result, Err(RpcError::ValidationError(_))));
+        assert_eq!(attempts, 3);
+    }
+}
+
+mod amount_overflow {
+    use super::utxo;
+    use crate::amounts::{checked_add, checked_sum};
+    use crate::basic_bitcoin::common::select_utxos_greedy;
+    use crate::basic_bitcoin::service::send_from_p2wpkh_address::check_spendable;
+
+    #[test]
+    fn should_report_overflowing_utxo_sum() {
+        let utxos = vec![utxo(0, 0, u64::MAX, 100), utxo(0, 1, 1, 100)];
+
+        let error = check_spendable(&utxos, 10_000).unwrap_err();
+        assert!(error.starts_with("AmountOverflow"), "{}", error);
+
+        let error = select_utxos_greedy(&utxos, u64::MAX, 1_000).unwrap_err();
+        assert!(error.starts_with("AmountOverflow"), "{}", error);
+    }
+
+    #[test]
+    fn should_add_amounts_within_range() {
+        assert_eq!(checked_sum([1, 2, 3]), Ok(6));
+        assert_eq!(checked_add(u64::MAX - 1, 1), Ok(u64::MAX));
+        assert!(checked_add(u64::MAX, 1).is_err());
+    }
+}
+
+mod public_keys {
+    use crate::basic_bitcoin::service::get_p2wpkh_address::p2wpkh_address_from_public_key;
+    use bitcoin::Network;
+
+    // Compressed secp256k1 generator point and its P2WPKH address, from BIP-173
+    const PUBKEY_HEX: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+    #[test]
+    fn should_derive_p2wpkh_address_from_returned_public_key() {
+        let public_key = hex::decode(PUBKEY_HEX).unwrap();
+        assert_eq!(
+            p2wpkh_address_from_public_key(&public_key, Network::Bitcoin).unwrap(),
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"
+        );
+    }
+
+    #[test]
+    fn should_reject_uncompressed_public_key() {
+        let mut public_key = hex::decode(PUBKEY_HEX).unwrap();
+        public_key[0] = 0x04;
+        assert!(p2wpkh_address_from_public_key(&public_key, Network::Bitcoin).is_err());
+    }
+}
+
+mod solana_memo {
+    use super::{order, principal};
+    use crate::orders::{deposit_instructions, solana_deposit_memo};
+    use crate::solana_integration::{has_memo, MEMO_PROGRAM_ID};
+    use crate::storage::{CANISTER_SOL_ADDRESS, CONFIG};
+    use crate::types::{Asset, DepositLeg, OrderStatus};
+    use solana_instruction::Instruction;
+    use solana_message::{Message, VersionedMessage};
+    use solana_pubkey::Pubkey;
+    use solana_system_interface::instruction::transfer;
+    use std::str::FromStr;
+
+    fn transfer_with_memo(memo: &str) -> VersionedMessage {
+        let payer = Pubkey::new_from_array([1; 32]);
+        let canister = Pubkey::new_from_array([7; 32]);
+        let memo = Instruction::new_with_bytes(
+            Pubkey::from_str(MEMO_PROGRAM_ID).unwrap(),
+            memo.as_bytes(),
+            vec![],
+        );
+        VersionedMessage::Legacy(Message::new(
+            &[transfer(&payer, &canister, 1_000_000_000), memo],
+            Some(&payer),
+        ))
+    }
+
+    #[test]
+    fn should_not_credit_transfer_with_wrong_memo() {
+        let expected = solana_deposit_memo(1);
+        assert!(has_memo(&transfer_with_memo(&expected), &expected));
+        assert!(!has_memo(
+            &transfer_with_memo(&solana_deposit_memo(2)),
+            &expected
+        ));
+
+        let payer = Pubkey::new_from_array([1; 32]);
+        let without_memo = VersionedMessage::Legacy(Message::new(
+            &[transfer(&payer, &Pubkey::new_from_array([7; 32]), 1_000)],
+            Some(&payer),
+        ));
+        assert!(!has_memo(&without_memo, &expected));
+    }
+
+    #[test]
+    fn should_include_memo_in_sol_deposit_instructions_when_required() {
+        let canister_sol = Pubkey::new_from_array([7; 32]).to_string();
+        CANISTER_SOL_ADDRESS.with(|addr| *addr.borrow_mut() = Some(canister_sol));
+        let order = order(5, principal(1), OrderStatus::DepositReceived);
+
+        let instructions = deposit_instructions(&order, &DepositLeg::Resolver).unwrap();
+        assert_eq!(instructions.asset, Asset::Solana);
+        assert_eq!(instructions.memo, None);
+
+        CONFIG.with(|config| config.borrow_mut().require_solana_memo = true);
+        let instructions = deposit_instructions(&order, &DepositLeg::Resolver).unwrap();
+        assert_eq!(instructions.memo, Some(solana_deposit_memo(5)));
+
+        // Bitcoin deposits have their own address per order, so they never need a memo
+        let instructions = deposit_instructions(&order, &DepositLeg::Creator).unwrap();
+        assert_eq!(instructions.memo, None);
+        CONFIG.with(|config| config.borrow_mut().require_solana_memo = false);
+    }
+}
+
+mod chain_circuit_breaker {
+    use crate::storage::{
+        check_chain_available, get_chain_status, record_chain_failure, record_chain_success,
+        CHAIN_FAILURE_THRESHOLD, CHAIN_RETRY_AFTER_NANOS,
+    };
+    use crate::types::Chain;
+
+    const NOW: u64 = 1_000_000_000_000;
+
+    #[test]
+    fn should_trip_after_repeated_failures_and_reset_on_success() {
+        for _ in 0..CHAIN_FAILURE_THRESHOLD - 1 {
+            record_chain_failure(&Chain::Solana, "sol-rpc call rejected", NOW);
+        }
+        assert_eq!(check_chain_available(&Chain::Solana, NOW), Ok(()));
+
+        record_chain_failure(&Chain::Solana, "sol-rpc call rejected", NOW);
+        let error = check_chain_available(&Chain::Solana, NOW).unwrap_err();
+        assert!(error.starts_with("ChainUnavailable"), "{}", error);
+        assert!(!get_chain_status(&Chain::Solana, NOW).available);
+
+        // The other chain keeps working
+        assert_eq!(check_chain_available(&Chain::Bitcoin, NOW), Ok(()));
+
+        // After the retry delay a probe is let through, and its success closes the breaker
+        let later = NOW + CHAIN_RETRY_AFTER_NANOS;
+        assert_eq!(check_chain_available(&Chain::Solana, later), Ok(()));
+        record_chain_success(&Chain::Solana);
+
+        let status = get_chain_status(&Chain::Solana, later);
+        assert!(status.available);
+        assert_eq!(status.consecutive_failures, 0);
+        assert_eq!(status.last_error, None);
+    }
+
+    #[test]
+    fn should_trip_again_when_probe_fails() {
+        for _ in 0..CHAIN_FAILURE_THRESHOLD {
+            record_chain_failure(&Chain::Bitcoin, "Failed to get UTXOs", NOW);
+        }
+        let later = NOW + CHAIN_RETRY_AFTER_NANOS;
+        assert_eq!(check_chain_available(&Chain::Bitcoin, later), Ok(()));
+
+        record_chain_failure(&Chain::Bitcoin, "Failed to get UTXOs", later);
+        assert!(check_chain_available(&Chain::Bitcoin, later + 1).is_err());
+        record_chain_success(&Chain::Bitcoin);
+    }
+}
+
+mod candid_interface {
+    use candid_parser::utils::{service_equal, CandidSource};
+    use std::path::Path;
+
+    #[test]
+    fn should_match_committed_candid_interface() {
+        let generated = crate::__export_service();
+        let committed = Path::new(env!("CARGO_MANIFEST_DIR")).join("intentswaps_backend.did");
+
+        service_equal(
+            CandidSource::Text(&generated),
+            CandidSource::File(&committed),
+        )
+        .unwrap_or_else(|e| {
+            panic!(
+                "The generated Candid interface differs from intentswaps_backend.did; \
+                 update the .did if the change is intended: {:?}",
+                e
+            )
+        });
+    }
+}
+
+mod resolver_secret_reveal {
+    use super::{order, principal};
+    use crate::orders::{check_secret_reveal, PayoutLock};
+    use crate::types::OrderStatus;
+
+    #[test]
+    fn should_let_resolver_settle_with_valid_secret_only() {
+        let mut order = order(1, principal(1), OrderStatus::ResolverDeposited);
+        order.resolver = Some(principal(2));
+
+        assert_eq!(
+            check_secret_reveal(&order, principal(2), "secret", 2_000),
+            Ok(())
+        );
+        assert_eq!(
+            check_secret_reveal(&order, principal(2), "wrong", 2_000),
+            Err("Secret does not match hash".to_string())
+        );
+    }
+
+    #[test]
+    fn should_reject_reveal_by_third_party() {
+        let mut order = order(1, principal(1), OrderStatus::ResolverDeposited);
+        order.resolver = Some(principal(2));
+
+        assert_eq!(
+            check_secret_reveal(&order, principal(1), "secret", 2_000),
+            Ok(())
+        );
+        assert!(check_secret_reveal(&order, principal(3), "secret", 2_000).is_err());
+    }
+
+    #[test]
+    fn should_reject_second_payout_while_one_is_in_flight() {
+        let lock = PayoutLock::acquire(1).unwrap();
+        let error = PayoutLock::acquire(1).err().unwrap();
+        assert!(error.starts_with("InProgress"), "{}", error);
+        // Other orders are unaffected
+        assert!(PayoutLock::acquire(2).is_ok());
+
+        // A failed payout releases the order for a retry
+        drop(lock);
+        assert!(PayoutLock::acquire(1).is_ok());
+    }
+}
+
+mod acceptable_orders {
+    use super::{insert_orders, order, principal};
+    use crate::storage::{get_order, is_acceptable_by};
+    use crate::types::OrderStatus;
+
+    #[test]
+    fn should_filter_out_orders_resolver_created_or_accepted() {
+        let resolver = principal(2);
+        let open = order(1, principal(1), OrderStatus::DepositReceived);
+        let own = order(2, resolver, OrderStatus::DepositReceived);
+        let mut accepted = order(3, principal(1), OrderStatus::DepositReceived);
+        accepted.resolver = Some(resolver);
+        let mut taken_by_other = order(4, principal(1), OrderStatus::DepositReceived);
+        taken_by_other.resolver = Some(principal(3));
+        let awaiting = order(5, principal(1), OrderStatus::AwaitingDeposit);
+        insert_orders(vec![open, own, accepted, taken_by_other, awaiting]);
+
+        let acceptable: Vec<u64> = (1..=5)
+            .filter_map(get_order)
+            .filter(|order| is_acceptable_by(order, resolver, 2_000))
+            .map(|order| order.id)
+            .collect();
+        assert_eq!(acceptable, vec![1, 4]);
+
+        // Expired orders cannot be accepted either
+        let open = get_order(1).unwrap();
+        assert!(!is_acceptable_by(&open, resolver, open.expires_at));
+    }
+}
+
+mod opaque_order_ids {
+    use super::principal;
+    use crate::storage::{generate_order_id, opaque_order_id, CONFIG};
+
+    #[test]
+    fn should_keep_sequential_ids_by_default() {
+        let first = generate_order_id(principal(9), 1_000);
+        let second = generate_order_id(principal(9), 1_000);
+        assert_eq!(first.0, first.1);
+        assert_eq!(second, (first.0 + 1, first.1 + 1));
+    }
+
+    #[test]
+    fn should_generate_unique_non_sequential_ids_in_opaque_mode() {
+        CONFIG.with(|config| config.borrow_mut().opaque_order_ids = true);
+
+        let generated: Vec<(u64, u64)> = (0..100)
+            .map(|_| generate_order_id(principal(9), 1_000))
+            .collect();
+        let ids: std::collections::HashSet<u64> = generated.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids.len(), generated.len());
+
+        for pair in generated.windows(2) {
+            let ((id, sequence), (next_id, next_sequence)) = (pair[0], pair[1]);
+            assert_eq!(next_sequence, sequence + 1);
+            assert_ne!(next_id, id + 1);
+            assert_ne!(id, sequence);
+        }
+
+        CONFIG.with(|config| config.borrow_mut().opaque_order_ids = false);
+    }
+
+    #[test]
+    fn should_derive_opaque_id_from_every_input() {
+        let canister = principal(9);
+        let base = opaque_order_id(1, canister.as_slice(), 1_000, 0);
+        assert_eq!(base, opaque_order_id(1, canister.as_slice(), 1_000, 0));
+        assert_ne!(base, opaque_order_id(2, canister.as_slice(), 1_000, 0));
+        assert_ne!(base, opaque_order_id(1, principal(8).as_slice(), 1_000, 0));
+        assert_ne!(base, opaque_order_id(1, canister.as_slice(), 1_001, 0));
+        assert_ne!(base, opaque_order_id(1, canister.as_slice(), 1_000, 1));
+    }
+}
+
+mod bitcoin_tx_size {
+    use super::{order, poll_once, principal, utxo};
+    use crate::basic_bitcoin::{p2wpkh::estimate_transaction_vsize, BTC_CONTEXT};
+    use crate::orders::bitcoin_payout;
+    use crate::types::{Asset, OrderStatus};
+    use bitcoin::{
+        absolute::LockTime, ecdsa::Signature, hashes::Hash,
+        secp256k1::ecdsa::Signature as SecpSignature, sighash::EcdsaSighashType,
+        transaction::Version, Address, Amount, CompressedPublicKey, Network, OutPoint, PublicKey,
+        ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, Witness,
+    };
+    use std::str::FromStr;
+
+    const PUBKEY_HEX: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    const DESTINATION: &str = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx";
+
+    #[test]
+    fn should_report_size_of_manually_built_payout() {
+        let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+        let public_key = PublicKey::from_slice(&hex::decode(PUBKEY_HEX).unwrap()).unwrap();
+        let own_address = Address::p2wpkh(
+            &CompressedPublicKey::try_from(public_key).unwrap(),
+            Network::Testnet,
+        );
+        let dst_address = Address::from_str(DESTINATION).unwrap().assume_checked();
+        let utxos = vec![utxo(1, 0, 100_000, 100)];
+
+        let estimated = poll_once(estimate_transaction_vsize(
+            &ctx,
+            &public_key,
+            &own_address,
+            &utxos,
+            &dst_address,
+            50_000,
+            2_000,
+        ))
+        .unwrap();
+
+        // One P2WPKH input signed with the same fixed signature, paying the destination plus change
+        let signature = Signature {
+            signature: SecpSignature::from_compact(&[1; 64]).unwrap(),
+            sighash_type: EcdsaSighashType::All,
+        };
+        let manual = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: Txid::from_byte_array([1; 32]),
+                    vout: 0,
+                },
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::from_slice(&[signature.to_vec(), public_key.to_bytes()]),
+            }],
+            output: vec![
+                TxOut {
+                    value: Amount::from_sat(50_000),
+                    script_pubkey: dst_address.script_pubkey(),
+                },
+                TxOut {
+                    value: Amount::from_sat(100_000 - 50_000 - 282),
+                    script_pubkey: own_address.script_pubkey(),
+                },
+            ],
+        };
+
+        assert_eq!(estimated, manual.vsize() as u64);
+        assert_eq!(estimated, 141);
+    }
+
+    #[test]
+    fn should_pick_bitcoin_payout_leg() {
+        let mut order = order(1, principal(1), OrderStatus::ResolverDeposited);
+        assert!(bitcoin_payout(&order).is_err());
+
+        order.resolver_btc_address = Some("tb1qresolver".to_string());
+        assert_eq!(
+            bitcoin_payout(&order),
+            Ok(("tb1qresolver".to_string(), 100_000))
+        );
+
+        order.from_asset = Asset::Solana;
+        order.to_asset = Asset::Bitcoin;
+        assert_eq!(
+            bitcoin_payout(&order),
+            Ok(("tb1qcreator".to_string(), order.to_amount))
+        );
+
+        order.to_asset = Asset::Solana;
+        assert!(bitcoin_payout(&order).is_err());
+    }
+}
+
+mod order_registration {
+    use super::{order, principal};
+    use crate::orders::register_order;
+    use crate::storage::{get_order, CONFIG, NEXT_ORDER_ID};
+    use crate::types::{Asset, AssetLimit, OrderStatus};
+
+    #[test]
+    fn should_not_burn_id_when_creation_fails() {
+        CONFIG.with(|config| {
+            config.borrow_mut().max_order_amounts = vec![AssetLimit {
+                asset: Asset::Bitcoin,
+                amount: 50_000,
+            }]
+        });
+        let next_id = NEXT_ORDER_ID.with(|id| *id.borrow());
+
+        let too_large = order(0, principal(1), OrderStatus::AwaitingDeposit);
+        assert!(register_order(too_large, principal(9)).is_err());
+        assert_eq!(NEXT_ORDER_ID.with(|id| *id.borrow()), next_id);
+
+        let mut fits = order(0, principal(1), OrderStatus::AwaitingDeposit);
+        fits.from_amount = 50_000;
+        assert_eq!(register_order(fits, principal(9)), Ok(next_id));
+
+        let stored = get_order(next_id).unwrap();
+        assert_eq!(stored.id, next_id);
+        assert_eq!(stored.from_amount, 50_000);
+
+        CONFIG.with(|config| config.borrow_mut().max_order_amounts.clear());
+    }
+}
+
+mod force_expire {
+    use super::{insert_orders, order, principal};
+    use crate::orders::{check_force_expirable, mark_expired};
+    use crate::storage::{open_order_count, track_open_order, ORDERS};
+    use crate::types::{Asset, OrderStatus};
+
+    #[test]
+    fn should_force_expire_and_refund_wedged_order() {
+        let mut wedged = order(1, principal(1), OrderStatus::ResolverDeposited);
+        wedged.resolver = Some(principal(2));
+        wedged.creator_deposited = true;
+        wedged.resolver_deposited = true;
+        track_open_order(&wedged);
+        insert_orders(vec![wedged.clone()]);
+
+        // Both deposits are refunded even though the order has not reached `expires_at`
+        assert_eq!(check_force_expirable(&wedged), Ok((true, true)));
+
+        mark_expired(1);
+        let expired = ORDERS.with(|orders| orders.borrow()[&1].clone());
+        assert_eq!(expired.status, OrderStatus::Expired);
+        assert_eq!(open_order_count(&Asset::Bitcoin), 0);
+        assert!(check_force_expirable(&expired).is_err());
+    }
+
+    #[test]
+    fn should_refuse_completed_or_paid_out_orders() {
+        let completed = order(1, principal(1), OrderStatus::Completed);
+        assert_eq!(
+            check_force_expirable(&completed),
+            Err("Completed orders cannot be force-expired".to_string())
+        );
+
+        let mut half_settled = order(2, principal(1), OrderStatus::SettlementFailed);
+        half_settled.resolver_payout_txid = Some("payout".to_string());
+        assert!(check_force_expirable(&half_settled).is_err());
+
+        let awaiting = order(3, principal(1), OrderStatus::AwaitingDeposit);
+        assert_eq!(check_force_expirable(&awaiting), Ok((false, false)));
+    }
+}
+
+mod token_balances {
+    use crate::solana_integration::{
+        parse_token_accounts, reconcile_token_accounts, SPL_TOKEN_PROGRAM_ID, TOKEN_2022_PROGRAM_ID,
+    };
+    use sol_rpc_types::{MultiRpcResult, RpcSource, SupportedRpcProviderId};
+
+    const USDC_MINT: &str = "4zMMC9srt5Ri5X14GAgXhaHii3GnPAEERYPJgZJDncDU";
+    const PYUSD_MINT: &str = "2b1kV6DkPAnxd5ixfnxCpjxmKwqjjaYmCZfHsFu24GXo";
+
+    fn token_account(pubkey: &str, mint: &str, program: &str, amount: u64, decimals: u8) -> String {
+        format!(
+            r#"{{"pubkey":"{pubkey}","account":{{"owner":"{program}","lamports":2039280,"data":{{"program":"spl-token","parsed":{{"type":"account","info":{{"mint":"{mint}","owner":"9WzDXwBbmkg8ZTbNMqUxvQRAyrZzDsGYdLVL9zYtAWWM","tokenAmount":{{"amount":"{amount}","decimals":{decimals},"uiAmountString":"0"}}}}}}}}}}}}"#
+        )
+    }
+
+    fn response(slot: u64, accounts: &[String]) -> String {
+        format!(
+            r#"{{"jsonrpc":"2.0","result":{{"context":{{"slot":{slot}}},"value":[{}]}},"id":1}}"#,
+            accounts.join(",")
+        )
+    }
+
+    #[test]
+    fn should_parse_every_token_account() {
+        let json = response(
+            100,
+            &[
+                token_account("AccountA", USDC_MINT, SPL_TOKEN_PROGRAM_ID, 25_000_000, 6),
+                token_account("AccountB", PYUSD_MINT, TOKEN_2022_PROGRAM_ID, 0, 6),
+            ],
+        );
+
+        let balances = parse_token_accounts(&json).unwrap();
+        assert_eq!(balances.len(), 2);
+        assert_eq!(balances[0].token_account, "AccountA");
+        assert_eq!(balances[0].mint_address, USDC_MINT);
+        assert_eq!(balances[0].token_program, SPL_TOKEN_PROGRAM_ID);
+        assert_eq!(balances[0].amount, 25_000_000);
+        assert_eq!(balances[0].decimals, 6);
+        assert_eq!(balances[1].mint_address, PYUSD_MINT);
+        assert_eq!(balances[1].token_program, TOKEN_2022_PROGRAM_ID);
+        assert_eq!(balances[1].amount, 0);
+    }
+
+    #[test]
+    fn should_return_empty_list_without_token_accounts() {
+        assert_eq!(parse_token_accounts(&response(100, &[])), Ok(vec![]));
+        assert!(
+            parse_token_accounts(r#"{"jsonrpc":"2.0","error":{"code":-32602},"id":1}"#).is_err()
+        );
+    }
+
+    #[test]
+    fn should_agree_on_accounts_reported_at_different_slots() {
+        let accounts = [token_account(
+            "AccountA",
+            USDC_MINT,
+            SPL_TOKEN_PROGRAM_ID,
+            5,
+            6,
+        )];
+        let reconciled = reconcile_token_accounts(MultiRpcResult::Inconsistent(vec![
+            (
+                RpcSource::Supported(SupportedRpcProviderId::AlchemyDevnet),
+                Ok(response(100, &accounts)),
+            ),
+            (
+                RpcSource::Supported(SupportedRpcProviderId::HeliusDevnet),
+                Ok(response(101, &accounts)),
+            ),
+            (
+                RpcSource::Supported(SupportedRpcProviderId::AnkrDevnet),
+                Ok(response(101, &[])),
+            ),
+        ]));
+
+        match reconciled {
+            MultiRpcResult::Consistent(Ok(balances)) => {
+                assert_eq!(balances.len(), 1);
+                assert_eq!(balances[0].amount, 5);
+            }
+            other => panic!("expected consistent balances, got {:?}", other),
+        }
+    }
+}
+
+mod deferred_secret {
+    use super::{insert_orders, order, principal};
+    use crate::orders::{mark_settled, mark_settlement_failed};
+    use crate::storage::{CONFIG, ORDERS};
+    use crate::types::{Order, OrderStatus};
+
+    fn stored(order_id: u64) -> Order {
+        ORDERS.with(|orders| orders.borrow()[&order_id].clone())
+    }
+
+    #[test]
+    fn should_keep_secret_unset_until_both_legs_finish() {
+        CONFIG.with(|config| config.borrow_mut().defer_secret_until_settled = true);
+        let mut settling = order(1, principal(1), OrderStatus::ResolverDeposited);
+        settling.resolver = Some(principal(2));
+        insert_orders(vec![settling]);
+
+        // Resolver leg paid, creator leg failed
+        mark_settlement_failed(1, principal(1), "secret", Some(&"resolver-tx".to_string()));
+        let failed = stored(1);
+        assert_eq!(failed.status, OrderStatus::SettlementFailed);
+        assert_eq!(failed.resolver_payout_txid, Some("resolver-tx".to_string()));
+        assert_eq!(failed.secret, None);
+
+        mark_settled(
+            1,
+            principal(1),
+            "secret".to_string(),
+            "resolver-tx",
+            "creator-tx",
+            2_000,
+        );
+        let completed = stored(1);
+        assert_eq!(completed.status, OrderStatus::Completed);
+        assert_eq!(completed.secret, Some("secret".to_string()));
+        assert_eq!(completed.completed_at, Some(2_000));
+
+        CONFIG.with(|config| config.borrow_mut().defer_secret_until_settled = false);
+    }
+
+    #[test]
+    fn should_store_secret_on_failure_by_default() {
+        insert_orders(vec![order(1, principal(1), OrderStatus::ResolverDeposited)]);
+        mark_settlement_failed(1, principal(1), "secret", None);
+        assert_eq!(stored(1).secret, Some("secret".to_string()));
+    }
+}
+
+mod settlement_economics {
+    use super::{order, principal};
+    use crate::orders::check_settlement_economics;
+    use crate::types::{Asset, OrderStatus};
+
+    #[test]
+    fn should_refuse_settlement_when_fee_exceeds_spare_funds() {
+        let order = order(1, principal(1), OrderStatus::ResolverDeposited);
+        let fees = [(Asset::Bitcoin, 1_410), (Asset::Solana, 5_000)];
+        let surplus = [(Asset::Bitcoin, 1_000), (Asset::Solana, 1_000_000)];
+
+        let error = check_settlement_economics(&order, &fees, &surplus).unwrap_err();
+        assert!(error.starts_with("UneconomicalSettlement"), "{}", error);
+    }
+
+    #[test]
+    fn should_settle_when_surplus_covers_fees() {
+        let order = order(1, principal(1), OrderStatus::ResolverDeposited);
+        let fees = [(Asset::Bitcoin, 1_410), (Asset::Solana, 5_000)];
+        let surplus = [(Asset::Bitcoin, 1_410), (Asset::Solana, 5_000)];
+
+        assert_eq!(check_settlement_economics(&order, &fees, &surplus), Ok(()));
+    }
+
+    #[test]
+    fn should_sum_fees_of_both_legs_on_the_same_chain() {
+        let mut order = order(1, principal(1), OrderStatus::ResolverDeposited);
+        order.from_asset = Asset::Solana;
+        order.to_asset = Asset::SplToken {
+            mint_address: "4zMMC9srt5Ri5X14GAgXhaHii3GnPAEERYPJgZJDncDU".to_string(),
+            decimals: 6,
+        };
+        let fees = [(Asset::Solana, 5_000), (Asset::Solana, 5_000)];
+
+        assert!(check_settlement_economics(&order, &fees, &[(Asset::Solana, 5_000)]).is_err());
+        assert_eq!(
+            check_settlement_economics(&order, &fees, &[(Asset::Solana, 10_000)]),
+            Ok(())
+        );
+    }
+}
+
+mod actionable_orders {
+    use super::{insert_orders, order, principal};
+    use crate::storage::{get_actionable_orders, next_action};
+    use crate::types::{DepositLeg, OrderAction, OrderStatus};
+
+    const NOW: u64 = 2_000;
+
+    #[test]
+    fn should_classify_creator_actions() {
+        let creator = DepositLeg::Creator;
+        let awaiting = order(1, principal(1), OrderStatus::AwaitingDeposit);
+        assert_eq!(next_action(&awaiting, &creator, NOW), OrderAction::Deposit);
+
+        let deposited = order(2, principal(1), OrderStatus::DepositReceived);
+        assert_eq!(
+            next_action(&deposited, &creator, NOW),
+            OrderAction::WaitForResolver
+        );
+        assert_eq!(
+            next_action(&deposited, &creator, deposited.expires_at),
+            OrderAction::ClaimRefund
+        );
+
+        let mut ready = order(3, principal(1), OrderStatus::ResolverDeposited);
+        assert_eq!(
+            next_action(&ready, &creator, NOW),
+            OrderAction::RevealSecret
+        );
+        ready.require_resolver_ack = true;
+        assert_eq!(
+            next_action(&ready, &creator, NOW),
+            OrderAction::WaitForResolver
+        );
+
+        let failed = order(4, principal(1), OrderStatus::SettlementFailed);
+        assert_eq!(
+            next_action(&failed, &creator, NOW),
+            OrderAction::RevealSecret
+        );
+
+        let completed = order(5, principal(1), OrderStatus::Completed);
+        assert_eq!(next_action(&completed, &creator, NOW), OrderAction::None);
+    }
+
+    #[test]
+    fn should_classify_resolver_actions() {
+        let resolver = DepositLeg::Resolver;
+        let accepted = order(1, principal(1), OrderStatus::DepositReceived);
+        assert_eq!(next_action(&accepted, &resolver, NOW), OrderAction::Deposit);
+
+        let mut deposited = order(2, principal(1), OrderStatus::ResolverDeposited);
+        assert_eq!(next_action(&deposited, &resolver, NOW), OrderAction::None);
+        assert_eq!(
+            next_action(&deposited, &resolver, deposited.expires_at),
+            OrderAction::ClaimRefund
+        );
+        deposited.require_resolver_ack = true;
+        assert_eq!(
+            next_action(&deposited, &resolver, NOW),
+            OrderAction::Acknowledge
+        );
+    }
+
+    #[test]
+    fn should_list_open_orders_for_each_role_of_the_caller() {
+        let caller = principal(2);
+        let created = order(1, caller, OrderStatus::AwaitingDeposit);
+        let mut accepted = order(2, principal(1), OrderStatus::DepositReceived);
+        accepted.resolver = Some(caller);
+        let unrelated = order(3, principal(1), OrderStatus::DepositReceived);
+        let mut finished = order(4, caller, OrderStatus::Completed);
+        finished.resolver = Some(principal(3));
+        insert_orders(vec![created, accepted, unrelated, finished]);
+
+        let actions: Vec<(u64, DepositLeg, OrderAction)> = get_actionable_orders(caller, NOW)
+            .into_iter()
+            .map(|entry| (entry.order_id, entry.role, entry.action))
+            .collect();
+        assert_eq!(
+            actions,
+            vec![
+                (1, DepositLeg::Creator, OrderAction::Deposit),
+                (2, DepositLeg::Resolver, OrderAction::Deposit),
+            ]
+        );
+    }
+}
+
+mod resolver_refund {
+    use super::{insert_orders, order, principal};
+    use crate::orders::{check_resolver_refund, mark_resolver_refunded, refund_legs};
+    use crate::storage::ORDERS;
+    use crate::types::{Order, OrderStatus};
+
+    fn resolver_deposited() -> Order {
+        let mut order = order(1, principal(1), OrderStatus::ResolverDeposited);
+        order.resolver = Some(principal(2));
+        order.resolver_btc_address = Some("tb1qresolver".to_string());
+        order.resolver_sol_address = Some("ResolverSolAddress".to_string());
+        order.creator_deposited = true;
+        order.resolver_deposited = true;
+        order
+    }
+
+    #[test]
+    fn should_refund_only_resolver_leg_after_expiry() {
+        let order = resolver_deposited();
+        let expired = order.expires_at;
+        assert!(check_resolver_refund(&order, principal(2), expired - 1).is_err());
+        assert!(check_resolver_refund(&order, principal(1), expired).is_err());
+        assert_eq!(check_resolver_refund(&order, principal(2), expired), Ok(()));
+
+        insert_orders(vec![order]);
+        mark_resolver_refunded(1, "resolver-refund", expired);
+        let refunded = ORDERS.with(|orders| orders.borrow()[&1].clone());
+
+        // The creator's deposit is still held and refundable; the resolver cannot claim twice
+        assert_eq!(refunded.status, OrderStatus::ResolverDeposited);
+        assert_eq!(refund_legs(&refunded), (true, false));
+        assert!(check_resolver_refund(&refunded, principal(2), expired).is_err());
+    }
+
+    #[test]
+    fn should_close_order_when_no_creator_deposit_remains() {
+        let mut order = resolver_deposited();
+        order.creator_deposited = false;
+        insert_orders(vec![order]);
+
+        mark_resolver_refunded(1, "resolver-refund", 5_000);
+        let refunded = ORDERS.with(|orders| orders.borrow()[&1].clone());
+        assert_eq!(refunded.status, OrderStatus::Cancelled);
+        assert_eq!(refunded.cancelled_at, Some(5_000));
+    }
+
+    #[test]
+    fn should_not_refund_resolver_already_paid_by_settlement() {
+        let mut order = resolver_deposited();
+        order.status = OrderStatus::SettlementFailed;
+        order.resolver_payout_txid = Some("payout".to_string());
+        assert!(check_resolver_refund(&order, principal(2), order.expires_at).is_err());
+    }
+
+    #[test]
+    fn should_not_refund_a_leg_twice() {
+        let mut order = resolver_deposited();
+        order.status = OrderStatus::Cancelled;
+        assert_eq!(refund_legs(&order), (true, true));
+
+        order.creator_refund_amount = Some(100_000);
+        assert_eq!(refund_legs(&order), (false, true));
+        // A partial refund counts too; what was kept back paid the network fees
+        order.resolver_refund_amount = Some(999_000_000);
+        assert_eq!(refund_legs(&order), (false, false));
+    }
+}
+
+mod order_indexes {
+    use super::{insert_orders, order, poll_once, principal};
+    use crate::deposit_watcher::{watch_deposits_with, DetectedDeposit};
+    use crate::orders::{mark_settled, register_order};
+    use crate::storage::{
+        claim_deposit, expired_order_ids, pending_order_ids, principal_order_ids, update_order,
+        OrderIndexes, MAX_PENDING_SCAN, ORDERS, ORDER_INDEXES,
+    };
+    use crate::types::{Asset, OrderStatus};
+
+    // The maintained indexes match ones built from scratch over the stored orders
+    fn assert_consistent() {
+        let mut rebuilt = OrderIndexes::default();
+        ORDERS.with(|orders| {
+            orders
+                .borrow()
+                .values()
+                .for_each(|order| rebuilt.insert(order))
+        });
+        ORDER_INDEXES.with(|indexes| assert_eq!(*indexes.borrow(), rebuilt));
+    }
+
+    fn confirm_deposit(order_id: u64, txid: &str, amount: u64, now: u64) -> Vec<u64> {
+        claim_deposit(order_id, txid);
+        let deposit = DetectedDeposit {
+            asset: Asset::Bitcoin,
+            txid: txid.to_string(),
+            amount,
+        };
+        poll_once(watch_deposits_with(|| async { Ok(vec![deposit]) }, now))
+    }
+
+    #[test]
+    fn should_keep_indexes_in_step_through_create_confirm_and_complete() {
+        let creator = principal(1);
+        let resolver = principal(2);
+
+        let mut created = order(0, creator, OrderStatus::AwaitingDeposit);
+        created.expires_at = 10_000;
+        let order_id = register_order(created, principal(9)).unwrap();
+        assert_consistent();
+        assert_eq!(principal_order_ids(creator), vec![order_id]);
+        assert!(pending_order_ids(1_000).is_empty());
+
+        assert_eq!(
+            confirm_deposit(order_id, "deposit-tx", 100_000, 1_000),
+            vec![order_id]
+        );
+        assert_consistent();
+        assert_eq!(pending_order_ids(1_000), vec![order_id]);
+        assert!(pending_order_ids(10_000).is_empty());
+        assert_eq!(expired_order_ids(10_000), vec![order_id]);
+
+        update_order(order_id, |ord| {
+            ord.resolver = Some(resolver);
+            ord.status = OrderStatus::ResolverDeposited;
+            ord.resolver_deposited = true;
+        });
+        assert_consistent();
+        assert!(pending_order_ids(1_000).is_empty());
+        assert_eq!(principal_order_ids(resolver), vec![order_id]);
+
+        mark_settled(
+            order_id,
+            creator,
+            "secret".to_string(),
+            "payout-tx",
+            "creator-tx",
+            2_000,
+        );
+        assert_consistent();
+        assert!(expired_order_ids(10_000).is_empty());
+        assert_eq!(principal_order_ids(creator), vec![order_id]);
+    }
+
+    #[test]
+    fn should_keep_indexes_in_step_when_order_is_cancelled() {
+        let mut pending = order(1, principal(1), OrderStatus::DepositReceived);
+        pending.creator_deposited = true;
+        pending.expires_at = 10_000;
+        insert_orders(vec![
+            pending,
+            order(2, principal(3), OrderStatus::AwaitingDeposit),
+        ]);
+        assert_consistent();
+        assert_eq!(pending_order_ids(1_000), vec![1]);
+
+        update_order(1, |ord| {
+            ord.status = OrderStatus::Cancelled;
+            ord.cancelled_at = Some(2_000);
+        });
+        assert_consistent();
+        assert!(pending_order_ids(1_000).is_empty());
+        assert!(expired_order_ids(10_000).is_empty());
+        assert_eq!(principal_order_ids(principal(1)), vec![1]);
+        assert_eq!(principal_order_ids(principal(3)), vec![2]);
+    }
+
+    #[test]
+    fn should_not_let_expired_pending_orders_hide_live_ones() {
+        let pending = |id: u64, expires_at: u64| {
+            let mut pending = order(id, principal(1), OrderStatus::DepositReceived);
+            pending.creator_deposited = true;
+            pending.expires_at = expires_at;
+            pending
+        };
+        // More expired orders than one call reads, all with lower ids than the live one
+        let count = MAX_PENDING_SCAN as u64 + 1;
+        let mut orders: Vec<_> = (1..=count).map(|id| pending(id, 500)).collect();
+        orders.push(pending(count + 1, 10_000));
+        insert_orders(orders);
+
+        assert_eq!(pending_order_ids(1_000), vec![count + 1]);
+    }
+
+    #[test]
+    fn should_skip_expired_orders_without_deposits() {
+        let mut funded = order(1, principal(1), OrderStatus::DepositReceived);
+        funded.creator_deposited = true;
+        funded.expires_at = 5_000;
+        let mut unfunded = order(2, principal(1), OrderStatus::AwaitingDeposit);
+        unfunded.expires_at = 4_000;
+        insert_orders(vec![funded, unfunded]);
+
+        assert_eq!(expired_order_ids(4_999), Vec::<u64>::new());
+        assert_eq!(expired_order_ids(5_000), vec![1]);
+    }
+}
+
+mod payout_denylist {
+    use super::{insert_orders, order, principal};
+    use crate::orders::check_payout_destination;
+    use crate::storage::{add_blocked_address, get_order, remove_blocked_address};
+    use crate::types::OrderStatus;
+
+    const BLOCKED: &str = "tb1qblocked";
+
+    #[test]
+    fn should_block_payout_to_denied_address_and_flag_order() {
+        insert_orders(vec![order(1, principal(1), OrderStatus::ResolverDeposited)]);
+        assert!(add_blocked_address(BLOCKED.to_string()));
+        assert!(!add_blocked_address(BLOCKED.to_string()));
+
+        let error = check_payout_destination(1, BLOCKED).unwrap_err();
+        assert!(error.starts_with("BlockedAddress"), "{}", error);
+
+        let flagged = get_order(1).unwrap();
+        assert_eq!(flagged.manual_review, Some(error));
+        assert_eq!(flagged.status, OrderStatus::ResolverDeposited);
+    }
+
+    #[test]
+    fn should_allow_payout_to_address_not_on_denylist() {
+        insert_orders(vec![order(1, principal(1), OrderStatus::ResolverDeposited)]);
+        add_blocked_address(BLOCKED.to_string());
+
+        assert_eq!(check_payout_destination(1, "tb1qcreator"), Ok(()));
+        assert_eq!(get_order(1).unwrap().manual_review, None);
+
+        assert!(remove_blocked_address(BLOCKED));
+        assert_eq!(check_payout_destination(1, BLOCKED), Ok(()));
+        assert_eq!(get_order(1).unwrap().manual_review, None);
+    }
+}
+
+mod partial_refund {
+    use super::{insert_orders, order, principal, utxo};
+    use crate::basic_bitcoin::common::{max_payout_amount, select_utxos_greedy};
+    use crate::orders::{is_insufficient_balance, partial_refund_amount, record_refund};
+    use crate::storage::get_order;
+    use crate::types::{DepositLeg, OrderStatus};
+
+    #[test]
+    fn should_refund_what_is_spendable_when_balance_is_below_deposit() {
+        let deposit = 100_000;
+        let held = [utxo(0, 0, 99_500, 100)];
+
+        let error = select_utxos_greedy(&held, deposit, 0).unwrap_err();
+        assert!(is_insufficient_balance(&error), "{}", error);
+
+        // 11 + 68 + 43 vbytes at 2 sat/vbyte
+        let (spendable, fee) = max_payout_amount(&held, 2_000).unwrap();
+        assert_eq!(fee, 244);
+        assert_eq!(spendable, 99_256);
+        assert_eq!(partial_refund_amount(deposit, spendable), Some(99_256));
+    }
+
+    #[test]
+    fn should_not_fall_back_when_deposit_is_coverable_or_nothing_is_left() {
+        assert_eq!(partial_refund_amount(100_000, 100_000), None);
+        assert_eq!(partial_refund_amount(100_000, 150_000), None);
+        assert_eq!(partial_refund_amount(100_000, 0), None);
+
+        let error = max_payout_amount(&[utxo(0, 0, 200, 100)], 2_000).unwrap_err();
+        assert!(error.starts_with("InsufficientBalance"), "{}", error);
+        assert!(!is_insufficient_balance("Failed to broadcast transaction"));
+    }
+
+    #[test]
+    fn should_record_refunded_amount_on_order() {
+        let mut expired = order(1, principal(1), OrderStatus::Expired);
+        expired.creator_deposited = true;
+        insert_orders(vec![expired]);
+        assert_eq!(get_order(1).unwrap().creator_refund_amount, None);
+
+        record_refund(1, &DepositLeg::Creator, 99_256);
+        let refunded = get_order(1).unwrap();
+        assert_eq!(refunded.creator_refund_amount, Some(99_256));
+        assert_eq!(refunded.resolver_refund_amount, None);
+    }
+}
+
+mod self_test {
+    use super::poll_once;
+    use crate::orders::self_test_with;
+
+    #[test]
+    fn should_report_both_chains_reachable() {
+        let report = poll_once(self_test_with(
+            || async { Ok("tb1qcanister holds 0 satoshis".to_string()) },
+            || async { Ok("CanisterSolAddress holds 0 lamports".to_string()) },
+        ));
+
+        assert!(report.btc_ok);
+        assert!(report.sol_ok);
+        assert_eq!(
+            report.details,
+            vec![
+                "Bitcoin: ok, tb1qcanister holds 0 satoshis".to_string(),
+                "Solana: ok, CanisterSolAddress holds 0 lamports".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_report_failing_chain_without_hiding_the_other() {
+        let report = poll_once(self_test_with(
+            || async { Ok("tb1qcanister holds 0 satoshis".to_string()) },
+            || async { Err("ChainUnavailable: Solana RPC unreachable".to_string()) },
+        ));
+
+        assert!(report.btc_ok);
+        assert!(!report.sol_ok);
+        assert_eq!(
+            report.details[1],
+            "Solana: failed, ChainUnavailable: Solana RPC unreachable"
+        );
+    }
+}
+
+mod resolver_cancel {
+    use super::{order, principal};
+    use crate::orders::{check_resolver_cancel, refund_legs};
+    use crate::types::{Order, OrderStatus};
+
+    const SECOND: u64 = 1_000_000_000;
+    const PROTECTION_SECONDS: u64 = 600;
+
+    // Both sides deposited, the resolver at t = 1000s, and the creator has gone quiet
+    fn awaiting_secret() -> Order {
+        let mut order = order(1, principal(1), OrderStatus::ResolverDeposited);
+        order.resolver = Some(principal(2));
+        order.resolver_btc_address = Some("tb1qresolver".to_string());
+        order.resolver_sol_address = Some("ResolverSolAddress".to_string());
+        order.creator_deposited = true;
+        order.resolver_deposited = true;
+        order.resolver_deposited_at = Some(1_000 * SECOND);
+        order
+    }
+
+    #[test]
+    fn should_let_resolver_refund_both_legs_after_protection_period() {
+        let order = awaiting_secret();
+        let unlocks_at = 1_000 * SECOND + PROTECTION_SECONDS * SECOND;
+        assert!(unlocks_at < order.expires_at);
+
+        assert!(
+            check_resolver_cancel(&order, principal(2), PROTECTION_SECONDS, unlocks_at - 1)
+                .is_err()
+        );
+        assert_eq!(
+            check_resolver_cancel(&order, principal(2), PROTECTION_SECONDS, unlocks_at),
+            Ok(())
+        );
+        assert_eq!(refund_legs(&order), (true, true));
+    }
+
+    #[test]
+    fn should_reject_resolver_cancel_by_others_or_once_settling() {
+        let order = awaiting_secret();
+        let later = order.expires_at;
+
+        assert!(check_resolver_cancel(&order, principal(1), PROTECTION_SECONDS, later).is_err());
+
+        let mut revealed = awaiting_secret();
+        revealed.status = OrderStatus::SettlementFailed;
+        revealed.secret_revealed_by = Some(principal(1));
+        assert!(check_resolver_cancel(&revealed, principal(2), PROTECTION_SECONDS, later).is_err());
+
+        let mut awaiting_deposit = awaiting_secret();
+        awaiting_deposit.status = OrderStatus::DepositReceived;
+        assert!(
+            check_resolver_cancel(&awaiting_deposit, principal(2), PROTECTION_SECONDS, later)
+                .is_err()
+        );
+    }
+}
+
+mod asset_info {
+    use super::{insert_orders, order, principal};
+    use crate::storage::{cache_token_metadata, get_order};
+    use crate::types::{Asset, AssetInfo, OrderStatus, TokenMetadata};
+
+    const USDC: &str = "4zMMC9srt5Ri5X14GAgXhaHii3GnPAEERYPJgZJDncDU";
+
+    #[test]
+    fn should_flatten_bitcoin_and_spl_assets_in_order_info() {
+        let mut btc_for_usdc = order(1, principal(1), OrderStatus::AwaitingDeposit);
+        btc_for_usdc.to_asset = Asset::SplToken {
+            mint_address: USDC.to_string(),
+            decimals: 6,
+        };
+        insert_orders(vec![btc_for_usdc]);
+
+        let info = get_order(1).unwrap();
+        assert_eq!(
+            info.from_asset_info,
+            AssetInfo {
+                kind: "Bitcoin".to_string(),
+                mint: None,
+                decimals: 8,
+                symbol: Some("BTC".to_string()),
+            }
+        );
+        assert_eq!(info.to_asset_info.kind, "SplToken");
+        assert_eq!(info.to_asset_info.mint, Some(USDC.to_string()));
+        assert_eq!(info.to_asset_info.decimals, 6);
+        assert_eq!(info.to_asset_info.symbol, None);
+
+        cache_token_metadata(TokenMetadata {
+            mint_address: USDC.to_string(),
+            name: Some("USD Coin".to_string()),
+            symbol: Some("USDC".to_string()),
+            decimals: 6,
+        });
+        let info = get_order(1).unwrap();
+        assert_eq!(info.to_asset_info.symbol, Some("USDC".to_string()));
+    }
+}
+
+mod utxo_reservation {
+    use super::{poll_once, utxo};
+    use crate::basic_bitcoin::{common::spent_outpoints, p2wpkh::build_transaction, BTC_CONTEXT};
+    use crate::storage::{release_utxos, reserve_utxos, unreserved_utxos, RESERVED_UTXOS};
+    use bitcoin::{Address, CompressedPublicKey, Network, PublicKey};
+    use ic_cdk::bitcoin_canister::Utxo;
+    use std::str::FromStr;
+
+    const PUBKEY_HEX: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    const DESTINATION: &str = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx";
+
+    // Pick inputs for a 40k sat payout from whatever is not reserved, then reserve them
+    fn start_payout(listed: &[Utxo]) -> Vec<(Vec<u8>, u32)> {
+        let ctx = BTC_CONTEXT.with(|ctx| ctx.get());
+        let public_key = PublicKey::from_slice(&hex::decode(PUBKEY_HEX).unwrap()).unwrap();
+        let own_address = Address::p2wpkh(
+            &CompressedPublicKey::try_from(public_key).unwrap(),
+            Network::Testnet,
+        );
+        let dst_address = Address::from_str(DESTINATION).unwrap().assume_checked();
+
+        let own_utxos = unreserved_utxos(listed.to_vec());
+        let (transaction, _) = poll_once(build_transaction(
+            &ctx,
+            &public_key,
+            &own_address,
+            &own_utxos,
+            &dst_address,
+            40_000,
+            2_000,
+        ))
+        .unwrap();
+        let outpoints = spent_outpoints(&transaction);
+        reserve_utxos(&outpoints);
+        outpoints
+    }
+
+    #[test]
+    fn should_give_concurrent_payouts_disjoint_utxos() {
+        let listed = vec![
+            utxo(1, 0, 50_000, 100),
+            utxo(2, 0, 50_000, 100),
+            utxo(3, 0, 50_000, 100),
+        ];
+
+        let first = start_payout(&listed);
+        let second = start_payout(&listed);
+        assert_eq!(first.len(), 1);
+        assert_eq!(second.len(), 1);
+        assert_ne!(first, second);
+
+        // The first payout failed to broadcast: its input is free again
+        release_utxos(&first);
+        assert_eq!(unreserved_utxos(listed.clone()).len(), 2);
+    }
+
+    #[test]
+    fn should_drop_reservation_once_spend_is_mined() {
+        let listed = vec![utxo(1, 0, 50_000, 100), utxo(2, 0, 50_000, 100)];
+        let spent = start_payout(&listed);
+
+        let still_listed: Vec<Utxo> = listed
+            .into_iter()
+            .filter(|utxo| (utxo.outpoint.txid.clone(), utxo.outpoint.vout) != spent[0])
+            .collect();
+        assert_eq!(unreserved_utxos(still_listed).len(), 1);
+        RESERVED_UTXOS.with(|reserved| assert!(reserved.borrow().is_empty()));
+    }
+}
+
+mod settlement_attestation {
+    use super::{order, principal};
+    use crate::basic_bitcoin::common::DerivationPath;
+    use crate::orders::{
+        attestation_derivation_path, attestation_digest, attestation_message, verify_attestation,
+    };
+    use crate::types::{Attestation, Order, OrderStatus};
+    use bitcoin::secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+
+    fn completed() -> Order {
+        let mut order = order(7, principal(1), OrderStatus::Completed);
+        order.resolver_payout_txid = Some("resolver-payout".to_string());
+        order.creator_payout_txid = Some("creator-payout".to_string());
+        order
+    }
+
+    // Sign as the canister would, with a local key standing in for the threshold key
+    fn attest(order: &Order, secret_key: &SecretKey) -> Attestation {
+        let message = attestation_message(order).unwrap();
+        let digest = Message::from_digest(attestation_digest(&message));
+        let signature = Secp256k1::new().sign_ecdsa(&digest, secret_key);
+        Attestation {
+            order_id: order.id,
+            message,
+            signature_hex: hex::encode(signature.serialize_compact()),
+        }
+    }
+
+    #[test]
+    fn should_verify_attestation_against_canister_public_key() {
+        let secret_key = SecretKey::from_slice(&[7; 32]).unwrap();
+        let public_key_hex =
+            hex::encode(PublicKey::from_secret_key(&Secp256k1::new(), &secret_key).serialize());
+
+        let attestation = attest(&completed(), &secret_key);
+        assert!(attestation.message.contains("order_id: 7"));
+        assert!(attestation
+            .message
+            .contains("resolver_payout_txid: resolver-payout"));
+        assert!(attestation
+            .message
+            .contains("creator_payout_txid: creator-payout"));
+        assert!(attestation.message.contains("from_amount: 100000"));
+        assert_eq!(verify_attestation(&attestation, &public_key_hex), Ok(()));
+
+        let mut tampered = attestation.clone();
+        tampered.message = tampered.message.replace("order_id: 7", "order_id: 8");
+        assert!(verify_attestation(&tampered, &public_key_hex).is_err());
+
+        let other_key = SecretKey::from_slice(&[8; 32]).unwrap();
+        let other_hex =
+            hex::encode(PublicKey::from_secret_key(&Secp256k1::new(), &other_key).serialize());
+        assert!(verify_attestation(&attestation, &other_hex).is_err());
+    }
+
+    #[test]
+    fn should_refuse_to_attest_incomplete_order() {
+        let mut settling = completed();
+        settling.status = OrderStatus::SettlementFailed;
+        assert!(attestation_message(&settling).is_err());
+
+        let mut unrecorded = completed();
+        unrecorded.creator_payout_txid = None;
+        assert!(attestation_message(&unrecorded).is_err());
+    }
+
+    #[test]
+    fn should_sign_attestations_apart_from_custody_keys() {
+        let path = attestation_derivation_path();
+        for account in 0..2 {
+            for index in 0..2 {
+                assert_ne!(
+                    path,
+                    DerivationPath::p2wpkh(account, index).to_vec_u8_path()
+                );
+                assert_ne!(path, DerivationPath::p2pkh(account, index).to_vec_u8_path());
+                assert_ne!(path, DerivationPath::p2tr(account, index).to_vec_u8_path());
+            }
+        }
+        // Bitcoin paths are made of 4-byte segments only
+        assert!(path.iter().any(|segment| segment.len() != 4));
+    }
+}
+
+mod balance_cache {
+    use super::poll_once;
+    use crate::storage::{invalidate_balance, read_balance_cached, CONFIG};
+    use std::cell::Cell;
+
+    const SECOND: u64 = 1_000_000_000;
+    const ADDRESS: &str = "CanisterSolAddress";
+
+    #[test]
+    fn should_serve_second_read_within_ttl_from_cache_until_a_send() {
+        CONFIG.with(|config| config.borrow_mut().balance_cache_ttl_seconds = 5);
+        let fetches = Cell::new(0);
+        let read = |balance: u64, now: u64| {
+            poll_once(read_balance_cached(ADDRESS.to_string(), now, |_| {
+                fetches.set(fetches.get() + 1);
+                async move { Ok(balance) }
+            }))
+        };
+
+        assert_eq!(read(1_000, 10 * SECOND), Ok(1_000));
+        assert_eq!(read(2_000, 12 * SECOND), Ok(1_000));
+        assert_eq!(fetches.get(), 1);
+
+        // The canister sent from the address, so the next read goes to the chain
+        invalidate_balance(ADDRESS);
+        assert_eq!(read(2_000, 13 * SECOND), Ok(2_000));
+        assert_eq!(fetches.get(), 2);
+
+        // Past the TTL the balance is read again
+        assert_eq!(read(3_000, 18 * SECOND), Ok(3_000));
+        assert_eq!(fetches.get(), 3);
+    }
+
+    #[test]
+    fn should_always_fetch_when_ttl_is_zero() {
+        CONFIG.with(|config| config.borrow_mut().balance_cache_ttl_seconds = 0);
+        let fetches = Cell::new(0);
+        for _ in 0..2 {
+            let balance = poll_once(read_balance_cached(ADDRESS.to_string(), SECOND, |_| {
+                fetches.set(fetches.get() + 1);
+                async { Ok(500) }
+            }));
+            assert_eq!(balance, Ok(500));
+        }
+        assert_eq!(fetches.get(), 2);
+    }
+}
+
+mod treasury_sweep {
+    use super::{insert_orders, order, principal};
+    use crate::orders::check_sweep;
+    use crate::storage::expected_held_amounts;
+    use crate::types::OrderStatus;
+
+    #[test]
+    fn should_reject_sweep_beyond_idle_balance() {
+        let mut held = order(1, principal(1), OrderStatus::DepositReceived);
+        held.creator_deposited = true;
+        insert_orders(vec![held]);
+        let reserved = expected_held_amounts()[0].1;
+        assert_eq!(reserved, 100_000);
+
+        // 150_000 spendable with 100_000 locked in the open order leaves 50_000 idle
+        assert_eq!(check_sweep(50_000, 150_000, reserved), Ok(()));
+        let error = check_sweep(50_001, 150_000, reserved).unwrap_err();
+        assert!(error.starts_with("InsufficientBalance:"));
+    }
+
+    #[test]
+    fn should_reject_any_sweep_when_balance_is_all_locked() {
+        assert!(check_sweep(1, 90_000, 100_000).is_err());
+        assert!(check_sweep(0, 200_000, 100_000).is_err());
+    }
+}
+
+mod order_metadata {
+    use super::{insert_orders, order, principal};
+    use crate::orders::{check_order_metadata, register_order, MAX_ORDER_METADATA_BYTES};
+    use crate::storage::get_order;
+    use crate::types::OrderStatus;
+
+    #[test]
+    fn should_return_metadata_verbatim_from_get_order() {
+        insert_orders(vec![]);
+        let metadata = " invoice #42 / ext-order=abc ".to_string();
+        let mut created = order(0, principal(1), OrderStatus::AwaitingDeposit);
+        created.metadata = Some(metadata.clone());
+
+        let order_id = register_order(created, principal(9)).unwrap();
+        assert_eq!(get_order(order_id).unwrap().metadata, Some(metadata));
+    }
+
+    #[test]
+    fn should_reject_oversized_metadata() {
+        let fits = "x".repeat(MAX_ORDER_METADATA_BYTES);
+        assert_eq!(check_order_metadata(Some(&fits)), Ok(()));
+        assert_eq!(check_order_metadata(None), Ok(()));
+
+        // Multi-byte characters count by their encoded size
+        let oversized = "é".repeat(MAX_ORDER_METADATA_BYTES / 2 + 1);
+        assert!(check_order_metadata(Some(&oversized)).is_err());
+    }
+}
+
+mod overpayment_refund {
+    use super::{insert_orders, order, poll_once, principal};
+    use crate::orders::{
+        check_overpayment_refundable, deposit_overpayment, refund_flagged_overpayment_with,
+        refund_overpayment_with,
+    };
+    use crate::storage::{get_order, CONFIG};
+    use crate::types::{Asset, OrderStatus};
+    use std::cell::Cell;
+
+    #[test]
+    fn should_detect_overpayment_only_above_from_amount() {
+        assert_eq!(deposit_overpayment(100_000, 150_000), Some(50_000));
+        assert_eq!(deposit_overpayment(100_000, 100_000), None);
+        assert_eq!(deposit_overpayment(100_000, 90_000), None);
+    }
+
+    #[test]
+    fn should_refund_excess_less_fee_and_record_it() {
+        insert_orders(vec![order(1, principal(1), OrderStatus::DepositReceived)]);
+        let sent = Cell::new(0);
+
+        // The creator sent 150_000 against a from_amount of 100_000
+        let excess = deposit_overpayment(100_000, 150_000).unwrap();
+        let result = poll_once(refund_overpayment_with(1, excess, 5_000, |amount| {
+            sent.set(amount);
+            async { Ok("refund-tx".to_string()) }
+        }));
+
+        assert_eq!(result, Ok("refund-tx".to_string()));
+        assert_eq!(sent.get(), 45_000);
+        let info = get_order(1).unwrap();
+        assert_eq!(info.creator_overpayment, Some(50_000));
+        assert_eq!(info.overpayment_refund_txid, Some("refund-tx".to_string()));
+    }
+
+    #[test]
+    fn should_keep_record_when_excess_does_not_cover_fee() {
+        insert_orders(vec![order(1, principal(1), OrderStatus::DepositReceived)]);
+
+        let result = poll_once(refund_overpayment_with(1, 3_000, 5_000, |_| async {
+            Ok::<_, String>("never-sent".to_string())
+        }));
+
+        assert!(result.unwrap_err().starts_with("UneconomicalSettlement:"));
+        let info = get_order(1).unwrap();
+        assert_eq!(info.creator_overpayment, Some(3_000));
+        assert_eq!(info.overpayment_refund_txid, None);
+    }
+
+    #[test]
+    fn should_only_refund_deposits_bound_by_memo() {
+        let mut sol_order = order(1, principal(1), OrderStatus::DepositReceived);
+        sol_order.from_asset = Asset::Solana;
+        sol_order.creator_txid = Some("deposit-tx".to_string());
+        let btc_order = order(2, principal(1), OrderStatus::DepositReceived);
+        insert_orders(vec![sol_order.clone(), btc_order.clone()]);
+
+        // Without the memo anyone's transfer to the shared address could be named as the deposit
+        assert!(check_overpayment_refundable(&sol_order, "deposit-tx").is_err());
+        assert!(check_overpayment_refundable(&btc_order, "deposit-tx").is_err());
+
+        CONFIG.with(|config| config.borrow_mut().require_solana_memo = true);
+        assert_eq!(
+            check_overpayment_refundable(&sol_order, "deposit-tx"),
+            Ok(())
+        );
+        assert!(check_overpayment_refundable(&btc_order, "deposit-tx").is_err());
+        CONFIG.with(|config| config.borrow_mut().require_solana_memo = false);
+    }
+
+    #[test]
+    fn should_refund_flagged_overpayment_once() {
+        let mut flagged = order(1, principal(1), OrderStatus::DepositReceived);
+        flagged.creator_overpayment = Some(50_000);
+        flagged.manual_review = Some(
+            "Overpayment of 50000 not refunded: Bitcoin overpayments are refunded by an operator"
+                .to_string(),
+        );
+        insert_orders(vec![flagged.clone()]);
+        let sent = Cell::new(0);
+
+        let result = poll_once(refund_flagged_overpayment_with(&flagged, 5_000, |amount| {
+            sent.set(amount);
+            async { Ok("refund-tx".to_string()) }
+        }));
+
+        assert_eq!(result, Ok("refund-tx".to_string()));
+        assert_eq!(sent.get(), 45_000);
+        let info = get_order(1).unwrap();
+        assert_eq!(info.overpayment_refund_txid, Some("refund-tx".to_string()));
+        assert_eq!(info.manual_review, None);
+
+        let mut refunded = flagged;
+        refunded.overpayment_refund_txid = info.overpayment_refund_txid;
+        let again = poll_once(refund_flagged_overpayment_with(
+            &refunded,
+            5_000,
+            |_| async { panic!("overpayment must not be refunded twice") },
+        ));
+        assert!(again.is_err());
+    }
+
+    #[test]
+    fn should_not_refund_without_recorded_overpayment() {
+        let exact = order(1, principal(1), OrderStatus::DepositReceived);
+        insert_orders(vec![exact.clone()]);
+
+        let result = poll_once(refund_flagged_overpayment_with(&exact, 0, |_| async {
+            panic!("nothing to refund")
+        }));
+        assert_eq!(
+            result,
+            Err("Order has no overpayment to refund".to_string())
+        );
+    }
+}
+
+mod broadcast_retry {
+    use super::poll_once;
+    use crate::basic_bitcoin::common::{retry_broadcast, BroadcastError};
+    use std::cell::{Cell, RefCell};
+    use std::time::Duration;
+
+    const BACKOFF: Duration = Duration::from_secs(2);
+
+    #[test]
+    fn should_retry_transient_failure_then_succeed() {
+        let attempts = Cell::new(0);
+        let waits = RefCell::new(Vec::new());
+
+        let result = poll_once(retry_broadcast(
+            3,
+            BACKOFF,
+            || {
+                attempts.set(attempts.get() + 1);
+                let outcome = match attempts.get() {
+                    1 => Err(BroadcastError::Transient("rate limited".to_string())),
+                    _ => Ok(()),
+                };
+                async move { outcome }
+            },
+            |delay| {
+                waits.borrow_mut().push(delay);
+                async {}
+            },
+        ));
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(attempts.get(), 2);
+        assert_eq!(*waits.borrow(), vec![BACKOFF]);
+    }
+
+    #[test]
+    fn should_not_retry_permanent_rejects() {
+        let attempts = Cell::new(0);
+        let rejected = BroadcastError::Permanent("malformed transaction".to_string());
+
+        let result = poll_once(retry_broadcast(
+            3,
+            BACKOFF,
+            || {
+                attempts.set(attempts.get() + 1);
+                let outcome = Err(rejected.clone());
+                async move { outcome }
+            },
+            |_| async {},
+        ));
+
+        assert_eq!(result, Err(rejected));
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[test]
+    fn should_give_up_after_max_attempts_with_doubling_backoff() {
+        let waits = RefCell::new(Vec::new());
+
+        let result = poll_once(retry_broadcast(
+            3,
+            BACKOFF,
+            || async { Err(BroadcastError::Transient("unavailable".to_string())) },
+            |delay| {
+                waits.borrow_mut().push(delay);
+                async {}
+            },
+        ));
+
+        assert_eq!(
+            result,
+            Err(BroadcastError::Transient("unavailable".to_string()))
+        );
+        assert_eq!(*waits.borrow(), vec![BACKOFF, BACKOFF * 2]);
+    }
+}
+
+mod resolved_orders {
+    use super::{insert_orders, order, principal};
+    use crate::storage::get_orders_resolved_by;
+    use crate::types::OrderStatus;
+
+    #[test]
+    fn should_exclude_orders_the_principal_only_created() {
+        let resolver = principal(2);
+        let mut resolving = order(1, principal(1), OrderStatus::DepositReceived);
+        resolving.resolver = Some(resolver);
+        let created = order(2, resolver, OrderStatus::DepositReceived);
+        let mut other_resolver = order(3, principal(1), OrderStatus::DepositReceived);
+        other_resolver.resolver = Some(principal(3));
+        insert_orders(vec![resolving, created, other_resolver]);
+
+        let ids: Vec<u64> = get_orders_resolved_by(resolver)
+            .into_iter()
+            .map(|info| info.id)
+            .collect();
+        assert_eq!(ids, vec![1]);
+    }
+}
+
+mod replace_order {
+    use super::{insert_orders, order, principal};
+    use crate::orders::swap_in_replacement;
+    use crate::storage::{NEXT_ORDER_ID, ORDERS};
+    use crate::types::{Order, OrderStatus};
+
+    const NOW: u64 = 1_000;
+
+    fn stored(order_id: u64) -> Order {
+        ORDERS.with(|orders| orders.borrow()[&order_id].clone())
+    }
+
+    // Stores the deposited order 1 and numbers new orders after it
+    fn setup() {
+        insert_orders(vec![deposited_order()]);
+        NEXT_ORDER_ID.with(|id| *id.borrow_mut() = 2);
+    }
+
+    fn deposited_order() -> Order {
+        let mut old = order(1, principal(1), OrderStatus::DepositReceived);
+        old.creator_btc_address = Some("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx".to_string());
+        old.creator_sol_address = Some("CreatorSolAddress".to_string());
+        old.creator_deposited = true;
+        old.creator_txid = Some("deposit-tx".to_string());
+        old
+    }
+
+    #[test]
+    fn should_keep_refund_addresses_and_create_fresh_order() {
+        setup();
+        let mut replacement = order(0, principal(1), OrderStatus::AwaitingDeposit);
+        replacement.from_amount = 120_000;
+
+        let (new_id, to_refund) =
+            swap_in_replacement(principal(1), 1, replacement, principal(9), NOW).unwrap();
+
+        assert_eq!(new_id, 2);
+        let new = stored(new_id);
+        assert_eq!(
+            new.creator_btc_address,
+            deposited_order().creator_btc_address
+        );
+        assert_eq!(
+            new.creator_sol_address,
+            deposited_order().creator_sol_address
+        );
+        // A different amount needs a fresh deposit; the old one is refunded
+        assert_eq!(new.status, OrderStatus::AwaitingDeposit);
+        assert!(!new.creator_deposited);
+        assert_eq!(to_refund.map(|old| old.id), Some(1));
+        assert_eq!(stored(1).status, OrderStatus::Cancelled);
+    }
+
+    #[test]
+    fn should_carry_matching_deposit_over() {
+        setup();
+        let replacement = order(0, principal(1), OrderStatus::AwaitingDeposit);
+
+        let (new_id, to_refund) =
+            swap_in_replacement(principal(1), 1, replacement, principal(9), NOW).unwrap();
+
+        assert!(to_refund.is_none());
+        let new = stored(new_id);
+        assert_eq!(new.status, OrderStatus::DepositReceived);
+        assert_eq!(new.creator_txid, Some("deposit-tx".to_string()));
+        // The old order no longer holds the deposit, so it cannot be refunded twice
+        assert!(!stored(1).creator_deposited);
+    }
+
+    #[test]
+    fn should_only_let_the_creator_replace() {
+        setup();
+        let replacement = order(0, principal(2), OrderStatus::AwaitingDeposit);
+
+        assert!(swap_in_replacement(principal(2), 1, replacement, principal(9), NOW).is_err());
+        assert_eq!(stored(1).status, OrderStatus::DepositReceived);
+    }
+}
+
+mod payout_address_checks {
+    use crate::bitcoin_integration::check_bitcoin_address_network;
+    use crate::orders::validate_receive_address;
+    use crate::solana_integration::{validate_sol_transfer_destination, SPL_TOKEN_PROGRAM_ID};
+    use crate::types::Asset;
+    use bitcoin::Network;
+    use solana_pubkey::Pubkey;
+    use std::str::FromStr;
+
+    // Compressed ed25519 base point, a key with a private counterpart
+    const ON_CURVE_HEX: &str = "5866666666666666666666666666666666666666666666666666666666666666";
+
+    fn on_curve_address() -> String {
+        let bytes: [u8; 32] = hex::decode(ON_CURVE_HEX).unwrap().try_into().unwrap();
+        Pubkey::new_from_array(bytes).to_string()
+    }
+
+    fn off_curve_address() -> String {
+        let program = Pubkey::from_str(SPL_TOKEN_PROGRAM_ID).unwrap();
+        Pubkey::find_program_address(&[b"vault"], &program)
+            .0
+            .to_string()
+    }
+
+    #[test]
+    fn should_reject_off_curve_sol_destination() {
+        let error = validate_sol_transfer_destination(&off_curve_address()).unwrap_err();
+        assert!(error.starts_with("InvalidAddress:"), "{}", error);
+        assert!(error.contains("off-curve"));
+        assert!(validate_receive_address(&Asset::Solana, &off_curve_address()).is_err());
+        assert_eq!(
+            validate_sol_transfer_destination(&on_curve_address()),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn should_reject_wrong_network_btc_destination() {
+        let mainnet = "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq";
+        let error = validate_receive_address(&Asset::Bitcoin, mainnet).unwrap_err();
+        assert!(error.starts_with("WrongNetworkAddress:"), "{}", error);
+    }
+
+    #[test]
+    fn should_reject_unspendable_btc_script_type() {
+        // Witness version 16: a valid checksum, but nothing can spend it yet
+        let error = check_bitcoin_address_network("bc1sw50qgdz25j", Network::Bitcoin).unwrap_err();
+        assert!(error.starts_with("Unsupported Bitcoin address type"));
+    }
+}
+
+mod settlement_receipt {
+    use super::{insert_orders, order, principal};
+    use crate::orders::{mark_settled, record_settlement_receipt, settlement_receipt};
+    use crate::storage::get_settlement_receipt;
+    use crate::types::{Asset, OrderStatus};
+
+    #[test]
+    fn should_populate_fees_after_settlement() {
+        let settling = order(1, principal(1), OrderStatus::ResolverDeposited);
+        insert_orders(vec![settling.clone()]);
+        assert_eq!(get_settlement_receipt(1), None);
+
+        // BTC to the resolver, SOL to the creator
+        let fees = vec![(Asset::Bitcoin, 1_410), (Asset::Solana, 5_000)];
+        mark_settled(
+            1,
+            principal(1),
+            "secret".to_string(),
+            "btc-tx",
+            "sol-tx",
+            1_000,
+        );
+        record_settlement_receipt(1, settlement_receipt(&settling, &fees, 42_000_000));
+
+        let receipt = get_settlement_receipt(1).unwrap();
+        assert_eq!(receipt.btc_fee, 1_410);
+        assert_eq!(receipt.sol_fee, 5_000);
+        assert_eq!(receipt.cycles_spent, 42_000_000);
+        assert_eq!(
+            receipt.payout_amounts,
+            vec![
+                (settling.from_asset.clone(), settling.from_amount),
+                (settling.to_asset.clone(), settling.to_amount),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_add_up_fees_paid_in_the_same_asset() {
+        let settling = order(1, principal(1), OrderStatus::ResolverDeposited);
+        let fees = vec![(Asset::Solana, 5_000), (Asset::Solana, 5_000)];
+
+        let receipt = settlement_receipt(&settling, &fees, 0);
+        assert_eq!(receipt.btc_fee, 0);
+        assert_eq!(receipt.sol_fee, 10_000);
+    }
+}
+
+mod refund_grace {
+    use super::{order, principal};
+    use crate::orders::{check_refund_window, check_resolver_refund, check_settleable};
+    use crate::storage::CONFIG;
+    use crate::types::OrderStatus;
+
+    const SECOND: u64 = 1_000_000_000;
+    const EXPIRES_AT: u64 = 1_000 * SECOND;
+    const GRACE_SECONDS: u64 = 60;
+
+    #[test]
+    fn should_allow_neither_reveal_nor_refund_at_expiry() {
+        let mut deposited = order(1, principal(1), OrderStatus::ResolverDeposited);
+        deposited.expires_at = EXPIRES_AT;
+
+        assert_eq!(check_settleable(&deposited, EXPIRES_AT - 1), Ok(()));
+        assert!(check_settleable(&deposited, EXPIRES_AT).is_err());
+        assert!(check_refund_window(EXPIRES_AT, GRACE_SECONDS, EXPIRES_AT).is_err());
+    }
+
+    #[test]
+    fn should_refuse_refund_within_grace_period() {
+        let last_refused = EXPIRES_AT + GRACE_SECONDS * SECOND - 1;
+        let error = check_refund_window(EXPIRES_AT, GRACE_SECONDS, last_refused).unwrap_err();
+        assert!(error.contains("refunds open 60 seconds after expiry"));
+    }
+
+    #[test]
+    fn should_allow_refund_once_grace_period_has_passed() {
+        let refundable_at = EXPIRES_AT + GRACE_SECONDS * SECOND;
+        assert_eq!(
+            check_refund_window(EXPIRES_AT, GRACE_SECONDS, refundable_at),
+            Ok(())
+        );
+        // Without a grace period, refunds open right at expiry
+        assert_eq!(check_refund_window(EXPIRES_AT, 0, EXPIRES_AT), Ok(()));
+    }
+
+    #[test]
+    fn should_apply_configured_grace_to_resolver_refund() {
+        CONFIG.with(|config| config.borrow_mut().refund_grace_seconds = GRACE_SECONDS);
+        let mut deposited = order(1, principal(1), OrderStatus::ResolverDeposited);
+        deposited.resolver = Some(principal(2));
+        deposited.resolver_deposited = true;
+        deposited.expires_at = EXPIRES_AT;
+
+        assert!(check_resolver_refund(&deposited, principal(2), EXPIRES_AT + SECOND).is_err());
+        let refundable_at = EXPIRES_AT + GRACE_SECONDS * SECOND;
+        assert_eq!(
+            check_resolver_refund(&deposited, principal(2), refundable_at),
+            Ok(())
+        );
+    }
+}
+
+mod basket_orders {
+    use super::{insert_orders, order, principal};
+    use crate::orders::{
+        attestation_message, check_basket_legs, check_leg_txids, mark_settled,
+        record_settlement_receipt, settlement_receipt, unsent_extra_legs,
+    };
+    use crate::storage::{
+        expected_held_amounts, get_settlement_receipt, outstanding_amount, ORDERS,
+    };
+    use crate::types::{Asset, Order, OrderPricing, OrderStatus};
+
+    fn usdc() -> Asset {
+        Asset::SplToken {
+            mint_address: "EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v".to_string(),
+            decimals: 6,
+        }
+    }
+
+    // BTC for SOL plus 50 USDC
+    fn basket(status: OrderStatus) -> Order {
+        let mut basket = order(1, principal(1), status);
+        basket.extra_to_legs = vec![(usdc(), 50_000_000)];
+        basket.resolver = Some(principal(2));
+        basket.resolver_btc_address = Some("tb1qresolver".to_string());
+        basket.creator_deposited = true;
+        basket.resolver_deposited = true;
+        basket
+    }
+
+    #[test]
+    fn should_reject_repeated_assets_and_oracle_pricing() {
+        let legs = vec![(usdc(), 50_000_000)];
+        assert_eq!(check_basket_legs(&Asset::Solana, &legs, None), Ok(()));
+
+        let repeated = vec![(Asset::Solana, 1)];
+        assert!(check_basket_legs(&Asset::Solana, &repeated, None).is_err());
+        let empty_leg = vec![(usdc(), 0)];
+        assert!(check_basket_legs(&Asset::Solana, &empty_leg, None).is_err());
+
+        let oracle = OrderPricing::Oracle {
+            reference: 1_000_000_000,
+            max_slippage_bps: 100,
+        };
+        assert!(check_basket_legs(&Asset::Solana, &legs, Some(&oracle)).is_err());
+    }
+
+    #[test]
+    fn should_require_a_deposit_transaction_per_leg() {
+        let basket = basket(OrderStatus::DepositReceived);
+        assert!(check_leg_txids(&basket, &[]).is_err());
+        assert_eq!(check_leg_txids(&basket, &["usdc-tx".to_string()]), Ok(()));
+
+        // Both legs count as held and outstanding
+        insert_orders(vec![basket.clone()]);
+        assert_eq!(outstanding_amount(&usdc()), 50_000_000);
+        assert!(expected_held_amounts().contains(&(usdc(), 50_000_000)));
+        assert!(expected_held_amounts().contains(&(Asset::Solana, basket.to_amount)));
+    }
+
+    #[test]
+    fn should_settle_two_output_basket() {
+        let settling = basket(OrderStatus::ResolverDeposited);
+        assert_eq!(
+            settling.to_legs(),
+            vec![(Asset::Solana, 1_000_000_000), (usdc(), 50_000_000)]
+        );
+        insert_orders(vec![settling.clone()]);
+
+        // The USDC leg went out before the SOL payout failed; a retry must not send it again
+        ORDERS.with(|orders| {
+            let mut orders = orders.borrow_mut();
+            orders
+                .get_mut(&1)
+                .unwrap()
+                .extra_leg_txids
+                .push("usdc-tx".to_string());
+        });
+        let retried = ORDERS.with(|orders| orders.borrow()[&1].clone());
+        assert!(unsent_extra_legs(&retried).is_empty());
+        assert_eq!(unsent_extra_legs(&settling), &[(usdc(), 50_000_000)]);
+
+        mark_settled(
+            1,
+            principal(1),
+            "secret".to_string(),
+            "btc-tx",
+            "sol-tx",
+            1_000,
+        );
+        record_settlement_receipt(1, settlement_receipt(&retried, &[], 0));
+
+        let receipt = get_settlement_receipt(1).unwrap();
+        assert_eq!(
+            receipt.payout_amounts,
+            vec![
+                (Asset::Bitcoin, 100_000),
+                (Asset::Solana, 1_000_000_000),
+                (usdc(), 50_000_000),
+            ]
+        );
+
+        let settled = ORDERS.with(|orders| orders.borrow()[&1].clone());
+        assert_eq!(settled.status, OrderStatus::Completed);
+        let message = attestation_message(&settled).unwrap();
+        assert!(message.ends_with(
+            "\nextra_to_leg: SplToken:EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v 50000000 usdc-tx"
+        ));
+    }
+}
+
+mod order_deadlines {
+    use super::{insert_orders, order, principal};
+    use crate::storage::{get_order_deadlines, order_deadlines, CONFIG};
+    use crate::types::OrderStatus;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    #[test]
+    fn should_count_down_remaining_seconds_mid_lifecycle() {
+        // Fixture orders are created at 1_000 and expire an hour later
+        let pending = order(1, principal(1), OrderStatus::DepositReceived);
+        let now = pending.created_at + 1_200 * SECOND + SECOND / 2;
+
+        let deadlines = order_deadlines(&pending, 60, None, now);
+        assert_eq!(deadlines.now, now);
+        assert_eq!(deadlines.acceptance_deadline, pending.expires_at);
+        assert_eq!(
+            deadlines.resolver_refund_at,
+            pending.expires_at + 60 * SECOND
+        );
+        // Partial seconds are dropped
+        assert_eq!(deadlines.seconds_to_expiry, 2_399);
+        assert_eq!(deadlines.seconds_to_acceptance_deadline, 2_399);
+        assert_eq!(deadlines.seconds_to_resolver_refund, 2_459);
+    }
+
+    #[test]
+    fn should_clamp_passed_deadlines_to_zero() {
+        let expired = order(1, principal(1), OrderStatus::DepositReceived);
+        let now = expired.expires_at + 30 * SECOND;
+
+        let deadlines = order_deadlines(&expired, 60, None, now);
+        assert_eq!(deadlines.seconds_to_expiry, 0);
+        assert_eq!(deadlines.seconds_to_acceptance_deadline, 0);
+        assert_eq!(deadlines.seconds_to_resolver_refund, 30);
+    }
+
+    #[test]
+    fn should_use_configured_refund_grace() {
+        CONFIG.with(|config| config.borrow_mut().refund_grace_seconds = 120);
+        let stored = order(1, principal(1), OrderStatus::DepositReceived);
+        insert_orders(vec![stored.clone()]);
+
+        let deadlines = get_order_deadlines(1, stored.expires_at).unwrap();
+        assert_eq!(deadlines.seconds_to_resolver_refund, 120);
+        assert_eq!(get_order_deadlines(2, stored.expires_at), None);
+    }
+}
+
+mod full_swap {
+    use super::{order, principal};
+    use crate::orders::{
+        check_full_swap_access, check_secret_reveal, check_test_mode, mark_settled, register_order,
+        stage_full_swap,
+    };
+    use crate::storage::ORDERS;
+    use crate::types::{OrderStatus, PartyAddresses, SwapConfig};
+
+    const NOW: u64 = 2_000;
+
+    fn resolver_addresses() -> PartyAddresses {
+        PartyAddresses {
+            btc_address: Some("tb1qresolver".to_string()),
+            sol_address: Some("ResolverSolAddress".to_string()),
+        }
+    }
+
+    #[test]
+    fn should_reject_full_swap_outside_test_mode() {
+        let error = check_test_mode(&SwapConfig::default()).unwrap_err();
+        assert!(error.starts_with("TestModeDisabled:"));
+
+        let config = SwapConfig {
+            test_mode: true,
+            ..SwapConfig::default()
+        };
+        assert_eq!(check_test_mode(&config), Ok(()));
+    }
+
+    #[test]
+    fn should_reject_full_swap_from_non_controller_in_test_mode() {
+        let config = SwapConfig {
+            test_mode: true,
+            ..SwapConfig::default()
+        };
+        assert_eq!(
+            check_full_swap_access(&config, false),
+            Err("Only controllers can call this method".to_string())
+        );
+        assert_eq!(check_full_swap_access(&config, true), Ok(()));
+
+        let error = check_full_swap_access(&SwapConfig::default(), true).unwrap_err();
+        assert!(error.starts_with("TestModeDisabled:"));
+    }
+
+    #[test]
+    fn should_complete_swap_end_to_end() {
+        let mut created = order(0, principal(1), OrderStatus::AwaitingDeposit);
+        created.creator_btc_address = Some("tb1qcreator".to_string());
+        created.creator_sol_address = Some("CreatorSolAddress".to_string());
+        created.require_resolver_ack = true;
+        let order_id = register_order(created, principal(9)).unwrap();
+
+        // The caller resolves their own order with distinct addresses
+        stage_full_swap(order_id, principal(1), resolver_addresses(), NOW).unwrap();
+        let staged = ORDERS.with(|orders| orders.borrow()[&order_id].clone());
+        assert_eq!(staged.status, OrderStatus::ResolverDeposited);
+        assert_eq!(staged.resolver, Some(principal(1)));
+        assert!(staged.creator_deposited && staged.resolver_deposited);
+        assert_eq!(
+            check_secret_reveal(&staged, principal(1), "secret", NOW),
+            Ok(())
+        );
+
+        mark_settled(
+            order_id,
+            principal(1),
+            "secret".to_string(),
+            "btc-tx",
+            "sol-tx",
+            NOW,
+        );
+        let settled = ORDERS.with(|orders| orders.borrow()[&order_id].clone());
+        assert_eq!(settled.status, OrderStatus::Completed);
+
+        // An order can only be staged once
+        assert!(stage_full_swap(order_id, principal(1), resolver_addresses(), NOW).is_err());
+    }
+
+    #[test]
+    fn should_refuse_shared_addresses() {
+        let mut created = order(0, principal(1), OrderStatus::AwaitingDeposit);
+        created.creator_sol_address = Some("ResolverSolAddress".to_string());
+        let order_id = register_order(created, principal(9)).unwrap();
+
+        assert!(stage_full_swap(order_id, principal(1), resolver_addresses(), NOW).is_err());
+    }
+}
+
+mod test_mode {
+    use crate::basic_solana::SolanaNetwork;
+    use crate::solana_integration::check_test_send_allowed;
+
+    #[test]
+    fn should_reject_test_send_when_test_mode_is_off() {
+        let error = check_test_send_allowed(false, &SolanaNetwork::Devnet).unwrap_err();
+        assert!(error.starts_with("TestModeDisabled:"));
+    }
+
+    #[test]
+    fn should_permit_test_send_in_test_mode_off_mainnet() {
+        assert_eq!(
+            check_test_send_allowed(true, &SolanaNetwork::Devnet),
+            Ok(())
+        );
+        assert!(check_test_send_allowed(true, &SolanaNetwork::Mainnet).is_err());
+    }
+}
+
+mod bitcoin_deposit_address_types {
+    use super::utxo;
+    use crate::bitcoin_integration::{
+        assess_deposit_utxos, check_bitcoin_address_network, transaction_utxos,
+    };
+    use bitcoin::Network;
+    use ic_cdk::bitcoin_canister::Utxo;
+
+    const P2WPKH: &str = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx";
+    const P2TR: &str = "tb1pqqqqp399et2xygdj5xreqhjjvcmzhxw4aywxecjdzew6hylgvsesf3hn0c";
+
+    // Two outputs adding up to the deposit, one of them still pending
+    fn deposit_utxos() -> Vec<Utxo> {
+        vec![utxo(1, 0, 60_000, 100), utxo(2, 0, 40_000, 106)]
+    }
+
+    #[test]
+    fn should_verify_deposit_to_p2wpkh_address() {
+        assert_eq!(
+            check_bitcoin_address_network(P2WPKH, Network::Testnet),
+            Ok(())
+        );
+
+        let result = assess_deposit_utxos(&deposit_utxos(), 105, 100_000).unwrap();
+        assert!(result.verified);
+        assert_eq!(result.observed_amount, 100_000);
+        assert_eq!(result.confirmations, Some(0));
+    }
+
+    #[test]
+    fn should_verify_deposit_to_p2tr_address() {
+        assert_eq!(
+            check_bitcoin_address_network(P2TR, Network::Testnet),
+            Ok(())
+        );
+
+        let result = assess_deposit_utxos(&deposit_utxos(), 105, 100_000).unwrap();
+        assert!(result.verified);
+        assert_eq!(result.observed_amount, 100_000);
+    }
+
+    #[test]
+    fn should_report_short_deposit() {
+        let result = assess_deposit_utxos(&deposit_utxos()[..1], 105, 100_000).unwrap();
+        assert!(!result.verified);
+        assert_eq!(result.observed_amount, 60_000);
+    }
+
+    #[test]
+    fn should_only_count_outputs_of_named_transaction() {
+        let txid = "02".repeat(32);
+        let utxos = transaction_utxos(&deposit_utxos(), &txid).unwrap();
+        assert_eq!(utxos.len(), 1);
+        assert_eq!(utxos[0].value, 40_000);
+        // The address holds 100_000 in all, but this transaction paid only part of it
+        let result = assess_deposit_utxos(&utxos, 105, 100_000).unwrap();
+        assert!(!result.verified);
+
+        assert!(transaction_utxos(&deposit_utxos(), "not-a-txid").is_err());
+    }
+}
+
+mod supported_assets {
+    use crate::orders::get_supported_assets;
+    use crate::storage::{add_allowed_spl_mint, cache_token_metadata, remove_allowed_spl_mint};
+    use crate::types::TokenMetadata;
+
+    const USDC_MINT: &str = "EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v";
+    const BONK_MINT: &str = "DezXAZ8z7PnrnRJjz3wXBoRgixCa6xjnB7YaB1pPB263";
+
+    #[test]
+    fn should_list_native_assets_first() {
+        let assets = get_supported_assets();
+        assert_eq!(assets.len(), 2);
+        assert_eq!(assets[0].symbol.as_deref(), Some("BTC"));
+        assert_eq!(assets[0].decimals, Some(8));
+        assert_eq!(assets[0].network, "Bitcoin Testnet");
+        assert_eq!(assets[1].symbol.as_deref(), Some("SOL"));
+        assert_eq!(assets[1].decimals, Some(9));
+        assert_eq!(assets[1].network, "Solana Devnet");
+    }
+
+    #[test]
+    fn should_follow_spl_allowlist() {
+        add_allowed_spl_mint(USDC_MINT.to_string());
+        add_allowed_spl_mint(BONK_MINT.to_string());
+        cache_token_metadata(TokenMetadata {
+            mint_address: USDC_MINT.to_string(),
+            name: Some("USD Coin".to_string()),
+            symbol: Some("USDC".to_string()),
+            decimals: 6,
+        });
+
+        let assets = get_supported_assets();
+        let mints: Vec<_> = assets
+            .iter()
+            .filter_map(|asset| asset.mint.as_deref())
+            .collect();
+        assert_eq!(mints, vec![USDC_MINT, BONK_MINT]);
+        assert_eq!(assets[2].symbol.as_deref(), Some("USDC"));
+        assert_eq!(assets[2].decimals, Some(6));
+        // Metadata of this mint was never fetched
+        assert_eq!(assets[3].decimals, None);
+
+        // A mint removed from the allowlist disappears from the list
+        remove_allowed_spl_mint(BONK_MINT);
+        assert_eq!(get_supported_assets().len(), 3);
+    }
+}
+
+mod accept_expiry {
+    use super::{order, principal};
+    use crate::orders::check_acceptance;
+    use crate::types::OrderStatus;
+
+    #[test]
+    fn should_reject_accepting_expired_order() {
+        let pending = order(1, principal(1), OrderStatus::DepositReceived);
+        assert_eq!(check_acceptance(&pending, pending.expires_at - 1), Ok(()));
+
+        let error = check_acceptance(&pending, pending.expires_at).unwrap_err();
+        assert!(error.starts_with("Expired:"));
+    }
+
+    #[test]
+    fn should_reject_accepting_order_without_deposit() {
+        let awaiting = order(1, principal(1), OrderStatus::AwaitingDeposit);
+        assert!(check_acceptance(&awaiting, awaiting.created_at).is_err());
+    }
+}
+
+mod compute_unit_price {
+    use crate::solana_integration::{
+        compute_budget_instructions, compute_unit_price, sampled_prioritization_fees,
+    };
+    use crate::storage::{cache_compute_unit_price, cached_compute_unit_price};
+    use sol_rpc_types::{
+        MultiRpcResult, PrioritizationFee, RpcError, RpcSource, SupportedRpcProviderId,
+    };
+
+    const MAX_PRICE: u64 = 100_000;
+
+    fn fees(values: &[u64]) -> Vec<PrioritizationFee> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(index, fee)| PrioritizationFee {
+                slot: 338_637_772 + index as u64,
+                prioritization_fee: *fee,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn should_price_from_mocked_recent_fees() {
+        // Congested: most recent slots paid for priority
+        let response = MultiRpcResult::Consistent(Ok(fees(&[
+            0, 1_000, 5_000, 10_000, 12_000, 15_000, 20_000, 25_000, 50_000,
+        ])));
+        let samples = sampled_prioritization_fees(response).unwrap();
+        assert_eq!(compute_unit_price(&samples, MAX_PRICE), 20_000);
+
+        // Idle: no overpaying
+        let idle = sampled_prioritization_fees(MultiRpcResult::Consistent(Ok(fees(&[0; 150]))));
+        assert_eq!(compute_unit_price(&idle.unwrap(), MAX_PRICE), 0);
+
+        // Spikes are capped
+        assert_eq!(compute_unit_price(&[5_000_000; 10], MAX_PRICE), MAX_PRICE);
+    }
+
+    #[test]
+    fn should_pool_samples_of_disagreeing_providers() {
+        let response = MultiRpcResult::Inconsistent(vec![
+            (
+                RpcSource::Supported(SupportedRpcProviderId::AlchemyDevnet),
+                Ok(fees(&[1_000, 2_000])),
+            ),
+            (
+                RpcSource::Supported(SupportedRpcProviderId::HeliusDevnet),
+                Err(RpcError::ValidationError("timeout".to_string())),
+            ),
+        ]);
+        assert_eq!(
+            sampled_prioritization_fees(response),
+            Ok(vec![1_000, 2_000])
+        );
+
+        let failed = MultiRpcResult::Consistent(Err(RpcError::ValidationError("down".to_string())));
+        assert!(sampled_prioritization_fees(failed).is_err());
+    }
+
+    #[test]
+    fn should_add_budget_instructions_only_for_a_priority_fee() {
+        assert!(compute_budget_instructions(0).is_empty());
+
+        let instructions = compute_budget_instructions(20_000);
+        assert_eq!(instructions.len(), 2);
+        assert_eq!(instructions[0].data, [2, 0x60, 0xea, 0, 0]);
+        assert_eq!(instructions[1].data[0], 3);
+        assert_eq!(&instructions[1].data[1..], &20_000u64.to_le_bytes());
+    }
+
+    #[test]
+    fn should_reuse_sample_briefly() {
+        cache_compute_unit_price(20_000, 1_000);
+        assert_eq!(
+            cached_compute_unit_price(1_000 + 9_000_000_000, 10),
+            Some(20_000)
+        );
+        assert_eq!(cached_compute_unit_price(1_000 + 10_000_000_000, 10), None);
+    }
+}
+
+mod same_asset_swap {
+    use crate::orders::check_distinct_assets;
+    use crate::types::Asset;
+
+    fn spl(mint_address: &str) -> Asset {
+        Asset::SplToken {
+            mint_address: mint_address.to_string(),
+            decimals: 6,
+        }
+    }
+
+    #[test]
+    fn should_reject_btc_for_btc() {
+        let error = check_distinct_assets(&Asset::Bitcoin, &Asset::Bitcoin, &[]).unwrap_err();
+        assert!(error.starts_with("SameAssetSwap:"));
+        assert_eq!(
+            check_distinct_assets(&Asset::Bitcoin, &Asset::Solana, &[]),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn should_reject_spl_for_same_mint() {
+        let usdc = spl("EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v");
+        let usdt = spl("Es9vMFrzaCERmJfrF4H2FYD4KCoNkY11McCe8BenwNYB");
+
+        let error = check_distinct_assets(&usdc, &usdc, &[]).unwrap_err();
+        assert!(error.starts_with("SameAssetSwap:"));
+        assert_eq!(check_distinct_assets(&usdc, &usdt, &[]), Ok(()));
+        // Nor may a basket leg pay back the asset being sold
+        assert!(check_distinct_assets(&usdc, &usdt, &[(usdc.clone(), 1)]).is_err());
+    }
+}
+
+mod deposit_poll_backoff {
+    use super::{insert_orders, order, poll_once, principal};
+    use crate::deposit_watcher::{next_poll_at, watch_deposits_with};
+    use crate::storage::{claim_deposit, CONFIG};
+    use crate::types::OrderStatus;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    #[test]
+    fn should_poll_less_often_as_order_ages() {
+        // Created at 1_000 ns, expiring an hour later
+        let order = order(1, principal(1), OrderStatus::AwaitingDeposit);
+
+        let intervals: Vec<u64> = [
+            1_000,
+            1_000 + 600 * SECOND,
+            1_000 + 1_800 * SECOND,
+            order.expires_at,
+        ]
+        .into_iter()
+        .map(|polled_at| next_poll_at(&order, polled_at, 10, 600) - polled_at)
+        .collect();
+        assert_eq!(intervals[0], 10 * SECOND);
+        assert!(intervals.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(intervals[3], 600 * SECOND);
+        // Past expiry the interval stays at the maximum
+        let late = order.expires_at + 100 * SECOND;
+        assert_eq!(next_poll_at(&order, late, 10, 600) - late, 600 * SECOND);
+    }
+
+    #[test]
+    fn should_skip_fetch_until_an_order_is_due() {
+        CONFIG.with(|config| {
+            let mut config = config.borrow_mut();
+            config.deposit_watch_interval_seconds = Some(10);
+            config.deposit_poll_max_interval_seconds = 600;
+        });
+        insert_orders(vec![order(1, principal(1), OrderStatus::AwaitingDeposit)]);
+        claim_deposit(1, "deposit-tx");
+        let fetched = std::cell::Cell::new(0);
+        let poll = |now: u64| {
+            poll_once(watch_deposits_with(
+                || async {
+                    fetched.set(fetched.get() + 1);
+                    Ok(vec![])
+                },
+                now,
+            ));
+        };
+
+        poll(1_000);
+        poll(1_000 + 5 * SECOND);
+        assert_eq!(fetched.get(), 1);
+        poll(1_000 + 10 * SECOND);
+        assert_eq!(fetched.get(), 2);
+    }
+}
+
+mod resolver_evaluation {
+    use super::{order, principal};
+    use crate::orders::{evaluate_resolver_profit, resolver_deposit_fees};
+    use crate::types::{Asset, OrderStatus};
+
+    // USD micros per whole unit
+    fn prices(sol_usd_micros: u64) -> impl Fn(&Asset) -> Option<u64> {
+        move |asset| match asset {
+            Asset::Bitcoin => Some(60_000_000_000),
+            Asset::Solana => Some(sol_usd_micros),
+            Asset::SplToken { .. } => None,
+        }
+    }
+
+    #[test]
+    fn should_report_profit_when_from_side_is_worth_more() {
+        // Receives 0.001 BTC ($60), pays 1 SOL ($50) plus one 5000 lamport fee ($0.00025)
+        let order = order(1, principal(1), OrderStatus::DepositReceived);
+        let fees = resolver_deposit_fees(&order, 0);
+        assert_eq!(fees, vec![(Asset::Solana, 5_000)]);
+
+        let evaluation = evaluate_resolver_profit(&order, &fees, prices(50_000_000)).unwrap();
+        assert_eq!(evaluation.gross_spread, 10_000_000);
+        assert_eq!(evaluation.estimated_fees, 250);
+        assert_eq!(evaluation.net_profit, 9_999_750);
+        assert!(evaluation.profitable);
+    }
+
+    #[test]
+    fn should_report_loss_when_to_side_is_worth_more() {
+        let order = order(1, principal(1), OrderStatus::DepositReceived);
+        let fees = resolver_deposit_fees(&order, 0);
+
+        let evaluation = evaluate_resolver_profit(&order, &fees, prices(70_000_000)).unwrap();
+        assert_eq!(evaluation.gross_spread, -10_000_000);
+        assert_eq!(evaluation.net_profit, -10_000_350);
+        assert!(!evaluation.profitable);
+    }
+
+    #[test]
+    fn should_fail_without_a_feed_price() {
+        let order = order(1, principal(1), OrderStatus::DepositReceived);
+        let no_sol = |asset: &Asset| (*asset == Asset::Bitcoin).then_some(60_000_000_000);
+
+        let error = evaluate_resolver_profit(&order, &[], no_sol).unwrap_err();
+        assert_eq!(error, "No price available for Solana");
+    }
+
+    #[test]
+    fn should_size_bitcoin_deposit_fee_from_fee_rate() {
+        let mut order = order(1, principal(1), OrderStatus::DepositReceived);
+        order.from_asset = Asset::Solana;
+        order.to_asset = Asset::Bitcoin;
+
+        // 141 vbytes at 2000 millisatoshi/byte
+        assert_eq!(
+            resolver_deposit_fees(&order, 2_000),
+            vec![(Asset::Bitcoin, 282)]
+        );
+    }
+}
+
+mod listing_order {
+    use super::{insert_orders, order, principal};
+    use crate::storage::{get_my_orders, get_orders_by_wallet};
+    use crate::types::OrderStatus;
+
+    fn ids(orders: Vec<crate::types::OrderInfo>) -> Vec<u64> {
+        orders.into_iter().map(|order| order.id).collect()
+    }
+
+    // Opaque IDs do not follow creation, so the oldest order may have the largest ID
+    fn book() {
+        let creator = principal(1);
+        let mut oldest = order(900, creator, OrderStatus::DepositReceived);
+        oldest.created_at = 100;
+        let mut tied = order(7, creator, OrderStatus::AwaitingDeposit);
+        tied.created_at = 500;
+        let mut tied_later_id = order(42, creator, OrderStatus::Completed);
+        tied_later_id.created_at = 500;
+        let mut newest = order(3, creator, OrderStatus::DepositReceived);
+        newest.created_at = 800;
+        for order in [&mut oldest, &mut tied, &mut tied_later_id, &mut newest] {
+            order.creator_sol_address = Some("creator-sol".to_string());
+        }
+        insert_orders(vec![newest, tied_later_id, oldest, tied]);
+    }
+
+    #[test]
+    fn should_list_orders_by_creation_then_id() {
+        book();
+        assert_eq!(ids(get_my_orders(principal(1))), vec![900, 7, 42, 3]);
+    }
+
+    #[test]
+    fn should_return_identical_order_on_repeated_calls() {
+        book();
+        let by_wallet = || ids(get_orders_by_wallet(None, Some("creator-sol".to_string())));
+
+        let first = by_wallet();
+        assert_eq!(first, vec![900, 7, 42, 3]);
+        assert_eq!(by_wallet(), first);
+        assert_eq!(
+            ids(get_my_orders(principal(1))),
+            ids(get_my_orders(principal(1)))
+        );
+    }
+}
+
+mod unfunded_orders {
+    use super::{insert_orders, order, principal};
+    use crate::deposit_watcher::cancel_unfunded_orders;
+    use crate::storage::{
+        claim_deposit, creator_deposit_deadline, deposit_claims, get_order_deadlines,
+        get_order_status, CONFIG,
+    };
+    use crate::types::OrderStatus;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn set_deposit_window(seconds: Option<u64>) {
+        CONFIG.with(|config| config.borrow_mut().creator_deposit_seconds = seconds);
+    }
+
+    #[test]
+    fn should_cancel_order_without_deposit_after_deadline() {
+        set_deposit_window(Some(600));
+        // Fixture orders are created at 1_000 and expire an hour later
+        insert_orders(vec![
+            order(1, principal(1), OrderStatus::AwaitingDeposit),
+            order(2, principal(2), OrderStatus::DepositReceived),
+        ]);
+        let deadline = 1_000 + 600 * SECOND;
+
+        assert!(cancel_unfunded_orders(deadline - 1).is_empty());
+        assert_eq!(get_order_status(1), Some(OrderStatus::AwaitingDeposit));
+
+        assert_eq!(cancel_unfunded_orders(deadline), vec![1]);
+        assert_eq!(get_order_status(1), Some(OrderStatus::Cancelled));
+        assert_eq!(get_order_status(2), Some(OrderStatus::DepositReceived));
+        crate::storage::ORDERS.with(|orders| {
+            assert_eq!(
+                orders.borrow().get(&1).unwrap().cancelled_at,
+                Some(deadline)
+            );
+        });
+    }
+
+    #[test]
+    fn should_not_cancel_order_with_pending_deposit_confirmation() {
+        set_deposit_window(Some(600));
+        insert_orders(vec![
+            order(1, principal(1), OrderStatus::AwaitingDeposit),
+            order(2, principal(2), OrderStatus::AwaitingDeposit),
+        ]);
+        claim_deposit(1, "pending-tx");
+        let deadline = 1_000 + 600 * SECOND;
+
+        assert_eq!(cancel_unfunded_orders(deadline), vec![2]);
+        assert_eq!(get_order_status(1), Some(OrderStatus::AwaitingDeposit));
+        assert_eq!(deposit_claims(), vec![(1, "pending-tx".to_string())]);
+    }
+
+    #[test]
+    fn should_keep_orders_open_without_a_deposit_window() {
+        set_deposit_window(None);
+        let awaiting = order(1, principal(1), OrderStatus::AwaitingDeposit);
+        insert_orders(vec![awaiting.clone()]);
+
+        assert!(cancel_unfunded_orders(awaiting.expires_at).is_empty());
+        assert_eq!(get_order_status(1), Some(OrderStatus::AwaitingDeposit));
+    }
+
+    #[test]
+    fn should_never_fall_after_the_acceptance_deadline() {
+        let awaiting = order(1, principal(1), OrderStatus::AwaitingDeposit);
+        assert_eq!(
+            creator_deposit_deadline(&awaiting, Some(7_200)),
+            Some(awaiting.expires_at)
+        );
+
+        set_deposit_window(Some(600));
+        insert_orders(vec![awaiting.clone()]);
+        let deadlines = get_order_deadlines(1, awaiting.created_at).unwrap();
+        assert_eq!(
+            deadlines.creator_deposit_deadline,
+            Some(awaiting.created_at + 600 * SECOND)
+        );
+        assert!(deadlines.creator_deposit_deadline.unwrap() < deadlines.acceptance_deadline);
+    }
+}
+
+mod order_storage_size {
+    use super::{order, principal};
+    use crate::orders::{MAX_EXTRA_TO_LEGS, MAX_ORDER_METADATA_BYTES};
+    use crate::types::{Asset, Order, OrderPricing, OrderStatus, SettlementReceipt};
+    use candid::{decode_one, encode_one};
+
+    // The `max_size` a stable-storage `Storable::BOUND` for `Order` must declare; this audit is
+    // what justifies it, so raise both together when `Order` grows
+    const ORDER_MAX_SIZE: usize = 8 * 1024;
+
+    // Longest value each field plausibly holds, in bytes
+    const BTC_ADDRESS_LEN: usize = 90; // Bech32 limit; P2TR and P2WSH addresses are 62
+    const SOL_ADDRESS_LEN: usize = 44;
+    const TXID_LEN: usize = 88; // Base58 Solana signature; Bitcoin txids are 64 hex chars
+    const SECRET_HASH_LEN: usize = 32; // Hex MD5
+                                       // Neither the secret nor its hash is length-checked at creation, and `manual_review` embeds
+                                       // error text; the migration has to cap these before relying on the bound
+    const SECRET_LEN: usize = 64;
+    const MANUAL_REVIEW_LEN: usize = 512;
+
+    fn text(len: usize) -> Option<String> {
+        Some("x".repeat(len))
+    }
+
+    fn spl(tag: &str) -> Asset {
+        Asset::SplToken {
+            mint_address: tag.repeat(SOL_ADDRESS_LEN),
+            decimals: u8::MAX,
+        }
+    }
+
+    // Every optional field set, every list at its cap and every string at its longest
+    fn maximal_order() -> Order {
+        let mut order = order(u64::MAX, principal(1), OrderStatus::SettlementFailed);
+        order.sequence = u64::MAX;
+        order.creator_btc_address = text(BTC_ADDRESS_LEN);
+        order.creator_sol_address = text(SOL_ADDRESS_LEN);
+        order.from_asset = spl("A");
+        order.to_asset = spl("B");
+        order.from_amount = u64::MAX;
+        order.to_amount = u64::MAX;
+        order.pricing = OrderPricing::Oracle {
+            reference: u64::MAX,
+            max_slippage_bps: u16::MAX,
+        };
+        order.extra_to_legs = ["C", "D", "E"]
+            .into_iter()
+            .take(MAX_EXTRA_TO_LEGS)
+            .map(|tag| (spl(tag), u64::MAX))
+            .collect();
+        order.secret_hash = "x".repeat(SECRET_HASH_LEN);
+        order.secret = text(SECRET_LEN);
+        order.resolver = Some(principal(2));
+        order.resolver_btc_address = text(BTC_ADDRESS_LEN);
+        order.resolver_sol_address = text(SOL_ADDRESS_LEN);
+        order.created_at = u64::MAX;
+        order.expires_at = u64::MAX;
+        order.creator_txid = text(TXID_LEN);
+        order.resolver_txid = text(TXID_LEN);
+        order.resolver_payout_txid = text(TXID_LEN);
+        order.creator_payout_txid = text(TXID_LEN);
+        order.extra_leg_txids = vec!["x".repeat(TXID_LEN); MAX_EXTRA_TO_LEGS];
+        order.resolver_refund_txid = text(TXID_LEN);
+        order.creator_refund_amount = Some(u64::MAX);
+        order.resolver_refund_amount = Some(u64::MAX);
+        order.creator_overpayment = Some(u64::MAX);
+        order.overpayment_refund_txid = text(TXID_LEN);
+        order.settlement_receipt = Some(SettlementReceipt {
+            btc_fee: u64::MAX,
+            sol_fee: u64::MAX,
+            cycles_spent: u64::MAX,
+            payout_amounts: std::iter::once((order.from_asset.clone(), u64::MAX))
+                .chain(order.to_legs())
+                .collect(),
+        });
+        order.creator_deposited = true;
+        order.resolver_deposited = true;
+        order.require_resolver_ack = true;
+        order.secret_revealed_by = Some(principal(2));
+        order.manual_review = text(MANUAL_REVIEW_LEN);
+        order.metadata = text(MAX_ORDER_METADATA_BYTES);
+        order.deposit_confirmed_at = Some(u64::MAX);
+        order.accepted_at = Some(u64::MAX);
+        order.resolver_deposited_at = Some(u64::MAX);
+        order.resolver_acknowledged_at = Some(u64::MAX);
+        order.completed_at = Some(u64::MAX);
+        order.cancelled_at = Some(u64::MAX);
+        order
+    }
+
+    #[test]
+    fn should_fit_maximal_order_within_storage_bound() {
+        let order = maximal_order();
+
+        let bytes = encode_one(&order).unwrap();
+        assert!(
+            bytes.len() <= ORDER_MAX_SIZE,
+            "a maximal Order encodes to {} bytes, over the {} byte bound",
+            bytes.len(),
+            ORDER_MAX_SIZE
+        );
+
+        let decoded: Order = decode_one(&bytes).unwrap();
+        assert_eq!(decoded.metadata, order.metadata);
+        assert_eq!(decoded.extra_leg_txids, order.extra_leg_txids);
+    }
+}
+
+mod minimum_deposit {
+    use crate::orders::check_minimum_deposit;
+    use crate::types::{
+        Asset, AssetLimit, SwapConfig, SwapInitArg, DEFAULT_MIN_BTC_DEPOSIT_SATS,
+        DEFAULT_MIN_SOL_DEPOSIT_LAMPORTS,
+    };
+
+    fn default_config() -> SwapConfig {
+        SwapConfig::from(SwapInitArg::default())
+    }
+
+    #[test]
+    fn should_enforce_default_bitcoin_minimum() {
+        let config = default_config();
+        let min = DEFAULT_MIN_BTC_DEPOSIT_SATS;
+
+        assert_eq!(check_minimum_deposit(&config, &Asset::Bitcoin, min), Ok(()));
+        let error = check_minimum_deposit(&config, &Asset::Bitcoin, min - 1).unwrap_err();
+        assert!(error.starts_with("BelowMinimum:"));
+    }
+
+    #[test]
+    fn should_enforce_default_solana_minimum() {
+        let config = default_config();
+        let min = DEFAULT_MIN_SOL_DEPOSIT_LAMPORTS;
+
+        assert_eq!(check_minimum_deposit(&config, &Asset::Solana, min), Ok(()));
+        let error = check_minimum_deposit(&config, &Asset::Solana, min - 1).unwrap_err();
+        assert!(error.starts_with("BelowMinimum:"));
+    }
+
+    #[test]
+    fn should_use_configured_minimums() {
+        let config = SwapConfig::from(SwapInitArg {
+            min_deposit_amounts: Some(vec![AssetLimit {
+                asset: Asset::Bitcoin,
+                amount: 50_000,
+            }]),
+            ..SwapInitArg::default()
+        });
+
+        assert!(check_minimum_deposit(&config, &Asset::Bitcoin, 49_999).is_err());
+        assert_eq!(
+            check_minimum_deposit(&config, &Asset::Bitcoin, 50_000),
+            Ok(())
+        );
+        // Assets left out of the list have no minimum
+        assert_eq!(check_minimum_deposit(&config, &Asset::Solana, 1), Ok(()));
+    }
+}
+
+mod order_detail {
+    use super::{insert_orders, order, principal};
+    use crate::storage::get_my_order_detail;
+    use crate::types::OrderStatus;
+
+    fn settled_order(status: OrderStatus) {
+        let mut settled = order(1, principal(1), status);
+        settled.resolver = Some(principal(2));
+        settled.creator_txid = Some("creator-tx".to_string());
+        settled.resolver_txid = Some("resolver-tx".to_string());
+        settled.secret = Some("secret".to_string());
+        insert_orders(vec![settled]);
+    }
+
+    #[test]
+    fn should_show_txids_to_creator_and_resolver() {
+        settled_order(OrderStatus::Completed);
+
+        for party in [principal(1), principal(2)] {
+            let detail = get_my_order_detail(party, 1).unwrap();
+            assert_eq!(detail.info.id, 1);
+            assert_eq!(detail.creator_txid, Some("creator-tx".to_string()));
+            assert_eq!(detail.resolver_txid, Some("resolver-tx".to_string()));
+            assert_eq!(detail.secret, Some("secret".to_string()));
+        }
+    }
+
+    #[test]
+    fn should_withhold_secret_until_completed() {
+        settled_order(OrderStatus::SettlementFailed);
+
+        let detail = get_my_order_detail(principal(1), 1).unwrap();
+        assert_eq!(detail.creator_txid, Some("creator-tx".to_string()));
+        assert_eq!(detail.secret, None);
+    }
+
+    #[test]
+    fn should_reject_stranger() {
+        settled_order(OrderStatus::Completed);
+
+        assert_eq!(
+            get_my_order_detail(principal(3), 1).unwrap_err(),
+            "Only the order creator or resolver can view its details"
+        );
+        assert_eq!(
+            get_my_order_detail(principal(1), 2).unwrap_err(),
+            "Order not found"
+        );
+    }
+}
+
+mod solana_simulation {
+    use super::poll_once;
+    use crate::solana_integration::{check_simulation, send_after_preflight, simulation_outcome};
+    use sol_rpc_types::{MultiRpcResult, RpcError, RpcSource, SupportedRpcProviderId};
+    use std::cell::Cell;
+
+    const PASSED: &str = r#"{"jsonrpc":"2.0","id":1,"result":{"context":{"slot":1},"value":{"err":null,"logs":["Program 11111111111111111111111111111111 success"]}}}"#;
+    const FAILED: &str = r#"{"jsonrpc":"2.0","id":1,"result":{"context":{"slot":1},"value":{"err":{"InsufficientFundsForRent":{"account_index":1}},"logs":["Program 11111111111111111111111111111111 invoke [1]","Transfer: insufficient lamports 10, need 890880"]}}}"#;
+
+    fn send_with(simulation: &str, simulate: bool) -> (Result<String, String>, bool) {
+        let broadcast = Cell::new(false);
+        let result = poll_once(send_after_preflight(
+            simulate,
+            || async { MultiRpcResult::Consistent(Ok(simulation.to_string())) },
+            || async {
+                broadcast.set(true);
+                Ok("signature".to_string())
+            },
+        ));
+        (result, broadcast.get())
+    }
+
+    #[test]
+    fn should_not_broadcast_when_simulation_fails() {
+        let (result, broadcast) = send_with(FAILED, true);
+
+        assert_eq!(
+            result.unwrap_err(),
+            r#"SimulationFailed: {"InsufficientFundsForRent":{"account_index":1}} (Transfer: insufficient lamports 10, need 890880)"#
+        );
+        assert!(!broadcast);
+    }
+
+    #[test]
+    fn should_broadcast_after_passing_or_skipped_simulation() {
+        assert_eq!(send_with(PASSED, true), (Ok("signature".to_string()), true));
+        assert_eq!(
+            send_with(FAILED, false),
+            (Ok("signature".to_string()), true)
+        );
+    }
+
+    #[test]
+    fn should_surface_rpc_errors_from_simulation() {
+        let error = check_simulation(
+            r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32602,"message":"invalid transaction"}}"#,
+        )
+        .unwrap_err();
+        assert!(error.starts_with("SimulationFailed:"));
+        assert!(error.contains("invalid transaction"));
+    }
+
+    #[test]
+    fn should_only_block_when_most_answering_providers_fail() {
+        let source = RpcSource::Supported;
+        let unreachable = || Err(RpcError::ValidationError("timeout".to_string()));
+
+        let one_of_two = MultiRpcResult::Inconsistent(vec![
+            (
+                source(SupportedRpcProviderId::AlchemyDevnet),
+                Ok(FAILED.to_string()),
+            ),
+            (
+                source(SupportedRpcProviderId::HeliusDevnet),
+                Ok(PASSED.to_string()),
+            ),
+        ]);
+        assert_eq!(simulation_outcome(one_of_two), Ok(()));
+
+        let only_answer_fails = MultiRpcResult::Inconsistent(vec![
+            (
+                source(SupportedRpcProviderId::AlchemyDevnet),
+                Ok(FAILED.to_string()),
+            ),
+            (source(SupportedRpcProviderId::HeliusDevnet), unreachable()),
+        ]);
+        assert!(simulation_outcome(only_answer_fails).is_err());
+
+        assert_eq!(
+            simulation_outcome(MultiRpcResult::Consistent(unreachable())),
+            Ok(())
+        );
+    }
+}
+
+mod address_derivation_failure {
+    use super::{insert_orders, order, poll_once, principal};
+    use crate::orders::register_with_addresses;
+    use crate::storage::{open_order_count, NEXT_ORDER_ID, ORDERS};
+    use crate::types::{Asset, CanisterAddresses, OrderStatus};
+
+    fn addresses() -> CanisterAddresses {
+        CanisterAddresses {
+            bitcoin_address: "tb1qcanister".to_string(),
+            solana_address: "CanisterSolAddress".to_string(),
+        }
+    }
+
+    #[test]
+    fn should_not_store_order_when_derivation_fails() {
+        insert_orders(vec![]);
+        let next_id = NEXT_ORDER_ID.with(|id| *id.borrow());
+
+        let result = poll_once(register_with_addresses(
+            order(0, principal(1), OrderStatus::AwaitingDeposit),
+            || async { Err("Failed to derive the canister Bitcoin address".to_string()) },
+            principal(9),
+        ));
+
+        assert_eq!(
+            result.unwrap_err(),
+            "Failed to derive the canister Bitcoin address"
+        );
+        assert!(ORDERS.with(|orders| orders.borrow().is_empty()));
+        assert_eq!(NEXT_ORDER_ID.with(|id| *id.borrow()), next_id);
+        assert_eq!(open_order_count(&Asset::Bitcoin), 0);
+    }
+
+    #[test]
+    fn should_register_order_once_addresses_are_derived() {
+        insert_orders(vec![]);
+
+        let (order_id, canister_addresses) = poll_once(register_with_addresses(
+            order(0, principal(1), OrderStatus::AwaitingDeposit),
+            || async { Ok(addresses()) },
+            principal(9),
+        ))
+        .unwrap();
+
+        assert_eq!(canister_addresses.bitcoin_address, "tb1qcanister");
+        assert!(ORDERS.with(|orders| orders.borrow().contains_key(&order_id)));
+        assert_eq!(open_order_count(&Asset::Bitcoin), 1);
+    }
+}
+
+mod batch_verification {
+    use super::{insert_orders, order, poll_once, principal};
+    use crate::orders::{verify_deposits_with, PendingDeposit, MAX_BATCH_VERIFICATIONS};
+    use crate::types::{Asset, OrderStatus, VerificationResult};
+
+    fn verified(amount: u64) -> VerificationResult {
+        VerificationResult::assess(amount, amount, None, 0)
+    }
+
+    #[test]
+    fn should_verify_mixed_orders_checking_each_deposit_once() {
+        let creator = principal(1);
+        let confirmed = {
+            let mut order = order(1, creator, OrderStatus::DepositReceived);
+            order.creator_deposited = true;
+            order
+        };
+        let pending = {
+            let mut order = order(2, creator, OrderStatus::AwaitingDeposit);
+            order.creator_txid = Some("tx-a".to_string());
+            order
+        };
+        let same_deposit = {
+            let mut order = order(3, creator, OrderStatus::AwaitingDeposit);
+            order.creator_txid = Some("tx-a".to_string());
+            order
+        };
+        let unreachable = {
+            let mut order = order(4, creator, OrderStatus::AwaitingDeposit);
+            order.creator_txid = Some("tx-b".to_string());
+            order
+        };
+        let no_txid = order(5, creator, OrderStatus::AwaitingDeposit);
+        let resolving = {
+            let mut order = order(6, principal(2), OrderStatus::DepositReceived);
+            order.resolver = Some(creator);
+            order.resolver_txid = Some("tx-sol".to_string());
+            order
+        };
+        let someone_elses = order(7, principal(2), OrderStatus::AwaitingDeposit);
+        insert_orders(vec![
+            confirmed,
+            pending,
+            same_deposit,
+            unreachable,
+            no_txid,
+            resolving,
+            someone_elses,
+        ]);
+
+        let mut checked: Vec<PendingDeposit> = Vec::new();
+        let results = poll_once(verify_deposits_with(
+            creator,
+            vec![1, 2, 3, 4, 5, 6, 7, 99],
+            |deposit| {
+                checked.push(deposit.clone());
+                let result = match deposit.txid.as_str() {
+                    "tx-b" => Err("ChainUnavailable: Bitcoin".to_string()),
+                    _ => Ok(verified(deposit.amount)),
+                };
+                async move { result }
+            },
+        ))
+        .unwrap();
+
+        let verified_ids: Vec<u64> = results
+            .iter()
+            .filter(|(_, result)| result.verified)
+            .map(|(order_id, _)| *order_id)
+            .collect();
+        assert_eq!(verified_ids, vec![1, 2, 3, 6]);
+        assert_eq!(
+            results[3].1,
+            VerificationResult::not_found("ChainUnavailable: Bitcoin")
+        );
+        assert_eq!(
+            results[4].1,
+            VerificationResult::not_found("No deposit transaction recorded")
+        );
+        assert_eq!(
+            results[7].1,
+            VerificationResult::not_found("Order not found")
+        );
+        assert!(!results[6].1.verified);
+
+        let txids: Vec<&str> = checked
+            .iter()
+            .map(|deposit| deposit.txid.as_str())
+            .collect();
+        assert_eq!(txids, vec!["tx-a", "tx-b", "tx-sol"]);
+        assert_eq!(checked[0].asset, Asset::Bitcoin);
+        assert_eq!(checked[2].asset, Asset::Solana);
+        assert_eq!(checked[2].amount, 1_000_000_000);
+    }
+
+    #[test]
+    fn should_reject_batches_over_the_cap() {
+        insert_orders(vec![]);
+        let order_ids: Vec<u64> = (0..=MAX_BATCH_VERIFICATIONS as u64).collect();
+
+        let result = poll_once(verify_deposits_with(principal(1), order_ids, |deposit| {
+            let result = Ok(verified(deposit.amount));
+            async move { result }
+        }));
+
+        assert!(result.unwrap_err().starts_with("Too many orders"));
+    }
+}
+
+mod named_mints {
+    use crate::orders::{resolve_named_mint, WBTC_MINT};
+    use crate::solana_integration::{spl_transfer_instruction, SPL_TOKEN_PROGRAM_ID};
+    use crate::types::Asset;
+    use solana_pubkey::Pubkey;
+    use spl_associated_token_account_interface::address::get_associated_token_address_with_program_id;
+    use std::str::FromStr;
+
+    const RECIPIENT: &str = "9WzDXwBbmkg8ZTbNMqUxvQRAyrZzDsGYdLVL9zYtAWWM";
+
+    fn spl(mint_address: &str, decimals: u8) -> Asset {
+        Asset::SplToken {
+            mint_address: mint_address.to_string(),
+            decimals,
+        }
+    }
+
+    #[test]
+    fn should_send_wrapped_btc_order_with_canonical_mint() {
+        let asset = resolve_named_mint(spl("wbtc", 0)).unwrap();
+        assert_eq!(asset, spl(WBTC_MINT, 8));
+
+        let Asset::SplToken { mint_address, .. } = asset else {
+            unreachable!()
+        };
+        let canister = Pubkey::new_from_array([7; 32]);
+        let instruction = spl_transfer_instruction(
+            &canister,
+            RECIPIENT,
+            &mint_address,
+            50_000,
+            SPL_TOKEN_PROGRAM_ID,
+        )
+        .unwrap();
+
+        let token_program = Pubkey::from_str(SPL_TOKEN_PROGRAM_ID).unwrap();
+        let mint = Pubkey::from_str(WBTC_MINT).unwrap();
+        let accounts: Vec<Pubkey> = instruction
+            .accounts
+            .iter()
+            .map(|account| account.pubkey)
+            .collect();
+        assert_eq!(
+            accounts,
+            vec![
+                get_associated_token_address_with_program_id(&canister, &mint, &token_program),
+                get_associated_token_address_with_program_id(
+                    &Pubkey::from_str(RECIPIENT).unwrap(),
+                    &mint,
+                    &token_program
+                ),
+                canister,
+            ]
+        );
+    }
+
+    #[test]
+    fn should_validate_registered_mint_decimals() {
+        assert_eq!(resolve_named_mint(spl(WBTC_MINT, 8)), Ok(spl(WBTC_MINT, 8)));
+        assert!(resolve_named_mint(spl(WBTC_MINT, 6))
+            .unwrap_err()
+            .starts_with("MintMismatch:"));
+    }
+
+    #[test]
+    fn should_leave_other_assets_alone() {
+        const USDC_MINT: &str = "EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v";
+        assert_eq!(resolve_named_mint(spl(USDC_MINT, 6)), Ok(spl(USDC_MINT, 6)));
+        assert_eq!(resolve_named_mint(Asset::Bitcoin), Ok(Asset::Bitcoin));
+    }
+}
+
+mod fee_bounds {
+    use super::poll_once;
+    use crate::basic_bitcoin::common::{check_relay_fee, clamp_fee_per_byte, resolve_fee_per_byte};
+    use crate::types::{SwapConfig, SwapInitArg};
+
+    #[test]
+    fn should_clamp_zero_network_fee_to_floor() {
+        let config = SwapConfig::from(SwapInitArg::default());
+        let network_fee = poll_once(resolve_fee_per_byte(None, || async { 0 }));
+
+        assert_eq!(
+            clamp_fee_per_byte(
+                network_fee,
+                config.btc_min_fee_per_byte,
+                config.btc_max_fee_per_byte
+            ),
+            1_000
+        );
+    }
+
+    #[test]
+    fn should_clamp_to_ceiling_and_keep_rates_within_bounds() {
+        assert_eq!(clamp_fee_per_byte(2_000_000, 1_000, 500_000), 500_000);
+        assert_eq!(clamp_fee_per_byte(12_000, 1_000, 500_000), 12_000);
+        // Misconfigured bounds never let the rate fall below the floor
+        assert_eq!(clamp_fee_per_byte(0, 5_000, 2_000), 5_000);
+    }
+
+    #[test]
+    fn should_reject_fee_below_relay_minimum() {
+        assert_eq!(check_relay_fee(141, 141), Ok(()));
+        assert!(check_relay_fee(140, 141)
+            .unwrap_err()
+            .starts_with("FeeTooLow:"));
+        assert!(check_relay_fee(0, 141).is_err());
+    }
+}
+
+mod order_counts {
+    use super::{insert_orders, order, principal};
+    use crate::storage::{get_order_counts, insert_order, update_order};
+    use crate::types::{OrderCounts, OrderStatus};
+
+    #[test]
+    fn should_track_counts_through_state_transitions() {
+        insert_orders(vec![
+            order(1, principal(1), OrderStatus::AwaitingDeposit),
+            order(2, principal(1), OrderStatus::AwaitingDeposit),
+            order(3, principal(2), OrderStatus::Completed),
+        ]);
+        assert_eq!(
+            get_order_counts(),
+            OrderCounts {
+                awaiting_deposit: 2,
+                completed: 1,
+                ..OrderCounts::default()
+            }
+        );
+
+        update_order(1, |ord| ord.status = OrderStatus::DepositReceived);
+        update_order(1, |ord| ord.status = OrderStatus::ResolverDeposited);
+        update_order(2, |ord| ord.status = OrderStatus::Cancelled);
+        insert_order(order(4, principal(3), OrderStatus::AwaitingDeposit));
+        update_order(4, |ord| ord.status = OrderStatus::Expired);
+        assert_eq!(
+            get_order_counts(),
+            OrderCounts {
+                resolver_deposited: 1,
+                completed: 1,
+                cancelled: 1,
+                expired: 1,
+                ..OrderCounts::default()
+            }
+        );
+
+        // Changes that keep the status leave the counts alone
+        update_order(1, |ord| ord.resolver_txid = Some("tx".to_string()));
+        update_order(1, |ord| ord.status = OrderStatus::Completed);
+        assert_eq!(get_order_counts().completed, 2);
+        assert_eq!(get_order_counts().resolver_deposited, 0);
+    }
+}
+
+mod webhook_events {
+    use super::{insert_orders, order, poll_once, principal};
+    use crate::orders::record_refund;
+    use crate::storage::{update_order, CONFIG, WEBHOOK_QUEUE};
+    use crate::types::{DepositLeg, OrderStatus, WebhookEvent, WebhookEventKind};
+    use crate::webhooks::{deliver_webhooks_with, webhook_body, MAX_WEBHOOK_ATTEMPTS};
+
+    const WEBHOOK: &str = "https://example.com/hooks/swaps";
+
+    fn set_webhook(url: Option<&str>) {
+        CONFIG.with(|config| config.borrow_mut().webhook_url = url.map(str::to_string));
+        WEBHOOK_QUEUE.with(|queue| queue.borrow_mut().clear());
+    }
+
+    fn queued() -> Vec<(WebhookEventKind, u32)> {
+        WEBHOOK_QUEUE.with(|queue| {
+            queue
+                .borrow()
+                .iter()
+                .map(|(event, attempts)| (event.kind.clone(), *attempts))
+                .collect()
+        })
+    }
+
+    #[test]
+    fn should_enqueue_delivery_for_confirmed_deposit() {
+        set_webhook(Some(WEBHOOK));
+        insert_orders(vec![order(1, principal(1), OrderStatus::AwaitingDeposit)]);
+
+        update_order(1, |ord| {
+            ord.creator_deposited = true;
+            ord.status = OrderStatus::DepositReceived;
+        });
+        // Changes that are not lifecycle events queue nothing
+        update_order(1, |ord| ord.metadata = Some("note".to_string()));
+        record_refund(1, &DepositLeg::Creator, 100_000);
+
+        assert_eq!(
+            queued(),
+            vec![
+                (WebhookEventKind::DepositConfirmed(DepositLeg::Creator), 0),
+                (WebhookEventKind::Refunded(DepositLeg::Creator), 0),
+            ]
+        );
+        let (event, _) = WEBHOOK_QUEUE.with(|queue| queue.borrow()[0].clone());
+        assert_eq!(event.order_id, 1);
+        assert_eq!(event.status, OrderStatus::DepositReceived);
+        set_webhook(None);
+    }
+
+    #[test]
+    fn should_not_enqueue_without_webhook() {
+        set_webhook(None);
+        insert_orders(vec![order(1, principal(1), OrderStatus::ResolverDeposited)]);
+
+        update_order(1, |ord| ord.status = OrderStatus::Completed);
+
+        assert!(queued().is_empty());
+    }
+
+    #[test]
+    fn should_retry_failed_deliveries_a_bounded_number_of_times() {
+        set_webhook(Some(WEBHOOK));
+        insert_orders(vec![order(1, principal(1), OrderStatus::ResolverDeposited)]);
+        update_order(1, |ord| ord.status = OrderStatus::Completed);
+
+        for attempt in 1..MAX_WEBHOOK_ATTEMPTS {
+            let delivered = poll_once(deliver_webhooks_with(|url, _| async move {
+                assert_eq!(url, WEBHOOK);
+                Err("status 503".to_string())
+            }));
+            assert_eq!(delivered, 0);
+            assert_eq!(queued(), vec![(WebhookEventKind::Settled, attempt)]);
+        }
+        poll_once(deliver_webhooks_with(|_, _| async {
+            Err("status 503".to_string())
+        }));
+        assert!(queued().is_empty());
+
+        update_order(1, |ord| ord.creator_refund_amount = Some(1));
+        let delivered = poll_once(deliver_webhooks_with(|_, _| async { Ok(()) }));
+        assert_eq!(delivered, 1);
+        assert!(queued().is_empty());
+        set_webhook(None);
+    }
+
+    #[test]
+    fn should_serialize_event_as_json() {
+        let event = WebhookEvent {
+            order_id: 7,
+            kind: WebhookEventKind::DepositConfirmed(DepositLeg::Resolver),
+            status: OrderStatus::ResolverDeposited,
+            at: 1_000,
+        };
+        let body: serde_json::Value = serde_json::from_str(&webhook_body(&event)).unwrap();
+
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "order_id": 7,
+                "event": "deposit_confirmed",
+                "leg": "resolver",
+                "status": "ResolverDeposited",
+                "at": 1_000,
+            })
+        );
+    }
+}
+
+mod upgrade_config {
+    use crate::storage::upgraded_config;
+    use crate::types::{SwapConfig, SwapInitArg};
+
+    fn saved() -> SwapConfig {
+        SwapConfig {
+            webhook_url: Some("https://example.com/hook".to_string()),
+            refund_grace_seconds: 120,
+            ..SwapConfig::default()
+        }
+    }
+
+    #[test]
+    fn should_keep_saved_config_without_upgrade_argument() {
+        let config = upgraded_config(None, Some(saved()));
+        assert_eq!(
+            config.webhook_url,
+            Some("https://example.com/hook".to_string())
+        );
+        assert_eq!(config.refund_grace_seconds, 120);
+    }
+
+    #[test]
+    fn should_replace_config_with_upgrade_argument() {
+        let init_arg = SwapInitArg {
+            refund_grace_seconds: Some(30),
+            ..SwapInitArg::default()
+        };
+        let config = upgraded_config(Some(init_arg), Some(saved()));
+        assert_eq!(config.webhook_url, None);
+        assert_eq!(config.refund_grace_seconds, 30);
+    }
+
+    #[test]
+    fn should_fall_back_to_defaults_when_nothing_was_saved() {
+        let config = upgraded_config(None, None);
+        assert_eq!(
+            config.deposit_poll_max_interval_seconds,
+            SwapConfig::from(SwapInitArg::default()).deposit_poll_max_interval_seconds
+        );
+        assert!(config.webhook_url.is_none());
+    }
+}
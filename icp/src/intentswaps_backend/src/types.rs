@@ -1,16 +1,27 @@
 use candid::{CandidType, Deserialize, Principal};
 
 // Type definitions
-#[derive(CandidType, Deserialize, Clone, Debug)]
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
 pub enum OrderStatus {
     AwaitingDeposit,   // Order created, waiting for user to deposit
     DepositReceived,   // User deposited, waiting for resolver
     ResolverDeposited, // Resolver deposited, ready for swap
+    SettlementFailed,  // A payout failed; funds still held, reveal_secret can be retried
     Completed,         // Swap completed successfully
     Cancelled,         // Order cancelled
     Expired,           // Order expired
 }
 
+impl OrderStatus {
+    // Terminal orders hold no funds and can no longer change state
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            OrderStatus::Completed | OrderStatus::Cancelled | OrderStatus::Expired
+        )
+    }
+}
+
 #[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
 pub enum Chain {
     Bitcoin,
@@ -18,7 +29,7 @@ pub enum Chain {
 }
 
 // Represents an asset on a blockchain
-#[derive(CandidType, Deserialize, Clone, Debug)]
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
 pub enum Asset {
     Bitcoin, // Native BTC
     Solana,  // Native SOL
@@ -36,11 +47,67 @@ pub struct OrderRequest {
     pub to_amount: u64,   // Amount in smallest unit
     pub secret_hash: String, // MD5 hash of the secret
     pub timeout_seconds: u64, // Time before order expires
+    pub pricing: Option<OrderPricing>, // Replaces to_amount when set
+    pub require_resolver_ack: Option<bool>, // Settle only once the resolver has acknowledged
+    // Display-unit amounts (e.g. "1.5"), converted with the asset's decimals; when the raw
+    // amount is also non-zero the two must match
+    pub from_amount_ui: Option<String>,
+    pub to_amount_ui: Option<String>,
+    pub metadata: Option<String>, // Opaque integrator reference, stored and returned verbatim
+    // Further assets the creator receives alongside to_asset, making a basket order;
+    // raw amounts only, and each asset at most once across to_asset and these legs
+    pub extra_to_legs: Option<Vec<(Asset, u64)>>,
+}
+
+// How the amount the creator receives is determined
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum OrderPricing {
+    Fixed {
+        to_amount: u64,
+    },
+    // to_amount is taken from the price feed when a resolver accepts; acceptance fails if that
+    // is more than max_slippage_bps below the reference amount quoted at creation
+    Oracle {
+        reference: u64,
+        max_slippage_bps: u16,
+    },
+}
+
+// USD price of one whole unit of an asset, in millionths of a dollar
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct AssetPrice {
+    pub asset: Asset,
+    pub usd_micros: u64,
+}
+
+// An order as its creator or resolver sees it: the public view plus the transaction IDs and,
+// once completed, the secret
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct OrderDetail {
+    pub info: OrderInfo,
+    pub creator_txid: Option<String>,
+    pub resolver_txid: Option<String>,
+    pub resolver_payout_txid: Option<String>,
+    pub creator_payout_txid: Option<String>,
+    pub extra_leg_txids: Vec<String>,
+    pub resolver_refund_txid: Option<String>,
+    pub secret: Option<String>, // Withheld until the order is Completed
+}
+
+// A resolver's expected profit on an order at the feed prices, in millionths of a dollar
+// The resolver receives from_asset and pays every to leg plus the network fees of its deposits
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct ResolverEvaluation {
+    pub gross_spread: i64, // Value received minus value paid, before fees
+    pub estimated_fees: u64,
+    pub net_profit: i64,
+    pub profitable: bool,
 }
 
 #[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct Order {
     pub id: u64,
+    pub sequence: u64, // Monotonic creation counter; equals `id` unless opaque IDs are enabled
     pub creator: Principal,
     pub creator_btc_address: Option<String>, // User's Bitcoin address for refunds
     pub creator_sol_address: Option<String>, // User's Solana address for refunds/receives
@@ -48,19 +115,61 @@ pub struct Order {
     pub to_asset: Asset,
     pub from_amount: u64,
     pub to_amount: u64,
+    pub pricing: OrderPricing,
+    pub extra_to_legs: Vec<(Asset, u64)>, // Basket legs the resolver deposits beyond to_asset
     pub secret_hash: String,
     pub secret: Option<String>,
     pub status: OrderStatus,
     pub resolver: Option<Principal>,
     pub resolver_btc_address: Option<String>,
     pub resolver_sol_address: Option<String>,
-    pub created_at: u64,
+    pub created_at: u64, // Nanoseconds since epoch, like every timestamp here (see `get_time`)
     pub expires_at: u64,
     // Transaction tracking
     pub creator_txid: Option<String>, // Bitcoin/Solana transaction ID from creator
     pub resolver_txid: Option<String>, // Bitcoin/Solana transaction ID from resolver
+    pub resolver_payout_txid: Option<String>, // Set once settlement has paid the resolver
+    pub creator_payout_txid: Option<String>, // Set once settlement has paid the creator
+    pub extra_leg_txids: Vec<String>, // Payouts or refunds of extra_to_legs sent so far, in order
+    pub resolver_refund_txid: Option<String>, // Set once `claim_refund` returned the resolver's deposit
+    pub creator_refund_amount: Option<u64>, // Amount actually refunded, below from_amount when partial
+    pub resolver_refund_amount: Option<u64>, // Amount actually refunded, below to_amount when partial
+    pub creator_overpayment: Option<u64>, // How much the creator's deposit paid beyond from_amount
+    pub overpayment_refund_txid: Option<String>, // Set once that excess was sent back
+    pub settlement_receipt: Option<SettlementReceipt>, // Fees and cycles, once completed
     pub creator_deposited: bool,
     pub resolver_deposited: bool,
+    pub require_resolver_ack: bool, // reveal_secret waits for resolver_acknowledge
+    pub secret_revealed_by: Option<Principal>, // Creator or resolver, whoever called reveal_secret
+    pub manual_review: Option<String>, // Why an operator must handle the order, e.g. a blocked payout
+    pub metadata: Option<String>,      // From the request; never interpreted by the canister
+    // Lifecycle timestamps (nanoseconds since epoch)
+    pub deposit_confirmed_at: Option<u64>,
+    pub accepted_at: Option<u64>,
+    pub resolver_deposited_at: Option<u64>,
+    pub resolver_acknowledged_at: Option<u64>,
+    pub completed_at: Option<u64>,
+    pub cancelled_at: Option<u64>,
+}
+
+// An asset flattened for clients, so they need not match on `Asset` to read its details
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct AssetInfo {
+    pub kind: String,         // "Bitcoin", "Solana" or "SplToken"
+    pub mint: Option<String>, // SPL mint address
+    pub decimals: u8,
+    pub symbol: Option<String>, // None for SPL tokens whose metadata is not known
+}
+
+// An asset orders may use, with what an asset picker needs to display it
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct SupportedAsset {
+    pub kind: String,         // "Bitcoin", "Solana" or "SplToken"
+    pub mint: Option<String>, // SPL mint address
+    pub decimals: Option<u8>, // None for SPL mints whose metadata is not cached yet
+    pub symbol: Option<String>,
+    pub name: Option<String>,
+    pub network: String, // e.g. "Bitcoin Testnet" or "Solana Devnet"
 }
 
 #[derive(CandidType, Deserialize, Clone, Debug)]
@@ -71,19 +180,106 @@ pub struct OrderInfo {
     pub creator_sol_address: Option<String>,
     pub from_asset: Asset,
     pub to_asset: Asset,
+    pub from_asset_info: AssetInfo,
+    pub to_asset_info: AssetInfo,
     pub from_amount: u64,
     pub to_amount: u64,
+    pub pricing: OrderPricing,
     pub secret_hash: String,
     pub status: OrderStatus,
     pub resolver: Option<Principal>,
     pub resolver_btc_address: Option<String>,
     pub resolver_sol_address: Option<String>,
-    pub created_at: u64,
+    pub created_at: u64, // Nanoseconds since epoch; divide by 1_000_000 for JS milliseconds
     pub expires_at: u64,
     pub canister_btc_address: String,
     pub canister_sol_address: String,
     pub creator_deposited: bool,
     pub resolver_deposited: bool,
+    pub require_resolver_ack: bool,
+    pub resolver_acknowledged: bool,
+    pub secret_revealed_by: Option<Principal>,
+    pub manual_review: Option<String>,
+    pub creator_refund_amount: Option<u64>,
+    pub resolver_refund_amount: Option<u64>,
+    pub metadata: Option<String>,
+    pub creator_overpayment: Option<u64>,
+    pub overpayment_refund_txid: Option<String>,
+    pub extra_to_legs: Vec<(Asset, u64)>,
+}
+
+// Display details of an SPL mint
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct TokenMetadata {
+    pub mint_address: String,
+    pub name: Option<String>,   // None when the mint has no metadata
+    pub symbol: Option<String>, // None when the mint has no metadata
+    pub decimals: u8,
+}
+
+// One SPL token account of an owner, as listed by `get_all_token_balances`
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct TokenBalance {
+    pub token_account: String,
+    pub mint_address: String,
+    pub token_program: String, // Legacy SPL Token or Token-2022
+    pub amount: u64,           // Smallest unit of the mint
+    pub decimals: u8,
+}
+
+// Circuit breaker state of the calls the canister makes to one chain
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct ChainStatus {
+    pub chain: Chain,
+    pub available: bool, // False while the chain's operations fail fast with ChainUnavailable
+    pub consecutive_failures: u32,
+    pub tripped_at: Option<u64>, // When the failure threshold was last reached (ns)
+    pub last_error: Option<String>,
+}
+
+// Order timestamp a time-range search filters on
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum TimeField {
+    CreatedAt,
+    CompletedAt,
+}
+
+// Which party of an order a deposit is expected from
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum DepositLeg {
+    Creator,  // Deposits from_amount of from_asset
+    Resolver, // Deposits to_amount of to_asset
+}
+
+// What a party has to do next on an order
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum OrderAction {
+    Deposit,         // Send this party's leg to the canister
+    Acknowledge,     // Resolver confirms readiness (orders created with `require_resolver_ack`)
+    RevealSecret,    // Creator reveals the secret to settle, or retries a failed settlement
+    ClaimRefund,     // Order expired with this party's deposit still held
+    WaitForResolver, // Nothing to do until a resolver accepts, deposits or acknowledges
+    None,            // Nothing to do; the other party has to act
+}
+
+// The next action the caller has to take on one of their orders, in the role they hold
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct ActionableOrder {
+    pub order_id: u64,
+    pub role: DepositLeg,
+    pub action: OrderAction,
+}
+
+// Everything a depositor needs to "send exactly `amount` to `address_or_ata`"
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct DepositInstructions {
+    pub asset: Asset,
+    pub address_or_ata: String, // Associated token account for SPL tokens
+    pub amount: u64,            // In the asset's smallest unit
+    pub decimals: u8,
+    pub network: String, // e.g. "Bitcoin Testnet", "Solana Devnet"
+    pub confirmations_required: u64,
+    pub memo: Option<String>, // Memo instruction the transfer must carry, when required
 }
 
 #[derive(CandidType, Deserialize, Clone, Debug)]
@@ -91,3 +287,478 @@ pub struct CanisterAddresses {
     pub bitcoin_address: String,
     pub solana_address: String,
 }
+
+// One party's payout/refund wallets
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct PartyAddresses {
+    pub btc_address: Option<String>,
+    pub sol_address: Option<String>,
+}
+
+// Threshold public keys the canister signs payouts with, for verifying its signing identity.
+// `btc_ecdsa_pubkey_hex` is the compressed secp256k1 key behind the P2WPKH address.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PublicKeys {
+    pub btc_ecdsa_pubkey_hex: String,
+    pub sol_ed25519_pubkey_base58: String,
+    pub attestation_ecdsa_pubkey_hex: String, // Key settlement attestations are signed with
+}
+
+// Merkle inclusion proof for a Bitcoin deposit, as an alternative to a bare txid.
+// `merkle_block_hex` is the BIP-37 merkle block returned by `bitcoin-cli gettxoutproof`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BitcoinDepositProof {
+    pub raw_transaction_hex: String, // Serialized deposit transaction
+    pub merkle_block_hex: String,    // Block header + partial merkle tree
+    pub block_height: u32,           // Height of the block containing the transaction
+}
+
+// Transaction format used for Solana payouts
+// V0 transactions can reference address lookup tables to fit more accounts
+// DurableNonce transactions use the canister's nonce account instead of a recent blockhash,
+// so they stay valid until the nonce advances
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub enum SolanaTransactionFormat {
+    #[default]
+    Legacy,
+    V0 {
+        address_lookup_tables: Vec<String>, // Lookup table account addresses
+    },
+    DurableNonce,
+}
+
+// Per-asset amount limit, in the asset's smallest unit
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct AssetLimit {
+    pub asset: Asset,
+    pub amount: u64,
+}
+
+// Bonus for resolvers who deposit soon after accepting, decaying linearly to zero over the window
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct ResolverRebateConfig {
+    pub window_seconds: u64, // Deposits this long after acceptance or later earn nothing
+    pub max_rebate_bps: u64, // Rebate for an immediate deposit, in basis points of the payout
+}
+
+// Rebate accrued by a resolver when one of their orders settled
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct ResolverRebate {
+    pub order_id: u64,
+    pub asset: Asset,
+    pub amount: u64,
+}
+
+// Track record of a resolver across the orders they accepted
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ResolverStats {
+    pub accepted: u64,
+    pub deposited: u64,
+    pub completed: u64,
+    pub abandoned: u64, // Accepted but never deposited before the order expired
+    pub avg_deposit_latency: u64, // Nanoseconds from acceptance to deposit, over deposited orders
+}
+
+// Number of stored orders in each status, for dashboards that only need totals
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct OrderCounts {
+    pub awaiting_deposit: u64,
+    pub deposit_received: u64,
+    pub resolver_deposited: u64,
+    pub settlement_failed: u64,
+    pub completed: u64,
+    pub cancelled: u64,
+    pub expired: u64,
+}
+
+// Order lifecycle changes pushed to the configured webhook
+#[derive(Clone, Debug, PartialEq)]
+pub enum WebhookEventKind {
+    DepositConfirmed(DepositLeg),
+    Settled,
+    Refunded(DepositLeg),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WebhookEvent {
+    pub order_id: u64,
+    pub kind: WebhookEventKind,
+    pub status: OrderStatus, // Status of the order right after the change
+    pub at: u64,
+}
+
+// One state-changing call recorded in the audit log
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct AuditEntry {
+    pub id: u64, // Assigned on append, increasing across evictions
+    pub timestamp: u64,
+    pub caller: Principal,
+    pub order_id: Option<u64>,
+    pub action: String,
+    pub result: Result<(), String>,
+}
+
+// Outcome of checking a deposit on-chain, including why it does not (yet) count
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct VerificationResult {
+    pub verified: bool,
+    pub observed_amount: u64, // Smallest unit held at the recipient address
+    pub confirmations: Option<u64>, // Only tracked for Bitcoin
+    pub reason: Option<String>, // Set when not verified
+}
+
+impl VerificationResult {
+    pub fn not_found(reason: &str) -> Self {
+        VerificationResult {
+            verified: false,
+            observed_amount: 0,
+            confirmations: None,
+            reason: Some(reason.to_string()),
+        }
+    }
+
+    // A deposit that was seen: verified once both the amount and the confirmations suffice
+    pub fn assess(
+        observed_amount: u64,
+        expected_amount: u64,
+        confirmations: Option<u64>,
+        required_confirmations: u64,
+    ) -> Self {
+        let reason = if observed_amount < expected_amount {
+            Some(format!(
+                "Insufficient amount: observed {}, expected {}",
+                observed_amount, expected_amount
+            ))
+        } else {
+            match confirmations {
+                Some(seen) if seen < required_confirmations => Some(format!(
+                    "Seen with {} confirmations, waiting for {}",
+                    seen, required_confirmations
+                )),
+                _ => None,
+            }
+        };
+
+        VerificationResult {
+            verified: reason.is_none(),
+            observed_amount,
+            confirmations,
+            reason,
+        }
+    }
+}
+
+// Canister configuration supplied at init/upgrade
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct SwapInitArg {
+    // Largest from/to amount per order
+    pub max_order_amounts: Option<Vec<AssetLimit>>,
+    // Largest total held across open orders
+    pub max_outstanding_amounts: Option<Vec<AssetLimit>>,
+    // Pinned Bitcoin fee rate in millisatoshi/byte
+    pub btc_fee_per_byte_override: Option<u64>,
+    // Send Solana payouts with the canister's nonce account
+    pub use_durable_nonce: Option<bool>,
+    // Reward resolvers for depositing quickly after acceptance
+    pub resolver_rebate: Option<ResolverRebateConfig>,
+    // Longest an order may stay open, including extensions
+    pub max_timeout_seconds: Option<u64>,
+    // Most non-terminal orders allowed to involve any single asset
+    pub max_open_orders_per_asset: Option<u64>,
+    // Retry Bitcoin confirm_deposit calls whose transaction was not seen yet this often (off when
+    // unset); deposits no confirm_deposit named are not detected
+    pub deposit_watch_interval_seconds: Option<u64>,
+    // Measure the cycles spent by each order update call
+    pub track_operation_costs: Option<bool>,
+    // SPL mints orders may trade
+    pub allowed_spl_mints: Option<Vec<String>>,
+    // Accept any SPL mint, ignoring the allowlist (testing only)
+    pub allow_any_spl_mint: Option<bool>,
+    // Re-issue Solana RPC reads this many times when providers disagree (default 2)
+    pub solana_rpc_retries: Option<u32>,
+    // mempool.space style fees/recommended URL, used when the Bitcoin canister has no estimate
+    pub btc_fee_api_url: Option<String>,
+    // Only credit SOL deposits whose memo instruction carries the order reference
+    pub require_solana_memo: Option<bool>,
+    // Derive order IDs by hashing instead of exposing the sequential counter
+    pub opaque_order_ids: Option<bool>,
+    // Keep the secret out of the order until both settlement payouts have gone through
+    pub defer_secret_until_settled: Option<bool>,
+    // Refuse to settle when network fees would have to come out of other orders' deposits
+    pub guard_settlement_economics: Option<bool>,
+    // BTC/SOL addresses the canister refuses to pay out to
+    pub blocked_addresses: Option<Vec<String>>,
+    // How long a resolver waits for the secret before `resolver_cancel` may refund both sides
+    // (default 1 hour)
+    pub resolver_protection_seconds: Option<u64>,
+    // Serve repeated SOL/BTC balance reads of an address from cache for this long (default 5, 0 disables)
+    pub balance_cache_ttl_seconds: Option<u64>,
+    // Refunds open this long after `expires_at`, so they cannot race a last-moment reveal (default 60)
+    pub refund_grace_seconds: Option<u64>,
+    // Enables development-only endpoints such as execute_full_swap and test_send_sol; never set in
+    // production
+    pub test_mode: Option<bool>,
+    // Highest compute unit price payouts bid from recent prioritization fees, in micro-lamports
+    // (default 100000, 0 sends without a priority fee)
+    pub max_compute_unit_price: Option<u64>,
+    // Slowest a pending deposit confirmation is retried, reached as it nears expiry; retries start
+    // every deposit_watch_interval_seconds after creation and slow down linearly (default 600)
+    pub deposit_poll_max_interval_seconds: Option<u64>,
+    // Cancel orders whose creator deposit has not arrived this long after creation (off when
+    // unset); never later than expiry, so unfunded orders are gone before acceptance closes
+    pub creator_deposit_seconds: Option<u64>,
+    // Smallest from_amount per asset, so fees cannot dwarf the swap (defaults to 10000 sats and
+    // 0.001 SOL; an empty list disables the check)
+    pub min_deposit_amounts: Option<Vec<AssetLimit>>,
+    // Simulate Solana payouts before broadcasting them, so failing ones are never sent
+    // (default true; turn off to save the extra RPC call)
+    pub simulate_solana_sends: Option<bool>,
+    // Bounds the Bitcoin fee rate is clamped to, whether estimated or pinned, in millisatoshi/byte
+    // (defaults 1000 and 500000, i.e. 1 and 500 sat/vB)
+    pub btc_min_fee_per_byte: Option<u64>,
+    pub btc_max_fee_per_byte: Option<u64>,
+    // HTTPS URL that deposit, settlement and refund events are POSTed to as JSON (off when unset)
+    pub webhook_url: Option<String>,
+}
+
+// Solana RPC reads are re-issued this many times when providers disagree, unless configured
+pub const DEFAULT_SOLANA_RPC_RETRIES: u32 = 2;
+
+// A resolver may back out of an unsettled swap this long after depositing, unless configured
+pub const DEFAULT_RESOLVER_PROTECTION_SECONDS: u64 = 3_600;
+
+// Balance reads are served from cache this long, unless configured
+pub const DEFAULT_BALANCE_CACHE_TTL_SECONDS: u64 = 5;
+
+// Refunds wait this long past expiry, unless configured
+pub const DEFAULT_REFUND_GRACE_SECONDS: u64 = 60;
+
+// Payout priority fees are capped at this compute unit price (micro-lamports), unless configured
+pub const DEFAULT_MAX_COMPUTE_UNIT_PRICE: u64 = 100_000;
+
+// Orders close to expiry are checked for deposits at most this often, unless configured
+pub const DEFAULT_DEPOSIT_POLL_MAX_INTERVAL_SECONDS: u64 = 600;
+
+// Smallest deposits accepted unless configured: below these, network fees rival the amount swapped
+pub const DEFAULT_MIN_BTC_DEPOSIT_SATS: u64 = 10_000;
+pub const DEFAULT_MIN_SOL_DEPOSIT_LAMPORTS: u64 = 1_000_000;
+
+// Bitcoin fee rates are kept within these bounds (millisatoshi/byte), unless configured
+pub const DEFAULT_BTC_MIN_FEE_PER_BYTE: u64 = 1_000;
+pub const DEFAULT_BTC_MAX_FEE_PER_BYTE: u64 = 500_000;
+
+// Runtime configuration derived from `SwapInitArg`, saved across upgrades
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct SwapConfig {
+    pub max_order_amounts: Vec<AssetLimit>,
+    pub max_outstanding_amounts: Vec<AssetLimit>,
+    pub btc_fee_per_byte_override: Option<u64>,
+    pub use_durable_nonce: bool,
+    pub resolver_rebate: Option<ResolverRebateConfig>,
+    pub max_timeout_seconds: Option<u64>,
+    pub max_open_orders_per_asset: Option<u64>,
+    pub deposit_watch_interval_seconds: Option<u64>,
+    pub track_operation_costs: bool,
+    pub allowed_spl_mints: Vec<String>,
+    pub allow_any_spl_mint: bool,
+    pub solana_rpc_retries: u32,
+    pub btc_fee_api_url: Option<String>,
+    pub require_solana_memo: bool,
+    pub opaque_order_ids: bool,
+    pub defer_secret_until_settled: bool,
+    pub guard_settlement_economics: bool,
+    pub blocked_addresses: Vec<String>,
+    pub resolver_protection_seconds: u64,
+    pub balance_cache_ttl_seconds: u64,
+    pub refund_grace_seconds: u64,
+    pub test_mode: bool,
+    pub max_compute_unit_price: u64,
+    pub deposit_poll_max_interval_seconds: u64,
+    pub creator_deposit_seconds: Option<u64>,
+    pub min_deposit_amounts: Vec<AssetLimit>,
+    pub simulate_solana_sends: bool,
+    pub btc_min_fee_per_byte: u64,
+    pub btc_max_fee_per_byte: u64,
+    pub webhook_url: Option<String>,
+}
+
+impl Order {
+    // Every asset the creator receives, to_asset first
+    pub fn to_legs(&self) -> Vec<(Asset, u64)> {
+        let mut legs = vec![(self.to_asset.clone(), self.to_amount)];
+        legs.extend(self.extra_to_legs.iter().cloned());
+        legs
+    }
+}
+
+impl SwapConfig {
+    pub fn max_order_amount(&self, asset: &Asset) -> Option<u64> {
+        find_limit(&self.max_order_amounts, asset)
+    }
+
+    pub fn max_outstanding_amount(&self, asset: &Asset) -> Option<u64> {
+        find_limit(&self.max_outstanding_amounts, asset)
+    }
+
+    pub fn min_deposit_amount(&self, asset: &Asset) -> Option<u64> {
+        find_limit(&self.min_deposit_amounts, asset)
+    }
+
+    pub fn is_spl_mint_allowed(&self, mint_address: &str) -> bool {
+        self.allow_any_spl_mint
+            || self
+                .allowed_spl_mints
+                .iter()
+                .any(|mint| mint == mint_address)
+    }
+
+    pub fn is_address_blocked(&self, address: &str) -> bool {
+        self.blocked_addresses
+            .iter()
+            .any(|blocked| blocked == address)
+    }
+}
+
+fn default_min_deposit_amounts() -> Vec<AssetLimit> {
+    vec![
+        AssetLimit {
+            asset: Asset::Bitcoin,
+            amount: DEFAULT_MIN_BTC_DEPOSIT_SATS,
+        },
+        AssetLimit {
+            asset: Asset::Solana,
+            amount: DEFAULT_MIN_SOL_DEPOSIT_LAMPORTS,
+        },
+    ]
+}
+
+fn find_limit(limits: &[AssetLimit], asset: &Asset) -> Option<u64> {
+    limits
+        .iter()
+        .find(|limit| &limit.asset == asset)
+        .map(|limit| limit.amount)
+}
+
+impl From<SwapInitArg> for SwapConfig {
+    fn from(init_arg: SwapInitArg) -> Self {
+        SwapConfig {
+            max_order_amounts: init_arg.max_order_amounts.unwrap_or_default(),
+            max_outstanding_amounts: init_arg.max_outstanding_amounts.unwrap_or_default(),
+            btc_fee_per_byte_override: init_arg.btc_fee_per_byte_override,
+            use_durable_nonce: init_arg.use_durable_nonce.unwrap_or_default(),
+            resolver_rebate: init_arg.resolver_rebate,
+            max_timeout_seconds: init_arg.max_timeout_seconds,
+            max_open_orders_per_asset: init_arg.max_open_orders_per_asset,
+            deposit_watch_interval_seconds: init_arg.deposit_watch_interval_seconds,
+            track_operation_costs: init_arg.track_operation_costs.unwrap_or_default(),
+            allowed_spl_mints: init_arg.allowed_spl_mints.unwrap_or_default(),
+            allow_any_spl_mint: init_arg.allow_any_spl_mint.unwrap_or_default(),
+            solana_rpc_retries: init_arg
+                .solana_rpc_retries
+                .unwrap_or(DEFAULT_SOLANA_RPC_RETRIES),
+            btc_fee_api_url: init_arg.btc_fee_api_url,
+            require_solana_memo: init_arg.require_solana_memo.unwrap_or_default(),
+            opaque_order_ids: init_arg.opaque_order_ids.unwrap_or_default(),
+            defer_secret_until_settled: init_arg.defer_secret_until_settled.unwrap_or_default(),
+            guard_settlement_economics: init_arg.guard_settlement_economics.unwrap_or_default(),
+            blocked_addresses: init_arg.blocked_addresses.unwrap_or_default(),
+            resolver_protection_seconds: init_arg
+                .resolver_protection_seconds
+                .unwrap_or(DEFAULT_RESOLVER_PROTECTION_SECONDS),
+            balance_cache_ttl_seconds: init_arg
+                .balance_cache_ttl_seconds
+                .unwrap_or(DEFAULT_BALANCE_CACHE_TTL_SECONDS),
+            refund_grace_seconds: init_arg
+                .refund_grace_seconds
+                .unwrap_or(DEFAULT_REFUND_GRACE_SECONDS),
+            test_mode: init_arg.test_mode.unwrap_or_default(),
+            max_compute_unit_price: init_arg
+                .max_compute_unit_price
+                .unwrap_or(DEFAULT_MAX_COMPUTE_UNIT_PRICE),
+            deposit_poll_max_interval_seconds: init_arg
+                .deposit_poll_max_interval_seconds
+                .unwrap_or(DEFAULT_DEPOSIT_POLL_MAX_INTERVAL_SECONDS),
+            creator_deposit_seconds: init_arg.creator_deposit_seconds,
+            min_deposit_amounts: init_arg
+                .min_deposit_amounts
+                .unwrap_or_else(default_min_deposit_amounts),
+            simulate_solana_sends: init_arg.simulate_solana_sends.unwrap_or(true),
+            btc_min_fee_per_byte: init_arg
+                .btc_min_fee_per_byte
+                .unwrap_or(DEFAULT_BTC_MIN_FEE_PER_BYTE),
+            btc_max_fee_per_byte: init_arg
+                .btc_max_fee_per_byte
+                .unwrap_or(DEFAULT_BTC_MAX_FEE_PER_BYTE),
+            webhook_url: init_arg.webhook_url,
+        }
+    }
+}
+
+// When each lifecycle step of an order happened (nanoseconds since epoch)
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct OrderTimeline {
+    pub order_id: u64,
+    pub status: OrderStatus,
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub deposit_confirmed_at: Option<u64>,
+    pub accepted_at: Option<u64>,
+    pub resolver_deposited_at: Option<u64>,
+    pub completed_at: Option<u64>,
+    pub cancelled_at: Option<u64>,
+}
+
+// An order's deadlines and the whole seconds left until each, by the canister clock
+// Remaining seconds are 0 once a deadline has passed
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Deadlines {
+    pub now: u64,
+    pub expires_at: u64,
+    pub creator_deposit_deadline: Option<u64>, // Cancelled if still unfunded by then, when configured
+    pub acceptance_deadline: u64, // Last moment a resolver can still accept; equals expires_at
+    pub resolver_refund_at: u64, // When claim_refund opens: expires_at plus the refund grace period
+    pub seconds_to_expiry: u64,
+    pub seconds_to_acceptance_deadline: u64,
+    pub seconds_to_resolver_refund: u64,
+}
+
+// Deposited-but-unsettled amount of an asset versus what the canister actually holds
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct AssetSolvency {
+    pub asset: Asset,
+    pub expected_held: u64, // Sum of confirmed deposits on open orders
+    pub actual_held: u64,   // On-chain balance of the canister's deposit address
+    pub is_solvent: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct SolvencyReport {
+    pub assets: Vec<AssetSolvency>,
+    pub is_solvent: bool, // True when every asset is solvent
+}
+
+// Receipt of a completed swap signed by the canister's threshold ECDSA attestation key
+// Verify `signature_hex` over SHA-256(`message`) with `get_public_keys().attestation_ecdsa_pubkey_hex`
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Attestation {
+    pub order_id: u64,
+    pub message: String, // Order ID, both settlement txids and amounts, one per line
+    pub signature_hex: String, // 64-byte compact secp256k1 signature
+}
+
+// What settling a completed order cost, recorded by the reveal_secret call that completed it
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct SettlementReceipt {
+    pub btc_fee: u64,      // Satoshis of miner fees, as estimated before the payouts
+    pub sol_fee: u64,      // Lamports of transaction fees, SPL payouts included
+    pub cycles_spent: u64, // Drop in the canister's cycle balance during the settling call
+    pub payout_amounts: Vec<(Asset, u64)>, // Resolver payout, then creator payout
+}
+
+// Whether each chain answered an address derivation and a balance read
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct SelfTestReport {
+    pub btc_ok: bool,
+    pub sol_ok: bool,
+    pub details: Vec<String>, // One line per chain: what was read, or why it failed
+}
@@ -0,0 +1,150 @@
+// Push delivery of order lifecycle events, for integrators that cannot poll the canister
+use crate::storage::{read_config, requeue_webhook_event, take_webhook_events};
+use crate::types::{DepositLeg, WebhookEvent, WebhookEventKind};
+use candid::Nat;
+use ic_cdk::management_canister::{
+    http_request, HttpHeader, HttpMethod, HttpRequestArgs, HttpRequestResult, TransformArgs,
+    TransformContext,
+};
+use std::future::Future;
+use std::time::Duration;
+
+// How often queued events are sent; updates only queue them, so they never wait on the outcall
+const WEBHOOK_DELIVERY_INTERVAL_SECONDS: u64 = 5;
+// Events sent per tick, bounding the cycles one tick can spend on outcalls
+const WEBHOOK_DELIVERIES_PER_TICK: usize = 10;
+// Deliveries of an event before it is dropped
+pub const MAX_WEBHOOK_ATTEMPTS: u32 = 3;
+// Only the status of the response is read
+const WEBHOOK_MAX_RESPONSE_BYTES: u64 = 1_024;
+
+/// Send queued events every `WEBHOOK_DELIVERY_INTERVAL_SECONDS`
+/// Timers do not survive upgrades, so this is called from both `init` and `post_upgrade`
+pub fn start() {
+    ic_cdk_timers::set_timer_interval(
+        Duration::from_secs(WEBHOOK_DELIVERY_INTERVAL_SECONDS),
+        || async {
+            deliver_webhooks_with(post_webhook).await;
+        },
+    );
+}
+
+/// Send up to `WEBHOOK_DELIVERIES_PER_TICK` queued events with `send`
+/// A failed event goes back to the end of the queue until it has had `MAX_WEBHOOK_ATTEMPTS`
+/// Returns how many events were delivered
+pub async fn deliver_webhooks_with<F, Fut>(send: F) -> usize
+where
+    F: Fn(String, WebhookEvent) -> Fut,
+    Fut: Future<Output = Result<(), String>>,
+{
+    let Some(url) = read_config(|config| config.webhook_url.clone()) else {
+        return 0;
+    };
+
+    let mut delivered = 0;
+    for (event, attempts) in take_webhook_events(WEBHOOK_DELIVERIES_PER_TICK) {
+        match send(url.clone(), event.clone()).await {
+            Ok(()) => delivered += 1,
+            Err(e) if attempts + 1 < MAX_WEBHOOK_ATTEMPTS => {
+                ic_cdk::println!(
+                    "⚠️ Webhook for order {} failed, retrying: {}",
+                    event.order_id,
+                    e
+                );
+                requeue_webhook_event(event, attempts + 1);
+            }
+            Err(e) => {
+                ic_cdk::println!("❌ Dropping webhook for order {}: {}", event.order_id, e);
+            }
+        }
+    }
+    delivered
+}
+
+/// POST an event as JSON to the webhook
+///
+/// Every replica sends the request, so the receiver can see it more than once; the
+/// `Idempotency-Key` header is the same on all of them for deduplication.
+async fn post_webhook(url: String, event: WebhookEvent) -> Result<(), String> {
+    let request = HttpRequestArgs {
+        url,
+        max_response_bytes: Some(WEBHOOK_MAX_RESPONSE_BYTES),
+        method: HttpMethod::POST,
+        headers: vec![
+            HttpHeader {
+                name: "Content-Type".to_string(),
+                value: "application/json".to_string(),
+            },
+            HttpHeader {
+                name: "Idempotency-Key".to_string(),
+                value: webhook_idempotency_key(&event),
+            },
+        ],
+        body: Some(webhook_body(&event).into_bytes()),
+        transform: Some(TransformContext::from_name(
+            "transform_webhook_response".to_string(),
+            vec![],
+        )),
+        ..Default::default()
+    };
+
+    let response = http_request(&request)
+        .await
+        .map_err(|e| format!("Webhook request failed: {:?}", e))?;
+    if response.status < Nat::from(200u32) || response.status >= Nat::from(300u32) {
+        return Err(format!("Webhook returned status {}", response.status));
+    }
+    Ok(())
+}
+
+/// JSON body of an event, built only from replicated state so every replica sends the same bytes
+pub fn webhook_body(event: &WebhookEvent) -> String {
+    let (kind, leg) = match &event.kind {
+        WebhookEventKind::DepositConfirmed(leg) => ("deposit_confirmed", Some(leg)),
+        WebhookEventKind::Settled => ("settled", None),
+        WebhookEventKind::Refunded(leg) => ("refunded", Some(leg)),
+    };
+    serde_json::json!({
+        "order_id": event.order_id,
+        "event": kind,
+        "leg": leg.map(|leg| match leg {
+            DepositLeg::Creator => "creator",
+            DepositLeg::Resolver => "resolver",
+        }),
+        "status": format!("{:?}", event.status),
+        "at": event.at,
+    })
+    .to_string()
+}
+
+/// Same for every delivery of one event, including retries
+pub fn webhook_idempotency_key(event: &WebhookEvent) -> String {
+    let kind = match &event.kind {
+        WebhookEventKind::DepositConfirmed(DepositLeg::Creator) => "deposit-creator",
+        WebhookEventKind::DepositConfirmed(DepositLeg::Resolver) => "deposit-resolver",
+        WebhookEventKind::Settled => "settled",
+        WebhookEventKind::Refunded(DepositLeg::Creator) => "refund-creator",
+        WebhookEventKind::Refunded(DepositLeg::Resolver) => "refund-resolver",
+    };
+    format!("{}-{}", event.order_id, kind)
+}
+
+/// Strips a webhook response down to its status
+///
+/// Replicas must agree on the transformed response, and receivers are free to answer with
+/// anything, so headers and body are dropped.
+pub fn transform_webhook_response(args: TransformArgs) -> HttpRequestResult {
+    HttpRequestResult {
+        status: args.response.status,
+        headers: vec![],
+        body: vec![],
+    }
+}
+
+/// Only HTTPS URLs can be reached by outcalls
+pub fn validate_webhook_url(url: &str) -> Result<(), String> {
+    if !url.starts_with("https://") {
+        return Err(format!("Webhook URL must use https: {}", url));
+    }
+    Ok(())
+}
@@ -1001,7 +1001,7 @@ function App() {
     if (!actor) return;
     setLoading(true);
     try {
-      const result = await actor.cancel_order(BigInt(orderId));
+      const result = await actor.cancel_order(BigInt(orderId), []);
 
       if ('Ok' in result) {
         showMessage(result.Ok);